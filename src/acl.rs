@@ -0,0 +1,646 @@
+use super::config::{self, Config};
+use super::glob;
+use super::parser::{AclOp, ClientOp, Command, Value};
+use super::sha256;
+use super::Error;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::sync::RwLock;
+
+/// Groups of commands that users are allowed to run
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Category {
+    Read,
+    Write,
+    Admin,
+    Pubsub,
+}
+
+const CATEGORIES: [Category; 4] = [
+    Category::Read,
+    Category::Write,
+    Category::Admin,
+    Category::Pubsub,
+];
+
+impl Category {
+    fn name(self) -> &'static str {
+        match self {
+            Category::Read => "read",
+            Category::Write => "write",
+            Category::Admin => "admin",
+            Category::Pubsub => "pubsub",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Vec<Category>> {
+        match s.to_ascii_lowercase().as_ref() {
+            "all" => Some(CATEGORIES.to_vec()),
+            s => CATEGORIES.iter().find(|c| c.name() == s).map(|&c| vec![c]),
+        }
+    }
+}
+
+/// A named user, holding the SHA-256 of its passwords, the categories of
+/// commands it may run and the patterns of the keys it may touch
+#[derive(Debug, PartialEq, Clone)]
+pub struct User {
+    enabled: bool,
+    /// Whether any password authenticates the user
+    nopass: bool,
+    passwords: BTreeSet<String>,
+    categories: BTreeSet<Category>,
+    keys: Vec<String>,
+}
+
+impl User {
+    /// A user that can neither log in nor run anything until given rules
+    fn new() -> Self {
+        User {
+            enabled: false,
+            nopass: false,
+            passwords: BTreeSet::new(),
+            categories: BTreeSet::new(),
+            keys: Vec::new(),
+        }
+    }
+
+    /// The user connections start as, allowed everything without a
+    /// password unless `requirepass` or the ACL file say otherwise
+    fn default_user() -> Self {
+        User {
+            enabled: true,
+            nopass: true,
+            passwords: BTreeSet::new(),
+            categories: CATEGORIES.iter().cloned().collect(),
+            keys: vec!["*".into()],
+        }
+    }
+
+    /// Apply a rule as given to ACL SETUSER, such as `on`, `>password`,
+    /// `+@read` or `~cache:*`
+    fn apply(&mut self, rule: &str) -> Result<(), String> {
+        match rule.to_ascii_lowercase().as_ref() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allcommands" => self.categories = CATEGORIES.iter().cloned().collect(),
+            "nocommands" => self.categories.clear(),
+            "allkeys" => self.keys = vec!["*".into()],
+            "resetkeys" => self.keys.clear(),
+            "reset" => *self = User::new(),
+            _ => match rule.split_at(rule.chars().next().map_or(0, char::len_utf8)) {
+                (">", password) => {
+                    self.nopass = false;
+                    self.passwords.insert(sha256::hex(password.as_bytes()));
+                }
+                ("<", password) => {
+                    self.passwords.remove(&sha256::hex(password.as_bytes()));
+                }
+                ("#", digest) => {
+                    self.nopass = false;
+                    self.passwords.insert(password_hash(digest)?);
+                }
+                ("!", digest) => {
+                    self.passwords.remove(&password_hash(digest)?);
+                }
+                ("~", pattern) => {
+                    if !self.keys.iter().any(|p| p == pattern) {
+                        self.keys.push(pattern.into());
+                    }
+                }
+                (sign @ "+", category) | (sign @ "-", category) if category.starts_with('@') => {
+                    let categories = Category::parse(&category[1..])
+                        .ok_or_else(|| "Unknown command category".to_string())?;
+                    for category in categories {
+                        match sign {
+                            "+" => self.categories.insert(category),
+                            _ => self.categories.remove(&category),
+                        };
+                    }
+                }
+                _ => return Err("Syntax error".into()),
+            },
+        }
+        Ok(())
+    }
+
+    /// Whether `password` logs in as this user
+    fn accepts(&self, password: &str) -> bool {
+        self.enabled && (self.nopass || self.passwords.contains(&sha256::hex(password.as_bytes())))
+    }
+
+    fn commands(&self) -> String {
+        match self.categories.len() {
+            0 => "-@all".into(),
+            n if n == CATEGORIES.len() => "+@all".into(),
+            _ => self
+                .categories
+                .iter()
+                .map(|c| format!("+@{}", c.name()))
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
+
+    /// The rules recreating this user, as listed by ACL LIST and written in
+    /// ACL files
+    fn describe(&self) -> String {
+        let mut rules = vec![if self.enabled { "on" } else { "off" }.to_string()];
+        if self.nopass {
+            rules.push("nopass".into());
+        }
+        rules.extend(self.passwords.iter().map(|p| format!("#{}", p)));
+        rules.extend(self.keys.iter().map(|k| format!("~{}", k)));
+        rules.push(self.commands());
+        rules.join(" ")
+    }
+
+    fn allows_key(&self, key: &str) -> bool {
+        self.keys.iter().any(|pattern| glob::matches(pattern, key))
+    }
+}
+
+/// Check a password hash given to ACL SETUSER, which must be SHA-256 hex
+fn password_hash(s: &str) -> Result<String, String> {
+    match s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        true => Ok(s.to_ascii_lowercase()),
+        false => Err("The password hash must be 64 hexadecimal characters".into()),
+    }
+}
+
+/// Users of the server, which clients authenticate as and which every
+/// command is checked against before it reaches a database
+pub struct Acl {
+    users: RwLock<BTreeMap<String, User>>,
+}
+
+impl Acl {
+    /// The default user, given the password of `requirepass` if set, and
+    /// then the users of `aclfile` if set
+    pub fn load(config: &Config) -> Result<Acl, config::Error> {
+        let mut default = User::default_user();
+        if let Some(ref password) = config.requirepass {
+            default
+                .apply(&format!(">{}", password))
+                .expect("Valid rule");
+        }
+        let mut users = BTreeMap::new();
+        users.insert("default".to_string(), default);
+        if let Some(ref path) = config.aclfile {
+            let file = fs::read_to_string(path).map_err(config::Error::Io)?;
+            Acl::parse(&file, &mut users)?;
+        }
+        Ok(Acl {
+            users: RwLock::new(users),
+        })
+    }
+
+    /// Read ACL file lines of the form `user <name> <rule>...`, where each
+    /// user starts from nothing allowed
+    fn parse(s: &str, users: &mut BTreeMap<String, User>) -> Result<(), config::Error> {
+        for (idx, line) in s.lines().enumerate() {
+            let args = line.split_whitespace().collect::<Vec<_>>();
+            let error = |msg: String| config::Error::Directive(idx + 1, msg);
+            match args.as_slice() {
+                [] => (),
+                [comment, ..] if comment.starts_with('#') => (),
+                ["user", name, rules @ ..] => {
+                    let mut user = User::new();
+                    for rule in rules {
+                        user.apply(rule)
+                            .map_err(|e| error(format!("{} in rule {}", e, rule)))?;
+                    }
+                    users.insert(name.to_string(), user);
+                }
+                _ => return Err(error("expected user <name> <rule>...".into())),
+            }
+        }
+        Ok(())
+    }
+
+    /// The user new connections are logged in as, if it needs no password
+    pub fn initial_user(&self) -> Option<String> {
+        let users = self.users.read().expect("Poisoned ACL lock");
+        match users.get("default") {
+            Some(user) if user.accepts("") => Some("default".into()),
+            _ => None,
+        }
+    }
+
+    /// The user named by a client certificate, if it exists and is enabled
+    pub fn certificate_user(&self, name: &str) -> Option<String> {
+        let users = self.users.read().expect("Poisoned ACL lock");
+        match users.get(name) {
+            Some(user) if user.enabled => Some(name.into()),
+            _ => None,
+        }
+    }
+
+    /// Check the password of a user, by default the default user
+    pub fn authenticate(&self, name: Option<&str>, password: &str) -> Result<String, Error> {
+        let name = name.unwrap_or("default");
+        let users = self.users.read().expect("Poisoned ACL lock");
+        match users.get(name) {
+            Some(user) if user.accepts(password) => Ok(name.into()),
+            _ => Err(Error::WrongPass),
+        }
+    }
+
+    /// Whether the client logged in as `name`, if at all, may run
+    /// `command`
+    pub fn check(&self, name: Option<&str>, command: &Command) -> Result<(), Error> {
+        let name = match (name, command) {
+            (_, Command::Disconnect)
+            | (_, Command::Hello(_))
+            | (_, Command::Auth(..))
+            | (Some(_), Command::Acl(AclOp::WhoAmI)) => return Ok(()),
+            (Some(name), _) => name,
+            (None, _) => return Err(Error::NoAuth),
+        };
+        let users = self.users.read().expect("Poisoned ACL lock");
+        let user = users.get(name).ok_or(Error::NoAuth)?;
+        if let Some(category) = category(command) {
+            if !user.categories.contains(&category) {
+                return Err(Error::NoPerm(format!(
+                    "User {} has no permissions to run {} commands",
+                    name,
+                    category.name()
+                )));
+            }
+        }
+        let allowed = match keys(command) {
+            Some(keys) => keys.iter().all(|key| user.allows_key(key)),
+            // Commands acting on every key need access to every key
+            None => user.keys.iter().any(|pattern| pattern == "*"),
+        };
+        match allowed {
+            true => Ok(()),
+            false => Err(Error::NoPerm("No permissions to access a key".into())),
+        }
+    }
+
+    /// Create or modify a user, applying every rule or none of them
+    pub fn setuser(&self, name: &str, rules: &[String]) -> Result<Value, Error> {
+        let mut users = self.users.write().expect("Poisoned ACL lock");
+        let mut user = users.get(name).cloned().unwrap_or_else(User::new);
+        for rule in rules {
+            user.apply(rule)
+                .map_err(|e| Error::AclRule(rule.clone(), e))?;
+        }
+        users.insert(name.into(), user);
+        Ok(Value::Status("OK".into()))
+    }
+
+    pub fn getuser(&self, name: &str) -> Value {
+        let users = self.users.read().expect("Poisoned ACL lock");
+        let user = match users.get(name) {
+            Some(user) => user,
+            None => return Value::Null,
+        };
+        let text = |s: &str| Value::Text(s.into());
+        let mut flags = vec![text(if user.enabled { "on" } else { "off" })];
+        if user.nopass {
+            flags.push(text("nopass"));
+        }
+        Value::Array(vec![
+            text("flags"),
+            Value::Array(flags),
+            text("passwords"),
+            Value::Array(user.passwords.iter().map(|p| text(p)).collect()),
+            text("commands"),
+            text(&user.commands()),
+            text("keys"),
+            Value::Array(user.keys.iter().map(|k| text(k)).collect()),
+        ])
+    }
+
+    pub fn list(&self) -> Value {
+        let users = self.users.read().expect("Poisoned ACL lock");
+        let lines = users
+            .iter()
+            .map(|(name, user)| Value::Text(format!("user {} {}", name, user.describe())))
+            .collect();
+        Value::Array(lines)
+    }
+}
+
+/// Category of `command`, or `None` for connection commands anyone may run
+pub fn category(command: &Command) -> Option<Category> {
+    Some(match command {
+        Command::Disconnect | Command::Hello(_) | Command::Auth(..) | Command::Select(_) => {
+            return None
+        }
+        Command::Acl(AclOp::WhoAmI) => return None,
+        Command::Client(ClientOp::Kill(_))
+        | Command::Client(ClientOp::List)
+        | Command::Client(ClientOp::Pause(..))
+        | Command::Client(ClientOp::Unpause)
+        | Command::Info(_) => Category::Admin,
+        Command::Client(_) => return None,
+        Command::Acl(_) | Command::SwapDb(..) | Command::FlushDb | Command::FlushAll => {
+            Category::Admin
+        }
+        Command::Subscribe(_) => Category::Pubsub,
+        Command::Read(_)
+        | Command::StrLen(_)
+        | Command::GetRange(..)
+        | Command::GetBit(..)
+        | Command::BitCount(..)
+        | Command::BitPos(..)
+        | Command::PfCount(_)
+        | Command::LRange(..)
+        | Command::LLen(_)
+        | Command::LIndex(..)
+        | Command::HGet(..)
+        | Command::HMGet(..)
+        | Command::HGetAll(_)
+        | Command::HKeys(_)
+        | Command::HVals(_)
+        | Command::HLen(_)
+        | Command::HExists(..)
+        | Command::HScan(..)
+        | Command::SScan(..)
+        | Command::ZScan(..)
+        | Command::Scan(_)
+        | Command::Keys(_)
+        | Command::DbSize
+        | Command::RandomKey
+        | Command::Range(..)
+        | Command::Prefix(_)
+        | Command::Exists(_)
+        | Command::Type(_)
+        | Command::Touch(_)
+        | Command::Object(..)
+        | Command::MGet(_)
+        | Command::SIsMember(..)
+        | Command::SMembers(_)
+        | Command::SCard(_)
+        | Command::SRandMember(..)
+        | Command::SUnion(_)
+        | Command::SInter(_)
+        | Command::SDiff(_)
+        | Command::ZScore(..)
+        | Command::ZRank(..)
+        | Command::ZCard(_)
+        | Command::ZRange(..)
+        | Command::XLen(_)
+        | Command::XRange(..)
+        | Command::XRevRange(..)
+        | Command::XPending(..)
+        | Command::GeoPos(..)
+        | Command::GeoDist(..)
+        | Command::GeoHash(..)
+        | Command::GeoSearch(..)
+        | Command::JsonGet(..) => Category::Read,
+        // Reading a stream as part of a group moves entries into the
+        // group's pending list
+        Command::XRead(read) if read.group.is_none() => Category::Read,
+        _ => Category::Write,
+    })
+}
+
+/// Keys `command` touches, or `None` if it acts on every key of a database
+pub fn keys(command: &Command) -> Option<Vec<&str>> {
+    fn one(key: &str) -> Vec<&str> {
+        vec![key]
+    }
+    fn many(keys: &[String]) -> Vec<&str> {
+        keys.iter().map(|k| k.as_str()).collect()
+    }
+    fn with<'a>(key: &'a str, keys: &'a [String]) -> Vec<&'a str> {
+        let mut all = vec![key];
+        all.extend(many(keys));
+        all
+    }
+    Some(match command {
+        Command::Disconnect
+        | Command::Hello(_)
+        | Command::Auth(..)
+        | Command::Acl(_)
+        | Command::Client(_)
+        | Command::Info(_)
+        | Command::Select(_)
+        | Command::Scan(_)
+        | Command::Keys(_)
+        | Command::DbSize
+        | Command::RandomKey => vec![],
+        Command::Range(..) | Command::Prefix(_) => return None,
+        Command::SwapDb(..) | Command::FlushDb | Command::FlushAll => return None,
+        Command::Create(key, _)
+        | Command::Read(key)
+        | Command::Update(key, _)
+        | Command::Delete(key)
+        | Command::Subscribe(key)
+        | Command::Incr(key)
+        | Command::Decr(key)
+        | Command::IncrBy(key, _)
+        | Command::DecrBy(key, _)
+        | Command::IncrByFloat(key, _)
+        | Command::Append(key, _)
+        | Command::GetRange(key, ..)
+        | Command::SetRange(key, ..)
+        | Command::StrLen(key)
+        | Command::GetSet(key, _)
+        | Command::GetDel(key)
+        | Command::GetEx(key, _)
+        | Command::SetBit(key, ..)
+        | Command::GetBit(key, _)
+        | Command::BitCount(key, _)
+        | Command::BitPos(key, ..)
+        | Command::BitField(key, _)
+        | Command::PfAdd(key, _)
+        | Command::LPush(key, _)
+        | Command::RPush(key, _)
+        | Command::LPop(key, _)
+        | Command::RPop(key, _)
+        | Command::LRange(key, ..)
+        | Command::LLen(key)
+        | Command::LIndex(key, _)
+        | Command::LSet(key, ..)
+        | Command::LTrim(key, ..)
+        | Command::LRem(key, ..)
+        | Command::LInsert(key, ..)
+        | Command::HSet(key, _)
+        | Command::HGet(key, _)
+        | Command::HMGet(key, _)
+        | Command::HDel(key, _)
+        | Command::HGetAll(key)
+        | Command::HKeys(key)
+        | Command::HVals(key)
+        | Command::HLen(key)
+        | Command::HExists(key, _)
+        | Command::HIncrBy(key, ..)
+        | Command::HScan(key, _)
+        | Command::SScan(key, _)
+        | Command::ZScan(key, _)
+        | Command::Move(key, _)
+        | Command::Type(key)
+        | Command::Object(_, key)
+        | Command::Set(key, ..)
+        | Command::SAdd(key, _)
+        | Command::SRem(key, _)
+        | Command::SIsMember(key, _)
+        | Command::SMembers(key)
+        | Command::SCard(key)
+        | Command::SPop(key, _)
+        | Command::SRandMember(key, _)
+        | Command::ZAdd(key, ..)
+        | Command::ZScore(key, _)
+        | Command::ZRank(key, _)
+        | Command::ZCard(key)
+        | Command::ZRange(key, _)
+        | Command::ZRem(key, _)
+        | Command::ZRemRangeByScore(key, ..)
+        | Command::ZPopMin(key, _)
+        | Command::ZPopMax(key, _)
+        | Command::XAdd(key, _)
+        | Command::XLen(key)
+        | Command::XRange(key, ..)
+        | Command::XRevRange(key, ..)
+        | Command::XAck(key, ..)
+        | Command::XPending(key, ..)
+        | Command::GeoAdd(key, ..)
+        | Command::GeoPos(key, _)
+        | Command::GeoDist(key, ..)
+        | Command::GeoHash(key, _)
+        | Command::GeoSearch(key, _)
+        | Command::JsonSet(key, ..)
+        | Command::JsonGet(key, ..)
+        | Command::JsonDel(key, _)
+        | Command::JsonArrAppend(key, ..)
+        | Command::JsonNumIncrBy(key, ..) => one(key),
+        Command::XClaim(claim) => one(&claim.key),
+        Command::XAutoClaim(claim) => one(&claim.key),
+        Command::XGroup(op) => one(op.key()),
+        Command::LMove(source, dest, ..)
+        | Command::BLMove(source, dest, ..)
+        | Command::Rename(source, dest)
+        | Command::RenameNx(source, dest)
+        | Command::Copy(source, dest, ..) => vec![source.as_str(), dest.as_str()],
+        Command::PfCount(keys)
+        | Command::BLPop(keys, _)
+        | Command::BRPop(keys, _)
+        | Command::Exists(keys)
+        | Command::Touch(keys)
+        | Command::Unlink(keys)
+        | Command::MGet(keys)
+        | Command::SUnion(keys)
+        | Command::SInter(keys)
+        | Command::SDiff(keys) => many(keys),
+        Command::BitOp(_, dest, keys)
+        | Command::PfMerge(dest, keys)
+        | Command::SUnionStore(dest, keys)
+        | Command::SInterStore(dest, keys)
+        | Command::SDiffStore(dest, keys)
+        | Command::ZUnionStore(dest, keys, ..)
+        | Command::ZInterStore(dest, keys, ..) => with(dest, keys),
+        Command::MSet(pairs) | Command::MSetNx(pairs) => {
+            pairs.iter().map(|(k, _)| k.as_str()).collect()
+        }
+        Command::XRead(read) => read.streams.iter().map(|(k, _)| k.as_str()).collect(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn acl(file: &str) -> Acl {
+        let mut users = BTreeMap::new();
+        users.insert("default".to_string(), User::default_user());
+        Acl::parse(file, &mut users).unwrap();
+        Acl {
+            users: RwLock::new(users),
+        }
+    }
+
+    #[test]
+    fn rules() {
+        let mut user = User::new();
+        for rule in &["on", ">secret", "+@all", "-@admin", "~cache:*", "~user:?"] {
+            user.apply(rule).unwrap();
+        }
+        assert!(user.accepts("secret") && !user.accepts("guess"));
+        assert_eq!(
+            user.describe(),
+            format!(
+                "on #{} ~cache:* ~user:? +@read +@write +@pubsub",
+                sha256::hex(b"secret")
+            )
+        );
+        user.apply("<secret").unwrap();
+        assert!(!user.accepts("secret"));
+        assert!(user.apply("+@nope").is_err());
+        assert!(user.apply("#abc").is_err());
+        assert!(user.apply("bogus").is_err());
+
+        user.apply("nopass").unwrap();
+        assert!(user.accepts("anything"));
+        user.apply("off").unwrap();
+        assert!(!user.accepts("anything"));
+    }
+
+    #[test]
+    fn permissions() {
+        let acl = acl(
+            "# users\nuser reader on >pw ~cache:* +@read\n\nuser admin on nopass allkeys +@all\n",
+        );
+        let read = Command::Read("cache:1".into());
+        let write = Command::Delete("cache:1".into());
+        assert_eq!(acl.check(None, &read), Err(Error::NoAuth));
+        assert_eq!(acl.check(None, &Command::Auth(None, "pw".into())), Ok(()));
+
+        assert_eq!(acl.authenticate(Some("reader"), "pw"), Ok("reader".into()));
+        assert_eq!(
+            acl.authenticate(Some("reader"), "no"),
+            Err(Error::WrongPass)
+        );
+        assert_eq!(
+            acl.authenticate(Some("nobody"), "pw"),
+            Err(Error::WrongPass)
+        );
+        assert_eq!(acl.check(Some("reader"), &read), Ok(()));
+        assert!(acl.check(Some("reader"), &write).is_err());
+        assert!(acl
+            .check(
+                Some("reader"),
+                &Command::MGet(vec!["cache:1".into(), "x".into()])
+            )
+            .is_err());
+        assert!(acl
+            .check(Some("reader"), &Command::Prefix("cache:".into()))
+            .is_err());
+        assert_eq!(
+            acl.check(Some("reader"), &Command::Acl(AclOp::WhoAmI)),
+            Ok(())
+        );
+
+        assert_eq!(acl.check(Some("admin"), &Command::FlushAll), Ok(()));
+        assert_eq!(
+            acl.setuser("reader", &["+@write".into(), "bad".into()]),
+            Err(Error::AclRule("bad".into(), "Syntax error".into()))
+        );
+        // A failed SETUSER leaves the user as it was
+        assert!(acl.check(Some("reader"), &write).is_err());
+        acl.setuser("reader", &["+@write".into()]).unwrap();
+        assert_eq!(acl.check(Some("reader"), &write), Ok(()));
+        assert_eq!(acl.initial_user(), Some("default".into()));
+    }
+
+    #[test]
+    fn bad_file() {
+        let mut users = BTreeMap::new();
+        match Acl::parse("user a on\nuser b +@some\n", &mut users) {
+            Err(config::Error::Directive(2, _)) => (),
+            e => panic!("expected directive error, got {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use super::parser::{BitFieldOp, BitOp, BitRange, BitType, BitUnit, Overflow, Value};
+use super::string::{self, as_bytes};
+use super::{Database, Error, Key};
+use std::borrow::Cow;
+
+/// Bits are numbered from the most significant bit of the first byte, and
+/// read as zero past the end of the string
+fn get_bit(bytes: &[u8], offset: u64) -> bool {
+    let byte = (offset / 8) as usize;
+    bytes
+        .get(byte)
+        .is_some_and(|b| b & (0x80 >> (offset % 8)) != 0)
+}
+
+/// Set a bit, zero-extending the string as needed
+fn set_bit(bytes: &mut Vec<u8>, offset: u64, bit: bool) {
+    let byte = (offset / 8) as usize;
+    if bytes.len() <= byte {
+        bytes.resize(byte + 1, 0);
+    }
+    let mask = 0x80 >> (offset % 8);
+    if bit {
+        bytes[byte] |= mask;
+    } else {
+        bytes[byte] &= !mask;
+    }
+}
+
+impl BitType {
+    fn min(&self) -> i128 {
+        if self.signed {
+            -(1 << (self.bits - 1))
+        } else {
+            0
+        }
+    }
+
+    fn max(&self) -> i128 {
+        if self.signed {
+            (1 << (self.bits - 1)) - 1
+        } else {
+            (1 << self.bits) - 1
+        }
+    }
+
+    /// Read the field at `offset`, most significant bit first
+    fn get(&self, bytes: &[u8], offset: u64) -> i64 {
+        let bits = self.bits as u64;
+        let v = (0..bits).fold(0u64, |v, i| (v << 1) | get_bit(bytes, offset + i) as u64);
+        if self.signed && bits < 64 && v & (1 << (bits - 1)) != 0 {
+            // Sign extend
+            (v | (!0 << bits)) as i64
+        } else {
+            v as i64
+        }
+    }
+
+    fn set(&self, bytes: &mut Vec<u8>, offset: u64, value: i64) {
+        let bits = self.bits as u64;
+        for i in 0..bits {
+            set_bit(bytes, offset + i, (value as u64 >> (bits - 1 - i)) & 1 == 1);
+        }
+    }
+
+    /// Fit `value` into the field as `overflow` dictates, returning `None`
+    /// if it doesn't fit and overflow should fail
+    fn fit(&self, value: i128, overflow: Overflow) -> Option<i64> {
+        let (min, max) = (self.min(), self.max());
+        if min <= value && value <= max {
+            return Some(value as i64);
+        }
+        match overflow {
+            Overflow::Wrap => Some(((value - min).rem_euclid(1 << self.bits) + min) as i64),
+            Overflow::Sat => Some(value.clamp(min, max) as i64),
+            Overflow::Fail => None,
+        }
+    }
+}
+
+/// Resolve a BITCOUNT or BITPOS range to an inclusive range of bit offsets
+fn bit_range(len: usize, range: &BitRange) -> Option<(u64, u64)> {
+    let end = range.end.unwrap_or(-1);
+    match range.unit {
+        BitUnit::Byte => string::range(len, range.start, end)
+            .map(|(start, end)| (start as u64 * 8, end as u64 * 8 + 7)),
+        BitUnit::Bit => {
+            string::range(len * 8, range.start, end).map(|(start, end)| (start as u64, end as u64))
+        }
+    }
+}
+
+/// Count set bits in an inclusive range of bit offsets, a byte at a time
+/// where possible
+fn count_bits(bytes: &[u8], start: u64, end: u64) -> u64 {
+    let (first, last) = ((start / 8) as usize, (end / 8) as usize);
+    if first == last {
+        return (start..=end).filter(|&i| get_bit(bytes, i)).count() as u64;
+    }
+    let head = (start..(first as u64 + 1) * 8)
+        .filter(|&i| get_bit(bytes, i))
+        .count() as u64;
+    let middle: u64 = bytes[first + 1..last]
+        .iter()
+        .map(|b| b.count_ones() as u64)
+        .sum();
+    let tail = (last as u64 * 8..=end)
+        .filter(|&i| get_bit(bytes, i))
+        .count() as u64;
+    head + middle + tail
+}
+
+impl Database {
+    fn bitmap(&self, key: &str) -> Result<Cow<'_, [u8]>, Error> {
+        Ok(self.string(key)?.map(as_bytes).unwrap_or_default())
+    }
+
+    /// Set a single bit, replying with its previous value
+    pub fn setbit(&mut self, key: Key, offset: u64, bit: bool) -> Result<Value, Error> {
+        let bytes = self.bytes_mut(key.clone())?;
+        let old = get_bit(bytes, offset);
+        set_bit(bytes, offset, bit);
+        self.notify(&key);
+        Ok(Value::Integer(old as i64))
+    }
+
+    pub fn getbit(&self, key: &str, offset: u64) -> Result<Value, Error> {
+        Ok(Value::Integer(get_bit(&self.bitmap(key)?, offset) as i64))
+    }
+
+    pub fn bitcount(&self, key: &str, range: Option<BitRange>) -> Result<Value, Error> {
+        let bytes = self.bitmap(key)?;
+        let count = match range {
+            None => bytes.iter().map(|b| b.count_ones() as u64).sum(),
+            Some(range) => match bit_range(bytes.len(), &range) {
+                Some((start, end)) => count_bits(&bytes, start, end),
+                None => 0,
+            },
+        };
+        Ok(Value::Integer(count as i64))
+    }
+
+    /// Find the first bit set to `bit`. When looking for a clear bit without
+    /// an explicit end, the string is treated as padded with zeros.
+    pub fn bitpos(&self, key: &str, bit: bool, range: Option<BitRange>) -> Result<Value, Error> {
+        let bytes = self.bitmap(key)?;
+        let explicit_end = range.as_ref().is_some_and(|r| r.end.is_some());
+        let range = range.unwrap_or(BitRange {
+            start: 0,
+            end: None,
+            unit: BitUnit::Byte,
+        });
+        let pos = match bit_range(bytes.len(), &range) {
+            Some((start, end)) => match (start..=end).find(|&i| get_bit(&bytes, i) == bit) {
+                Some(i) => i as i64,
+                None if !bit && !explicit_end => bytes.len() as i64 * 8,
+                None => -1,
+            },
+            None if bytes.is_empty() && !bit => 0,
+            None => -1,
+        };
+        Ok(Value::Integer(pos))
+    }
+
+    /// Combine the strings at `keys` bitwise into `dest`, replying with the
+    /// length of the result. Shorter strings are treated as zero-padded.
+    pub fn bitop(&mut self, op: BitOp, dest: Key, keys: &[String]) -> Result<Value, Error> {
+        let mut sources = Vec::with_capacity(keys.len());
+        for key in keys {
+            sources.push(self.bitmap(key)?);
+        }
+        let len = sources.iter().map(|s| s.len()).max().unwrap_or(0);
+        let result: Vec<u8> = (0..len)
+            .map(|i| {
+                let mut bytes = sources.iter().map(|s| s.get(i).cloned().unwrap_or(0));
+                let first = bytes.next().unwrap_or(0);
+                match op {
+                    BitOp::And => bytes.fold(first, |acc, b| acc & b),
+                    BitOp::Or => bytes.fold(first, |acc, b| acc | b),
+                    BitOp::Xor => bytes.fold(first, |acc, b| acc ^ b),
+                    BitOp::Not => !first,
+                }
+            })
+            .collect();
+        if result.is_empty() {
+            self.data.remove(&dest);
+        } else {
+            self.set_string(dest, Value::Bytes(result));
+        }
+        Ok(Value::Integer(len as i64))
+    }
+
+    /// Run BITFIELD operations in order, replying with one result each. Only
+    /// creates the key if something is written.
+    pub fn bitfield(&mut self, key: Key, ops: Vec<BitFieldOp>) -> Result<Value, Error> {
+        let writes = ops.iter().any(|op| match op {
+            BitFieldOp::Set(..) | BitFieldOp::IncrBy(..) => true,
+            BitFieldOp::Get(..) | BitFieldOp::Overflow(_) => false,
+        });
+        if !writes {
+            let bytes = self.bitmap(&key)?;
+            let replies = ops
+                .iter()
+                .filter_map(|op| match op {
+                    BitFieldOp::Get(ty, offset) => Some(Value::Integer(ty.get(&bytes, *offset))),
+                    _ => None,
+                })
+                .collect();
+            return Ok(Value::Array(replies));
+        }
+
+        let bytes = self.bytes_mut(key.clone())?;
+        let mut overflow = Overflow::Wrap;
+        let mut replies = Vec::new();
+        for op in ops {
+            let reply = match op {
+                BitFieldOp::Overflow(o) => {
+                    overflow = o;
+                    continue;
+                }
+                BitFieldOp::Get(ty, offset) => Some(ty.get(bytes, offset)),
+                BitFieldOp::Set(ty, offset, value) => {
+                    let old = ty.get(bytes, offset);
+                    ty.fit(value as i128, overflow).map(|value| {
+                        ty.set(bytes, offset, value);
+                        old
+                    })
+                }
+                BitFieldOp::IncrBy(ty, offset, delta) => {
+                    let old = ty.get(bytes, offset);
+                    ty.fit(old as i128 + delta as i128, overflow)
+                        .inspect(|&value| ty.set(bytes, offset, value))
+                }
+            };
+            replies.push(reply.map_or(Value::Null, Value::Integer));
+        }
+        self.notify(&key);
+        Ok(Value::Array(replies))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ty(signed: bool, bits: u32) -> BitType {
+        BitType { signed, bits }
+    }
+
+    #[test]
+    fn set_get_count() {
+        let mut db = Database::new();
+        assert_eq!(db.setbit("b".into(), 7, true), Ok(Value::Integer(0)));
+        assert_eq!(db.setbit("b".into(), 7, true), Ok(Value::Integer(1)));
+        assert_eq!(db.setbit("b".into(), 100, true), Ok(Value::Integer(0)));
+        assert_eq!(db.strlen("b"), Ok(Value::Integer(13)));
+        assert_eq!(db.getbit("b", 7), Ok(Value::Integer(1)));
+        assert_eq!(db.getbit("b", 8), Ok(Value::Integer(0)));
+        assert_eq!(db.getbit("b", 10_000), Ok(Value::Integer(0)));
+
+        db.create("s".into(), Value::Text("foobar".into()));
+        assert_eq!(db.bitcount("s", None), Ok(Value::Integer(26)));
+        let bytes = |start, end| BitRange {
+            start,
+            end: Some(end),
+            unit: BitUnit::Byte,
+        };
+        assert_eq!(db.bitcount("s", Some(bytes(1, 1))), Ok(Value::Integer(6)));
+        assert_eq!(db.bitcount("s", Some(bytes(-2, -1))), Ok(Value::Integer(7)));
+        let bits = BitRange {
+            start: 5,
+            end: Some(30),
+            unit: BitUnit::Bit,
+        };
+        assert_eq!(db.bitcount("s", Some(bits)), Ok(Value::Integer(17)));
+    }
+
+    #[test]
+    fn positions() {
+        let mut db = Database::new();
+        db.create("s".into(), Value::Bytes(vec![0xff, 0xf0, 0x00]));
+        assert_eq!(db.bitpos("s", false, None), Ok(Value::Integer(12)));
+        db.create("s".into(), Value::Bytes(vec![0xff, 0xff, 0xff]));
+        assert_eq!(db.bitpos("s", false, None), Ok(Value::Integer(24)));
+        let explicit = BitRange {
+            start: 0,
+            end: Some(-1),
+            unit: BitUnit::Byte,
+        };
+        assert_eq!(
+            db.bitpos("s", false, Some(explicit)),
+            Ok(Value::Integer(-1))
+        );
+        db.create("s".into(), Value::Bytes(vec![0x00, 0x0f]));
+        let from = BitRange {
+            start: 1,
+            end: None,
+            unit: BitUnit::Byte,
+        };
+        assert_eq!(db.bitpos("s", true, Some(from)), Ok(Value::Integer(12)));
+        assert_eq!(db.bitpos("missing", true, None), Ok(Value::Integer(-1)));
+        assert_eq!(db.bitpos("missing", false, None), Ok(Value::Integer(0)));
+    }
+
+    #[test]
+    fn operations() {
+        let mut db = Database::new();
+        db.create("a".into(), Value::Text("foobar".into()));
+        db.create("b".into(), Value::Text("abcdef".into()));
+        let keys = vec!["a".to_string(), "b".to_string()];
+        assert_eq!(
+            db.bitop(BitOp::And, "d".into(), &keys),
+            Ok(Value::Integer(6))
+        );
+        assert_eq!(db.read("d"), Some(&Value::Bytes(b"`bc`ab".to_vec())));
+        db.create("c".into(), Value::Bytes(vec![0x0f]));
+        assert_eq!(
+            db.bitop(BitOp::Not, "d".into(), &["c".into()]),
+            Ok(Value::Integer(1))
+        );
+        assert_eq!(db.read("d"), Some(&Value::Bytes(vec![0xf0])));
+        assert_eq!(
+            db.bitop(BitOp::Or, "d".into(), &["x".into(), "y".into()]),
+            Ok(Value::Integer(0))
+        );
+        assert!(db.read("d").is_none());
+    }
+
+    #[test]
+    fn fields() {
+        let mut db = Database::new();
+        let ops = vec![
+            BitFieldOp::Set(ty(true, 8), 0, -100),
+            BitFieldOp::IncrBy(ty(true, 8), 0, -100),
+            BitFieldOp::Overflow(Overflow::Sat),
+            BitFieldOp::IncrBy(ty(true, 8), 0, -100),
+            BitFieldOp::Overflow(Overflow::Fail),
+            BitFieldOp::IncrBy(ty(false, 4), 8, 16),
+            BitFieldOp::Set(ty(false, 4), 8, 15),
+            BitFieldOp::Get(ty(false, 12), 4),
+        ];
+        assert_eq!(
+            db.bitfield("f".into(), ops),
+            Ok(Value::Array(vec![
+                Value::Integer(0),
+                Value::Integer(56),
+                Value::Integer(-44),
+                Value::Null,
+                Value::Integer(0),
+                Value::Integer(0x4f0),
+            ]))
+        );
+        assert_eq!(
+            db.bitfield("f".into(), vec![BitFieldOp::Get(ty(true, 64), 0)]),
+            Ok(Value::Array(vec![Value::Integer((0xd4f0u64 << 48) as i64)]))
+        );
+        assert_eq!(
+            db.bitfield("g".into(), vec![BitFieldOp::Get(ty(false, 8), 0)]),
+            Ok(Value::Array(vec![Value::Integer(0)]))
+        );
+        assert!(db.read("g").is_none());
+    }
+}
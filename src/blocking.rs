@@ -0,0 +1,406 @@
+use super::buffer::Output;
+use super::parser::{End, Value, XRead};
+use super::{Database, Error, Key};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// What a blocked client will do once one of its keys has an element, or
+/// for streams, an entry it has not read yet
+#[derive(Debug, PartialEq, Clone)]
+pub enum Blocked {
+    Pop(End),
+    Move(Key, End, End),
+    Read(XRead),
+}
+
+/// Outcome of a blocking command: either it could be served straight away,
+/// or the client has been queued under the given id
+#[derive(Debug, PartialEq)]
+pub enum Block {
+    Ready(Value),
+    Waiting(usize),
+}
+
+struct Waiter {
+    keys: Vec<Key>,
+    op: Blocked,
+    /// Output of the client, closed once it disconnects or is killed, after
+    /// which it is passed over rather than handed elements
+    output: Output,
+}
+
+/// Clients blocked on list or stream keys. Each key keeps its waiters in
+/// the order they blocked, so that the longest waiting client is served
+/// first.
+///
+/// Replies are handed over through `served` rather than a channel so that a
+/// client that times out while being served cannot lose its element: both
+/// happen under the database lock.
+#[derive(Default)]
+pub struct Waiters {
+    next_id: usize,
+    queues: HashMap<Key, VecDeque<usize>>,
+    waiting: HashMap<usize, Waiter>,
+    served: HashMap<usize, Value>,
+    ready: Arc<Condvar>,
+}
+
+impl Waiters {
+    fn remove(&mut self, id: usize) -> Option<Waiter> {
+        let waiter = self.waiting.remove(&id)?;
+        for key in &waiter.keys {
+            if let Some(queue) = self.queues.get_mut(key) {
+                queue.retain(|&i| i != id);
+                if queue.is_empty() {
+                    self.queues.remove(key);
+                }
+            }
+        }
+        Some(waiter)
+    }
+
+    /// Number of clients currently blocked
+    pub fn len(&self) -> usize {
+        self.waiting.len()
+    }
+
+    /// Condvar the blocked clients wait on, notified whenever one is served
+    pub fn ready(&self) -> Arc<Condvar> {
+        self.ready.clone()
+    }
+}
+
+impl Database {
+    fn has_elements(&self, key: &str) -> bool {
+        match self.list(key) {
+            Ok(Some(list)) => !list.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Whether `op` can run against `key` without waiting
+    fn ready(&self, key: &str, op: &Blocked) -> bool {
+        match op {
+            Blocked::Pop(_) | Blocked::Move(..) => self.has_elements(key),
+            Blocked::Read(read) => self.stream_ready(key, read),
+        }
+    }
+
+    /// Run `op` against `key`, which must be ready for it
+    fn run_blocked(&mut self, key: &str, op: &Blocked) -> Value {
+        let result = match op {
+            Blocked::Pop(end) => self
+                .pop(key, None, *end)
+                .map(|v| Value::Array(vec![Value::Text(key.into()), v])),
+            Blocked::Move(dest, from, to) => self.lmove(key, dest, *from, *to),
+            Blocked::Read(read) => self.read_streams(read),
+        };
+        result.unwrap_or_else(|e| Value::Error(e.to_string()))
+    }
+
+    /// Run `op` against the first of `keys` it is ready for, or queue the
+    /// client writing to `output` until one of them is
+    pub fn block(&mut self, keys: Vec<Key>, op: Blocked, output: Output) -> Result<Block, Error> {
+        let op = match op {
+            Blocked::Read(read) => Blocked::Read(self.prepare_read(read)?),
+            op => {
+                for key in &keys {
+                    self.list(key)?;
+                }
+                if let Blocked::Move(ref dest, ..) = op {
+                    self.list(dest)?;
+                }
+                op
+            }
+        };
+        if let Some(key) = keys.iter().find(|k| self.ready(k, &op)) {
+            return Ok(Block::Ready(self.run_blocked(key, &op)));
+        }
+
+        let id = self.blocked.next_id;
+        self.blocked.next_id += 1;
+        for key in &keys {
+            self.blocked
+                .queues
+                .entry(key.clone())
+                .or_default()
+                .push_back(id);
+        }
+        self.blocked.waiting.insert(id, Waiter { keys, op, output });
+        Ok(Block::Waiting(id))
+    }
+
+    /// Stop waiting, returning the reply if the client was served meanwhile
+    pub fn unblock(&mut self, id: usize) -> Option<Value> {
+        self.blocked.remove(id);
+        self.blocked.served.remove(&id)
+    }
+
+    /// Hand elements of `key` to the clients blocked on it, oldest first.
+    /// Must be called whenever elements may have been added to `key`.
+    ///
+    /// Pops stop being ready once the list runs dry, while stream reads
+    /// consume nothing, so every waiter is given its turn.
+    pub fn serve_blocked(&mut self, key: &str) {
+        let queued: Vec<usize> = match self.blocked.queues.get(key) {
+            Some(queue) => queue.iter().cloned().collect(),
+            None => return,
+        };
+        for id in queued {
+            // Serving a move may have served this client already, and a
+            // client that has gone away waits only until it notices
+            match self.blocked.waiting.get(&id) {
+                Some(waiter) if !waiter.output.is_closed() && self.ready(key, &waiter.op) => (),
+                _ => continue,
+            }
+            // Dequeue first, as serving a move into the same key would
+            // otherwise find this client again
+            let waiter = self.blocked.remove(id).expect("Queued waiter");
+            let reply = self.run_blocked(key, &waiter.op);
+            self.blocked.served.insert(id, reply);
+            self.blocked.ready.notify_all();
+        }
+    }
+}
+
+impl Database {
+    /// Serve the clients blocked on any key, after keys may have been added
+    /// other than through the usual commands
+    pub fn serve_all_blocked(&mut self) {
+        let keys: Vec<Key> = self.blocked.queues.keys().cloned().collect();
+        for key in keys {
+            self.serve_blocked(&key);
+        }
+    }
+}
+
+/// Park a client queued by `Database::block` until it is served or
+/// `deadline` passes, releasing the database lock meanwhile. Timing out
+/// replies with `Value::Null`.
+///
+/// Returns `None`, with the client still queued, if it is woken otherwise
+/// or `poll` elapses first, so that the caller can check the client is
+/// still connected and not killed.
+pub fn wait(
+    mut db: MutexGuard<Database>,
+    id: usize,
+    deadline: Option<Instant>,
+    poll: Duration,
+) -> (MutexGuard<Database>, Option<Value>) {
+    let ready = db.blocked.ready.clone();
+    let mut woken = false;
+    loop {
+        if let Some(value) = db.blocked.served.remove(&id) {
+            db.unblock(id);
+            return (db, Some(value));
+        }
+        let now = Instant::now();
+        if deadline.is_some_and(|deadline| now >= deadline) {
+            let reply = db.unblock(id).unwrap_or(Value::Null);
+            return (db, Some(reply));
+        }
+        if woken {
+            return (db, None);
+        }
+        let timeout = deadline.map_or(poll, |deadline| poll.min(deadline - now));
+        db = ready
+            .wait_timeout(db, timeout)
+            .expect("Poisoned database lock")
+            .0;
+        woken = true;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use buffer::Limits;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.into())
+    }
+
+    fn output() -> Output {
+        Output::new(Limits::default(), None)
+    }
+
+    #[test]
+    fn ready() {
+        let mut db = Database::new();
+        db.push("b".into(), vec![text("x")], End::Back).unwrap();
+        assert_eq!(
+            db.block(
+                vec!["a".into(), "b".into()],
+                Blocked::Pop(End::Front),
+                output()
+            ),
+            Ok(Block::Ready(Value::Array(vec![text("b"), text("x")])))
+        );
+    }
+
+    #[test]
+    fn fifo() {
+        let mut db = Database::new();
+        let first = db.block(vec!["a".into()], Blocked::Pop(End::Front), output());
+        let second = db.block(
+            vec!["b".into(), "a".into()],
+            Blocked::Pop(End::Front),
+            output(),
+        );
+        assert_eq!(first, Ok(Block::Waiting(0)));
+        assert_eq!(second, Ok(Block::Waiting(1)));
+
+        db.push("a".into(), vec![text("x")], End::Back).unwrap();
+        assert_eq!(
+            db.unblock(0),
+            Some(Value::Array(vec![text("a"), text("x")]))
+        );
+        db.push("b".into(), vec![text("y"), text("z")], End::Back)
+            .unwrap();
+        assert_eq!(
+            db.unblock(1),
+            Some(Value::Array(vec![text("b"), text("y")]))
+        );
+        assert_eq!(db.llen("b"), Ok(Value::Integer(1)));
+        assert_eq!(db.blocked.len(), 0);
+    }
+
+    #[test]
+    fn move_to_served_key() {
+        let mut db = Database::new();
+        let op = Blocked::Move("b".into(), End::Front, End::Back);
+        assert_eq!(
+            db.block(vec!["a".into()], op, output()),
+            Ok(Block::Waiting(0))
+        );
+        let op = Blocked::Pop(End::Front);
+        assert_eq!(
+            db.block(vec!["b".into()], op, output()),
+            Ok(Block::Waiting(1))
+        );
+
+        db.push("a".into(), vec![text("job")], End::Back).unwrap();
+        assert_eq!(db.unblock(0), Some(text("job")));
+        assert_eq!(
+            db.unblock(1),
+            Some(Value::Array(vec![text("b"), text("job")]))
+        );
+    }
+
+    #[test]
+    fn stream_readers() {
+        use parser::{ReadFrom, XAdd, XAddId};
+        use stream::StreamId;
+
+        let mut db = Database::new();
+        let read = XRead {
+            group: None,
+            count: None,
+            block: Some(None),
+            noack: false,
+            streams: vec![("s".into(), ReadFrom::Last)],
+        };
+        // Both readers are served by the same entry, as reads consume nothing
+        let op = Blocked::Read(read.clone());
+        assert_eq!(
+            db.block(vec!["s".into()], op, output()),
+            Ok(Block::Waiting(0))
+        );
+        let op = Blocked::Read(read);
+        assert_eq!(
+            db.block(vec!["s".into()], op, output()),
+            Ok(Block::Waiting(1))
+        );
+        let add = XAdd {
+            id: XAddId::Explicit(StreamId { ms: 1, seq: 0 }),
+            maxlen: None,
+            nomkstream: false,
+            fields: vec![("f".into(), text("v"))],
+        };
+        db.xadd("s".into(), add).unwrap();
+        let reply = Value::Array(vec![Value::Array(vec![
+            text("s"),
+            Value::Array(vec![Value::Array(vec![
+                text("1-0"),
+                Value::Array(vec![text("f"), text("v")]),
+            ])]),
+        ])]);
+        assert_eq!(db.unblock(0), Some(reply.clone()));
+        assert_eq!(db.unblock(1), Some(reply));
+    }
+
+    #[test]
+    fn disconnected() {
+        let mut db = Database::new();
+        let gone = output();
+        let op = Blocked::Pop(End::Front);
+        assert_eq!(
+            db.block(vec!["a".into()], op, gone.clone()),
+            Ok(Block::Waiting(0))
+        );
+        let op = Blocked::Pop(End::Front);
+        assert_eq!(
+            db.block(vec!["a".into()], op, output()),
+            Ok(Block::Waiting(1))
+        );
+
+        // The closed client is passed over for the next one
+        gone.close();
+        db.push("a".into(), vec![text("x"), text("y")], End::Back)
+            .unwrap();
+        assert_eq!(db.unblock(0), None);
+        assert_eq!(
+            db.unblock(1),
+            Some(Value::Array(vec![text("a"), text("x")]))
+        );
+        assert_eq!(db.llen("a"), Ok(Value::Integer(1)));
+    }
+
+    #[test]
+    fn wait_timeout() {
+        let db = Arc::new(Mutex::new(Database::new()));
+        let mut guard = db.lock().unwrap();
+        let id = match guard.block(vec!["a".into()], Blocked::Pop(End::Back), output()) {
+            Ok(Block::Waiting(id)) => id,
+            r => panic!("expected to block, got {:?}", r),
+        };
+        let (guard, reply) = wait(guard, id, None, Duration::from_millis(10));
+        assert_eq!(reply, None);
+        assert_eq!(guard.blocked.len(), 1);
+        let deadline = Instant::now() + Duration::from_millis(10);
+        let (guard, reply) = wait(guard, id, Some(deadline), Duration::from_secs(60));
+        assert_eq!(reply, Some(Value::Null));
+        assert_eq!(guard.blocked.len(), 0);
+    }
+
+    #[test]
+    fn wait_served() {
+        let db = Arc::new(Mutex::new(Database::new()));
+        let mut guard = db.lock().unwrap();
+        let id = match guard.block(vec!["a".into()], Blocked::Pop(End::Back), output()) {
+            Ok(Block::Waiting(id)) => id,
+            r => panic!("expected to block, got {:?}", r),
+        };
+        let pusher = db.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            pusher
+                .lock()
+                .unwrap()
+                .push("a".into(), vec![text("x")], End::Front)
+                .unwrap();
+        });
+        let (mut guard, mut reply) = wait(guard, id, None, Duration::from_secs(60));
+        // Spurious wakeups return early
+        while reply.is_none() {
+            let (g, r) = wait(guard, id, None, Duration::from_secs(60));
+            guard = g;
+            reply = r;
+        }
+        assert_eq!(reply, Some(Value::Array(vec![text("a"), text("x")])));
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,301 @@
+use super::socket::Socket;
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::Shutdown;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Client classes, each held to its own output buffer limit
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Class {
+    Normal,
+    Pubsub,
+    Replica,
+}
+
+/// A hard limit disconnects a client as soon as it is reached, while a soft
+/// limit only does so once it has been continuously exceeded for `soft_duration`.
+/// A limit of zero is disabled.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Limit {
+    pub hard: usize,
+    pub soft: usize,
+    pub soft_duration: Duration,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Limits {
+    pub normal: Limit,
+    pub pubsub: Limit,
+    pub replica: Limit,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Error {
+    Closed,
+    HardLimit(usize),
+    SoftLimit(usize, Duration),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Closed => write!(f, "connection closed"),
+            Error::HardLimit(bytes) => write!(f, "hard limit reached with {} bytes queued", bytes),
+            Error::SoftLimit(bytes, d) => write!(
+                f,
+                "soft limit exceeded for {:?} with {} bytes queued",
+                d, bytes
+            ),
+        }
+    }
+}
+
+struct State {
+    queue: VecDeque<Vec<u8>>,
+    bytes: usize,
+    class: Class,
+    soft_since: Option<Instant>,
+    closed: bool,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    ready: Condvar,
+    limits: Limits,
+    stream: Option<Socket>,
+}
+
+/// Bounded queue of pending replies for a single client, shared between the
+/// producers (the client itself and any keys it subscribes to) and the
+/// client's writer thread.
+///
+/// Unlike a plain channel, sending never blocks: a client that falls too far
+/// behind is disconnected instead.
+#[derive(Clone)]
+pub struct Output {
+    inner: Arc<Inner>,
+}
+
+impl Limit {
+    pub fn new(hard: usize, soft: usize, soft_duration: Duration) -> Self {
+        Limit {
+            hard,
+            soft,
+            soft_duration,
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Limit::new(0, 0, Duration::from_secs(0))
+    }
+
+    fn check(&self, bytes: usize, soft_since: &mut Option<Instant>) -> Result<(), Error> {
+        if self.hard > 0 && bytes >= self.hard {
+            return Err(Error::HardLimit(bytes));
+        }
+        if self.soft > 0 && bytes >= self.soft {
+            let since = *soft_since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= self.soft_duration {
+                return Err(Error::SoftLimit(bytes, since.elapsed()));
+            }
+        } else {
+            *soft_since = None;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Limits {
+    /// Same defaults as Redis: normal clients are unbounded, while pubsub and
+    /// replica clients are allowed 32mb and 256mb respectively.
+    fn default() -> Self {
+        Limits {
+            normal: Limit::unlimited(),
+            pubsub: Limit::new(32 << 20, 8 << 20, Duration::from_secs(60)),
+            replica: Limit::new(256 << 20, 64 << 20, Duration::from_secs(60)),
+        }
+    }
+}
+
+impl Limits {
+    pub fn get(&self, class: Class) -> &Limit {
+        match class {
+            Class::Normal => &self.normal,
+            Class::Pubsub => &self.pubsub,
+            Class::Replica => &self.replica,
+        }
+    }
+
+    pub fn get_mut(&mut self, class: Class) -> &mut Limit {
+        match class {
+            Class::Normal => &mut self.normal,
+            Class::Pubsub => &mut self.pubsub,
+            Class::Replica => &mut self.replica,
+        }
+    }
+}
+
+impl Output {
+    /// `stream` is shut down when the client is disconnected for exceeding
+    /// its limits, so that a writer blocked on a full socket is released.
+    pub fn new(limits: Limits, stream: Option<Socket>) -> Self {
+        Output {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    queue: VecDeque::new(),
+                    bytes: 0,
+                    class: Class::Normal,
+                    soft_since: None,
+                    closed: false,
+                }),
+                ready: Condvar::new(),
+                limits,
+                stream,
+            }),
+        }
+    }
+
+    /// Queue a message for the writer thread, disconnecting the client if
+    /// this pushes it over the limit for its class.
+    pub fn send(&self, message: Vec<u8>) -> Result<(), Error> {
+        let mut state = self.inner.state.lock().expect("Poisoned output buffer");
+        if state.closed {
+            return Err(Error::Closed);
+        }
+        state.bytes += message.len();
+        state.queue.push_back(message);
+
+        let limit = self.inner.limits.get(state.class);
+        let bytes = state.bytes;
+        if let Err(e) = limit.check(bytes, &mut state.soft_since) {
+            state.closed = true;
+            state.queue.clear();
+            state.bytes = 0;
+            drop(state);
+            self.inner.ready.notify_all();
+            if let Some(ref stream) = self.inner.stream {
+                println!(
+                    "Client {} closed for overcoming of output buffer limits: {}",
+                    stream.peer_addr(),
+                    e
+                );
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            return Err(e);
+        }
+        drop(state);
+        self.inner.ready.notify_one();
+        Ok(())
+    }
+
+    /// Block until a message is available, returning `None` once the buffer
+    /// has been closed.
+    pub fn recv(&self) -> Option<Vec<u8>> {
+        let mut state = self.inner.state.lock().expect("Poisoned output buffer");
+        loop {
+            if state.closed {
+                return None;
+            }
+            if let Some(message) = state.queue.pop_front() {
+                state.bytes -= message.len();
+                return Some(message);
+            }
+            state = self
+                .inner
+                .ready
+                .wait(state)
+                .expect("Poisoned output buffer");
+        }
+    }
+
+    pub fn close(&self) {
+        self.inner
+            .state
+            .lock()
+            .expect("Poisoned output buffer")
+            .closed = true;
+        self.inner.ready.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner
+            .state
+            .lock()
+            .expect("Poisoned output buffer")
+            .closed
+    }
+
+    pub fn set_class(&self, class: Class) {
+        let mut state = self.inner.state.lock().expect("Poisoned output buffer");
+        state.class = class;
+        state.soft_since = None;
+    }
+
+    /// Number of bytes queued but not yet handed to the socket
+    pub fn pending(&self) -> usize {
+        self.inner
+            .state
+            .lock()
+            .expect("Poisoned output buffer")
+            .bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits(limit: Limit) -> Limits {
+        Limits {
+            normal: limit,
+            pubsub: limit,
+            replica: limit,
+        }
+    }
+
+    #[test]
+    fn hard_limit() {
+        let out = Output::new(limits(Limit::new(10, 0, Duration::from_secs(0))), None);
+        assert_eq!(out.send(vec![0; 6]), Ok(()));
+        assert_eq!(out.pending(), 6);
+        assert_eq!(out.send(vec![0; 6]), Err(Error::HardLimit(12)));
+        assert!(out.is_closed());
+        assert_eq!(out.send(vec![0; 1]), Err(Error::Closed));
+        assert_eq!(out.recv(), None);
+    }
+
+    #[test]
+    fn soft_limit() {
+        let out = Output::new(limits(Limit::new(0, 4, Duration::from_millis(20))), None);
+        assert_eq!(out.send(vec![0; 5]), Ok(()));
+        std::thread::sleep(Duration::from_millis(30));
+        match out.send(vec![0; 1]) {
+            Err(Error::SoftLimit(6, _)) => (),
+            e => panic!("expected soft limit, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn drain_resets_soft_limit() {
+        let out = Output::new(limits(Limit::new(0, 4, Duration::from_millis(20))), None);
+        assert_eq!(out.send(vec![1; 5]), Ok(()));
+        assert_eq!(out.recv(), Some(vec![1; 5]));
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(out.send(vec![2; 1]), Ok(()));
+        assert_eq!(out.send(vec![3; 5]), Ok(()));
+        assert_eq!(out.pending(), 6);
+    }
+
+    #[test]
+    fn class_limits() {
+        let l = Limits {
+            pubsub: Limit::new(4, 0, Duration::from_secs(0)),
+            ..Limits::default()
+        };
+        let out = Output::new(l, None);
+        assert_eq!(out.send(vec![0; 8]), Ok(()));
+        out.set_class(Class::Pubsub);
+        assert_eq!(out.send(vec![0; 1]), Err(Error::HardLimit(9)));
+    }
+}
@@ -0,0 +1,514 @@
+use super::acl::{self, Category};
+use super::buffer::Output;
+use super::config::Config;
+use super::parser::{ClientKill, ClientOp, Command, Value};
+use super::socket::Socket;
+use super::Error;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Shutdown};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// What a client last reported about itself, updated after every command
+#[derive(Debug, Clone)]
+pub struct State {
+    pub name: Option<String>,
+    pub user: Option<String>,
+    pub db: usize,
+    pub subscriptions: usize,
+    /// Bytes read but not yet parsed
+    pub input: usize,
+    pub last_command: Instant,
+    /// Condvar of the database the client is blocked on, if it is, which
+    /// CLIENT KILL notifies so that it stops waiting
+    pub blocked: Option<Arc<Condvar>>,
+}
+
+/// A connected client as listed by CLIENT LIST, holding its socket and
+/// output buffer so that CLIENT KILL can disconnect it
+pub struct Connection {
+    pub id: u64,
+    pub addr: String,
+    /// IP address of a TCP client, counted towards `maxclients-per-ip`
+    ip: Option<IpAddr>,
+    socket: Socket,
+    output: Output,
+    created: Instant,
+    state: Mutex<State>,
+}
+
+impl Connection {
+    pub fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Poisoned client state")
+    }
+
+    pub fn output(&self) -> &Output {
+        &self.output
+    }
+
+    /// A line of CLIENT LIST
+    fn describe(&self) -> String {
+        let state = self.state();
+        format!(
+            "id={} addr={} name={} age={} idle={} flags={} db={} sub={} qbuf={} omem={} user={}",
+            self.id,
+            self.addr,
+            state.name.as_ref().map_or("", |n| n.as_str()),
+            self.created.elapsed().as_secs(),
+            state.last_command.elapsed().as_secs(),
+            if state.subscriptions > 0 { "P" } else { "N" },
+            state.db,
+            state.subscriptions,
+            state.input,
+            self.output.pending(),
+            state.user.as_ref().map_or("", |u| u.as_str()),
+        )
+    }
+
+    /// Close the connection. Its reader thread then sees the socket shut,
+    /// or if blocked, wakes to find its output closed, and removes it from
+    /// the table.
+    fn kill(&self) {
+        self.output.close();
+        let _ = self.socket.shutdown(Shutdown::Both);
+        if let Some(ref ready) = self.state().blocked {
+            ready.notify_all();
+        }
+    }
+}
+
+/// Longest pause, so that a typo can't hold up clients for years
+const MAX_PAUSE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// An ongoing CLIENT PAUSE
+struct Pause {
+    until: Instant,
+    /// Whether every command is held up, rather than only writes
+    all: bool,
+}
+
+/// Table of the connected clients
+pub struct Clients {
+    maxclients: usize,
+    /// Most clients from one IP address, or zero for no limit
+    maxclients_per_ip: usize,
+    next_id: AtomicU64,
+    table: Mutex<BTreeMap<u64, Arc<Connection>>>,
+    /// Addresses of the clients still in a TLS handshake, which count
+    /// towards the limits before they are registered
+    handshakes: Mutex<Vec<Option<IpAddr>>>,
+    pause: Mutex<Option<Pause>>,
+    unpaused: Condvar,
+}
+
+impl Clients {
+    pub fn new(config: &Config) -> Self {
+        Clients {
+            maxclients: config.maxclients,
+            maxclients_per_ip: config.maxclients_per_ip,
+            next_id: AtomicU64::new(1),
+            table: Mutex::new(BTreeMap::new()),
+            handshakes: Mutex::new(Vec::new()),
+            pause: Mutex::new(None),
+            unpaused: Condvar::new(),
+        }
+    }
+
+    fn table(&self) -> MutexGuard<'_, BTreeMap<u64, Arc<Connection>>> {
+        self.table.lock().expect("Poisoned client table")
+    }
+
+    fn handshakes(&self) -> MutexGuard<'_, Vec<Option<IpAddr>>> {
+        self.handshakes.lock().expect("Poisoned handshakes")
+    }
+
+    /// Check there is room for another client from `ip`, counting those
+    /// registered in `table` and those still handshaking
+    fn admit(
+        &self,
+        table: &BTreeMap<u64, Arc<Connection>>,
+        ip: Option<IpAddr>,
+    ) -> Result<(), Error> {
+        let handshakes = self.handshakes();
+        if table.len() + handshakes.len() >= self.maxclients {
+            return Err(Error::MaxClients);
+        }
+        if let (Some(ip), n) = (ip, self.maxclients_per_ip) {
+            let registered = table.values().filter(|c| c.ip == Some(ip)).count();
+            let handshaking = handshakes.iter().filter(|&&h| h == Some(ip)).count();
+            if n > 0 && registered + handshaking >= n {
+                return Err(Error::MaxClientsPerIp);
+            }
+        }
+        Ok(())
+    }
+
+    /// Hold a place for a client from `ip` for as long as its TLS handshake
+    /// takes, unless there are already as many clients as allowed
+    pub fn handshake(self: &Arc<Self>, ip: Option<IpAddr>) -> Result<Handshake, Error> {
+        self.admit(&self.table(), ip)?;
+        self.handshakes().push(ip);
+        Ok(Handshake {
+            clients: self.clone(),
+            ip,
+        })
+    }
+
+    /// Add a client to the table, giving it the next ID, unless there are
+    /// already as many clients as allowed
+    pub fn register(
+        &self,
+        socket: Socket,
+        output: Output,
+        user: Option<String>,
+    ) -> Result<Arc<Connection>, Error> {
+        let mut table = self.table();
+        let ip = socket.peer_ip();
+        self.admit(&table, ip)?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let connection = Arc::new(Connection {
+            id,
+            addr: socket.peer_addr(),
+            ip,
+            socket,
+            output,
+            created: Instant::now(),
+            state: Mutex::new(State {
+                name: None,
+                user,
+                db: 0,
+                subscriptions: 0,
+                input: 0,
+                last_command: Instant::now(),
+                blocked: None,
+            }),
+        });
+        table.insert(id, connection.clone());
+        Ok(connection)
+    }
+
+    pub fn remove(&self, id: u64) {
+        self.table().remove(&id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.table().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table().is_empty()
+    }
+
+    /// Run a CLIENT subcommand for `me`
+    pub fn execute(&self, me: &Connection, op: ClientOp) -> Result<Value, Error> {
+        Ok(match op {
+            ClientOp::Id => Value::Integer(me.id as i64),
+            ClientOp::Info => Value::Text(format!("{}\n", me.describe())),
+            ClientOp::List => Value::Text(
+                self.table()
+                    .values()
+                    .map(|c| format!("{}\n", c.describe()))
+                    .collect(),
+            ),
+            ClientOp::SetName(name) => {
+                if name.chars().any(|c| c <= ' ' || c > '~') {
+                    return Err(Error::ClientName);
+                }
+                me.state().name = if name.is_empty() { None } else { Some(name) };
+                Value::Status("OK".into())
+            }
+            ClientOp::GetName => me.state().name.clone().map_or(Value::Null, Value::Text),
+            ClientOp::Kill(kill) => return self.kill(me, &kill),
+            ClientOp::Pause(timeout, all) => {
+                *self.pause.lock().expect("Poisoned pause") = Some(Pause {
+                    until: Instant::now() + timeout.min(MAX_PAUSE),
+                    all,
+                });
+                Value::Status("OK".into())
+            }
+            ClientOp::Unpause => {
+                *self.pause.lock().expect("Poisoned pause") = None;
+                self.unpaused.notify_all();
+                Value::Status("OK".into())
+            }
+        })
+    }
+
+    /// Disconnect the clients matching every filter of `kill`
+    fn kill(&self, me: &Connection, kill: &ClientKill) -> Result<Value, Error> {
+        let table = self.table();
+        let matches = table.values().filter(|c| {
+            kill.id.is_none_or(|id| c.id == id)
+                && kill.addr.as_ref().is_none_or(|addr| &c.addr == addr)
+                && (kill.user.is_none() || c.state().user == kill.user)
+                && !(kill.skipme && c.id == me.id)
+        });
+        let mut killed = 0;
+        for connection in matches {
+            connection.kill();
+            killed += 1;
+        }
+        match (kill.legacy, killed) {
+            (true, 0) => Err(Error::NoSuchClient),
+            (true, _) => Ok(Value::Status("OK".into())),
+            (false, n) => Ok(Value::Integer(n)),
+        }
+    }
+
+    /// Wait out any CLIENT PAUSE holding up `commands`. CLIENT commands
+    /// are never held up, so that the pause can be lifted early.
+    pub fn wait_unpaused(&self, commands: &[Command]) {
+        let mut pause = self.pause.lock().expect("Poisoned pause");
+        loop {
+            let (until, all) = match *pause {
+                Some(ref p) if p.until > Instant::now() => (p.until, p.all),
+                _ => return,
+            };
+            let held = commands.iter().any(|c| match c {
+                Command::Client(_) => false,
+                _ => all || writes(c),
+            });
+            if !held {
+                return;
+            }
+            let timeout = until.saturating_duration_since(Instant::now());
+            pause = self
+                .unpaused
+                .wait_timeout(pause, timeout)
+                .expect("Poisoned pause")
+                .0;
+        }
+    }
+}
+
+/// A place among the clients held by one still in a TLS handshake, given
+/// up once dropped
+pub struct Handshake {
+    clients: Arc<Clients>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for Handshake {
+    fn drop(&mut self) {
+        let mut handshakes = self.clients.handshakes();
+        if let Some(i) = handshakes.iter().position(|&h| h == self.ip) {
+            handshakes.swap_remove(i);
+        }
+    }
+}
+
+/// Whether `command` may modify the keyspace, and so is held up by CLIENT
+/// PAUSE WRITE
+fn writes(command: &Command) -> bool {
+    match command {
+        Command::SwapDb(..) | Command::FlushDb | Command::FlushAll => true,
+        c => acl::category(c) == Some(Category::Write),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use blocking::{self, Block, Blocked};
+    use buffer::Limits;
+    use parser::End;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use Database;
+
+    /// Both ends of a local TCP connection
+    fn pair() -> (Socket, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (Socket::Tcp(listener.accept().unwrap().0), peer)
+    }
+
+    /// A registered client, along with the peer end of its socket
+    fn connect(clients: &Clients, user: &str) -> (Arc<Connection>, TcpStream) {
+        let (socket, peer) = pair();
+        let output = Output::new(Limits::default(), None);
+        let connection = clients.register(socket, output, Some(user.into()));
+        (connection.unwrap(), peer)
+    }
+
+    fn kill(id: Option<u64>, user: Option<&str>) -> ClientOp {
+        ClientOp::Kill(ClientKill {
+            id,
+            addr: None,
+            user: user.map(|u| u.into()),
+            skipme: true,
+            legacy: false,
+        })
+    }
+
+    #[test]
+    fn list_and_kill() {
+        let clients = Clients::new(&Config::default());
+        let (me, _) = connect(&clients, "default");
+        let (other, mut peer) = connect(&clients, "app");
+        assert_eq!(
+            clients.execute(&me, ClientOp::SetName("has space".into())),
+            Err(Error::ClientName)
+        );
+        clients
+            .execute(&me, ClientOp::SetName("admin".into()))
+            .unwrap();
+        assert_eq!(
+            clients.execute(&me, ClientOp::GetName),
+            Ok(Value::Text("admin".into()))
+        );
+        other.state().db = 3;
+
+        let list = match clients.execute(&me, ClientOp::List) {
+            Ok(Value::Text(list)) => list,
+            r => panic!("expected a list, got {:?}", r),
+        };
+        let lines = list.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(&format!("id={} addr={} name=admin age=0", me.id, me.addr)));
+        assert!(lines[1].contains(" name= ") && lines[1].contains(" db=3 "));
+        assert!(lines[1].ends_with(" user=app"));
+
+        // Filters combine, and the killer is skipped by default
+        assert_eq!(
+            clients.execute(&me, kill(None, Some("nobody"))),
+            Ok(Value::Integer(0))
+        );
+        assert_eq!(
+            clients.execute(&me, kill(Some(me.id), None)),
+            Ok(Value::Integer(0))
+        );
+        assert_eq!(
+            clients.execute(&me, kill(None, Some("app"))),
+            Ok(Value::Integer(1))
+        );
+        let mut buf = [0u8; 1];
+        assert_eq!(std::io::Read::read(&mut peer, &mut buf).unwrap(), 0);
+
+        let legacy = ClientKill {
+            id: None,
+            addr: Some("nowhere:1".into()),
+            user: None,
+            skipme: false,
+            legacy: true,
+        };
+        assert_eq!(
+            clients.execute(&me, ClientOp::Kill(legacy)),
+            Err(Error::NoSuchClient)
+        );
+        clients.remove(other.id);
+        assert_eq!(clients.len(), 1);
+    }
+
+    #[test]
+    fn kill_blocked() {
+        let clients = Clients::new(&Config::default());
+        let (me, _) = connect(&clients, "default");
+        let (blocked, _peer) = connect(&clients, "default");
+        let db = Arc::new(Mutex::new(Database::new()));
+        let mut guard = db.lock().unwrap();
+        let op = Blocked::Pop(End::Front);
+        let id = match guard.block(vec!["q".into()], op, blocked.output().clone()) {
+            Ok(Block::Waiting(id)) => id,
+            r => panic!("expected to block, got {:?}", r),
+        };
+        blocked.state().blocked = Some(guard.blocked.ready());
+        drop(guard);
+
+        let waiting = db.clone();
+        let waiter = thread::spawn(move || {
+            let guard = waiting.lock().unwrap();
+            let (mut guard, reply) = blocking::wait(guard, id, None, Duration::from_secs(60));
+            guard.unblock(id);
+            reply
+        });
+        thread::sleep(Duration::from_millis(20));
+        let start = Instant::now();
+        assert_eq!(
+            clients.execute(&me, kill(Some(blocked.id), None)),
+            Ok(Value::Integer(1))
+        );
+        let mut guard = db.lock().unwrap();
+        guard
+            .push("q".into(), vec![Value::Text("x".into())], End::Back)
+            .unwrap();
+        assert_eq!(guard.llen("q"), Ok(Value::Integer(1)));
+        drop(guard);
+        // Woken by the kill rather than at the end of the poll interval
+        assert_eq!(waiter.join().unwrap(), None);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn limits() {
+        let clients = Clients::new(&Config {
+            maxclients: 3,
+            maxclients_per_ip: 2,
+            ..Config::default()
+        });
+        let (first, _peer) = connect(&clients, "default");
+        let (_, _peer) = connect(&clients, "default");
+        let (socket, _peer) = pair();
+        let output = Output::new(Limits::default(), None);
+        assert_eq!(
+            clients.register(socket, output, None).err(),
+            Some(Error::MaxClientsPerIp)
+        );
+        clients.remove(first.id);
+        let (_, _peer) = connect(&clients, "default");
+        assert_eq!(clients.len(), 2);
+
+        let clients = Clients::new(&Config {
+            maxclients: 1,
+            ..Config::default()
+        });
+        let (_, _peer) = connect(&clients, "default");
+        let (socket, _peer) = pair();
+        let output = Output::new(Limits::default(), None);
+        assert_eq!(
+            clients.register(socket, output, None).err(),
+            Some(Error::MaxClients)
+        );
+
+        // Clients still handshaking count towards the limits
+        let clients = Arc::new(Clients::new(&Config {
+            maxclients: 2,
+            maxclients_per_ip: 1,
+            ..Config::default()
+        }));
+        let ip = Some(IpAddr::from([127, 0, 0, 1]));
+        let handshake = clients.handshake(ip).unwrap();
+        assert_eq!(clients.handshake(ip).err(), Some(Error::MaxClientsPerIp));
+        let other = clients.handshake(Some(IpAddr::from([10, 0, 0, 1])));
+        assert_eq!(clients.handshake(None).err(), Some(Error::MaxClients));
+        drop((handshake, other));
+        let (_, _peer) = connect(&clients, "default");
+        assert!(clients.handshake(ip).is_err());
+    }
+
+    #[test]
+    fn pause() {
+        let clients = Arc::new(Clients::new(&Config::default()));
+        let (me, _) = connect(&clients, "default");
+        let read = vec![Command::Read("k".into())];
+        let write = vec![Command::Delete("k".into())];
+        let pause = ClientOp::Pause(Duration::from_millis(50), false);
+        clients.execute(&me, pause).unwrap();
+
+        let start = Instant::now();
+        clients.wait_unpaused(&read);
+        assert!(start.elapsed() < Duration::from_millis(50));
+        clients.wait_unpaused(&write);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        clients
+            .execute(&me, ClientOp::Pause(Duration::from_secs(60), true))
+            .unwrap();
+        let waiting = clients.clone();
+        let waiter = thread::spawn(move || waiting.wait_unpaused(&read));
+        thread::sleep(Duration::from_millis(20));
+        clients.execute(&me, ClientOp::Unpause).unwrap();
+        waiter.join().unwrap();
+    }
+}
@@ -0,0 +1,316 @@
+use super::buffer::{Class, Limit, Limits};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+/// Server configuration, read from a redis.conf style file of
+/// `directive arg...` lines. Anything not given keeps its default.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Config {
+    /// File the config was read from, if any
+    pub file: Option<String>,
+    pub bind: String,
+    /// TCP port, or zero to listen on `unixsocket` alone
+    pub port: u16,
+    /// Path of a Unix socket to listen on as well
+    pub unixsocket: Option<String>,
+    /// Permissions of `unixsocket`, such as 0o770
+    pub unixsocketperm: Option<u32>,
+    pub client_output_buffer_limit: Limits,
+    /// Most clients connected at once
+    pub maxclients: usize,
+    /// Most clients connected at once from one IP address, or zero for no
+    /// limit beyond `maxclients`
+    pub maxclients_per_ip: usize,
+    /// How long a client may send nothing before it is closed, unless it
+    /// is subscribed to keys
+    pub timeout: Option<Duration>,
+    /// Interval of TCP keepalive probes on idle connections, with the
+    /// `keepalive` feature
+    pub tcp_keepalive: Option<Duration>,
+    /// Number of logical databases
+    pub databases: usize,
+    /// Whether to keep an ordered index of the keys, for RANGE and PREFIX
+    pub ordered_keyspace: bool,
+    /// Databases overriding `ordered_keyspace`
+    pub ordered_databases: BTreeMap<usize, bool>,
+    /// Password of the default user
+    pub requirepass: Option<String>,
+    /// File of users to load at startup
+    pub aclfile: Option<String>,
+    /// Port serving TLS connections alongside `port`, with the `tls`
+    /// feature
+    pub tls_port: Option<u16>,
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    /// CA certificates that client certificates are verified against
+    pub tls_ca_cert_file: Option<String>,
+    pub tls_auth_clients: TlsAuth,
+}
+
+/// Whether TLS clients must present a certificate. A verified certificate
+/// logs the client in as the ACL user named by its common name, if any.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TlsAuth {
+    No,
+    Optional,
+    Yes,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Directive(usize, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Directive(line, msg) => write!(f, "line {}: {}", line, msg),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            file: None,
+            bind: "0.0.0.0".into(),
+            port: 1122,
+            unixsocket: None,
+            unixsocketperm: None,
+            client_output_buffer_limit: Limits::default(),
+            maxclients: 10000,
+            maxclients_per_ip: 0,
+            timeout: None,
+            tcp_keepalive: Some(Duration::from_secs(300)),
+            databases: 16,
+            ordered_keyspace: false,
+            ordered_databases: BTreeMap::new(),
+            requirepass: None,
+            aclfile: None,
+            tls_port: None,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_ca_cert_file: None,
+            tls_auth_clients: TlsAuth::No,
+        }
+    }
+}
+
+/// Parse a memory amount such as `1024`, `64mb` or `1g`
+pub fn parse_memory(s: &str) -> Option<usize> {
+    let s = s.to_ascii_lowercase();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let mult = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    n.parse::<usize>().ok().and_then(|n| n.checked_mul(mult))
+}
+
+/// Parse a number of seconds, where zero disables what it configures
+fn parse_seconds(s: &str) -> Result<Option<Duration>, String> {
+    match s.parse() {
+        Ok(0) => Ok(None),
+        Ok(secs) => Ok(Some(Duration::from_secs(secs))),
+        Err(_) => Err(format!("invalid number of seconds {}", s)),
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, Error> {
+        let mut config = Config::parse(&fs::read_to_string(path).map_err(Error::Io)?)?;
+        config.file = Some(path.into());
+        Ok(config)
+    }
+
+    pub fn parse(s: &str) -> Result<Config, Error> {
+        let mut config = Config::default();
+        // Lines naming a database, checked once `databases` is known
+        let mut db_lines = BTreeMap::new();
+        for (idx, line) in s.lines().enumerate() {
+            let args = line.split_whitespace().collect::<Vec<_>>();
+            if args.is_empty() || args[0].starts_with('#') {
+                continue;
+            }
+            let directive = args[0].to_ascii_lowercase();
+            config
+                .set(&directive, &args[1..])
+                .map_err(|e| Error::Directive(idx + 1, e))?;
+            if let ("ordered-keyspace", [_, db]) = (directive.as_ref(), &args[1..]) {
+                db_lines.insert(db.parse::<usize>().expect("Checked by set"), idx + 1);
+            }
+        }
+        match db_lines.range(config.databases..).next() {
+            Some((db, &line)) => Err(Error::Directive(
+                line,
+                format!(
+                    "database {} out of range, {} configured",
+                    db, config.databases
+                ),
+            )),
+            None => Ok(config),
+        }
+    }
+
+    /// Whether database `db` keeps an ordered index of its keys
+    pub fn is_ordered(&self, db: usize) -> bool {
+        *self
+            .ordered_databases
+            .get(&db)
+            .unwrap_or(&self.ordered_keyspace)
+    }
+
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.bind, self.port)
+    }
+
+    fn set(&mut self, directive: &str, args: &[&str]) -> Result<(), String> {
+        match (directive, args) {
+            ("bind", [addr]) => self.bind = addr.to_string(),
+            ("port", [port]) => {
+                self.port = port.parse().map_err(|_| format!("invalid port {}", port))?
+            }
+            ("unixsocket", [path]) => self.unixsocket = Some(path.to_string()),
+            ("unixsocketperm", [mode]) => {
+                self.unixsocketperm = Some(
+                    u32::from_str_radix(mode, 8)
+                        .map_err(|_| format!("invalid octal permissions {}", mode))?,
+                )
+            }
+            ("client-output-buffer-limit", [class, hard, soft, secs]) => {
+                let class = match class.to_ascii_lowercase().as_ref() {
+                    "normal" => Class::Normal,
+                    "pubsub" => Class::Pubsub,
+                    "replica" | "slave" => Class::Replica,
+                    _ => return Err(format!("invalid client class {}", class)),
+                };
+                let memory = |s: &str| parse_memory(s).ok_or(format!("invalid memory {}", s));
+                *self.client_output_buffer_limit.get_mut(class) = Limit::new(
+                    memory(hard)?,
+                    memory(soft)?,
+                    Duration::from_secs(
+                        secs.parse()
+                            .map_err(|_| format!("invalid duration {}", secs))?,
+                    ),
+                );
+            }
+            ("maxclients", [n]) => {
+                self.maxclients = match n.parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(format!("invalid number of clients {}", n)),
+                }
+            }
+            ("maxclients-per-ip", [n]) => {
+                self.maxclients_per_ip = n
+                    .parse()
+                    .map_err(|_| format!("invalid number of clients {}", n))?
+            }
+            ("timeout", [secs]) => self.timeout = parse_seconds(secs)?,
+            ("tcp-keepalive", [secs]) => self.tcp_keepalive = parse_seconds(secs)?,
+            ("databases", [n]) => {
+                self.databases = match n.parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(format!("invalid number of databases {}", n)),
+                }
+            }
+            ("ordered-keyspace", [flag, db @ ..]) if db.len() <= 1 => {
+                let ordered = match flag.to_ascii_lowercase().as_ref() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err(format!("expected yes or no, got {}", flag)),
+                };
+                match db.first() {
+                    Some(db) => {
+                        let db = db.parse().map_err(|_| format!("invalid database {}", db))?;
+                        self.ordered_databases.insert(db, ordered);
+                    }
+                    None => self.ordered_keyspace = ordered,
+                }
+            }
+            ("requirepass", [password]) => self.requirepass = Some(password.to_string()),
+            ("aclfile", [path]) => self.aclfile = Some(path.to_string()),
+            ("tls-port", [port]) => {
+                self.tls_port = Some(port.parse().map_err(|_| format!("invalid port {}", port))?)
+            }
+            ("tls-cert-file", [path]) => self.tls_cert_file = Some(path.to_string()),
+            ("tls-key-file", [path]) => self.tls_key_file = Some(path.to_string()),
+            ("tls-ca-cert-file", [path]) => self.tls_ca_cert_file = Some(path.to_string()),
+            ("tls-auth-clients", [auth]) => {
+                self.tls_auth_clients = match auth.to_ascii_lowercase().as_ref() {
+                    "no" => TlsAuth::No,
+                    "optional" => TlsAuth::Optional,
+                    "yes" => TlsAuth::Yes,
+                    _ => return Err(format!("expected yes, no or optional, got {}", auth)),
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "bad directive or wrong number of arguments for {}",
+                    directive
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn memory() {
+        assert_eq!(parse_memory("100"), Some(100));
+        assert_eq!(parse_memory("1k"), Some(1000));
+        assert_eq!(parse_memory("64mb"), Some(64 << 20));
+        assert_eq!(parse_memory("1GB"), Some(1 << 30));
+        assert_eq!(parse_memory("12xb"), None);
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = Config::parse(
+            "# comment\nport 6000\nmaxclients 50\ntimeout 0\ntcp-keepalive 60\n\nclient-output-buffer-limit pubsub 1mb 512kb 10\nordered-keyspace yes\nordered-keyspace no 3\ndatabases 4\nrequirepass hunter2\ntls-port 6001\ntls-auth-clients optional\nunixsocket /tmp/kv.sock\nunixsocketperm 770\n",
+        )
+        .unwrap();
+        assert_eq!(config.addr(), "0.0.0.0:6000");
+        assert_eq!(config.databases, 4);
+        assert_eq!(config.maxclients, 50);
+        assert_eq!(config.timeout, None);
+        assert_eq!(config.tcp_keepalive, Some(Duration::from_secs(60)));
+        assert_eq!(config.requirepass, Some("hunter2".into()));
+        assert_eq!(config.tls_port, Some(6001));
+        assert_eq!(config.unixsocketperm, Some(0o770));
+        assert_eq!(config.tls_auth_clients, TlsAuth::Optional);
+        assert!(config.is_ordered(0) && !config.is_ordered(3));
+        assert_eq!(
+            config.client_output_buffer_limit.pubsub,
+            Limit::new(1 << 20, 512 << 10, Duration::from_secs(10))
+        );
+        assert_eq!(config.client_output_buffer_limit.normal, Limit::unlimited());
+    }
+
+    #[test]
+    fn bad_directive() {
+        match Config::parse("port 1\nclient-output-buffer-limit normal 1 2\n") {
+            Err(Error::Directive(2, _)) => (),
+            e => panic!("expected directive error, got {:?}", e),
+        }
+        match Config::parse("ordered-keyspace yes 4\ndatabases 4\n") {
+            Err(Error::Directive(1, _)) => (),
+            e => panic!("expected directive error, got {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use super::config::Config;
+use super::keyspace::Keyspace;
+use super::parser::Value;
+use super::{Database, Error, Key};
+use std::mem;
+use std::sync::{Mutex, MutexGuard};
+
+/// The numbered logical databases of a server. Each has its own lock, so
+/// that clients of different databases don't contend, and keeps its own
+/// keys, expiration index and blocked clients.
+///
+/// Commands spanning databases lock them in index order, which can't
+/// deadlock as long as the caller holds no other database lock meanwhile.
+pub struct Databases {
+    dbs: Vec<Mutex<Database>>,
+}
+
+impl Databases {
+    pub fn new(config: &Config) -> Self {
+        let dbs = (0..config.databases)
+            .map(|i| {
+                Mutex::new(match config.is_ordered(i) {
+                    true => Database::ordered(),
+                    false => Database::new(),
+                })
+            })
+            .collect();
+        Databases { dbs }
+    }
+
+    pub fn len(&self) -> usize {
+        self.dbs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dbs.is_empty()
+    }
+
+    pub fn lock(&self, index: usize) -> MutexGuard<'_, Database> {
+        self.dbs[index].lock().expect("Poisoned database lock")
+    }
+
+    fn check(&self, index: usize) -> Result<(), Error> {
+        match index < self.dbs.len() {
+            true => Ok(()),
+            false => Err(Error::DbIndex),
+        }
+    }
+
+    /// Lock two distinct databases, returning their guards in the order
+    /// asked for
+    fn lock_pair(
+        &self,
+        a: usize,
+        b: usize,
+    ) -> (MutexGuard<'_, Database>, MutexGuard<'_, Database>) {
+        if a < b {
+            let first = self.lock(a);
+            (first, self.lock(b))
+        } else {
+            let first = self.lock(b);
+            (self.lock(a), first)
+        }
+    }
+
+    /// Move `key` from database `from` to `to`, along with its expiration
+    /// time and subscribers. Replies 1 if moved, or 0 if `key` is missing
+    /// or `to` already holds it.
+    pub fn move_key(&self, from: usize, key: &str, to: usize) -> Result<Value, Error> {
+        self.check(to)?;
+        if from == to {
+            return Err(Error::SameObject);
+        }
+        let (mut source, mut dest) = self.lock_pair(from, to);
+        source.expire_due();
+        dest.expire_due();
+        if !source.data.contains_key(key) || dest.data.contains_key(key) {
+            return Ok(Value::Integer(0));
+        }
+        let entry = source.data.remove(key).expect("Key exists");
+        let expiration = entry.expiration;
+        dest.data.insert(key.into(), entry);
+        if let Some(at) = expiration {
+            dest.expirations.insert((at, key.into()));
+        }
+        dest.serve_blocked(key);
+        Ok(Value::Integer(1))
+    }
+
+    /// Copy `source` in database `from` to `dest` in database `to`, which
+    /// must differ, replying whether copied
+    pub fn copy(
+        &self,
+        from: usize,
+        source: &str,
+        to: usize,
+        dest: Key,
+        replace: bool,
+    ) -> Result<Value, Error> {
+        self.check(to)?;
+        let (mut source_db, mut dest_db) = self.lock_pair(from, to);
+        source_db.expire_due();
+        dest_db.expire_due();
+        let copied = match source_db.copy_out(source) {
+            Some(copy) => dest_db.copy_in(dest, copy, replace),
+            None => false,
+        };
+        Ok(Value::Integer(copied as i64))
+    }
+
+    /// Swap the contents of two databases, so that clients of either see
+    /// the keys of the other. Clients blocked on either are served by any
+    /// keys they can now be.
+    pub fn swap(&self, a: usize, b: usize) -> Result<Value, Error> {
+        self.check(a)?;
+        self.check(b)?;
+        if a != b {
+            let (mut a, mut b) = self.lock_pair(a, b);
+            a.data.swap(&mut b.data);
+            mem::swap(&mut a.expirations, &mut b.expirations);
+            a.serve_all_blocked();
+            b.serve_all_blocked();
+        }
+        Ok(Value::Status("OK".into()))
+    }
+
+    /// Empty every database at once
+    pub fn flush_all(&self) -> Value {
+        let mut guards: Vec<_> = (0..self.dbs.len()).map(|i| self.lock(i)).collect();
+        for db in &mut guards {
+            db.flush();
+        }
+        Value::Status("OK".into())
+    }
+}
+
+impl Database {
+    /// Remove every key, keeping the database ordered if it was
+    pub fn flush(&mut self) {
+        self.data = Keyspace::new(self.data.is_ordered());
+        self.expirations.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use blocking::{Block, Blocked};
+    use buffer::{Limits, Output};
+    use parser::End;
+    use std::time::{Duration, SystemTime};
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.into())
+    }
+
+    fn databases(n: usize) -> Databases {
+        Databases::new(&Config {
+            databases: n,
+            ..Config::default()
+        })
+    }
+
+    #[test]
+    fn move_keys() {
+        let dbs = databases(3);
+        dbs.lock(0).create("k".into(), text("v"));
+        let soon = SystemTime::now() + Duration::from_secs(60);
+        assert!(dbs.lock(0).set_expiration("k", Some(soon)));
+        dbs.lock(2).create("taken".into(), text("v"));
+        dbs.lock(0).create("taken".into(), text("w"));
+
+        assert_eq!(dbs.move_key(0, "k", 1), Ok(Value::Integer(1)));
+        assert_eq!(dbs.move_key(0, "k", 1), Ok(Value::Integer(0)));
+        assert_eq!(dbs.move_key(0, "taken", 2), Ok(Value::Integer(0)));
+        assert_eq!(dbs.move_key(0, "k", 0), Err(Error::SameObject));
+        assert_eq!(dbs.move_key(0, "k", 3), Err(Error::DbIndex));
+        assert!(dbs.lock(0).read("k").is_none());
+        let db = dbs.lock(1);
+        assert_eq!(db.data.get("k").and_then(|e| e.expiration), Some(soon));
+        assert!(db.expirations.contains(&(soon, "k".into())));
+        drop(db);
+
+        assert_eq!(
+            dbs.copy(1, "k", 2, "k".into(), false),
+            Ok(Value::Integer(1))
+        );
+        assert_eq!(
+            dbs.copy(1, "k", 2, "k".into(), false),
+            Ok(Value::Integer(0))
+        );
+        assert_eq!(dbs.lock(2).read("k"), Some(&text("v")));
+    }
+
+    #[test]
+    fn swap_and_flush() {
+        let dbs = databases(2);
+        dbs.lock(0).create("a".into(), text("0"));
+        let output = Output::new(Limits::default(), None);
+        let id = match dbs
+            .lock(1)
+            .block(vec!["l".into()], Blocked::Pop(End::Front), output)
+        {
+            Ok(Block::Waiting(id)) => id,
+            r => panic!("expected to block, got {:?}", r),
+        };
+        dbs.lock(0)
+            .push("l".into(), vec![text("x")], End::Back)
+            .unwrap();
+
+        // The client blocked on database 1 is served by the list swapped in
+        assert_eq!(dbs.swap(0, 1), Ok(Value::Status("OK".into())));
+        assert_eq!(
+            dbs.lock(1).unblock(id),
+            Some(Value::Array(vec![text("l"), text("x")]))
+        );
+        assert_eq!(dbs.lock(1).read("a"), Some(&text("0")));
+        assert!(dbs.lock(0).read("a").is_none());
+        assert_eq!(dbs.swap(0, 2), Err(Error::DbIndex));
+
+        dbs.lock(0).create("b".into(), text("1"));
+        dbs.flush_all();
+        assert!(dbs.lock(0).data.is_empty());
+        assert!(dbs.lock(1).data.is_empty());
+    }
+
+    #[test]
+    fn swap_keeps_ordering() {
+        let mut config = Config {
+            databases: 2,
+            ..Config::default()
+        };
+        config.ordered_databases.insert(0, true);
+        let dbs = Databases::new(&config);
+        dbs.lock(0).create("a0".into(), text("0"));
+        dbs.lock(1).create("b1".into(), text("1"));
+        dbs.lock(1).create("b2".into(), text("2"));
+
+        assert_eq!(dbs.swap(0, 1), Ok(Value::Status("OK".into())));
+        let pairs = vec![text("b1"), text("1"), text("b2"), text("2")];
+        assert_eq!(dbs.lock(0).prefix("b"), Ok(Value::Array(pairs)));
+        assert_eq!(dbs.lock(0).prefix("a"), Ok(Value::Array(Vec::new())));
+        assert_eq!(dbs.lock(1).prefix("a"), Err(Error::Unordered));
+        assert!(!dbs.lock(1).data.is_ordered());
+
+        assert_eq!(dbs.swap(1, 0), Ok(Value::Status("OK".into())));
+        let pairs = vec![text("a0"), text("0")];
+        assert_eq!(dbs.lock(0).prefix(""), Ok(Value::Array(pairs)));
+        assert_eq!(dbs.lock(1).prefix("b"), Err(Error::Unordered));
+    }
+}
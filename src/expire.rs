@@ -0,0 +1,92 @@
+use super::parser::Expire;
+use super::stats::STATS;
+use super::Database;
+use std::time::SystemTime;
+
+impl Expire {
+    /// The point in time this resolves to, if any
+    pub fn at(&self) -> Option<SystemTime> {
+        match self {
+            Expire::In(duration) => Some(SystemTime::now() + *duration),
+            Expire::At(time) => Some(*time),
+            Expire::Persist => None,
+        }
+    }
+}
+
+impl Database {
+    /// Set or clear the expiration time of `key`, deleting it straight away
+    /// if that time has already passed. Returns whether the key exists.
+    pub fn set_expiration(&mut self, key: &str, at: Option<SystemTime>) -> bool {
+        if let Some(at) = at {
+            if at <= SystemTime::now() {
+                return self.data.remove(key).is_some();
+            }
+        }
+        match self.data.get_mut(key) {
+            Some(entry) => {
+                entry.expiration = at;
+                if let Some(at) = at {
+                    self.expirations.insert((at, key.into()));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove every key whose expiration time has passed. The index may
+    /// hold stale times for keys that were since overwritten or persisted,
+    /// so each one is checked against the key's current expiration.
+    pub fn expire_due(&mut self) {
+        let now = SystemTime::now();
+        while let Some((at, key)) = self.expirations.iter().next().cloned() {
+            if at > now {
+                break;
+            }
+            self.expirations.remove(&(at, key.clone()));
+            if self.data.get(&key).and_then(|e| e.expiration) == Some(at) {
+                self.data.remove(&key);
+                STATS.expired.add(1);
+            }
+        }
+    }
+
+    /// Number of keys with an expiration time, skipping the stale times
+    /// in the index
+    pub fn expiring(&self) -> usize {
+        self.expirations
+            .iter()
+            .filter(|(at, key)| self.data.get(key).and_then(|e| e.expiration) == Some(*at))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parser::Value;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn expire() {
+        let mut db = Database::new();
+        db.create("a".into(), Value::Integer(1));
+        db.create("b".into(), Value::Integer(2));
+        let soon = Expire::In(Duration::from_millis(20)).at();
+        assert!(db.set_expiration("a", soon));
+        assert!(db.set_expiration("b", soon));
+        assert!(!db.set_expiration("c", soon));
+        // Overwriting a key clears its expiration
+        db.create("b".into(), Value::Integer(3));
+        thread::sleep(Duration::from_millis(30));
+        db.expire_due();
+        assert!(db.read("a").is_none());
+        assert_eq!(db.read("b"), Some(&Value::Integer(3)));
+        assert!(db.expirations.is_empty());
+
+        assert!(db.set_expiration("b", Some(SystemTime::UNIX_EPOCH)));
+        assert!(db.read("b").is_none());
+    }
+}
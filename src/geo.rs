@@ -0,0 +1,518 @@
+use super::parser::{GeoFrom, GeoMember, GeoSearch, GeoShape, Sort, Value, ZAddFlags};
+use super::{Database, Entry, Error, Key};
+use std::collections::{BTreeSet, HashMap};
+
+/// Bits per coordinate in a geohash, giving cells well under a metre wide
+const STEP: u32 = 26;
+/// Latitudes beyond this can't be projected onto the Web Mercator square
+pub const LAT_MAX: f64 = 85.051_128_78;
+pub const LON_MAX: f64 = 180.0;
+/// Earth's radius in metres, as used by Redis
+const EARTH_RADIUS: f64 = 6_372_797.560_856;
+/// Half the circumference of the Earth in Web Mercator metres
+const MERCATOR_MAX: f64 = 20_037_726.37;
+const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Spread the low 32 bits of `x` out to the even bits of the result
+fn spread(x: u32) -> u64 {
+    let mut x = x as u64;
+    x = (x | (x << 16)) & 0x0000_ffff_0000_ffff;
+    x = (x | (x << 8)) & 0x00ff_00ff_00ff_00ff;
+    x = (x | (x << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+    (x | (x << 1)) & 0x5555_5555_5555_5555
+}
+
+/// Gather the even bits of `x` back together
+fn squash(x: u64) -> u32 {
+    let mut x = x & 0x5555_5555_5555_5555;
+    x = (x | (x >> 1)) & 0x3333_3333_3333_3333;
+    x = (x | (x >> 2)) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | (x >> 4)) & 0x00ff_00ff_00ff_00ff;
+    x = (x | (x >> 8)) & 0x0000_ffff_0000_ffff;
+    ((x | (x >> 16)) & 0x0000_0000_ffff_ffff) as u32
+}
+
+/// Index of the cell holding `value` when `-max..max` is cut into
+/// `2^step` cells
+fn cell(value: f64, max: f64, step: u32) -> u32 {
+    let offset = (value + max) / (2.0 * max);
+    ((offset * (1u64 << step) as f64) as u64).min((1u64 << step) - 1) as u32
+}
+
+/// Interleave cell indices, longitude taking the odd bits so that it leads
+fn interleave(lon: u32, lat: u32) -> u64 {
+    spread(lat) | (spread(lon) << 1)
+}
+
+/// Encode a position to a `2 * step` bit geohash
+fn encode(lon: f64, lat: f64, lat_max: f64, step: u32) -> u64 {
+    interleave(cell(lon, LON_MAX, step), cell(lat, lat_max, step))
+}
+
+/// The centre of the cell a full precision geohash names
+fn decode(hash: u64) -> (f64, f64) {
+    let center = |index: u32, max: f64| {
+        let size = 2.0 * max / (1u64 << STEP) as f64;
+        (-max + (index as f64 + 0.5) * size).clamp(-max, max)
+    };
+    (
+        center(squash(hash >> 1), LON_MAX),
+        center(squash(hash), LAT_MAX),
+    )
+}
+
+/// Great circle distance in metres between two positions
+pub fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let u = ((lat2 - lat1) / 2.0).sin();
+    let v = ((lon2 - lon1).to_radians() / 2.0).sin();
+    2.0 * EARTH_RADIUS * (u * u + lat1.cos() * lat2.cos() * v * v).sqrt().asin()
+}
+
+/// The standard 11 character geohash of a position, which unlike the
+/// stored hash covers latitudes up to the poles
+fn geohash_string(lon: f64, lat: f64) -> String {
+    let hash = encode(lon, lat, 90.0, STEP);
+    (0..11)
+        .map(|i| {
+            // The last character only has padding left to encode
+            let index = if i == 10 {
+                0
+            } else {
+                (hash >> (52 - (i + 1) * 5)) & 0x1f
+            };
+            BASE32[index as usize] as char
+        })
+        .collect()
+}
+
+/// The coarsest cell size, as a step, such that a cell and its neighbours
+/// are sure to cover `radius` metres around a point at latitude `lat`
+fn search_step(radius: f64, lat: f64) -> u32 {
+    if radius == 0.0 {
+        return STEP;
+    }
+    let (mut step, mut range) = (1i32, radius);
+    while range < MERCATOR_MAX {
+        range *= 2.0;
+        step += 1;
+    }
+    // Two steps back, cells are over twice as wide as the radius at the
+    // equator but may fall just short of it in height, so go one coarser
+    // still, and more again as meridians converge
+    step -= 3;
+    if lat.abs() > 66.0 {
+        step -= 1;
+        if lat.abs() > 80.0 {
+            step -= 1;
+        }
+    }
+    step.clamp(1, STEP as i32) as u32
+}
+
+/// A set of members with positions, ordered by geohash so that the members
+/// in an area can be found through a few range lookups
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct GeoSet {
+    hashes: HashMap<String, u64>,
+    index: BTreeSet<(u64, String)>,
+}
+
+impl GeoSet {
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// Set the position of `member`, returning its previous geohash
+    pub fn insert(&mut self, member: String, lon: f64, lat: f64) -> Option<u64> {
+        let hash = encode(lon, lat, LAT_MAX, STEP);
+        let old = self.hashes.insert(member.clone(), hash);
+        if let Some(old) = old {
+            self.index.remove(&(old, member.clone()));
+        }
+        self.index.insert((hash, member));
+        old
+    }
+
+    /// The position of `member`, as the centre of its geohash cell
+    pub fn position(&self, member: &str) -> Option<(f64, f64)> {
+        self.hashes.get(member).map(|&hash| decode(hash))
+    }
+
+    /// Members ordered by geohash
+    pub fn members(&self) -> impl Iterator<Item = &str> {
+        self.index.iter().map(|(_, m)| m.as_str())
+    }
+
+    /// Members within `shape` around a point, with their distance from it
+    /// and geohash, in no particular order
+    fn search(&self, lon: f64, lat: f64, shape: &GeoShape) -> Vec<(&str, f64, u64)> {
+        let radius = match *shape {
+            GeoShape::Radius(r) => r,
+            GeoShape::Box(w, h) => (w * w + h * h).sqrt() / 2.0,
+        };
+        let step = search_step(radius, lat);
+        let (x, y) = (cell(lon, LON_MAX, step), cell(lat, LAT_MAX, step));
+        let cells = 1i64 << step;
+        let mut boxes = BTreeSet::new();
+        for dy in -1..=1 {
+            let y = y as i64 + dy;
+            if y < 0 || y >= cells {
+                continue;
+            }
+            for dx in -1..=1 {
+                // Longitude wraps around the antimeridian
+                let x = (x as i64 + dx).rem_euclid(cells);
+                boxes.insert(interleave(x as u32, y as u32));
+            }
+        }
+        let shift = 2 * (STEP - step);
+        let mut found = Vec::new();
+        for cell in boxes {
+            let (min, max) = (cell << shift, (cell + 1) << shift);
+            for (hash, member) in self.index.range((min, String::new())..(max, String::new())) {
+                let (mlon, mlat) = decode(*hash);
+                let dist = distance(lon, lat, mlon, mlat);
+                let inside = match *shape {
+                    GeoShape::Radius(r) => dist <= r,
+                    GeoShape::Box(w, h) => {
+                        distance(lon, lat, lon, mlat) <= h / 2.0
+                            && distance(lon, mlat, mlon, mlat) <= w / 2.0
+                    }
+                };
+                if inside {
+                    found.push((member.as_str(), dist, *hash));
+                }
+            }
+        }
+        found
+    }
+}
+
+/// Format a distance in `unit` metres the way GEODIST replies with it
+fn format_distance(meters: f64, unit: f64) -> String {
+    format!("{:.4}", meters / unit)
+}
+
+fn coordinates(lon: f64, lat: f64) -> Value {
+    Value::Array(vec![
+        Value::Text(lon.to_string()),
+        Value::Text(lat.to_string()),
+    ])
+}
+
+impl Database {
+    fn geo(&self, key: &str) -> Result<Option<&GeoSet>, Error> {
+        match self.data.get(key).map(|e| &e.value) {
+            Some(Value::Geo(geo)) => Ok(Some(geo)),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Add or move members, honouring NX, XX and CH. Replies with the
+    /// number of members added, or also moved with CH.
+    pub fn geoadd(
+        &mut self,
+        key: Key,
+        flags: ZAddFlags,
+        members: Vec<GeoMember>,
+    ) -> Result<Value, Error> {
+        self.geo(&key)?;
+        let entry = self
+            .data
+            .get_or_insert_with(key.clone(), || Entry::new(Value::Geo(GeoSet::default())));
+        let geo = match entry.value {
+            Value::Geo(ref mut geo) => geo,
+            _ => return Err(Error::WrongType),
+        };
+        let mut changed = 0;
+        for (lon, lat, member) in members {
+            let exists = geo.hashes.contains_key(&member);
+            if (exists && flags.nx) || (!exists && flags.xx) {
+                continue;
+            }
+            let hash = encode(lon, lat, LAT_MAX, STEP);
+            match geo.insert(member, lon, lat) {
+                None => changed += 1,
+                Some(old) if flags.ch && old != hash => changed += 1,
+                Some(_) => (),
+            }
+        }
+        if geo.is_empty() {
+            self.data.remove(&key);
+        }
+        Ok(Value::Integer(changed))
+    }
+
+    pub fn geopos(&self, key: &str, members: &[String]) -> Result<Value, Error> {
+        let geo = self.geo(key)?;
+        Ok(Value::Array(
+            members
+                .iter()
+                .map(|m| match geo.and_then(|g| g.position(m)) {
+                    Some((lon, lat)) => coordinates(lon, lat),
+                    None => Value::Null,
+                })
+                .collect(),
+        ))
+    }
+
+    /// Distance between two members in `unit` metres, or null if either is
+    /// missing
+    pub fn geodist(&self, key: &str, a: &str, b: &str, unit: f64) -> Result<Value, Error> {
+        let geo = match self.geo(key)? {
+            Some(geo) => geo,
+            None => return Ok(Value::Null),
+        };
+        Ok(match (geo.position(a), geo.position(b)) {
+            (Some((lon1, lat1)), Some((lon2, lat2))) => {
+                Value::Text(format_distance(distance(lon1, lat1, lon2, lat2), unit))
+            }
+            _ => Value::Null,
+        })
+    }
+
+    pub fn geohash(&self, key: &str, members: &[String]) -> Result<Value, Error> {
+        let geo = self.geo(key)?;
+        Ok(Value::Array(
+            members
+                .iter()
+                .map(|m| match geo.and_then(|g| g.position(m)) {
+                    Some((lon, lat)) => Value::Text(geohash_string(lon, lat)),
+                    None => Value::Null,
+                })
+                .collect(),
+        ))
+    }
+
+    pub fn geosearch(&self, key: &str, search: &GeoSearch) -> Result<Value, Error> {
+        let geo = match self.geo(key)? {
+            Some(geo) => geo,
+            None => return Ok(Value::Array(Vec::new())),
+        };
+        let (lon, lat) = match search.from {
+            GeoFrom::Member(ref member) => geo.position(member).ok_or(Error::NoSuchMember)?,
+            GeoFrom::LonLat(lon, lat) => (lon, lat),
+        };
+        let mut found = geo.search(lon, lat, &search.shape);
+        // COUNT without ANY means the closest matches
+        let sort = match (search.sort, search.count) {
+            (None, Some((_, false))) => Some(Sort::Asc),
+            (sort, _) => sort,
+        };
+        match sort {
+            Some(Sort::Asc) => found.sort_by(|a, b| a.1.total_cmp(&b.1)),
+            Some(Sort::Desc) => found.sort_by(|a, b| b.1.total_cmp(&a.1)),
+            None => (),
+        }
+        if let Some((count, _)) = search.count {
+            found.truncate(count);
+        }
+        let plain = !(search.with_dist || search.with_hash || search.with_coord);
+        Ok(Value::Array(
+            found
+                .into_iter()
+                .map(|(member, dist, hash)| {
+                    if plain {
+                        return Value::Text(member.into());
+                    }
+                    let mut item = vec![Value::Text(member.into())];
+                    if search.with_dist {
+                        item.push(Value::Text(format_distance(dist, search.unit)));
+                    }
+                    if search.with_hash {
+                        item.push(Value::Integer(hash as i64));
+                    }
+                    if search.with_coord {
+                        let (lon, lat) = decode(hash);
+                        item.push(coordinates(lon, lat));
+                    }
+                    Value::Array(item)
+                })
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn search(from: GeoFrom, shape: GeoShape) -> GeoSearch {
+        GeoSearch {
+            from,
+            shape,
+            unit: 1000.0,
+            sort: Some(Sort::Asc),
+            count: None,
+            with_coord: false,
+            with_dist: false,
+            with_hash: false,
+        }
+    }
+
+    fn sicily() -> Database {
+        let mut db = Database::new();
+        let members = vec![
+            (13.361389, 38.115556, "Palermo".into()),
+            (15.087269, 37.502669, "Catania".into()),
+            (12.758489, 38.788135, "edge1".into()),
+            (17.241510, 38.788135, "edge2".into()),
+        ];
+        db.geoadd("Sicily".into(), ZAddFlags::default(), members)
+            .unwrap();
+        db
+    }
+
+    #[test]
+    fn hashes() {
+        assert_eq!(squash(spread(0xdead_beef)), 0xdead_beef);
+        let hash = encode(13.361389, 38.115556, LAT_MAX, STEP);
+        // The score Redis gives Palermo
+        assert_eq!(hash, 3479099956230698);
+        let (lon, lat) = decode(hash);
+        assert!((lon - 13.361389).abs() < 1e-5 && (lat - 38.115556).abs() < 1e-5);
+        assert_eq!(geohash_string(13.361389, 38.115556), "sqc8b49rny0");
+        assert_eq!(geohash_string(15.087269, 37.502669), "sqdtr74hyu0");
+    }
+
+    #[test]
+    fn distances() {
+        let db = sicily();
+        assert_eq!(
+            db.geodist("Sicily", "Palermo", "Catania", 1.0),
+            Ok(Value::Text("166274.1516".into()))
+        );
+        assert_eq!(
+            db.geodist("Sicily", "Palermo", "Catania", 1000.0),
+            Ok(Value::Text("166.2742".into()))
+        );
+        assert_eq!(
+            db.geodist("Sicily", "Palermo", "Rome", 1.0),
+            Ok(Value::Null)
+        );
+        match db.geopos("Sicily", &["Palermo".into(), "Rome".into()]) {
+            Ok(Value::Array(positions)) => assert_eq!(positions[1], Value::Null),
+            r => panic!("unexpected reply {:?}", r),
+        }
+    }
+
+    #[test]
+    fn searches() {
+        let db = sicily();
+        let names = |v: Value| match v {
+            Value::Array(items) => items,
+            r => panic!("unexpected reply {:?}", r),
+        };
+        let found = db.geosearch(
+            "Sicily",
+            &search(GeoFrom::LonLat(15.0, 37.0), GeoShape::Radius(200_000.0)),
+        );
+        assert_eq!(
+            names(found.unwrap()),
+            vec![Value::Text("Catania".into()), Value::Text("Palermo".into())]
+        );
+        let found = db.geosearch(
+            "Sicily",
+            &search(
+                GeoFrom::LonLat(15.0, 37.0),
+                GeoShape::Box(400_000.0, 400_000.0),
+            ),
+        );
+        assert_eq!(
+            names(found.unwrap()),
+            vec![
+                Value::Text("Catania".into()),
+                Value::Text("Palermo".into()),
+                Value::Text("edge2".into()),
+                Value::Text("edge1".into()),
+            ]
+        );
+        let mut nearest = search(
+            GeoFrom::Member("Palermo".into()),
+            GeoShape::Radius(500_000.0),
+        );
+        nearest.sort = None;
+        nearest.count = Some((1, false));
+        nearest.with_dist = true;
+        assert_eq!(
+            names(db.geosearch("Sicily", &nearest).unwrap()),
+            vec![Value::Array(vec![
+                Value::Text("Palermo".into()),
+                Value::Text("0.0000".into())
+            ])]
+        );
+        nearest.from = GeoFrom::Member("Rome".into());
+        assert_eq!(db.geosearch("Sicily", &nearest), Err(Error::NoSuchMember));
+    }
+
+    #[test]
+    fn search_matches_scan() {
+        use random;
+        let coordinate = |max: f64| (random::next_u64() % 1_000_000) as f64 / 1e6 * 2.0 * max - max;
+        let mut geo = GeoSet::default();
+        for i in 0..2000 {
+            // Cluster half of the members so that small searches find some
+            let (lon, lat) = if i % 2 == 0 {
+                (coordinate(LON_MAX), coordinate(LAT_MAX))
+            } else {
+                (coordinate(1.0) + 10.0, coordinate(1.0) + 70.0)
+            };
+            geo.insert(format!("m{}", i), lon, lat);
+        }
+        for i in 0..200 {
+            let (lon, lat) = if i % 2 == 0 {
+                (coordinate(LON_MAX), coordinate(LAT_MAX))
+            } else {
+                (coordinate(1.0) + 10.0, coordinate(1.0) + 70.0)
+            };
+            let radius = (random::next_u64() % 2_000_000) as f64 + 1.0;
+            let shape = if i % 3 == 0 {
+                GeoShape::Box(radius, radius / 2.0)
+            } else {
+                GeoShape::Radius(radius)
+            };
+            let mut found: Vec<&str> = geo.search(lon, lat, &shape).iter().map(|f| f.0).collect();
+            let mut expected: Vec<&str> = geo
+                .index
+                .iter()
+                .filter(|(hash, _)| {
+                    let (mlon, mlat) = decode(*hash);
+                    match shape {
+                        GeoShape::Radius(r) => distance(lon, lat, mlon, mlat) <= r,
+                        GeoShape::Box(w, h) => {
+                            distance(lon, lat, lon, mlat) <= h / 2.0
+                                && distance(lon, mlat, mlon, mlat) <= w / 2.0
+                        }
+                    }
+                })
+                .map(|(_, m)| m.as_str())
+                .collect();
+            found.sort_unstable();
+            expected.sort_unstable();
+            assert_eq!(found, expected, "{:?} around {},{}", shape, lon, lat);
+        }
+    }
+
+    #[test]
+    fn search_across_antimeridian() {
+        let mut db = Database::new();
+        let members = vec![(179.9, 0.0, "east".into()), (-179.9, 0.0, "west".into())];
+        db.geoadd("g".into(), ZAddFlags::default(), members)
+            .unwrap();
+        let found = db.geosearch(
+            "g",
+            &search(GeoFrom::LonLat(180.0, 0.0), GeoShape::Radius(50_000.0)),
+        );
+        match found {
+            Ok(Value::Array(items)) => assert_eq!(items.len(), 2),
+            r => panic!("unexpected reply {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use super::keys::{self, Positions};
+use super::parser::{Scan, Value};
+use super::{Database, Entry, Error, Key};
+use std::collections::hash_map::{self, HashMap};
+use std::fmt;
+
+/// The fields of a hash and their values, indexed in SCAN order
+#[derive(Clone, Default)]
+pub struct Fields {
+    map: HashMap<String, Value>,
+    positions: Positions,
+}
+
+impl fmt::Debug for Fields {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.map.fmt(f)
+    }
+}
+
+impl PartialEq for Fields {
+    fn eq(&self, other: &Fields) -> bool {
+        self.map == other.map
+    }
+}
+
+impl From<HashMap<String, Value>> for Fields {
+    fn from(map: HashMap<String, Value>) -> Self {
+        let mut positions = Positions::default();
+        map.keys().for_each(|field| positions.insert(field));
+        Fields { map, positions }
+    }
+}
+
+impl Fields {
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn get(&self, field: &str) -> Option<&Value> {
+        self.map.get(field)
+    }
+
+    pub fn contains_key(&self, field: &str) -> bool {
+        self.map.contains_key(field)
+    }
+
+    pub fn insert(&mut self, field: String, value: Value) -> Option<Value> {
+        if !self.map.contains_key(&field) {
+            self.positions.insert(&field);
+        }
+        self.map.insert(field, value)
+    }
+
+    pub fn remove(&mut self, field: &str) -> Option<Value> {
+        let value = self.map.remove(field)?;
+        self.positions.remove(field);
+        Some(value)
+    }
+
+    pub fn iter(&self) -> hash_map::Iter<'_, String, Value> {
+        self.map.iter()
+    }
+
+    pub fn keys(&self) -> hash_map::Keys<'_, String, Value> {
+        self.map.keys()
+    }
+
+    pub fn values(&self) -> hash_map::Values<'_, String, Value> {
+        self.map.values()
+    }
+}
+
+impl Database {
+    pub fn hash(&self, key: &str) -> Result<Option<&Fields>, Error> {
+        match self.data.get(key).map(|e| &e.value) {
+            Some(Value::Map(map)) => Ok(Some(map)),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn hash_mut(&mut self, key: &str) -> Result<Option<&mut Fields>, Error> {
+        match self.data.get_mut(key).map(|e| &mut e.value) {
+            Some(Value::Map(map)) => Ok(Some(map)),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn hash_or_insert(&mut self, key: Key) -> Result<&mut Fields, Error> {
+        self.hash(&key)?;
+        let entry = self
+            .data
+            .get_or_insert_with(key, || Entry::new(Value::Map(Fields::default())));
+        match entry.value {
+            Value::Map(ref mut map) => Ok(map),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    /// Set each field, returning how many of them were newly created
+    pub fn hset(&mut self, key: Key, pairs: Vec<(String, Value)>) -> Result<Value, Error> {
+        let map = self.hash_or_insert(key)?;
+        let mut added = 0;
+        for (field, value) in pairs {
+            if map.insert(field, value).is_none() {
+                added += 1;
+            }
+        }
+        Ok(Value::Integer(added))
+    }
+
+    pub fn hget(&self, key: &str, field: &str) -> Result<Value, Error> {
+        Ok(self
+            .hash(key)?
+            .and_then(|map| map.get(field).cloned())
+            .unwrap_or(Value::Null))
+    }
+
+    pub fn hmget(&self, key: &str, fields: &[String]) -> Result<Value, Error> {
+        let map = self.hash(key)?;
+        Ok(Value::Array(
+            fields
+                .iter()
+                .map(|f| map.and_then(|m| m.get(f).cloned()).unwrap_or(Value::Null))
+                .collect(),
+        ))
+    }
+
+    pub fn hdel(&mut self, key: &str, fields: &[String]) -> Result<Value, Error> {
+        let (removed, empty) = match self.hash_mut(key)? {
+            Some(map) => (
+                fields.iter().filter(|f| map.remove(f).is_some()).count(),
+                map.is_empty(),
+            ),
+            None => (0, false),
+        };
+        // Hashes are never stored empty
+        if empty {
+            self.data.remove(key);
+        }
+        Ok(Value::Integer(removed as i64))
+    }
+
+    pub fn hgetall(&self, key: &str) -> Result<Value, Error> {
+        Ok(Value::Map(self.hash(key)?.cloned().unwrap_or_default()))
+    }
+
+    pub fn hkeys(&self, key: &str) -> Result<Value, Error> {
+        Ok(Value::Array(
+            self.hash(key)?
+                .map(|m| m.keys().map(|k| Value::Text(k.clone())).collect())
+                .unwrap_or_default(),
+        ))
+    }
+
+    pub fn hvals(&self, key: &str) -> Result<Value, Error> {
+        Ok(Value::Array(
+            self.hash(key)?
+                .map(|m| m.values().cloned().collect())
+                .unwrap_or_default(),
+        ))
+    }
+
+    pub fn hlen(&self, key: &str) -> Result<Value, Error> {
+        Ok(Value::Integer(
+            self.hash(key)?.map(|m| m.len()).unwrap_or(0) as i64,
+        ))
+    }
+
+    pub fn hexists(&self, key: &str, field: &str) -> Result<Value, Error> {
+        let exists = self.hash(key)?.map(|m| m.contains_key(field));
+        Ok(Value::Integer(exists.unwrap_or(false) as i64))
+    }
+
+    /// Add `delta` to an integer field, creating it at zero if missing
+    pub fn hincrby(&mut self, key: Key, field: String, delta: i64) -> Result<Value, Error> {
+        let map = self.hash_or_insert(key)?;
+        let current = match map.get(&field) {
+            None => 0,
+            Some(Value::Integer(i)) => *i,
+            Some(Value::Text(s)) => s.parse().map_err(|_| Error::HashNotInteger)?,
+            Some(_) => return Err(Error::HashNotInteger),
+        };
+        let next = current.checked_add(delta).ok_or(Error::Overflow)?;
+        map.insert(field, Value::Integer(next));
+        Ok(Value::Integer(next))
+    }
+
+    /// Iterate over the fields of a hash, replying with each field
+    /// followed by its value
+    pub fn hscan(&self, key: &str, scan: &Scan) -> Result<Value, Error> {
+        let map = match self.hash(key)? {
+            Some(map) => map,
+            None => return Ok(keys::reply(0, Vec::new())),
+        };
+        let (next, fields) = map.positions.page(scan);
+        let found = fields
+            .into_iter()
+            .flat_map(|field| {
+                let value = map.get(field).expect("Scanned field").clone();
+                vec![Value::Text(field.into()), value]
+            })
+            .collect();
+        Ok(keys::reply(next, found))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.into())
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, Value)> {
+        pairs
+            .iter()
+            .map(|(f, v)| (f.to_string(), text(v)))
+            .collect()
+    }
+
+    #[test]
+    fn set_get() {
+        let mut db = Database::new();
+        assert_eq!(
+            db.hset("h".into(), pairs(&[("a", "1"), ("b", "2")])),
+            Ok(Value::Integer(2))
+        );
+        assert_eq!(
+            db.hset("h".into(), pairs(&[("a", "3"), ("c", "4")])),
+            Ok(Value::Integer(1))
+        );
+        assert_eq!(db.hget("h", "a"), Ok(text("3")));
+        assert_eq!(db.hget("h", "z"), Ok(Value::Null));
+        assert_eq!(
+            db.hmget("h", &["b".into(), "z".into()]),
+            Ok(Value::Array(vec![text("2"), Value::Null]))
+        );
+        assert_eq!(db.hlen("h"), Ok(Value::Integer(3)));
+        assert_eq!(db.hexists("h", "c"), Ok(Value::Integer(1)));
+        assert_eq!(
+            db.hdel("h", &["a".into(), "b".into(), "c".into(), "z".into()]),
+            Ok(Value::Integer(3))
+        );
+        assert!(db.read("h").is_none());
+    }
+
+    #[test]
+    fn incrby() {
+        let mut db = Database::new();
+        db.hset("h".into(), pairs(&[("n", "10"), ("s", "x")]))
+            .unwrap();
+        assert_eq!(
+            db.hincrby("h".into(), "n".into(), -3),
+            Ok(Value::Integer(7))
+        );
+        assert_eq!(db.hincrby("h".into(), "m".into(), 1), Ok(Value::Integer(1)));
+        assert_eq!(
+            db.hincrby("h".into(), "s".into(), 1),
+            Err(Error::HashNotInteger)
+        );
+        assert_eq!(
+            db.hincrby("h".into(), "n".into(), i64::MAX),
+            Err(Error::Overflow)
+        );
+    }
+
+    #[test]
+    fn scan() {
+        let mut db = Database::new();
+        let fields = (0..25).map(|i| (format!("f{}", i), text("v"))).collect();
+        db.hset("h".into(), fields).unwrap();
+        let mut scan = Scan {
+            cursor: 0,
+            pattern: Some("f1*".into()),
+            count: 10,
+            kind: None,
+        };
+        let mut seen = Vec::new();
+        loop {
+            match db.hscan("h", &scan) {
+                Ok(Value::Array(ref reply)) => {
+                    if let Value::Array(ref found) = reply[1] {
+                        seen.extend(found.iter().step_by(2).cloned());
+                    }
+                    match reply[0] {
+                        Value::Text(ref c) if c == "0" => break,
+                        Value::Text(ref c) => scan.cursor = c.parse().unwrap(),
+                        _ => panic!("bad cursor"),
+                    }
+                }
+                r => panic!("unexpected reply {:?}", r),
+            }
+        }
+        assert_eq!(seen.len(), 11);
+    }
+
+    #[test]
+    fn wrong_type() {
+        let mut db = Database::new();
+        db.create("s".into(), text("v"));
+        assert_eq!(db.hget("s", "a"), Err(Error::WrongType));
+        assert_eq!(
+            db.hset("s".into(), pairs(&[("a", "1")])),
+            Err(Error::WrongType)
+        );
+    }
+}
@@ -0,0 +1,357 @@
+use super::parser::Value;
+use super::{Database, Entry, Error, Key};
+use std::convert::TryInto;
+
+/// Bits of the hash used to pick a register
+const P: u32 = 14;
+/// Number of registers, giving a standard error of 1.04 / sqrt(M) = 0.81%
+const M: usize = 1 << P;
+/// Bits of the hash left to count leading zeros in
+const Q: u32 = 64 - P;
+/// Sparse sketches are converted to dense once they hold this many
+/// registers, at around a quarter of the dense size
+const SPARSE_MAX: usize = 1000;
+
+/// MurmurHash64A, as used by Redis for HyperLogLog
+fn murmur64(data: &[u8], seed: u64) -> u64 {
+    const MUL: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (data.len() as u64).wrapping_mul(MUL);
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().expect("chunk of 8 bytes"));
+        k = k.wrapping_mul(MUL);
+        k ^= k >> R;
+        k = k.wrapping_mul(MUL);
+        h ^= k;
+        h = h.wrapping_mul(MUL);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, &b) in tail.iter().enumerate() {
+            h ^= (b as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(MUL);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(MUL);
+    h ^= h >> R;
+    h
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Registers {
+    /// Non-zero registers only, sorted by index
+    Sparse(Vec<(u16, u8)>),
+    Dense(Vec<u8>),
+}
+
+/// HyperLogLog sketch estimating the number of distinct elements added to
+/// it. Sketches start out sparse, holding only the registers that are set,
+/// and switch to a dense array of every register as they fill up.
+#[derive(Debug, PartialEq, Clone)]
+pub struct HyperLogLog {
+    registers: Registers,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog {
+            registers: Registers::Sparse(Vec::new()),
+        }
+    }
+}
+
+/// Ertl's sigma function for the improved raw estimator
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let (mut y, mut z) = (1.0, x);
+    loop {
+        x *= x;
+        let prev = z;
+        z += x * y;
+        y += y;
+        if z == prev {
+            return z;
+        }
+    }
+}
+
+/// Ertl's tau function for the improved raw estimator
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let (mut y, mut z) = (1.0, 1.0 - x);
+    loop {
+        x = x.sqrt();
+        let prev = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z == prev {
+            return z / 3.0;
+        }
+    }
+}
+
+impl HyperLogLog {
+    pub fn is_sparse(&self) -> bool {
+        matches!(self.registers, Registers::Sparse(_))
+    }
+
+    /// Raise register `index` to `count`, returning whether it changed
+    fn observe(&mut self, index: usize, count: u8) -> bool {
+        match self.registers {
+            Registers::Dense(ref mut registers) => {
+                if registers[index] < count {
+                    registers[index] = count;
+                    return true;
+                }
+                false
+            }
+            Registers::Sparse(ref mut registers) => {
+                match registers.binary_search_by_key(&(index as u16), |&(i, _)| i) {
+                    Ok(at) if registers[at].1 < count => registers[at].1 = count,
+                    Ok(_) => return false,
+                    Err(at) => registers.insert(at, (index as u16, count)),
+                }
+                if registers.len() > SPARSE_MAX {
+                    self.registers = Registers::Dense(self.dense());
+                }
+                true
+            }
+        }
+    }
+
+    /// Add an element, returning whether the estimate may have changed
+    pub fn add(&mut self, element: &[u8]) -> bool {
+        let hash = murmur64(element, 0xadc8_3b19);
+        let index = hash as usize & (M - 1);
+        // Count the run of zeros (plus one) in the remaining bits, capping
+        // it at Q + 1 with a sentinel bit
+        let count = ((hash >> P) | (1 << Q)).trailing_zeros() + 1;
+        self.observe(index, count as u8)
+    }
+
+    /// Every register, including the zero ones
+    fn dense(&self) -> Vec<u8> {
+        match self.registers {
+            Registers::Dense(ref registers) => registers.clone(),
+            Registers::Sparse(ref registers) => {
+                let mut dense = vec![0; M];
+                for &(i, count) in registers {
+                    dense[i as usize] = count;
+                }
+                dense
+            }
+        }
+    }
+
+    /// Fold `other` into this sketch, so that it estimates their union
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        match other.registers {
+            Registers::Sparse(ref registers) => {
+                for &(i, count) in registers {
+                    self.observe(i as usize, count);
+                }
+            }
+            Registers::Dense(ref registers) => {
+                let mut dense = self.dense();
+                for (mine, &theirs) in dense.iter_mut().zip(registers) {
+                    *mine = (*mine).max(theirs);
+                }
+                self.registers = Registers::Dense(dense);
+            }
+        }
+    }
+
+    /// Estimate the number of distinct elements added, using Ertl's
+    /// improved estimator, which needs no separate small or large range
+    /// corrections
+    pub fn count(&self) -> u64 {
+        let mut histogram = [0u32; Q as usize + 2];
+        match self.registers {
+            Registers::Dense(ref registers) => {
+                for &count in registers {
+                    histogram[count as usize] += 1;
+                }
+            }
+            Registers::Sparse(ref registers) => {
+                histogram[0] = (M - registers.len()) as u32;
+                for &(_, count) in registers {
+                    histogram[count as usize] += 1;
+                }
+            }
+        }
+        let m = M as f64;
+        let mut z = m * tau((m - histogram[Q as usize + 1] as f64) / m);
+        for &n in histogram[1..=Q as usize].iter().rev() {
+            z += n as f64;
+            z *= 0.5;
+        }
+        z += m * sigma(histogram[0] as f64 / m);
+        (0.5 / 2f64.ln() * m * m / z).round() as u64
+    }
+
+    /// The dense registers, one byte each, which is how a sketch is read
+    /// back by clients
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.dense()
+    }
+}
+
+impl Database {
+    fn hll(&self, key: &str) -> Result<Option<&HyperLogLog>, Error> {
+        match self.data.get(key).map(|e| &e.value) {
+            Some(Value::HyperLogLog(hll)) => Ok(Some(hll)),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn hll_or_insert(&mut self, key: Key) -> Result<(&mut HyperLogLog, bool), Error> {
+        let created = self.hll(&key)?.is_none();
+        let entry = self.data.get_or_insert_with(key, || {
+            Entry::new(Value::HyperLogLog(HyperLogLog::default()))
+        });
+        match entry.value {
+            Value::HyperLogLog(ref mut hll) => Ok((hll, created)),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    /// Add elements to the sketch at `key`, creating it if missing. Replies
+    /// 1 if the sketch was created or its estimate may have changed.
+    pub fn pfadd(&mut self, key: Key, elements: Vec<Vec<u8>>) -> Result<Value, Error> {
+        let (hll, mut changed) = self.hll_or_insert(key)?;
+        for element in elements {
+            changed |= hll.add(&element);
+        }
+        Ok(Value::Integer(changed as i64))
+    }
+
+    /// Estimate the number of distinct elements added to any of `keys`
+    pub fn pfcount(&self, keys: &[String]) -> Result<Value, Error> {
+        if let [key] = keys {
+            return Ok(Value::Integer(
+                self.hll(key)?.map_or(0, HyperLogLog::count) as i64
+            ));
+        }
+        let mut union = HyperLogLog::default();
+        for key in keys {
+            if let Some(hll) = self.hll(key)? {
+                union.merge(hll);
+            }
+        }
+        Ok(Value::Integer(union.count() as i64))
+    }
+
+    /// Merge the sketches at `keys` into the one at `dest`, creating it if
+    /// missing
+    pub fn pfmerge(&mut self, dest: Key, keys: &[String]) -> Result<Value, Error> {
+        let mut union = self.hll(&dest)?.cloned().unwrap_or_default();
+        for key in keys {
+            if let Some(hll) = self.hll(key)? {
+                union.merge(hll);
+            }
+        }
+        *self.hll_or_insert(dest)?.0 = union;
+        Ok(Value::Status("OK".into()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stream(prefix: &str, n: usize) -> impl Iterator<Item = Vec<u8>> + '_ {
+        (0..n).map(move |i| format!("{}:{}", prefix, i).into_bytes())
+    }
+
+    fn relative_error(hll: &HyperLogLog, n: usize) -> f64 {
+        (hll.count() as f64 - n as f64).abs() / n as f64
+    }
+
+    #[test]
+    fn small_counts_are_exact() {
+        let mut hll = HyperLogLog::default();
+        for n in 1..=100 {
+            assert!(hll.add(format!("e{}", n).as_bytes()));
+            assert!(!hll.add(b"e1"));
+            assert!((hll.count() as i64 - n as i64).abs() <= 1);
+        }
+        assert!(hll.is_sparse());
+    }
+
+    #[test]
+    fn accuracy() {
+        let mut total = 0.0;
+        let sizes = [1_000, 10_000, 50_000, 200_000];
+        for (trial, &n) in sizes.iter().enumerate() {
+            let mut hll = HyperLogLog::default();
+            for element in stream(&format!("trial{}", trial), n) {
+                hll.add(&element);
+            }
+            // Duplicates must not move the estimate
+            for element in stream(&format!("trial{}", trial), n / 10) {
+                assert!(!hll.add(&element));
+            }
+            let error = relative_error(&hll, n);
+            // Five standard errors
+            assert!(error < 0.0405, "n = {} error = {}", n, error);
+            total += error;
+        }
+        assert!(!total.is_nan());
+        assert!(total / (sizes.len() as f64) < 0.02);
+    }
+
+    #[test]
+    fn sparse_to_dense() {
+        let mut sparse = HyperLogLog::default();
+        let mut dense = HyperLogLog::default();
+        for element in stream("a", 500) {
+            sparse.add(&element);
+        }
+        for element in stream("b", 20_000) {
+            dense.add(&element);
+        }
+        assert!(sparse.is_sparse());
+        assert!(!dense.is_sparse());
+        let mut union = sparse.clone();
+        union.merge(&dense);
+        assert!(!union.is_sparse());
+        assert!(relative_error(&union, 20_500) < 0.0405);
+        assert_eq!(union.count(), {
+            let mut other = dense.clone();
+            other.merge(&sparse);
+            other.count()
+        });
+    }
+
+    #[test]
+    fn commands() {
+        let mut db = Database::new();
+        let elements = |prefix| stream(prefix, 1000).collect();
+        assert_eq!(db.pfadd("a".into(), elements("x")), Ok(Value::Integer(1)));
+        assert_eq!(db.pfadd("a".into(), elements("x")), Ok(Value::Integer(0)));
+        assert_eq!(db.pfadd("e".into(), vec![]), Ok(Value::Integer(1)));
+        assert_eq!(db.pfcount(&["e".into()]), Ok(Value::Integer(0)));
+        db.pfadd("b".into(), elements("y")).unwrap();
+        let count = |db: &Database, keys: &[&str]| match db
+            .pfcount(&keys.iter().map(|k| k.to_string()).collect::<Vec<_>>())
+        {
+            Ok(Value::Integer(n)) => n,
+            r => panic!("unexpected reply {:?}", r),
+        };
+        assert!((count(&db, &["a", "b", "missing"]) - 2000).abs() < 80);
+        assert_eq!(
+            db.pfmerge("c".into(), &["a".into(), "b".into()]),
+            Ok(Value::Status("OK".into()))
+        );
+        assert_eq!(count(&db, &["c"]), count(&db, &["a", "b"]));
+        db.create("s".into(), Value::Text("v".into()));
+        assert_eq!(db.pfcount(&["s".into()]), Err(Error::WrongType));
+    }
+}
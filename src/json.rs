@@ -0,0 +1,949 @@
+use super::parser::{Condition, JsonFormat, Value};
+use super::{Database, Entry, Error, Key};
+use std::char;
+use std::fmt::Write;
+
+/// Deepest nesting of arrays and objects accepted, so that parsing and
+/// serializing can't overflow the stack
+const MAX_DEPTH: usize = 128;
+
+/// A JSON document. Integers are kept apart from floats so that they
+/// survive a round trip exactly, and objects keep their insertion order.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+/// Recursive descent parser over the bytes of a document
+struct Reader<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error<T>(&self, what: &str) -> Result<T, String> {
+        Err(format!("{} at offset {}", what, self.pos))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.input.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        if self.peek() != Some(byte) {
+            return self.error(&format!("expected '{}'", byte as char));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str, json: Json) -> Result<Json, String> {
+        if !self.input[self.pos..].starts_with(word.as_bytes()) {
+            return self.error("invalid literal");
+        }
+        self.pos += word.len();
+        Ok(json)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, String> {
+        if depth > MAX_DEPTH {
+            return self.error("nesting too deep");
+        }
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut array = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(array));
+                }
+                loop {
+                    array.push(self.value(depth + 1)?);
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(Json::Array(array));
+                        }
+                        _ => return self.error("expected ',' or ']'"),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut object: Vec<(String, Json)> = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(object));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    self.expect(b':')?;
+                    let value = self.value(depth + 1)?;
+                    // Later duplicates win, as in most parsers
+                    match object.iter_mut().find(|(k, _)| *k == key) {
+                        Some(entry) => entry.1 = value,
+                        None => object.push((key, value)),
+                    }
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(Json::Object(object));
+                        }
+                        _ => return self.error("expected ',' or '}'"),
+                    }
+                }
+            }
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            Some(_) => self.error("unexpected character"),
+            None => self.error("unexpected end of input"),
+        }
+    }
+
+    fn digits(&mut self) -> usize {
+        let start = self.pos;
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        let leading_zero = self.peek() == Some(b'0');
+        match self.digits() {
+            0 => return self.error("expected digit"),
+            n if leading_zero && n > 1 => return self.error("leading zero"),
+            _ => (),
+        }
+        let mut integer = true;
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            integer = false;
+            if self.digits() == 0 {
+                return self.error("expected digit");
+            }
+        }
+        if let Some(b'e') | Some(b'E') = self.peek() {
+            self.pos += 1;
+            integer = false;
+            if let Some(b'+') | Some(b'-') = self.peek() {
+                self.pos += 1;
+            }
+            if self.digits() == 0 {
+                return self.error("expected digit");
+            }
+        }
+        // Only ASCII has been consumed
+        let text = std::str::from_utf8(&self.input[start..self.pos]).expect("ASCII number");
+        if integer {
+            if let Ok(i) = text.parse() {
+                return Ok(Json::Int(i));
+            }
+        }
+        match text.parse::<f64>() {
+            Ok(f) if f.is_finite() => Ok(Json::Float(f)),
+            _ => self.error("number out of range"),
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let hex = self
+            .input
+            .get(self.pos..self.pos + 4)
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u32::from_str_radix(h, 16).ok());
+        match hex {
+            Some(n) => {
+                self.pos += 4;
+                Ok(n)
+            }
+            None => self.error("invalid unicode escape"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            match self.peek() {
+                Some(b'"') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escaped = match self.peek() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            self.pos += 1;
+                            let mut code = self.hex4()?;
+                            // Characters outside the BMP are escaped as a
+                            // surrogate pair
+                            if (0xd800..0xdc00).contains(&code)
+                                && self.input[self.pos..].starts_with(b"\\u")
+                            {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return self.error("invalid surrogate pair");
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            let c = match char::from_u32(code) {
+                                Some(c) => c,
+                                None => return self.error("invalid unicode escape"),
+                            };
+                            let mut buf = [0; 4];
+                            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                            continue;
+                        }
+                        _ => return self.error("invalid escape"),
+                    };
+                    self.pos += 1;
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(escaped.encode_utf8(&mut buf).as_bytes());
+                }
+                Some(c) if c < 0x20 => return self.error("control character in string"),
+                Some(c) => {
+                    self.pos += 1;
+                    bytes.push(c);
+                }
+                None => return self.error("unterminated string"),
+            }
+        }
+        String::from_utf8(bytes).or_else(|_| self.error("invalid UTF-8 in string"))
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+impl Json {
+    pub fn parse(s: &str) -> Result<Json, String> {
+        let mut reader = Reader {
+            input: s.as_bytes(),
+            pos: 0,
+        };
+        let json = reader.value(0)?;
+        reader.skip_whitespace();
+        if reader.pos != s.len() {
+            return reader.error("trailing characters");
+        }
+        Ok(json)
+    }
+
+    pub fn is_number(&self) -> bool {
+        matches!(self, Json::Int(_) | Json::Float(_))
+    }
+
+    /// Serialize, laid out as `format` asks for
+    pub fn serialize(&self, format: &JsonFormat) -> String {
+        let mut out = String::new();
+        self.write(&mut out, format, 0);
+        out
+    }
+
+    fn write(&self, out: &mut String, format: &JsonFormat, level: usize) {
+        let newline = |out: &mut String, level: usize| {
+            out.push_str(&format.newline);
+            for _ in 0..level {
+                out.push_str(&format.indent);
+            }
+        };
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(b) => {
+                let _ = write!(out, "{}", b);
+            }
+            Json::Int(i) => {
+                let _ = write!(out, "{}", i);
+            }
+            // Debug formatting keeps a fraction or exponent, so that the
+            // number reads back as a float
+            Json::Float(f) => {
+                let _ = write!(out, "{:?}", f);
+            }
+            Json::String(s) => write_string(out, s),
+            Json::Array(array) if array.is_empty() => out.push_str("[]"),
+            Json::Array(array) => {
+                out.push('[');
+                for (i, item) in array.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    newline(out, level + 1);
+                    item.write(out, format, level + 1);
+                }
+                newline(out, level);
+                out.push(']');
+            }
+            Json::Object(object) if object.is_empty() => out.push_str("{}"),
+            Json::Object(object) => {
+                out.push('{');
+                for (i, (key, value)) in object.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    newline(out, level + 1);
+                    write_string(out, key);
+                    out.push(':');
+                    out.push_str(&format.space);
+                    value.write(out, format, level + 1);
+                }
+                newline(out, level);
+                out.push('}');
+            }
+        }
+    }
+
+    /// The value at `location`, a list of child positions
+    fn get(&self, location: &[usize]) -> Option<&Json> {
+        location.iter().try_fold(self, |node, &i| match node {
+            Json::Array(array) => array.get(i),
+            Json::Object(object) => object.get(i).map(|(_, v)| v),
+            _ => None,
+        })
+    }
+
+    fn get_mut(&mut self, location: &[usize]) -> Option<&mut Json> {
+        location.iter().try_fold(self, |node, &i| match node {
+            Json::Array(array) => array.get_mut(i),
+            Json::Object(object) => object.get_mut(i).map(|(_, v)| v),
+            _ => None,
+        })
+    }
+
+    pub fn children(&self) -> usize {
+        match self {
+            Json::Array(array) => array.len(),
+            Json::Object(object) => object.len(),
+            _ => 0,
+        }
+    }
+
+    /// Add `by` to a number, keeping it an integer if both are
+    fn increment(&mut self, by: &Json) -> Result<(), Error> {
+        *self = match (&*self, by) {
+            (Json::Int(a), Json::Int(b)) => Json::Int(a.checked_add(*b).ok_or(Error::Overflow)?),
+            (a, b) => {
+                let float = |n: &Json| match *n {
+                    Json::Int(i) => i as f64,
+                    Json::Float(f) => f,
+                    _ => f64::NAN,
+                };
+                match float(a) + float(b) {
+                    f if f.is_finite() => Json::Float(f),
+                    _ => return Err(Error::NotFinite),
+                }
+            }
+        };
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Selector {
+    Name(String),
+    Index(i64),
+    Wildcard,
+    /// Array elements from `start` up to `end`, either of which may count
+    /// back from the end
+    Slice(Option<i64>, Option<i64>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct Segment {
+    /// Whether the selector applies at any depth, as in `..name`
+    descend: bool,
+    selector: Selector,
+}
+
+/// A JSONPath selecting values in a document, such as `$.users[0].name`,
+/// `$..price` or `$.tags[*]`. Legacy paths not starting with `$`, such as
+/// `.users`, select only their first match and reply with it alone.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Path {
+    text: String,
+    legacy: bool,
+    segments: Vec<Segment>,
+}
+
+/// Normalize a possibly negative index into a collection of `len`
+fn index(i: i64, len: usize) -> Option<usize> {
+    let i = if i < 0 { i + len as i64 } else { i };
+    if i >= 0 && (i as usize) < len {
+        Some(i as usize)
+    } else {
+        None
+    }
+}
+
+impl Path {
+    pub fn root() -> Path {
+        Path {
+            text: "$".into(),
+            legacy: false,
+            segments: Vec::new(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Path, String> {
+        let legacy = !text.starts_with('$');
+        let rest = match text {
+            _ if !legacy => &text[1..],
+            "." => "",
+            _ if text.starts_with('.') || text.starts_with('[') => text,
+            _ => return Path::parse_segments(&format!(".{}", text), text, true),
+        };
+        Path::parse_segments(rest, text, legacy)
+    }
+
+    fn parse_segments(mut rest: &str, text: &str, legacy: bool) -> Result<Path, String> {
+        let invalid = || format!("invalid JSONPath '{}'", text);
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            let descend = rest.starts_with("..");
+            if descend {
+                rest = &rest[2..];
+            } else if rest.starts_with('.') {
+                rest = &rest[1..];
+            } else if !rest.starts_with('[') {
+                return Err(invalid());
+            }
+            let selector = if rest.starts_with('[') {
+                let (selector, after) = Path::bracket(&rest[1..]).ok_or_else(invalid)?;
+                rest = after;
+                selector
+            } else if rest.starts_with('*') {
+                rest = &rest[1..];
+                Selector::Wildcard
+            } else {
+                let end = rest.find(['.', '[']).unwrap_or(rest.len());
+                if end == 0 {
+                    return Err(invalid());
+                }
+                let name = &rest[..end];
+                rest = &rest[end..];
+                Selector::Name(name.into())
+            };
+            segments.push(Segment { descend, selector });
+        }
+        Ok(Path {
+            text: text.into(),
+            legacy,
+            segments,
+        })
+    }
+
+    /// Parse the inside of a `[...]` selector, returning it along with the
+    /// text after the closing bracket
+    fn bracket(s: &str) -> Option<(Selector, &str)> {
+        if let Some(quote) = s.chars().next().filter(|&c| c == '\'' || c == '"') {
+            let mut name = String::new();
+            let mut chars = s[1..].char_indices();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => name.push(chars.next()?.1),
+                    c if c == quote => {
+                        let rest = s[1 + i + 1..].strip_prefix(']')?;
+                        return Some((Selector::Name(name), rest));
+                    }
+                    c => name.push(c),
+                }
+            }
+            return None;
+        }
+        let end = s.find(']')?;
+        let (inner, rest) = (s[..end].trim(), &s[end + 1..]);
+        let selector = match inner.split_once(':') {
+            _ if inner == "*" => Selector::Wildcard,
+            Some((start, end)) => {
+                let bound = |b: &str| match b.trim() {
+                    "" => Some(None),
+                    b => b.parse().ok().map(Some),
+                };
+                Selector::Slice(bound(start)?, bound(end)?)
+            }
+            None => Selector::Index(inner.parse().ok()?),
+        };
+        Some((selector, rest))
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// The locations of every value selected in `doc`, in document order
+    fn locate(&self, doc: &Json) -> Vec<Vec<usize>> {
+        let mut current = vec![Vec::new()];
+        for segment in &self.segments {
+            let mut next = Vec::new();
+            for location in current {
+                let mut candidates = vec![location];
+                if segment.descend {
+                    // Every descendant, depth first
+                    let mut i = 0;
+                    while i < candidates.len() {
+                        let children = doc.get(&candidates[i]).map_or(0, Json::children);
+                        let mut below: Vec<Vec<usize>> = (0..children)
+                            .map(|c| {
+                                let mut child = candidates[i].clone();
+                                child.push(c);
+                                child
+                            })
+                            .collect();
+                        i += 1;
+                        let rest = candidates.split_off(i);
+                        candidates.append(&mut below);
+                        candidates.extend(rest);
+                    }
+                }
+                for candidate in candidates {
+                    let node = match doc.get(&candidate) {
+                        Some(node) => node,
+                        None => continue,
+                    };
+                    let selected: Vec<usize> = match (&segment.selector, node) {
+                        (Selector::Name(name), Json::Object(object)) => object
+                            .iter()
+                            .position(|(k, _)| k == name)
+                            .into_iter()
+                            .collect(),
+                        (Selector::Index(i), Json::Array(array)) => {
+                            index(*i, array.len()).into_iter().collect()
+                        }
+                        (Selector::Wildcard, node) => (0..node.children()).collect(),
+                        (Selector::Slice(start, end), Json::Array(array)) => {
+                            let len = array.len() as i64;
+                            let clamp = |b: i64| (if b < 0 { b + len } else { b }).clamp(0, len);
+                            let start = start.map_or(0, clamp);
+                            let end = end.map_or(len, clamp);
+                            (start..end.max(start)).map(|i| i as usize).collect()
+                        }
+                        _ => Vec::new(),
+                    };
+                    for i in selected {
+                        let mut child = candidate.clone();
+                        child.push(i);
+                        next.push(child);
+                    }
+                }
+            }
+            current = next;
+        }
+        current
+    }
+
+    /// Locations ordered so that mutating one never moves another: later
+    /// siblings and descendants come first
+    fn locate_for_update(&self, doc: &Json) -> Vec<Vec<usize>> {
+        let mut locations = self.locate(doc);
+        locations.sort_unstable_by(|a, b| b.cmp(a));
+        locations.dedup();
+        locations
+    }
+}
+
+impl Database {
+    fn json(&self, key: &str) -> Result<Option<&Json>, Error> {
+        match self.data.get(key).map(|e| &e.value) {
+            Some(Value::Json(json)) => Ok(Some(json)),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn json_mut(&mut self, key: &str) -> Result<Option<&mut Json>, Error> {
+        match self.data.get_mut(key).map(|e| &mut e.value) {
+            Some(Value::Json(json)) => Ok(Some(json)),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Set the values selected by `path`, or add a missing member to the
+    /// objects selected by its parent. Replies null if nothing was set.
+    pub fn json_set(
+        &mut self,
+        key: Key,
+        path: &Path,
+        value: Json,
+        condition: Option<Condition>,
+    ) -> Result<Value, Error> {
+        let ok = Ok(Value::Status("OK".into()));
+        let doc = match self.json_mut(&key)? {
+            Some(doc) => doc,
+            None if !path.is_root() => return Err(Error::NotRoot),
+            None if condition == Some(Condition::Xx) => return Ok(Value::Null),
+            None => {
+                self.data.insert(key, Entry::new(Value::Json(value)));
+                return ok;
+            }
+        };
+        let locations = path.locate_for_update(doc);
+        if !locations.is_empty() {
+            if condition == Some(Condition::Nx) {
+                return Ok(Value::Null);
+            }
+            for location in locations {
+                if let Some(target) = doc.get_mut(&location) {
+                    *target = value.clone();
+                }
+            }
+            return ok;
+        }
+        let (name, parent) = match path.segments.split_last() {
+            Some((
+                Segment {
+                    descend: false,
+                    selector: Selector::Name(name),
+                },
+                parent,
+            )) if condition != Some(Condition::Xx) => (name, parent),
+            _ => return Ok(Value::Null),
+        };
+        let parent = Path {
+            segments: parent.to_vec(),
+            ..path.clone()
+        };
+        let mut added = false;
+        for location in parent.locate_for_update(doc) {
+            if let Some(Json::Object(object)) = doc.get_mut(&location) {
+                object.push((name.clone(), value.clone()));
+                added = true;
+            }
+        }
+        if added {
+            ok
+        } else {
+            Ok(Value::Null)
+        }
+    }
+
+    /// Serialize what `paths` select: with one path, its matches, or with
+    /// several, an object of the matches of each
+    pub fn json_get(&self, key: &str, paths: &[Path], format: &JsonFormat) -> Result<Value, Error> {
+        let doc = match self.json(key)? {
+            Some(doc) => doc,
+            None => return Ok(Value::Null),
+        };
+        let select = |path: &Path| {
+            let mut matches = path
+                .locate(doc)
+                .into_iter()
+                .filter_map(|l| doc.get(&l).cloned());
+            if path.legacy {
+                matches
+                    .next()
+                    .ok_or_else(|| Error::NoPath(path.text.clone()))
+            } else {
+                Ok(Json::Array(matches.collect()))
+            }
+        };
+        let json = match paths {
+            [] => doc.clone(),
+            [path] => select(path)?,
+            paths => Json::Object(
+                paths
+                    .iter()
+                    .map(|p| Ok((p.text.clone(), select(p)?)))
+                    .collect::<Result<_, Error>>()?,
+            ),
+        };
+        Ok(Value::Text(json.serialize(format)))
+    }
+
+    /// Delete the values selected by `path`, replying with how many were
+    pub fn json_del(&mut self, key: &str, path: &Path) -> Result<Value, Error> {
+        let doc = match self.json_mut(key)? {
+            Some(doc) => doc,
+            None => return Ok(Value::Integer(0)),
+        };
+        if path.is_root() {
+            self.data.remove(key);
+            return Ok(Value::Integer(1));
+        }
+        let mut deleted = 0;
+        for mut location in path.locate_for_update(doc) {
+            let i = location.pop().expect("Only the root has an empty location");
+            match doc.get_mut(&location) {
+                Some(Json::Array(array)) if i < array.len() => {
+                    array.remove(i);
+                }
+                Some(Json::Object(object)) if i < object.len() => {
+                    object.remove(i);
+                }
+                _ => continue,
+            }
+            deleted += 1;
+        }
+        Ok(Value::Integer(deleted))
+    }
+
+    /// Run `op` on each value selected by `path`, collecting its replies:
+    /// one per match for JSONPath, or that of the first match for legacy
+    /// paths, which must select a value `op` applies to
+    fn json_update<F>(&mut self, key: &str, path: &Path, mut op: F) -> Result<Vec<Json>, Error>
+    where
+        F: FnMut(&mut Json) -> Result<Option<Json>, Error>,
+    {
+        let doc = self.json_mut(key)?.ok_or(Error::NoSuchKey)?;
+        let mut locations = path.locate(doc);
+        if path.legacy {
+            locations.truncate(1);
+            if locations.is_empty() {
+                return Err(Error::NoPath(path.text.clone()));
+            }
+        }
+        let mut replies = Vec::with_capacity(locations.len());
+        for location in locations {
+            let target = doc.get_mut(&location).expect("Located value");
+            match op(target)? {
+                Some(reply) => replies.push(reply),
+                None if path.legacy => return Err(Error::WrongType),
+                None => replies.push(Json::Null),
+            }
+        }
+        Ok(replies)
+    }
+
+    /// Append to each array selected by `path`, replying with their new
+    /// lengths, or null for values that are not arrays
+    pub fn json_arrappend(
+        &mut self,
+        key: &str,
+        path: &Path,
+        values: Vec<Json>,
+    ) -> Result<Value, Error> {
+        let lengths = self.json_update(key, path, |target| match target {
+            Json::Array(array) => {
+                array.extend(values.iter().cloned());
+                Ok(Some(Json::Int(array.len() as i64)))
+            }
+            _ => Ok(None),
+        })?;
+        let reply = |len: Json| match len {
+            Json::Int(len) => Value::Integer(len),
+            _ => Value::Null,
+        };
+        Ok(match path.legacy {
+            true => lengths.into_iter().next().map_or(Value::Null, reply),
+            false => Value::Array(lengths.into_iter().map(reply).collect()),
+        })
+    }
+
+    /// Add `by` to each number selected by `path`, replying with the new
+    /// values serialized
+    pub fn json_numincrby(&mut self, key: &str, path: &Path, by: &Json) -> Result<Value, Error> {
+        let values = self.json_update(key, path, |target| {
+            if !target.is_number() {
+                return Ok(None);
+            }
+            target.increment(by)?;
+            Ok(Some(target.clone()))
+        })?;
+        let format = JsonFormat::default();
+        Ok(Value::Text(match path.legacy {
+            true => values
+                .into_iter()
+                .next()
+                .unwrap_or(Json::Null)
+                .serialize(&format),
+            false => Json::Array(values).serialize(&format),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn json(s: &str) -> Json {
+        Json::parse(s).unwrap()
+    }
+
+    fn path(s: &str) -> Path {
+        Path::parse(s).unwrap()
+    }
+
+    fn get(db: &Database, key: &str, p: &str) -> Value {
+        db.json_get(key, &[path(p)], &JsonFormat::default())
+            .unwrap()
+    }
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.into())
+    }
+
+    #[test]
+    fn parse_and_serialize() {
+        let doc =
+            r#" {"a": [1, -2.5e3, true, null], "b": {"c": "q\"\u00e9\ud83d\ude00\n"}, "a": 0} "#;
+        let parsed = json(doc);
+        assert_eq!(
+            parsed.serialize(&JsonFormat::default()),
+            "{\"a\":0,\"b\":{\"c\":\"q\\\"\u{e9}\u{1f600}\\n\"}}"
+        );
+        assert_eq!(
+            json("[1.0, 3]"),
+            Json::Array(vec![Json::Float(1.0), Json::Int(3)])
+        );
+        assert_eq!(json("[1.0]").serialize(&JsonFormat::default()), "[1.0]");
+        for bad in &[
+            "",
+            "[1,]",
+            "01",
+            "{\"a\" 1}",
+            "\"\\x\"",
+            "[1] x",
+            "tru",
+            "1e999",
+        ] {
+            assert!(Json::parse(bad).is_err(), "{} parsed", bad);
+        }
+        assert!(Json::parse(&"[".repeat(MAX_DEPTH + 2)).is_err());
+        let pretty = JsonFormat {
+            indent: "  ".into(),
+            newline: "\n".into(),
+            space: " ".into(),
+        };
+        assert_eq!(
+            json(r#"{"a":[1,{}]}"#).serialize(&pretty),
+            "{\n  \"a\": [\n    1,\n    {}\n  ]\n}"
+        );
+    }
+
+    #[test]
+    fn paths() {
+        let doc = json(
+            r#"{"store":{"book":[{"price":8},{"price":12,"tags":["x"]}],"bike":{"price":19}}}"#,
+        );
+        let select = |p: &str| -> Vec<String> {
+            path(p)
+                .locate(&doc)
+                .iter()
+                .map(|l| doc.get(l).unwrap().serialize(&JsonFormat::default()))
+                .collect()
+        };
+        assert_eq!(select("$..price"), vec!["8", "12", "19"]);
+        assert_eq!(select("$.store.book[-1].price"), vec!["12"]);
+        assert_eq!(select("$.store.book[*].price"), vec!["8", "12"]);
+        assert_eq!(select("$['store'].bike"), vec![r#"{"price":19}"#]);
+        assert_eq!(select("$.store.book[0:1]"), vec![r#"{"price":8}"#]);
+        assert_eq!(select(".store.bike.price"), vec!["19"]);
+        assert_eq!(select("store.bike.price"), vec!["19"]);
+        assert_eq!(select("$.missing"), Vec::<String>::new());
+        assert!(Path::parse("$.a[").is_err());
+        assert!(Path::parse("$.a..").is_err());
+    }
+
+    #[test]
+    fn commands() {
+        let mut db = Database::new();
+        assert_eq!(
+            db.json_set("k".into(), &path("$.a"), Json::Int(1), None),
+            Err(Error::NotRoot)
+        );
+        let doc = json(r#"{"a":1,"b":{"a":[1]},"c":"s"}"#);
+        db.json_set("k".into(), &Path::root(), doc, None).unwrap();
+        assert_eq!(
+            db.json_set("k".into(), &path("$.d"), json("{}"), None),
+            Ok(Value::Status("OK".into()))
+        );
+        assert_eq!(
+            db.json_set("k".into(), &path("$.d"), json("{}"), Some(Condition::Nx)),
+            Ok(Value::Null)
+        );
+        assert_eq!(
+            db.json_set("k".into(), &path("$.x.y"), json("1"), None),
+            Ok(Value::Null)
+        );
+        assert_eq!(get(&db, "k", "$..a"), text("[1,[1]]"));
+        assert_eq!(get(&db, "k", ".d"), text("{}"));
+        assert_eq!(
+            db.json_get("k", &[path(".x")], &JsonFormat::default()),
+            Err(Error::NoPath(".x".into()))
+        );
+
+        assert_eq!(
+            db.json_numincrby("k", &path("$..a"), &Json::Float(0.5)),
+            Ok(text("[1.5,null]"))
+        );
+        assert_eq!(
+            db.json_numincrby("k", &path(".a"), &Json::Int(1)),
+            Ok(text("2.5"))
+        );
+        assert_eq!(
+            db.json_numincrby("k", &path(".c"), &Json::Int(1)),
+            Err(Error::WrongType)
+        );
+        assert_eq!(
+            db.json_arrappend("k", &path("$..a"), vec![json("2"), json("\"t\"")]),
+            Ok(Value::Array(vec![Value::Null, Value::Integer(3)]))
+        );
+        assert_eq!(get(&db, "k", "$.b"), text(r#"[{"a":[1,2,"t"]}]"#));
+
+        assert_eq!(db.json_del("k", &path("$.b.a[0:2]")), Ok(Value::Integer(2)));
+        assert_eq!(get(&db, "k", ".b.a"), text(r#"["t"]"#));
+        assert_eq!(db.json_del("k", &path("$..a")), Ok(Value::Integer(2)));
+        assert_eq!(get(&db, "k", "$"), text(r#"[{"b":{},"c":"s","d":{}}]"#));
+        assert_eq!(db.json_del("k", &path("$")), Ok(Value::Integer(1)));
+        assert_eq!(
+            db.json_get("k", &[], &JsonFormat::default()),
+            Ok(Value::Null)
+        );
+    }
+}
@@ -0,0 +1,498 @@
+use super::glob;
+use super::parser::{ObjectInfo, Scan, Value};
+use super::random;
+use super::{Database, Entry, Error, Key};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc::{self, Sender};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::SystemTime;
+
+/// Values made of more allocations than this are freed by UNLINK on a
+/// background thread, as freeing them could stall every other client
+const LAZYFREE_THRESHOLD: usize = 64;
+
+/// Drop `entries` on a background thread, started on first use
+fn free_later(entries: Vec<Entry>) {
+    static FREER: OnceLock<Sender<Vec<Entry>>> = OnceLock::new();
+    let freer = FREER.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<Vec<Entry>>();
+        thread::spawn(move || rx.into_iter().for_each(drop));
+        tx
+    });
+    // Should the thread have died, the entries are dropped here instead
+    let _ = freer.send(entries);
+}
+
+/// Position of `name` in the order the SCAN family iterates in. Hashing
+/// with fixed keys makes it independent of the table's capacity, so a
+/// cursor stays valid however the table is resized between calls.
+fn position(name: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Select the names positioned at or after the cursor of `scan`, up to
+/// its count, that match its pattern. Returns them in position order
+/// along with the cursor to continue from, which is 0 once done.
+///
+/// A cursor is the position after the last name returned, so every name
+/// present for the whole iteration is returned exactly once, while names
+/// added or removed meanwhile may or may not be. Each call walks all of
+/// `names`, so this is only for collections too small to keep indexed.
+pub fn page<'a, I>(names: I, scan: &Scan) -> (u64, Vec<&'a str>)
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut candidates: Vec<(u64, &str)> = names
+        .into_iter()
+        .map(|name| (position(name), name))
+        .filter(|&(p, _)| p >= scan.cursor)
+        .collect();
+    let mut next = 0;
+    if candidates.len() > scan.count {
+        let last = candidates.select_nth_unstable(scan.count - 1).1 .0;
+        // Names sharing the last position must be returned together, as
+        // the cursor can't point between them
+        candidates.retain(|&(p, _)| p <= last);
+        next = last.wrapping_add(1);
+    }
+    candidates.sort_unstable();
+    let found = candidates
+        .into_iter()
+        .map(|(_, name)| name)
+        .filter(|name| wanted(scan, name))
+        .collect();
+    (next, found)
+}
+
+fn wanted(scan: &Scan, name: &str) -> bool {
+    scan.pattern.as_ref().is_none_or(|p| glob::matches(p, name))
+}
+
+/// Names ordered by their SCAN position, kept alongside a hash table of
+/// the same names so that a page is read from any cursor in O(log N +
+/// COUNT), rather than by walking the whole table as `page` does
+#[derive(Debug, Clone, Default)]
+pub struct Positions(BTreeSet<(u64, String)>);
+
+impl Positions {
+    pub fn insert(&mut self, name: &str) {
+        self.0.insert((position(name), name.into()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.remove(&(position(name), name.into()));
+    }
+
+    /// The first name at or after a random position, wrapping around. Names
+    /// following wider gaps between positions are more likely to be picked,
+    /// but hashing spreads the positions evenly enough for sampling.
+    pub fn random(&self) -> Option<&str> {
+        let start = (random::next_u64(), String::new());
+        let mut after = self.0.range(start..).chain(self.0.iter());
+        after.next().map(|(_, name)| name.as_str())
+    }
+
+    /// Select names as `page` does, with the same cursors
+    pub fn page(&self, scan: &Scan) -> (u64, Vec<&str>) {
+        let (mut taken, mut last, mut found) = (0, None, Vec::new());
+        for &(p, ref name) in self.0.range((scan.cursor, String::new())..) {
+            // Names sharing the last position must be returned together,
+            // as the cursor can't point between them
+            if taken >= scan.count && last != Some(p) {
+                return (p, found);
+            }
+            taken += 1;
+            last = Some(p);
+            if wanted(scan, name) {
+                found.push(name.as_str());
+            }
+        }
+        (0, found)
+    }
+}
+
+/// Reply to a SCAN family command
+pub fn reply(next: u64, found: Vec<Value>) -> Value {
+    Value::Array(vec![Value::Text(next.to_string()), Value::Array(found)])
+}
+
+impl Database {
+    /// Iterate over the keys, optionally only those holding the type of
+    /// value `scan` asks for
+    pub fn scan(&self, scan: &Scan) -> Result<Value, Error> {
+        let (next, keys) = self.data.scan(scan);
+        let found = keys
+            .into_iter()
+            .filter(|key| {
+                scan.kind.as_ref().is_none_or(|k| {
+                    k.eq_ignore_ascii_case(
+                        self.data.get(key).expect("Scanned key").value.type_name(),
+                    )
+                })
+            })
+            .map(|key| Value::Text(key.into()))
+            .collect();
+        Ok(reply(next, found))
+    }
+
+    /// All keys matching `pattern`
+    pub fn keys(&self, pattern: &str) -> Result<Value, Error> {
+        Ok(Value::Array(
+            self.data
+                .keys()
+                .filter(|key| glob::matches(pattern, key))
+                .map(|key| Value::Text(key.clone()))
+                .collect(),
+        ))
+    }
+
+    pub fn dbsize(&self) -> Result<Value, Error> {
+        Ok(Value::Integer(self.data.len() as i64))
+    }
+
+    /// Count how many of `keys` exist, counting repeated keys each time
+    pub fn exists(&self, keys: &[Key]) -> Result<Value, Error> {
+        let count = keys.iter().filter(|k| self.data.get(k).is_some()).count();
+        Ok(Value::Integer(count as i64))
+    }
+
+    pub fn key_type(&self, key: &str) -> Result<Value, Error> {
+        let kind = self.data.get(key).map_or("none", |e| e.value.type_name());
+        Ok(Value::Status(kind.into()))
+    }
+
+    /// Rename `key`, keeping its expiration time and subscribers. With `nx`,
+    /// only if `new` doesn't exist, replying whether it was renamed.
+    pub fn rename(&mut self, key: &str, new: Key, nx: bool) -> Result<Value, Error> {
+        if !self.data.contains_key(key) {
+            return Err(Error::NoSuchKey);
+        }
+        let renamed = match nx {
+            true => Value::Integer(1),
+            false => Value::Status("OK".into()),
+        };
+        if nx && self.data.contains_key(&new) {
+            return Ok(Value::Integer(0));
+        } else if key == new {
+            return Ok(renamed);
+        }
+        let entry = self.data.remove(key).expect("Key exists");
+        if let Some(at) = entry.expiration {
+            self.expirations.insert((at, new.clone()));
+        }
+        self.replace_entry(new.clone(), entry);
+        self.serve_blocked(&new);
+        Ok(renamed)
+    }
+
+    /// Store `entry` at `key` in place of any entry there, whose
+    /// subscribers carry over and are notified of the new value
+    fn replace_entry(&mut self, key: Key, entry: Entry) {
+        let old = self.data.insert(key.clone(), entry);
+        if let Some(subscribers) = old.and_then(|e| e.subscribers) {
+            let entry = self.data.get_mut(&key).expect("Just inserted");
+            entry
+                .subscribers
+                .get_or_insert_with(Vec::new)
+                .extend(subscribers);
+        }
+        self.notify(&key);
+    }
+
+    /// The value and expiration time of `key`, for copying elsewhere
+    pub fn copy_out(&self, key: &str) -> Option<(Value, Option<SystemTime>)> {
+        self.data.get(key).map(|e| (e.value.clone(), e.expiration))
+    }
+
+    /// Store a value copied by `copy_out` under `key`, unless it exists and
+    /// may not be replaced. Returns whether it was stored.
+    pub fn copy_in(&mut self, key: Key, copy: (Value, Option<SystemTime>), replace: bool) -> bool {
+        if !replace && self.data.contains_key(&key) {
+            return false;
+        }
+        let (value, expiration) = copy;
+        self.replace_entry(key.clone(), Entry::new(value));
+        self.set_expiration(&key, expiration);
+        self.serve_blocked(&key);
+        true
+    }
+
+    /// Copy `source` to `dest` in this database, replying whether copied
+    pub fn copy(&mut self, source: &str, dest: Key, replace: bool) -> Result<Value, Error> {
+        if source == dest {
+            return Err(Error::SameObject);
+        }
+        let copied = match self.copy_out(source) {
+            Some(copy) => self.copy_in(dest, copy, replace),
+            None => false,
+        };
+        Ok(Value::Integer(copied as i64))
+    }
+
+    /// Reply how many of `keys` exist, which like any command records an
+    /// access to each of them
+    pub fn touch(&self, keys: &[Key]) -> Result<Value, Error> {
+        let count = keys.iter().filter(|k| self.data.get(k).is_some()).count();
+        Ok(Value::Integer(count as i64))
+    }
+
+    /// Delete `keys`, leaving large values to be freed in the background,
+    /// and reply how many existed
+    pub fn unlink(&mut self, keys: &[Key]) -> Result<Value, Error> {
+        let mut count = 0;
+        let mut large = Vec::new();
+        for key in keys {
+            if let Some(entry) = self.data.remove(key) {
+                count += 1;
+                if entry.value.size() > LAZYFREE_THRESHOLD {
+                    large.push(entry);
+                }
+            }
+        }
+        if !large.is_empty() {
+            free_later(large);
+        }
+        Ok(Value::Integer(count))
+    }
+
+    /// Inspect how `key` is stored, without counting as an access to it
+    pub fn object(&self, info: ObjectInfo, key: &str) -> Result<Value, Error> {
+        let entry = match self.data.get(key) {
+            Some(entry) => entry,
+            None => return Ok(Value::Null),
+        };
+        Ok(match info {
+            ObjectInfo::Encoding => Value::Text(entry.value.encoding().into()),
+            ObjectInfo::IdleTime => Value::Integer(entry.access.idle().as_secs() as i64),
+            ObjectInfo::Freq => Value::Integer(entry.access.frequency() as i64),
+        })
+    }
+
+    pub fn randomkey(&self) -> Result<Value, Error> {
+        Ok(self
+            .data
+            .random_key()
+            .map_or(Value::Null, |key| Value::Text(key.into())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use buffer::{Limits, Output};
+    use std::collections::HashSet;
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.into())
+    }
+
+    fn names(values: &[Value]) -> Vec<String> {
+        let mut names: Vec<String> = values
+            .iter()
+            .map(|v| match v {
+                Value::Text(name) => name.clone(),
+                v => panic!("unexpected name {:?}", v),
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Run one SCAN call, returning the next cursor and the keys found
+    fn step(db: &Database, scan: &Scan) -> (u64, Vec<String>) {
+        match db.scan(scan) {
+            Ok(Value::Array(reply)) => match (&reply[0], &reply[1]) {
+                (Value::Text(cursor), Value::Array(found)) => {
+                    (cursor.parse().unwrap(), names(found))
+                }
+                r => panic!("unexpected reply {:?}", r),
+            },
+            r => panic!("unexpected reply {:?}", r),
+        }
+    }
+
+    #[test]
+    fn scan_survives_resizes() {
+        let mut db = Database::new();
+        for i in 0..100 {
+            db.create(format!("k{}", i), text("v"));
+        }
+        let mut scan = Scan {
+            cursor: 0,
+            pattern: None,
+            count: 10,
+            kind: None,
+        };
+        let mut seen = HashSet::new();
+        let mut calls = 0;
+        loop {
+            let (next, found) = step(&db, &scan);
+            assert!(found.len() <= 10);
+            for key in found {
+                assert!(seen.insert(key), "returned twice");
+            }
+            calls += 1;
+            // Grow the table several times over, and shrink it again,
+            // mid iteration
+            if calls == 3 {
+                for i in 0..2000 {
+                    db.create(format!("new{}", i), text("v"));
+                }
+            } else if calls == 6 {
+                db.data.retain(|k, _| !k.starts_with("new"));
+                db.data.shrink_to_fit();
+            }
+            match next {
+                0 => break,
+                next => scan.cursor = next,
+            }
+        }
+        assert!((0..100).all(|i| seen.contains(&format!("k{}", i))));
+    }
+
+    #[test]
+    fn scan_with_mutations() {
+        let mut db = Database::new();
+        for i in 0..1000 {
+            db.create(format!("k{}", i), text("v"));
+        }
+        let mut scan = Scan {
+            cursor: 0,
+            pattern: None,
+            count: 7,
+            kind: None,
+        };
+        let mut seen = HashSet::new();
+        let mut calls = 0;
+        loop {
+            let (next, found) = step(&db, &scan);
+            seen.extend(found);
+            // Delete some of the keys, whether already returned or not,
+            // and add others between every call
+            for i in 0..5 {
+                db.data.remove(&format!("k{}", (calls * 5 + i) * 3 % 1000));
+                db.create(format!("new{}-{}", calls, i), text("v"));
+            }
+            calls += 1;
+            match next {
+                0 => break,
+                next => scan.cursor = next,
+            }
+        }
+        assert!(calls >= 1000 / 7);
+        for key in db.data.keys().filter(|k| k.starts_with('k')) {
+            assert!(seen.contains(key), "{} never returned", key);
+        }
+    }
+
+    #[test]
+    fn scan_filters() {
+        let mut db = Database::new();
+        db.create("a1".into(), text("v"));
+        db.create("a2".into(), text("v"));
+        db.create("b1".into(), text("v"));
+        db.hset("a3".into(), vec![("f".into(), text("v"))]).unwrap();
+        let scan = Scan {
+            cursor: 0,
+            pattern: Some("a*".into()),
+            count: 100,
+            kind: Some("STRING".into()),
+        };
+        assert_eq!(step(&db, &scan), (0, vec!["a1".into(), "a2".into()]));
+    }
+
+    #[test]
+    fn rename_and_copy() {
+        let mut db = Database::new();
+        db.create("a".into(), text("1"));
+        let later = SystemTime::now() + std::time::Duration::from_secs(60);
+        db.set_expiration("a", Some(later));
+        db.create("b".into(), text("2"));
+
+        assert_eq!(db.rename("a", "b".into(), true), Ok(Value::Integer(0)));
+        assert_eq!(
+            db.rename("a", "c".into(), false),
+            Ok(Value::Status("OK".into()))
+        );
+        assert_eq!(db.rename("a", "d".into(), false), Err(Error::NoSuchKey));
+        assert_eq!(db.data.get("c").and_then(|e| e.expiration), Some(later));
+
+        assert_eq!(db.copy("c", "b".into(), false), Ok(Value::Integer(0)));
+        assert_eq!(db.copy("c", "b".into(), true), Ok(Value::Integer(1)));
+        assert_eq!(db.copy("c", "c".into(), true), Err(Error::SameObject));
+        assert_eq!(db.read("b"), Some(&text("1")));
+        assert_eq!(db.data.get("b").and_then(|e| e.expiration), Some(later));
+
+        let keys = vec!["b".into(), "b".into(), "z".into()];
+        assert_eq!(db.exists(&keys), Ok(Value::Integer(2)));
+        assert_eq!(db.touch(&keys), Ok(Value::Integer(2)));
+        assert_eq!(db.key_type("b"), Ok(Value::Status("string".into())));
+        assert_eq!(db.key_type("z"), Ok(Value::Status("none".into())));
+    }
+
+    #[test]
+    fn overwrite_notifies_subscribers() {
+        let mut db = Database::new();
+        db.create("a".into(), text("1"));
+        db.create("b".into(), text("2"));
+        db.create("c".into(), text("3"));
+        let output = Output::new(Limits::default(), None);
+        db.subscribe("b", output.clone());
+        let update = |value| Some(format!("update b->{}\r\n\r\n", value).into_bytes());
+        assert_eq!(output.recv(), update(text("2")));
+
+        db.rename("a", "b".into(), false).unwrap();
+        assert_eq!(output.recv(), update(text("1")));
+        db.copy("c", "b".into(), true).unwrap();
+        assert_eq!(output.recv(), update(text("3")));
+    }
+
+    #[test]
+    fn unlink_and_object() {
+        let mut db = Database::new();
+        let members = (0..1000).map(|i| i.to_string()).collect();
+        db.sadd("ints".into(), vec!["1".into()]).unwrap();
+        db.sadd("big".into(), members).unwrap();
+        db.create("s".into(), Value::Integer(1));
+        assert_eq!(db.object(ObjectInfo::Encoding, "ints"), Ok(text("intset")));
+        assert_eq!(
+            db.object(ObjectInfo::Encoding, "big"),
+            Ok(text("hashtable"))
+        );
+        assert_eq!(db.object(ObjectInfo::Encoding, "s"), Ok(text("int")));
+        assert_eq!(db.object(ObjectInfo::IdleTime, "s"), Ok(Value::Integer(0)));
+        assert_eq!(db.object(ObjectInfo::Freq, "s"), Ok(Value::Integer(5)));
+        assert_eq!(db.object(ObjectInfo::Freq, "z"), Ok(Value::Null));
+
+        let keys = vec!["big".into(), "s".into(), "z".into()];
+        assert_eq!(db.unlink(&keys), Ok(Value::Integer(2)));
+        assert_eq!(db.dbsize(), Ok(Value::Integer(1)));
+    }
+
+    #[test]
+    fn keyspace() {
+        let mut db = Database::new();
+        assert_eq!(db.randomkey(), Ok(Value::Null));
+        db.create("one".into(), text("v"));
+        db.create("two".into(), text("v"));
+        db.create("three".into(), text("v"));
+        assert_eq!(db.dbsize(), Ok(Value::Integer(3)));
+        match db.keys("t[wh]*") {
+            Ok(Value::Array(keys)) => assert_eq!(names(&keys), vec!["three", "two"]),
+            r => panic!("unexpected reply {:?}", r),
+        }
+        let mut picked = HashSet::new();
+        for _ in 0..1000 {
+            match db.randomkey() {
+                Ok(Value::Text(key)) => picked.insert(key),
+                r => panic!("unexpected reply {:?}", r),
+            };
+        }
+        assert_eq!(picked.len(), 3);
+    }
+}
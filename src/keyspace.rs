@@ -0,0 +1,354 @@
+use super::acl::{self, Category};
+use super::keys::Positions;
+use super::parser::{Command, Scan, Value};
+use super::random;
+use super::stats::STATS;
+use super::{Database, Entry, Error, Key};
+use std::cell::Cell;
+use std::collections::hash_map::{self, HashMap};
+use std::collections::BTreeSet;
+use std::mem;
+use std::ops::Bound;
+use std::time::{Duration, Instant};
+
+/// Frequency counter of a new key, so that it isn't the first to go
+const LFU_INIT: u8 = 5;
+/// How slowly the frequency counter saturates
+const LFU_LOG_FACTOR: f64 = 10.0;
+/// Idle time decrementing the frequency counter by one
+const LFU_DECAY: Duration = Duration::from_secs(60);
+
+/// When a key was last looked up, and a logarithmic counter of how often,
+/// as in Redis' LFU policy. Cells let commands record the access through
+/// a shared reference.
+#[derive(Debug)]
+pub struct Access {
+    at: Cell<Instant>,
+    counter: Cell<u8>,
+}
+
+impl Access {
+    pub fn new() -> Self {
+        Access {
+            at: Cell::new(Instant::now()),
+            counter: Cell::new(LFU_INIT),
+        }
+    }
+
+    pub fn idle(&self) -> Duration {
+        self.at.get().elapsed()
+    }
+
+    /// The frequency counter, decayed by the time since the last access
+    pub fn frequency(&self) -> u8 {
+        let periods = self.idle().as_secs() / LFU_DECAY.as_secs();
+        self.counter.get().saturating_sub(periods.min(255) as u8)
+    }
+
+    /// Record an access, incrementing the counter with a probability that
+    /// falls as it grows, so that it can count far more than 255 accesses
+    fn touch(&self) {
+        let counter = self.frequency();
+        let base = counter.saturating_sub(LFU_INIT) as f64;
+        let p = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+        let r = random::next_u64() as f64 / u64::MAX as f64;
+        self.counter.set(match r < p {
+            true => counter.saturating_add(1),
+            false => counter,
+        });
+        self.at.set(Instant::now());
+    }
+}
+
+/// The keys of a database and their entries, indexed in SCAN order, and
+/// optionally along with an ordered index of the keys for range and prefix
+/// queries. The ordered index costs another copy of every key and B-tree
+/// update whenever a key is added or removed, so databases only keep one
+/// when configured to.
+pub struct Keyspace {
+    map: HashMap<Key, Entry>,
+    positions: Positions,
+    ordered: Option<BTreeSet<Key>>,
+}
+
+impl Keyspace {
+    pub fn new(ordered: bool) -> Self {
+        Keyspace {
+            map: HashMap::new(),
+            positions: Positions::default(),
+            ordered: if ordered { Some(BTreeSet::new()) } else { None },
+        }
+    }
+
+    pub fn is_ordered(&self) -> bool {
+        self.ordered.is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// Look up `key`. Lookups aren't accesses, as a command may look a key
+    /// up several times; `access` records each command once instead.
+    pub fn get(&self, key: &str) -> Option<&Entry> {
+        self.map.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Entry> {
+        self.map.get_mut(key)
+    }
+
+    /// Record a command accessing `key`, counting it as a keyspace hit or
+    /// miss if the command only reads
+    pub fn access(&self, key: &str, read: bool) {
+        let entry = self.map.get(key);
+        if read {
+            match entry {
+                Some(_) => STATS.hits.add(1),
+                None => STATS.misses.add(1),
+            }
+        }
+        if let Some(entry) = entry {
+            entry.access.touch();
+        }
+    }
+
+    pub fn keys(&self) -> hash_map::Keys<'_, Key, Entry> {
+        self.map.keys()
+    }
+
+    pub fn insert(&mut self, key: Key, entry: Entry) -> Option<Entry> {
+        if !self.map.contains_key(&key) {
+            self.positions.insert(&key);
+            if let Some(ref mut ordered) = self.ordered {
+                ordered.insert(key.clone());
+            }
+        }
+        self.map.insert(key, entry)
+    }
+
+    /// The entry of `key`, inserting the one `f` makes if it is missing
+    pub fn get_or_insert_with<F: FnOnce() -> Entry>(&mut self, key: Key, f: F) -> &mut Entry {
+        match self.map.entry(key) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => {
+                self.positions.insert(entry.key());
+                if let Some(ref mut ordered) = self.ordered {
+                    ordered.insert(entry.key().clone());
+                }
+                entry.insert(f())
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.map.remove(key)?;
+        self.positions.remove(key);
+        if let Some(ref mut ordered) = self.ordered {
+            ordered.remove(key);
+        }
+        Some(entry)
+    }
+
+    pub fn retain<F: FnMut(&Key, &mut Entry) -> bool>(&mut self, mut f: F) {
+        let (positions, ordered) = (&mut self.positions, &mut self.ordered);
+        self.map.retain(|key, entry| {
+            let keep = f(key, entry);
+            if !keep {
+                positions.remove(key);
+                if let Some(ordered) = ordered.as_mut() {
+                    ordered.remove(key);
+                }
+            }
+            keep
+        });
+    }
+
+    /// A key picked at random, in O(log N)
+    pub fn random_key(&self) -> Option<&str> {
+        self.positions.random()
+    }
+
+    /// A page of keys for SCAN, as `keys::page` would select
+    pub fn scan(&self, scan: &Scan) -> (u64, Vec<&str>) {
+        self.positions.page(scan)
+    }
+
+    /// Swap the keys of two keyspaces, each keeping an ordered index only
+    /// if it already did
+    pub fn swap(&mut self, other: &mut Keyspace) {
+        mem::swap(&mut self.map, &mut other.map);
+        mem::swap(&mut self.positions, &mut other.positions);
+        match (self.ordered.is_some(), other.ordered.is_some()) {
+            (true, true) => mem::swap(&mut self.ordered, &mut other.ordered),
+            _ => {
+                self.reindex();
+                other.reindex();
+            }
+        }
+    }
+
+    /// Rebuild the ordered index, if kept, from the keys
+    fn reindex(&mut self) {
+        if let Some(ref mut ordered) = self.ordered {
+            *ordered = self.map.keys().cloned().collect();
+        }
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        self.map.shrink_to_fit();
+    }
+
+    /// Keys between `start` and `end` in order, or `None` without an
+    /// ordered index
+    pub fn range<'a>(
+        &'a self,
+        start: Bound<&'a str>,
+        end: Bound<&'a str>,
+    ) -> Option<Box<dyn DoubleEndedIterator<Item = &'a Key> + 'a>> {
+        let ordered = self.ordered.as_ref()?;
+        // BTreeSet::range panics on bounds that cross
+        let empty = match (start, end) {
+            (Bound::Included(s), Bound::Included(e)) => s > e,
+            (Bound::Included(s), Bound::Excluded(e))
+            | (Bound::Excluded(s), Bound::Included(e))
+            | (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+            _ => false,
+        };
+        if empty {
+            return Some(Box::new(std::iter::empty()));
+        }
+        Some(Box::new(ordered.range::<str, _>((start, end))))
+    }
+}
+
+impl Database {
+    /// Record an access to each key `command` names
+    pub fn record_access(&self, command: &Command) {
+        // OBJECT inspects keys without accessing them, as in Redis
+        if let Command::Object(..) = command {
+            return;
+        }
+        let read = acl::category(command) == Some(Category::Read);
+        for key in acl::keys(command).unwrap_or_default() {
+            self.data.access(key, read);
+        }
+    }
+
+    /// Reply with the given keys followed by their values, as far as `limit`
+    fn key_values<'a, I>(&self, keys: I, limit: Option<usize>) -> Value
+    where
+        I: Iterator<Item = &'a Key>,
+    {
+        let mut found = Vec::new();
+        for key in keys.take(limit.unwrap_or(usize::MAX)) {
+            let entry = self.data.get(key).expect("Indexed key");
+            found.push(Value::Text(key.clone()));
+            found.push(entry.value.clone());
+        }
+        Value::Array(found)
+    }
+
+    /// Keys between `start` and `end` along with their values, in order
+    /// or, with `rev`, in reverse. The bounds are given lowest first either
+    /// way.
+    pub fn range(
+        &self,
+        start: &Bound<String>,
+        end: &Bound<String>,
+        limit: Option<usize>,
+        rev: bool,
+    ) -> Result<Value, Error> {
+        let keys = self
+            .data
+            .range(bound(start), bound(end))
+            .ok_or(Error::Unordered)?;
+        Ok(match rev {
+            true => self.key_values(keys.rev(), limit),
+            false => self.key_values(keys, limit),
+        })
+    }
+
+    /// Keys starting with `prefix` along with their values, in order
+    pub fn prefix(&self, prefix: &str) -> Result<Value, Error> {
+        let keys = self
+            .data
+            .range(Bound::Included(prefix), Bound::Unbounded)
+            .ok_or(Error::Unordered)?;
+        Ok(self.key_values(keys.take_while(|k| k.starts_with(prefix)), None))
+    }
+}
+
+fn bound(b: &Bound<String>) -> Bound<&str> {
+    match b {
+        Bound::Included(s) => Bound::Included(s),
+        Bound::Excluded(s) => Bound::Excluded(s),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.into())
+    }
+
+    fn pairs(pairs: &[&str]) -> Value {
+        let values = pairs
+            .iter()
+            .flat_map(|k| vec![text(k), text(&k.to_uppercase())])
+            .collect();
+        Value::Array(values)
+    }
+
+    #[test]
+    fn index_follows_keys() {
+        let mut db = Database::ordered();
+        for key in &["user:2", "user:10", "order:1", "user:1", "users"] {
+            db.create(key.to_string(), text(&key.to_uppercase()));
+        }
+        db.data.remove("user:10");
+        db.data
+            .get_or_insert_with("user:3".into(), || Entry::new(text("USER:3")));
+        db.data.retain(|k, _| k != "users");
+        let included = |s: &str| Bound::Included(s.to_string());
+
+        assert_eq!(
+            db.prefix("user:"),
+            Ok(pairs(&["user:1", "user:2", "user:3"]))
+        );
+        assert_eq!(
+            db.range(&included("order:"), &included("user:2"), None, false),
+            Ok(pairs(&["order:1", "user:1", "user:2"]))
+        );
+        assert_eq!(
+            db.range(
+                &Bound::Excluded("user:1".into()),
+                &Bound::Unbounded,
+                Some(1),
+                true
+            ),
+            Ok(pairs(&["user:3"]))
+        );
+        assert_eq!(
+            db.range(&included("z"), &included("a"), None, false),
+            Ok(Value::Array(vec![]))
+        );
+    }
+
+    #[test]
+    fn unordered() {
+        let db = Database::new();
+        assert_eq!(db.prefix("a"), Err(Error::Unordered));
+    }
+}
@@ -1,5 +1,3 @@
-use std::char;
-use std::iter::Peekable;
 use std::str;
 
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
@@ -10,8 +8,131 @@ pub enum Token {
     Update,
     Delete,
     Subscribe,
+    Incr,
+    Decr,
+    IncrBy,
+    DecrBy,
+    IncrByFloat,
+    Append,
+    GetRange,
+    SetRange,
+    StrLen,
+    GetSet,
+    GetDel,
+    GetEx,
+    SetBit,
+    GetBit,
+    BitCount,
+    BitPos,
+    BitOp,
+    BitField,
+    PfAdd,
+    PfCount,
+    PfMerge,
+    LPush,
+    RPush,
+    LPop,
+    RPop,
+    LRange,
+    LLen,
+    LIndex,
+    LSet,
+    LTrim,
+    LRem,
+    LInsert,
+    LMove,
+    BLPop,
+    BRPop,
+    BLMove,
+    Hello,
+    HSet,
+    HGet,
+    HMGet,
+    HDel,
+    HGetAll,
+    HKeys,
+    HVals,
+    HLen,
+    HExists,
+    HIncrBy,
+    HScan,
+    SAdd,
+    SRem,
+    SIsMember,
+    SMembers,
+    SCard,
+    SPop,
+    SRandMember,
+    SUnion,
+    SInter,
+    SDiff,
+    SUnionStore,
+    SInterStore,
+    SDiffStore,
+    ZAdd,
+    ZScore,
+    ZRank,
+    ZCard,
+    ZRange,
+    ZRem,
+    ZRemRangeByScore,
+    ZPopMin,
+    ZPopMax,
+    ZUnionStore,
+    ZInterStore,
+    XAdd,
+    XLen,
+    XRange,
+    XRevRange,
+    XRead,
+    XReadGroup,
+    XGroup,
+    XAck,
+    XPending,
+    XClaim,
+    XAutoClaim,
+    GeoAdd,
+    GeoPos,
+    GeoDist,
+    GeoHash,
+    GeoSearch,
+    JsonSet,
+    JsonGet,
+    JsonDel,
+    JsonArrAppend,
+    JsonNumIncrBy,
+    Scan,
+    Keys,
+    DbSize,
+    RandomKey,
+    SScan,
+    ZScan,
+    Range,
+    Prefix,
+    Select,
+    Move,
+    SwapDb,
+    FlushDb,
+    FlushAll,
+    Exists,
+    Type,
+    Rename,
+    RenameNx,
+    Copy,
+    Touch,
+    Unlink,
+    Object,
+    MGet,
+    MSet,
+    MSetNx,
+    Set,
+    Auth,
+    Acl,
+    Client,
+    Info,
     Array(Vec<Token>),
     Identifier(String),
+    Bytes(Vec<u8>),
     Integer(i64),
 }
 
@@ -23,110 +144,282 @@ pub enum Error {
     Parse,
 }
 
+/// Command names recognized by the lexer
+const KEYWORDS: &[(&str, Token)] = &[
+    ("DISCONNECT", Token::Disconnect),
+    ("CREATE", Token::Create),
+    ("READ", Token::Read),
+    ("UPDATE", Token::Update),
+    ("DELETE", Token::Delete),
+    ("SUB", Token::Subscribe),
+    ("INCR", Token::Incr),
+    ("DECR", Token::Decr),
+    ("INCRBY", Token::IncrBy),
+    ("DECRBY", Token::DecrBy),
+    ("INCRBYFLOAT", Token::IncrByFloat),
+    ("APPEND", Token::Append),
+    ("GETRANGE", Token::GetRange),
+    ("SETRANGE", Token::SetRange),
+    ("STRLEN", Token::StrLen),
+    ("GETSET", Token::GetSet),
+    ("GETDEL", Token::GetDel),
+    ("GETEX", Token::GetEx),
+    ("SETBIT", Token::SetBit),
+    ("GETBIT", Token::GetBit),
+    ("BITCOUNT", Token::BitCount),
+    ("BITPOS", Token::BitPos),
+    ("BITOP", Token::BitOp),
+    ("BITFIELD", Token::BitField),
+    ("PFADD", Token::PfAdd),
+    ("PFCOUNT", Token::PfCount),
+    ("PFMERGE", Token::PfMerge),
+    ("LPUSH", Token::LPush),
+    ("RPUSH", Token::RPush),
+    ("LPOP", Token::LPop),
+    ("RPOP", Token::RPop),
+    ("LRANGE", Token::LRange),
+    ("LLEN", Token::LLen),
+    ("LINDEX", Token::LIndex),
+    ("LSET", Token::LSet),
+    ("LTRIM", Token::LTrim),
+    ("LREM", Token::LRem),
+    ("LINSERT", Token::LInsert),
+    ("LMOVE", Token::LMove),
+    ("BLPOP", Token::BLPop),
+    ("BRPOP", Token::BRPop),
+    ("BLMOVE", Token::BLMove),
+    ("HELLO", Token::Hello),
+    ("HSET", Token::HSet),
+    ("HGET", Token::HGet),
+    ("HMGET", Token::HMGet),
+    ("HDEL", Token::HDel),
+    ("HGETALL", Token::HGetAll),
+    ("HKEYS", Token::HKeys),
+    ("HVALS", Token::HVals),
+    ("HLEN", Token::HLen),
+    ("HEXISTS", Token::HExists),
+    ("HINCRBY", Token::HIncrBy),
+    ("HSCAN", Token::HScan),
+    ("SADD", Token::SAdd),
+    ("SREM", Token::SRem),
+    ("SISMEMBER", Token::SIsMember),
+    ("SMEMBERS", Token::SMembers),
+    ("SCARD", Token::SCard),
+    ("SPOP", Token::SPop),
+    ("SRANDMEMBER", Token::SRandMember),
+    ("SUNION", Token::SUnion),
+    ("SINTER", Token::SInter),
+    ("SDIFF", Token::SDiff),
+    ("SUNIONSTORE", Token::SUnionStore),
+    ("SINTERSTORE", Token::SInterStore),
+    ("SDIFFSTORE", Token::SDiffStore),
+    ("ZADD", Token::ZAdd),
+    ("ZSCORE", Token::ZScore),
+    ("ZRANK", Token::ZRank),
+    ("ZCARD", Token::ZCard),
+    ("ZRANGE", Token::ZRange),
+    ("ZREM", Token::ZRem),
+    ("ZREMRANGEBYSCORE", Token::ZRemRangeByScore),
+    ("ZPOPMIN", Token::ZPopMin),
+    ("ZPOPMAX", Token::ZPopMax),
+    ("ZUNIONSTORE", Token::ZUnionStore),
+    ("ZINTERSTORE", Token::ZInterStore),
+    ("XADD", Token::XAdd),
+    ("XLEN", Token::XLen),
+    ("XRANGE", Token::XRange),
+    ("XREVRANGE", Token::XRevRange),
+    ("XREAD", Token::XRead),
+    ("XREADGROUP", Token::XReadGroup),
+    ("XGROUP", Token::XGroup),
+    ("XACK", Token::XAck),
+    ("XPENDING", Token::XPending),
+    ("XCLAIM", Token::XClaim),
+    ("XAUTOCLAIM", Token::XAutoClaim),
+    ("GEOADD", Token::GeoAdd),
+    ("GEOPOS", Token::GeoPos),
+    ("GEODIST", Token::GeoDist),
+    ("GEOHASH", Token::GeoHash),
+    ("GEOSEARCH", Token::GeoSearch),
+    ("JSON.SET", Token::JsonSet),
+    ("JSON.GET", Token::JsonGet),
+    ("JSON.DEL", Token::JsonDel),
+    ("JSON.ARRAPPEND", Token::JsonArrAppend),
+    ("JSON.NUMINCRBY", Token::JsonNumIncrBy),
+    ("SCAN", Token::Scan),
+    ("KEYS", Token::Keys),
+    ("DBSIZE", Token::DbSize),
+    ("RANDOMKEY", Token::RandomKey),
+    ("SSCAN", Token::SScan),
+    ("ZSCAN", Token::ZScan),
+    ("RANGE", Token::Range),
+    ("PREFIX", Token::Prefix),
+    ("SELECT", Token::Select),
+    ("MOVE", Token::Move),
+    ("SWAPDB", Token::SwapDb),
+    ("FLUSHDB", Token::FlushDb),
+    ("FLUSHALL", Token::FlushAll),
+    ("EXISTS", Token::Exists),
+    ("TYPE", Token::Type),
+    ("RENAME", Token::Rename),
+    ("RENAMENX", Token::RenameNx),
+    ("COPY", Token::Copy),
+    ("TOUCH", Token::Touch),
+    ("UNLINK", Token::Unlink),
+    ("OBJECT", Token::Object),
+    ("MGET", Token::MGet),
+    ("MSET", Token::MSet),
+    ("MSETNX", Token::MSetNx),
+    ("SET", Token::Set),
+    ("AUTH", Token::Auth),
+    ("ACL", Token::Acl),
+    ("CLIENT", Token::Client),
+    ("INFO", Token::Info),
+];
+
+impl Token {
+    /// The command name a keyword token was lexed from, so that it can be
+    /// recovered when it appears as a key or value
+    pub fn keyword(&self) -> Option<&'static str> {
+        KEYWORDS
+            .iter()
+            .find(|(_, token)| token == self)
+            .map(|(name, _)| *name)
+    }
+}
+
+/// Longest bulk string accepted, matching Redis' default proto-max-bulk-len
+pub const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
 pub struct Lexer<'a> {
-    input: Peekable<str::Chars<'a>>,
+    input: &'a [u8],
     pos: usize,
 }
 
 impl<'a> Lexer<'a> {
-    pub fn from(s: &'a str) -> Self {
-        Lexer {
-            input: s.chars().peekable(),
-            pos: 0,
-        }
+    pub fn from(s: &'a [u8]) -> Self {
+        Lexer { input: s, pos: 0 }
+    }
+
+    /// Number of bytes consumed so far
+    pub fn pos(&self) -> usize {
+        self.pos
     }
 
-    fn peek(&mut self) -> Result<char, Error> {
-        match self.input.peek() {
+    fn peek(&mut self) -> Result<u8, Error> {
+        match self.input.get(self.pos) {
             Some(&c) => Ok(c),
             None => Err(Error::UnexpectedEOF),
         }
     }
 
-    fn consume(&mut self) -> Result<char, Error> {
-        let next = self.input.next();
-        if next.is_some() {
-            self.pos += 1;
-        }
-        next.ok_or(Error::UnexpectedEOF)
+    fn consume(&mut self) -> Result<u8, Error> {
+        let next = self.peek()?;
+        self.pos += 1;
+        Ok(next)
     }
 
-    fn consume_while<F: Fn(char) -> bool>(&mut self, lambda: F) -> Result<String, Error> {
+    /// Consume bytes while `lambda` holds, failing if the input runs out
+    /// first as more of them may be yet to arrive
+    fn consume_while<F: Fn(u8) -> bool>(&mut self, lambda: F) -> Result<String, Error> {
         let mut s = String::new();
         while let Ok(c) = self.peek() {
             if lambda(c) {
-                s.push(self.consume().expect("We have already peeked"));
+                s.push(self.consume().expect("We have already peeked") as char);
             } else {
                 return Ok(s);
             }
         }
-        Ok(s)
+        Err(Error::UnexpectedEOF)
     }
 
     fn consume_until_crlf(&mut self) -> Result<String, Error> {
         let mut s = String::new();
         while let Ok(c) = self.peek() {
-            if c != '\r' {
-                s.push(self.consume().expect("We have already peeked"));
+            if c != b'\r' {
+                s.push(self.consume().expect("We have already peeked") as char);
             } else {
                 return self.try_consume_crlf().map(|_| s);
             }
         }
-        Ok(s)
+        Err(Error::UnexpectedEOF)
+    }
+
+    /// Consume exactly `len` bytes
+    fn consume_bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let input = self.input;
+        match input.get(self.pos..self.pos + len) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => Err(Error::UnexpectedEOF),
+        }
     }
 
     fn try_consume_crlf(&mut self) -> Result<(), Error> {
         match self.peek() {
-            Ok('\r') => {
+            Ok(b'\r') => {
                 self.consume()?;
                 match self.peek() {
-                    Ok('\n') => self.consume().map(|_| ()),
-                    Ok(c) => Err(Error::Expected('\n', c, self.pos)),
+                    Ok(b'\n') => self.consume().map(|_| ()),
+                    Ok(c) => Err(Error::Expected('\n', c as char, self.pos)),
                     Err(e) => Err(e),
                 }
             }
-            Ok(c) => Err(Error::Expected('\r', c, self.pos)),
+            Ok(c) => Err(Error::Expected('\r', c as char, self.pos)),
             Err(e) => Err(e),
         }
     }
 
     fn identifier(&self, s: String) -> Token {
-        match s.as_ref() {
-            "DISCONNECT" => Token::Disconnect,
-            "CREATE" => Token::Create,
-            "READ" => Token::Read,
-            "UPDATE" => Token::Update,
-            "DELETE" => Token::Delete,
-            "SUB" => Token::Subscribe,
-            _ => Token::Identifier(s),
+        KEYWORDS
+            .iter()
+            .find(|(name, _)| *name == s)
+            .map(|(_, token)| token.clone())
+            .unwrap_or(Token::Identifier(s))
+    }
+
+    /// Lex the header of an array, returning how many elements follow
+    pub fn array_header(&mut self) -> Result<usize, Error> {
+        if self.consume()? != b'*' {
+            return Err(Error::Delimiter(self.pos - 1));
         }
+        let n = self.consume_while(|c| c.is_ascii_digit())?;
+        let len = n.parse::<usize>().map_err(|_| Error::Parse)?;
+        self.try_consume_crlf()?;
+        Ok(len)
     }
 
     pub fn lex(&mut self) -> Result<Token, Error> {
         if let Ok(c) = self.peek() {
             match c {
-                '$' => {
+                b'$' => {
                     let _ = self.consume()?;
-                    let n = self.consume_while(|c| c.is_numeric())?;
+                    let n = self.consume_while(|c| c.is_ascii_digit())?;
                     let len = n.parse::<usize>().map_err(|_| Error::Parse)?;
+                    if len > MAX_BULK_LEN {
+                        return Err(Error::Parse);
+                    }
                     self.try_consume_crlf()?;
-                    let string = self.input.by_ref().take(len).collect::<String>();
+                    let bytes = self.consume_bytes(len)?;
                     self.try_consume_crlf()?;
 
-                    return Ok(self.identifier(string));
+                    // Bulk strings are binary safe, but only valid UTF-8
+                    // can name a command or a key
+                    return Ok(match str::from_utf8(bytes) {
+                        Ok(s) => self.identifier(s.into()),
+                        Err(_) => Token::Bytes(bytes.to_vec()),
+                    });
                 }
-                '*' => {
-                    let _ = self.consume()?;
-                    let n = self.consume_while(|c| c.is_numeric())?;
-                    let len = n.parse::<usize>().map_err(|_| Error::Parse)?;
-                    self.try_consume_crlf()?;
-                    let mut array = Vec::with_capacity(len);
+                b'*' => {
+                    let len = self.array_header()?;
+                    let mut array = Vec::with_capacity(len.min(1024));
                     for _ in 0..len {
                         array.push(self.lex()?);
                     }
                     return Ok(Token::Array(array));
                 }
-                ':' => {
+                b':' => {
                     let _ = self.consume()?;
                     let n = self.consume_until_crlf()?;
                     let num = n.parse::<i64>().map_err(|_| Error::Parse)?;
@@ -146,7 +439,7 @@ mod test {
     #[test]
     fn lex_array_nested() {
         let mut lexer =
-            Lexer::from("*3\r\n$6\r\nCREATE\r\n$3\r\nkey\r\n*2\r\n$4\r\nval1\r\n$4\r\nval2\r\n");
+            Lexer::from(b"*3\r\n$6\r\nCREATE\r\n$3\r\nkey\r\n*2\r\n$4\r\nval1\r\n$4\r\nval2\r\n");
         assert_eq!(
             lexer.lex(),
             Ok(Token::Array(vec![
@@ -162,7 +455,7 @@ mod test {
 
     #[test]
     fn lex_array() {
-        let mut lexer = Lexer::from("*3\r\n$6\r\nhello!\r\n$3\r\nSUB\r\n:12341234\r\n");
+        let mut lexer = Lexer::from(b"*3\r\n$6\r\nhello!\r\n$3\r\nSUB\r\n:12341234\r\n");
         assert_eq!(
             lexer.lex(),
             Ok(Token::Array(vec![
@@ -173,9 +466,37 @@ mod test {
         );
     }
 
+    #[test]
+    fn keywords() {
+        let mut lexer = Lexer::from(b"*3\r\n$5\r\nLPUSH\r\n$4\r\nREAD\r\n$5\r\nlpush\r\n");
+        let tokens = Token::Array(vec![
+            Token::LPush,
+            Token::Read,
+            Token::Identifier(String::from("lpush")),
+        ]);
+        assert_eq!(lexer.lex(), Ok(tokens));
+        assert_eq!(Token::LPush.keyword(), Some("LPUSH"));
+        assert_eq!(Token::Integer(1).keyword(), None);
+    }
+
+    #[test]
+    fn lex_binary() {
+        let mut lexer = Lexer::from(b"*2\r\n$3\r\n\xe2\x82\xac\r\n$2\r\n\x80\r\r\n");
+        assert_eq!(
+            lexer.lex(),
+            Ok(Token::Array(vec![
+                Token::Identifier(String::from("\u{20ac}")),
+                Token::Bytes(vec![0x80, b'\r'])
+            ]))
+        );
+        assert_eq!(lexer.pos(), 21);
+        let mut lexer = Lexer::from(b"*2\r\n$3\r\nab");
+        assert_eq!(lexer.lex(), Err(Error::UnexpectedEOF));
+    }
+
     #[test]
     fn lex_int() {
-        let mut lexer = Lexer::from(":-100346\r\n");
+        let mut lexer = Lexer::from(b":-100346\r\n");
         assert_eq!(lexer.lex(), Ok(Token::Integer(-100346)));
     }
 }
@@ -0,0 +1,325 @@
+use super::parser::{End, Position, Value};
+use super::{Database, Entry, Error, Key};
+use std::collections::VecDeque;
+
+/// Resolve a possibly negative index against a list of `len` elements
+fn index(len: usize, i: i64) -> Option<usize> {
+    let i = if i < 0 { len as i64 + i } else { i };
+    if i >= 0 && (i as usize) < len {
+        Some(i as usize)
+    } else {
+        None
+    }
+}
+
+/// Clamp an inclusive, possibly negative, range to a list of `len` elements,
+/// returning `None` if nothing is left of it
+pub fn range(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        None
+    } else {
+        Some((start as usize, stop as usize))
+    }
+}
+
+impl Database {
+    pub fn list(&self, key: &str) -> Result<Option<&VecDeque<Value>>, Error> {
+        match self.data.get(key).map(|e| &e.value) {
+            Some(Value::List(list)) => Ok(Some(list)),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn list_mut(&mut self, key: &str) -> Result<Option<&mut VecDeque<Value>>, Error> {
+        match self.data.get_mut(key).map(|e| &mut e.value) {
+            Some(Value::List(list)) => Ok(Some(list)),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Lists are never stored empty, so drop `key` once its last element is gone
+    fn remove_if_empty(&mut self, key: &str) {
+        if let Ok(Some(list)) = self.list(key) {
+            if list.is_empty() {
+                self.data.remove(key);
+            }
+        }
+    }
+
+    pub fn push(&mut self, key: Key, values: Vec<Value>, end: End) -> Result<Value, Error> {
+        self.list(&key)?;
+        let entry = self
+            .data
+            .get_or_insert_with(key.clone(), || Entry::new(Value::List(VecDeque::new())));
+        let len = match entry.value {
+            Value::List(ref mut list) => {
+                for value in values {
+                    match end {
+                        End::Front => list.push_front(value),
+                        End::Back => list.push_back(value),
+                    }
+                }
+                list.len()
+            }
+            _ => return Err(Error::WrongType),
+        };
+        self.serve_blocked(&key);
+        Ok(Value::Integer(len as i64))
+    }
+
+    /// Pop a single element, or up to `count` elements as an array
+    pub fn pop(&mut self, key: &str, count: Option<usize>, end: End) -> Result<Value, Error> {
+        let reply = match self.list_mut(key)? {
+            None => return Ok(Value::Null),
+            Some(list) => {
+                let mut pop = || match end {
+                    End::Front => list.pop_front(),
+                    End::Back => list.pop_back(),
+                };
+                match count {
+                    None => pop().unwrap_or(Value::Null),
+                    Some(n) => Value::Array((0..n).map_while(|_| pop()).collect()),
+                }
+            }
+        };
+        self.remove_if_empty(key);
+        Ok(reply)
+    }
+
+    /// Atomically pop an element from `source` and push it onto `dest`
+    pub fn lmove(&mut self, source: &str, dest: &str, from: End, to: End) -> Result<Value, Error> {
+        self.list(dest)?;
+        match self.pop(source, None, from)? {
+            Value::Null => Ok(Value::Null),
+            value => {
+                self.push(dest.into(), vec![value.clone()], to)?;
+                Ok(value)
+            }
+        }
+    }
+
+    pub fn lrange(&self, key: &str, start: i64, stop: i64) -> Result<Value, Error> {
+        let list = match self.list(key)? {
+            Some(list) => list,
+            None => return Ok(Value::Array(Vec::new())),
+        };
+        Ok(Value::Array(match range(list.len(), start, stop) {
+            Some((start, stop)) => list.range(start..=stop).cloned().collect(),
+            None => Vec::new(),
+        }))
+    }
+
+    pub fn llen(&self, key: &str) -> Result<Value, Error> {
+        Ok(Value::Integer(
+            self.list(key)?.map(|l| l.len()).unwrap_or(0) as i64,
+        ))
+    }
+
+    pub fn lindex(&self, key: &str, i: i64) -> Result<Value, Error> {
+        Ok(self
+            .list(key)?
+            .and_then(|list| index(list.len(), i).map(|i| list[i].clone()))
+            .unwrap_or(Value::Null))
+    }
+
+    pub fn lset(&mut self, key: &str, i: i64, value: Value) -> Result<Value, Error> {
+        let list = self.list_mut(key)?.ok_or(Error::NoSuchKey)?;
+        let i = index(list.len(), i).ok_or(Error::OutOfRange)?;
+        list[i] = value;
+        Ok(Value::Status("OK".into()))
+    }
+
+    pub fn ltrim(&mut self, key: &str, start: i64, stop: i64) -> Result<Value, Error> {
+        if let Some(list) = self.list_mut(key)? {
+            match range(list.len(), start, stop) {
+                Some((start, stop)) => {
+                    list.truncate(stop + 1);
+                    list.drain(..start);
+                }
+                None => list.clear(),
+            }
+        }
+        self.remove_if_empty(key);
+        Ok(Value::Status("OK".into()))
+    }
+
+    /// Remove up to `count` occurrences of `value`, scanning from the tail if
+    /// `count` is negative, or every occurrence if it is zero
+    pub fn lrem(&mut self, key: &str, count: i64, value: &Value) -> Result<Value, Error> {
+        let list = match self.list_mut(key)? {
+            Some(list) => list,
+            None => return Ok(Value::Integer(0)),
+        };
+        let limit = if count == 0 {
+            usize::MAX
+        } else {
+            count.unsigned_abs() as usize
+        };
+        let mut removed = 0;
+        if count < 0 {
+            let mut i = list.len();
+            while i > 0 && removed < limit {
+                i -= 1;
+                if &list[i] == value {
+                    list.remove(i);
+                    removed += 1;
+                }
+            }
+        } else {
+            let mut i = 0;
+            while i < list.len() && removed < limit {
+                if &list[i] == value {
+                    list.remove(i);
+                    removed += 1;
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.remove_if_empty(key);
+        Ok(Value::Integer(removed as i64))
+    }
+
+    /// Insert `value` next to the first occurrence of `pivot`, returning the
+    /// new length, or -1 if `pivot` was not found
+    pub fn linsert(
+        &mut self,
+        key: &str,
+        position: Position,
+        pivot: &Value,
+        value: Value,
+    ) -> Result<Value, Error> {
+        let list = match self.list_mut(key)? {
+            Some(list) => list,
+            None => return Ok(Value::Integer(0)),
+        };
+        match list.iter().position(|v| v == pivot) {
+            Some(i) => {
+                match position {
+                    Position::Before => list.insert(i, value),
+                    Position::After => list.insert(i + 1, value),
+                }
+                Ok(Value::Integer(list.len() as i64))
+            }
+            None => Ok(Value::Integer(-1)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.into())
+    }
+
+    fn list(db: &mut Database, values: &[&str]) {
+        let values = values.iter().map(|s| text(s)).collect();
+        db.push("l".into(), values, End::Back).unwrap();
+    }
+
+    #[test]
+    fn push_pop() {
+        let mut db = Database::new();
+        assert_eq!(
+            db.push("l".into(), vec![text("a"), text("b")], End::Front),
+            Ok(Value::Integer(2))
+        );
+        assert_eq!(
+            db.push("l".into(), vec![text("c")], End::Back),
+            Ok(Value::Integer(3))
+        );
+        assert_eq!(
+            db.lrange("l", 0, -1),
+            Ok(Value::Array(vec![text("b"), text("a"), text("c")]))
+        );
+        assert_eq!(db.pop("l", None, End::Back), Ok(text("c")));
+        assert_eq!(
+            db.pop("l", Some(5), End::Front),
+            Ok(Value::Array(vec![text("b"), text("a")]))
+        );
+        assert!(db.read("l").is_none());
+        assert_eq!(db.pop("l", None, End::Front), Ok(Value::Null));
+    }
+
+    #[test]
+    fn ranges() {
+        let mut db = Database::new();
+        list(&mut db, &["a", "b", "c", "d"]);
+        assert_eq!(db.lrange("l", -3, 1), Ok(Value::Array(vec![text("b")])));
+        assert_eq!(db.lrange("l", 5, 10), Ok(Value::Array(vec![])));
+        assert_eq!(db.lindex("l", -1), Ok(text("d")));
+        assert_eq!(db.lindex("l", 4), Ok(Value::Null));
+        assert_eq!(db.lset("l", 4, text("x")), Err(Error::OutOfRange));
+        assert_eq!(db.lset("m", 0, text("x")), Err(Error::NoSuchKey));
+        db.ltrim("l", 1, -2).unwrap();
+        assert_eq!(
+            db.lrange("l", 0, -1),
+            Ok(Value::Array(vec![text("b"), text("c")]))
+        );
+        db.ltrim("l", 2, 1).unwrap();
+        assert_eq!(db.llen("l"), Ok(Value::Integer(0)));
+    }
+
+    #[test]
+    fn remove_insert() {
+        let mut db = Database::new();
+        list(&mut db, &["a", "b", "a", "c", "a"]);
+        assert_eq!(db.lrem("l", -2, &text("a")), Ok(Value::Integer(2)));
+        assert_eq!(
+            db.lrange("l", 0, -1),
+            Ok(Value::Array(vec![text("a"), text("b"), text("c")]))
+        );
+        assert_eq!(
+            db.linsert("l", Position::After, &text("b"), text("x")),
+            Ok(Value::Integer(4))
+        );
+        assert_eq!(
+            db.linsert("l", Position::Before, &text("z"), text("x")),
+            Ok(Value::Integer(-1))
+        );
+        assert_eq!(db.lrem("l", 0, &text("x")), Ok(Value::Integer(1)));
+    }
+
+    #[test]
+    fn lmove() {
+        let mut db = Database::new();
+        list(&mut db, &["a", "b"]);
+        assert_eq!(db.lmove("l", "l", End::Front, End::Back), Ok(text("a")));
+        assert_eq!(db.lmove("l", "m", End::Back, End::Front), Ok(text("a")));
+        assert_eq!(db.lrange("l", 0, -1), Ok(Value::Array(vec![text("b")])));
+        assert_eq!(db.lrange("m", 0, -1), Ok(Value::Array(vec![text("a")])));
+        assert_eq!(db.lmove("x", "m", End::Back, End::Front), Ok(Value::Null));
+        db.create("s".into(), text("v"));
+        assert_eq!(
+            db.lmove("l", "s", End::Back, End::Front),
+            Err(Error::WrongType)
+        );
+        assert_eq!(db.llen("l"), Ok(Value::Integer(1)));
+    }
+
+    #[test]
+    fn wrong_type() {
+        let mut db = Database::new();
+        db.create("s".into(), text("v"));
+        assert_eq!(db.llen("s"), Err(Error::WrongType));
+        assert_eq!(
+            db.push("s".into(), vec![text("a")], End::Front),
+            Err(Error::WrongType)
+        );
+    }
+}
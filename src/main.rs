@@ -1,29 +1,95 @@
 #![allow(dead_code)]
-use std::collections::HashMap;
+#[cfg(feature = "tls")]
+extern crate rustls;
+#[cfg(feature = "keepalive")]
+extern crate socket2;
+
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::fmt;
 use std::io::prelude::*;
 use std::io::Read;
 use std::net::*;
 use std::str;
-use std::sync::mpsc::{channel, Sender};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, MutexGuard};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
+mod acl;
+mod bitmap;
+mod blocking;
+mod buffer;
+mod clients;
+mod config;
+mod databases;
+mod expire;
+mod geo;
+mod glob;
+mod hash;
+mod hll;
+mod json;
+mod keys;
+mod keyspace;
 mod lexer;
+mod list;
 mod parser;
+mod random;
+mod set;
+mod sha256;
+mod socket;
+mod stats;
+mod stream;
+mod string;
+#[cfg(feature = "tls")]
+mod tls;
+mod zset;
 
-use parser::{Command, Parser, Value};
+use acl::{Acl, Category};
+use blocking::{Block, Blocked, Waiters};
+use buffer::{Class, Limits, Output};
+use clients::{Clients, Connection};
+use config::Config;
+use databases::Databases;
+use keyspace::{Access, Keyspace};
+use parser::{AclOp, Command, End, Frame, Protocol, Value};
+use set::Algebra;
+use socket::Socket;
+use stats::STATS;
 
 type Key = String;
 
+/// How often a blocked client checks that it is still connected
+const BLOCKED_POLL: Duration = Duration::from_millis(100);
+/// Longest a TLS client may take over its handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[global_allocator]
+static ALLOCATOR: stats::Counting = stats::Counting;
+
 struct Entry {
     value: Value,
-    expiration: Option<usize>,
-    subscribers: Option<Vec<Sender<Vec<u8>>>>,
+    expiration: Option<SystemTime>,
+    subscribers: Option<Vec<Output>>,
+    access: Access,
+}
+
+impl Entry {
+    fn new(value: Value) -> Self {
+        Entry {
+            value,
+            expiration: None,
+            subscribers: None,
+            access: Access::new(),
+        }
+    }
 }
 
 struct Database {
-    data: HashMap<Key, Entry>,
+    data: Keyspace,
     next_tx_id: usize,
+    blocked: Waiters,
+    /// Index of the keys with an expiration time, soonest first
+    expirations: BTreeSet<(SystemTime, Key)>,
 }
 
 // struct Transaction<'d> {
@@ -32,16 +98,121 @@ struct Database {
 //     val: Option<Value>,
 // }
 
-// enum Error {
-//     NonexistantKey,
-//     CreateExistingKey,
-// }
+#[derive(Debug, PartialEq, Clone)]
+enum Error {
+    WrongType,
+    NoSuchKey,
+    OutOfRange,
+    HashNotInteger,
+    Overflow,
+    NotANumber,
+    NotInteger,
+    NotFloat,
+    NotFinite,
+    TooLarge,
+    StreamIdZero,
+    StreamIdTooSmall,
+    NoStream,
+    NoGroup(Key, String),
+    BusyGroup,
+    NoSuchMember,
+    NotRoot,
+    NoPath(String),
+    Unordered,
+    DbIndex,
+    SameObject,
+    NoAuth,
+    WrongPass,
+    NoPerm(String),
+    /// A rule given to ACL SETUSER and what is wrong with it
+    AclRule(String, String),
+    NoSuchClient,
+    ClientName,
+    MaxClients,
+    MaxClientsPerIp,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::WrongType => write!(
+                f,
+                "WRONGTYPE Operation against a key holding the wrong kind of value"
+            ),
+            Error::NoSuchKey => write!(f, "ERR no such key"),
+            Error::OutOfRange => write!(f, "ERR index out of range"),
+            Error::HashNotInteger => write!(f, "ERR hash value is not an integer"),
+            Error::Overflow => write!(f, "ERR increment or decrement would overflow"),
+            Error::NotANumber => write!(f, "ERR resulting score is not a number (NaN)"),
+            Error::NotInteger => write!(f, "ERR value is not an integer or out of range"),
+            Error::NotFloat => write!(f, "ERR value is not a valid float"),
+            Error::NotFinite => write!(f, "ERR increment would produce NaN or Infinity"),
+            Error::TooLarge => write!(
+                f,
+                "ERR string exceeds maximum allowed size (proto-max-bulk-len)"
+            ),
+            Error::StreamIdZero => write!(f, "ERR The ID specified in XADD must be greater than 0-0"),
+            Error::StreamIdTooSmall => write!(
+                f,
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+            ),
+            Error::NoStream => write!(
+                f,
+                "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically."
+            ),
+            Error::NoGroup(key, group) => write!(
+                f,
+                "NOGROUP No such key '{}' or consumer group '{}'",
+                key, group
+            ),
+            Error::BusyGroup => write!(f, "BUSYGROUP Consumer Group name already exists"),
+            Error::NoSuchMember => write!(f, "ERR could not decode requested zset member"),
+            Error::NotRoot => write!(f, "ERR new objects must be created at the root"),
+            Error::NoPath(path) => write!(f, "ERR Path '{}' does not exist", path),
+            Error::DbIndex => write!(f, "ERR DB index is out of range"),
+            Error::SameObject => write!(f, "ERR source and destination objects are the same"),
+            Error::NoAuth => write!(f, "NOAUTH Authentication required."),
+            Error::WrongPass => write!(
+                f,
+                "WRONGPASS invalid username-password pair or user is disabled."
+            ),
+            Error::NoPerm(msg) => write!(f, "NOPERM {}", msg),
+            Error::AclRule(rule, msg) => {
+                write!(f, "ERR Error in ACL SETUSER modifier '{}': {}", rule, msg)
+            }
+            Error::NoSuchClient => write!(f, "ERR No such client"),
+            Error::MaxClients => write!(f, "ERR max number of clients reached"),
+            Error::MaxClientsPerIp => {
+                write!(f, "ERR max number of clients from this address reached")
+            }
+            Error::ClientName => write!(
+                f,
+                "ERR Client names cannot contain spaces, newlines or special characters."
+            ),
+            Error::Unordered => write!(
+                f,
+                "ERR this database does not keep its keys ordered (see ordered-keyspace)"
+            ),
+        }
+    }
+}
 
 impl Database {
     pub fn new() -> Self {
+        Database::with_keyspace(Keyspace::new(false))
+    }
+
+    /// A database keeping its keys ordered, for RANGE and PREFIX
+    pub fn ordered() -> Self {
+        Database::with_keyspace(Keyspace::new(true))
+    }
+
+    fn with_keyspace(data: Keyspace) -> Self {
         Database {
-            data: HashMap::new(),
+            data,
             next_tx_id: 0,
+            blocked: Waiters::default(),
+            expirations: BTreeSet::new(),
         }
     }
 
@@ -55,43 +226,157 @@ impl Database {
     //     }
     // }
 
-    // pub fn execute(&mut self, command: Command, sender: Option<Sender<Vec<u8>>>) -> Result<Transaction, Error> {
-    //     match command {
-    //         Command::Disconnect => Ok(self.transaction(None)),
-    //         Command::Create(key, val) => Ok(self.transaction(self.create(key, val))),
-    //         Command::Delete(key) => Ok(self.transaction(self.delete(&key))),
-    //         Command::Read(key) => match self.read(&key) {
-    //             Some(val) => Ok(self.transaction(Some(val))),
-    //             None => Err(Error::NonexistantKey),
-    //         }
-    //         Command::Update(key, val) => match db.update(&key, val).map(|v| v.clone()) {
-    //             Ok(r) => r,
-    //             e => {
-    //                 println!("Error writing to stream {:?}", self.stream.peer_addr());
-    //                 break;
-    //             }
-    //         },
-    //         Command::Subscribe(key) => {
-    //             match db.subscribe(&key, sender) {
-    //                 Ok(self.transaction(None))
-    //             }
-
-    //         }
-    //     }
-    //     //Err(Error::NonexistantKey)
-    //     }
+    /// Run a command against the database, returning the reply to send
+    /// back, if any. Connection level commands are handled by `Client`.
+    pub fn execute(&mut self, command: Command) -> Option<Value> {
+        self.expire_due();
+        self.record_access(&command);
+        let result = match command {
+            Command::Disconnect
+            | Command::Subscribe(_)
+            | Command::BLPop(..)
+            | Command::BRPop(..)
+            | Command::BLMove(..)
+            | Command::Hello(_)
+            | Command::Select(_)
+            | Command::Move(..)
+            | Command::SwapDb(..)
+            | Command::FlushAll
+            | Command::Auth(..)
+            | Command::Acl(_)
+            | Command::Client(_)
+            | Command::Info(_) => return None,
+            Command::FlushDb => {
+                self.flush();
+                Ok(Value::Status("OK".into()))
+            }
+            Command::Create(key, val) => return self.create(key, val),
+            Command::Delete(key) => return self.delete(&key),
+            Command::Read(key) => return self.read(&key).cloned(),
+            Command::Update(key, val) => return self.update(&key, val),
+            Command::Incr(key) => self.incrby(key, 1),
+            Command::Decr(key) => self.decrby(key, 1),
+            Command::IncrBy(key, delta) => self.incrby(key, delta),
+            Command::DecrBy(key, delta) => self.decrby(key, delta),
+            Command::IncrByFloat(key, delta) => self.incrbyfloat(key, delta),
+            Command::Append(key, bytes) => self.append(key, bytes),
+            Command::GetRange(key, start, stop) => self.getrange(&key, start, stop),
+            Command::SetRange(key, offset, bytes) => self.setrange(key, offset, bytes),
+            Command::StrLen(key) => self.strlen(&key),
+            Command::GetSet(key, value) => self.getset(key, value),
+            Command::GetDel(key) => self.getdel(&key),
+            Command::GetEx(key, expire) => self.getex(&key, expire),
+            Command::SetBit(key, offset, bit) => self.setbit(key, offset, bit),
+            Command::GetBit(key, offset) => self.getbit(&key, offset),
+            Command::BitCount(key, range) => self.bitcount(&key, range),
+            Command::BitPos(key, bit, range) => self.bitpos(&key, bit, range),
+            Command::BitOp(op, dest, keys) => self.bitop(op, dest, &keys),
+            Command::BitField(key, ops) => self.bitfield(key, ops),
+            Command::PfAdd(key, elements) => self.pfadd(key, elements),
+            Command::PfCount(keys) => self.pfcount(&keys),
+            Command::PfMerge(dest, keys) => self.pfmerge(dest, &keys),
+            Command::LPush(key, values) => self.push(key, values, End::Front),
+            Command::RPush(key, values) => self.push(key, values, End::Back),
+            Command::LPop(key, count) => self.pop(&key, count, End::Front),
+            Command::RPop(key, count) => self.pop(&key, count, End::Back),
+            Command::LRange(key, start, stop) => self.lrange(&key, start, stop),
+            Command::LLen(key) => self.llen(&key),
+            Command::LIndex(key, index) => self.lindex(&key, index),
+            Command::LSet(key, index, val) => self.lset(&key, index, val),
+            Command::LTrim(key, start, stop) => self.ltrim(&key, start, stop),
+            Command::LRem(key, count, val) => self.lrem(&key, count, &val),
+            Command::LInsert(key, pos, pivot, val) => self.linsert(&key, pos, &pivot, val),
+            Command::LMove(source, dest, from, to) => self.lmove(&source, &dest, from, to),
+            Command::HSet(key, pairs) => self.hset(key, pairs),
+            Command::HGet(key, field) => self.hget(&key, &field),
+            Command::HMGet(key, fields) => self.hmget(&key, &fields),
+            Command::HDel(key, fields) => self.hdel(&key, &fields),
+            Command::HGetAll(key) => self.hgetall(&key),
+            Command::HKeys(key) => self.hkeys(&key),
+            Command::HVals(key) => self.hvals(&key),
+            Command::HLen(key) => self.hlen(&key),
+            Command::HExists(key, field) => self.hexists(&key, &field),
+            Command::HIncrBy(key, field, delta) => self.hincrby(key, field, delta),
+            Command::HScan(key, scan) => self.hscan(&key, &scan),
+            Command::SScan(key, scan) => self.sscan(&key, &scan),
+            Command::ZScan(key, scan) => self.zscan(&key, &scan),
+            Command::Scan(scan) => self.scan(&scan),
+            Command::Keys(pattern) => self.keys(&pattern),
+            Command::DbSize => self.dbsize(),
+            Command::RandomKey => self.randomkey(),
+            Command::Range(start, end, limit, rev) => self.range(&start, &end, limit, rev),
+            Command::Prefix(prefix) => self.prefix(&prefix),
+            Command::Exists(keys) => self.exists(&keys),
+            Command::Type(key) => self.key_type(&key),
+            Command::Rename(key, new) => self.rename(&key, new, false),
+            Command::RenameNx(key, new) => self.rename(&key, new, true),
+            Command::Copy(source, dest, _, replace) => self.copy(&source, dest, replace),
+            Command::Touch(keys) => self.touch(&keys),
+            Command::Unlink(keys) => self.unlink(&keys),
+            Command::Object(info, key) => self.object(info, &key),
+            Command::Set(key, value, options) => self.set_key(key, value, &options),
+            Command::MGet(keys) => self.mget(&keys),
+            Command::MSet(pairs) => self.mset(pairs),
+            Command::MSetNx(pairs) => self.msetnx(pairs),
+            Command::SAdd(key, members) => self.sadd(key, members),
+            Command::SRem(key, members) => self.srem(&key, &members),
+            Command::SIsMember(key, member) => self.sismember(&key, &member),
+            Command::SMembers(key) => self.smembers(&key),
+            Command::SCard(key) => self.scard(&key),
+            Command::SPop(key, count) => self.spop(&key, count),
+            Command::SRandMember(key, count) => self.srandmember(&key, count),
+            Command::SUnion(keys) => self.sets(&keys, Algebra::Union),
+            Command::SInter(keys) => self.sets(&keys, Algebra::Inter),
+            Command::SDiff(keys) => self.sets(&keys, Algebra::Diff),
+            Command::SUnionStore(dest, keys) => self.sets_store(dest, &keys, Algebra::Union),
+            Command::SInterStore(dest, keys) => self.sets_store(dest, &keys, Algebra::Inter),
+            Command::SDiffStore(dest, keys) => self.sets_store(dest, &keys, Algebra::Diff),
+            Command::ZAdd(key, flags, pairs) => self.zadd(key, flags, pairs),
+            Command::ZScore(key, member) => self.zscore(&key, &member),
+            Command::ZRank(key, member) => self.zrank(&key, &member),
+            Command::ZCard(key) => self.zcard(&key),
+            Command::ZRange(key, range) => self.zrange(&key, &range),
+            Command::ZRem(key, members) => self.zrem(&key, &members),
+            Command::ZRemRangeByScore(key, min, max) => self.zremrangebyscore(&key, min, max),
+            Command::ZPopMin(key, count) => self.zpop(&key, count, false),
+            Command::ZPopMax(key, count) => self.zpop(&key, count, true),
+            Command::ZUnionStore(dest, keys, weights, aggregate) => {
+                self.zstore(dest, &keys, &weights, aggregate, false)
+            }
+            Command::ZInterStore(dest, keys, weights, aggregate) => {
+                self.zstore(dest, &keys, &weights, aggregate, true)
+            }
+            Command::XAdd(key, add) => self.xadd(key, add),
+            Command::XLen(key) => self.xlen(&key),
+            Command::XRange(key, start, end, count) => self.xrange(&key, start, end, count, false),
+            Command::XRevRange(key, start, end, count) => {
+                self.xrange(&key, start, end, count, true)
+            }
+            Command::XRead(read) => self.xread(read),
+            Command::XGroup(op) => self.xgroup(op),
+            Command::XAck(key, group, ids) => self.xack(&key, &group, &ids),
+            Command::XPending(key, group, range) => self.xpending(&key, &group, range),
+            Command::XClaim(claim) => self.xclaim(claim),
+            Command::XAutoClaim(claim) => self.xautoclaim(claim),
+            Command::GeoAdd(key, flags, members) => self.geoadd(key, flags, members),
+            Command::GeoPos(key, members) => self.geopos(&key, &members),
+            Command::GeoDist(key, a, b, unit) => self.geodist(&key, &a, &b, unit),
+            Command::GeoHash(key, members) => self.geohash(&key, &members),
+            Command::GeoSearch(key, search) => self.geosearch(&key, &search),
+            Command::JsonSet(key, path, value, condition) => {
+                self.json_set(key, &path, value, condition)
+            }
+            Command::JsonGet(key, format, paths) => self.json_get(&key, &paths, &format),
+            Command::JsonDel(key, path) => self.json_del(&key, &path),
+            Command::JsonArrAppend(key, path, values) => self.json_arrappend(&key, &path, values),
+            Command::JsonNumIncrBy(key, path, by) => self.json_numincrby(&key, &path, &by),
+        };
+        Some(result.unwrap_or_else(|e| Value::Error(e.to_string())))
+    }
 
     pub fn create(&mut self, key: Key, value: Value) -> Option<Value> {
         println!("create {}->{}", key, &value);
-        self.data
-            .insert(
-                key,
-                Entry {
-                    value,
-                    expiration: None,
-                    subscribers: None,
-                },
-            ).map(|e| e.value)
+        self.data.insert(key, Entry::new(value)).map(|e| e.value)
     }
 
     pub fn read(&self, key: &str) -> Option<&Value> {
@@ -99,25 +384,25 @@ impl Database {
         self.data.get(key).map(|e| &e.value)
     }
 
-    pub fn update(
-        &mut self,
-        key: &str,
-        value: Value,
-    ) -> Result<Option<Value>, Box<std::error::Error>> {
-        if self.data.contains_key(key) {
-            if let Some(exist) = self.data.get_mut(key) {
-                if let Some(ref subscribers) = exist.subscribers {
-                    for sub in subscribers.iter() {
-                        let response = format!("update {}->{}\r\n\r\n", key, value);
-                        sub.send(Vec::from(response.as_bytes()))?;
-                    }
-                }
-                Ok(Some(std::mem::replace(&mut exist.value, value)))
-            } else {
-                Ok(None)
+    /// Replace the value of an existing key, notifying its subscribers.
+    /// Subscribers that have been disconnected are dropped.
+    pub fn update(&mut self, key: &str, value: Value) -> Option<Value> {
+        let old = self
+            .data
+            .get_mut(key)
+            .map(|exist| std::mem::replace(&mut exist.value, value));
+        self.notify(key);
+        old
+    }
+
+    /// Send the current value of `key` to its subscribers, dropping those
+    /// that have been disconnected
+    pub fn notify(&mut self, key: &str) {
+        if let Some(exist) = self.data.get_mut(key) {
+            if let Some(ref mut subscribers) = exist.subscribers {
+                let response = format!("update {}->{}\r\n\r\n", key, exist.value);
+                subscribers.retain(|sub| sub.send(Vec::from(response.as_bytes())).is_ok());
             }
-        } else {
-            Ok(None)
         }
     }
 
@@ -125,7 +410,7 @@ impl Database {
         self.data.remove(key).map(|e| e.value)
     }
 
-    pub fn subscribe(&mut self, key: &str, sender: Sender<Vec<u8>>) -> usize {
+    pub fn subscribe(&mut self, key: &str, sender: Output) -> usize {
         let mut nsub = 0;
         if self.data.contains_key(key) {
             if let Some(exist) = self.data.get_mut(key) {
@@ -143,51 +428,233 @@ impl Database {
     }
 }
 
+/// The halves of a connection owned by the reading and writing threads of
+/// a client, which decrypt and encrypt TLS connections
+type Halves = (Box<dyn Read + Send>, Box<dyn Write + Send>);
+
+fn plaintext(stream: &Socket) -> std::io::Result<Halves> {
+    Ok((Box::new(stream.try_clone()?), Box::new(stream.try_clone()?)))
+}
+
 struct Client {
-    stream: TcpStream,
-    db: Arc<Mutex<Database>>,
+    /// The socket, for its address and to shut it down
+    stream: Socket,
+    reader: Box<dyn Read + Send>,
+    writer: Box<dyn Write + Send>,
+    dbs: Arc<Databases>,
+    acl: Arc<Acl>,
+    clients: Arc<Clients>,
+    config: Arc<Config>,
+    /// User this connection is logged in as, if any
+    user: Option<String>,
+    /// Database selected by this connection
+    index: usize,
+    /// Input that has been read but not yet parsed, as a frame may arrive
+    /// over several reads and a read may hold several frames
+    pending: Vec<u8>,
+    /// How far the frame at the start of `pending` has been lexed
+    frame: Frame,
+    limits: Limits,
+    protocol: Protocol,
 }
 
 impl Client {
-    pub fn spawn(stream: TcpStream, db: Arc<Mutex<Database>>) -> Self {
-        Client { stream, db }
+    pub fn spawn(stream: Socket, (reader, writer): Halves, server: &Server) -> Self {
+        let configured = stream
+            .set_keepalive(server.config.tcp_keepalive)
+            .and_then(|_| stream.set_read_timeout(server.config.timeout));
+        if let Err(e) = configured {
+            println!("Error configuring stream {}: {}", stream.peer_addr(), e);
+        }
+        Client {
+            stream,
+            reader,
+            writer,
+            dbs: server.dbs.clone(),
+            acl: server.acl.clone(),
+            clients: server.clients.clone(),
+            config: server.config.clone(),
+            user: server.acl.initial_user(),
+            index: 0,
+            pending: Vec::new(),
+            frame: Frame::default(),
+            limits: server.config.client_output_buffer_limit,
+            protocol: Protocol::Resp2,
+        }
     }
 
-    pub fn run(mut self) {
-        println!("Client {:?} connected", self.stream.peer_addr());
+    /// Switch protocol version if one is given, replying with a summary of
+    /// the server and connection
+    fn hello(&mut self, version: Option<i64>) -> Value {
+        self.protocol = match version {
+            None => self.protocol,
+            Some(2) => Protocol::Resp2,
+            Some(3) => Protocol::Resp3,
+            Some(_) => return Value::Error("NOPROTO unsupported protocol version".into()),
+        };
+        let mut info = HashMap::new();
+        info.insert("server".into(), Value::Text("kv".into()));
+        info.insert(
+            "version".into(),
+            Value::Text(env!("CARGO_PKG_VERSION").into()),
+        );
+        let proto = match self.protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        info.insert("proto".into(), Value::Integer(proto));
+        Value::Map(info.into())
+    }
 
-        match self.stream.write(b"connect to kv\r\n") {
-            Ok(0) | Err(_) => {
-                println!("Error writing to stream {:?}", self.stream.peer_addr());
-                return;
+    /// Run an ACL subcommand
+    fn acl(&self, op: AclOp) -> Result<Value, Error> {
+        match op {
+            AclOp::SetUser(name, rules) => self.acl.setuser(&name, &rules),
+            AclOp::GetUser(name) => Ok(self.acl.getuser(&name)),
+            AclOp::List => Ok(self.acl.list()),
+            AclOp::WhoAmI => Ok(Value::Text(self.user.clone().unwrap_or_default())),
+        }
+    }
+
+    /// Run a blocking command, parking this client until it is served or
+    /// times out. Returns `None` if the client disconnects or is killed
+    /// meanwhile, by which time it no longer waits on any key.
+    fn block<'a>(
+        &mut self,
+        shared: &'a Databases,
+        mut db: MutexGuard<'a, Database>,
+        keys: Vec<Key>,
+        op: Blocked,
+        timeout: Option<Duration>,
+        connection: &Connection,
+    ) -> (MutexGuard<'a, Database>, Option<Value>) {
+        db.expire_due();
+        let id = match db.block(keys, op, connection.output().clone()) {
+            Ok(Block::Ready(reply)) => return (db, Some(reply)),
+            Ok(Block::Waiting(id)) => id,
+            Err(e) => return (db, Some(Value::Error(e.to_string()))),
+        };
+        let deadline = timeout.map(|t| Instant::now() + t);
+        connection.state().blocked = Some(db.blocked.ready());
+        loop {
+            let (guard, reply) = blocking::wait(db, id, deadline, BLOCKED_POLL);
+            if reply.is_some() {
+                connection.state().blocked = None;
+                return (guard, reply);
+            }
+            drop(guard);
+            let connected = self.connected(connection);
+            if !connected {
+                connection.output().close();
+            }
+            db = shared.lock(self.index);
+            if !connected {
+                db.unblock(id);
+                return (db, None);
             }
-            Ok(_) => (),
         }
+    }
 
-        let (tx, rx) = channel::<Vec<u8>>();
-        let mut stream = self
+    /// Whether a blocked client is still connected and not killed. Anything
+    /// it has sent meanwhile is read into `pending`, to run once it is
+    /// unblocked.
+    fn connected(&mut self, connection: &Connection) -> bool {
+        if connection.output().is_closed() {
+            return false;
+        }
+        let mut buffer = [0u8; 1024];
+        let read = self
+            .stream
+            .set_read_timeout(Some(Duration::from_millis(1)))
+            .and_then(|_| self.reader.read(&mut buffer));
+        let _ = self.stream.set_read_timeout(self.config.timeout);
+        match read {
+            Ok(0) => false,
+            Ok(n) => {
+                STATS.net_input.add(n as u64);
+                self.pending.extend_from_slice(&buffer[..n]);
+                true
+            }
+            Err(ref e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::WouldBlock
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::Interrupted
+            ),
+        }
+    }
+
+    pub fn run(mut self) {
+        let stream = self
             .stream
             .try_clone()
             .expect("Error cloning client stream");
+        let tx = Output::new(self.limits, stream.try_clone().ok());
+        let rx = tx.clone();
+        let registered = self.clients.register(
+            stream.try_clone().expect("Error cloning client stream"),
+            tx.clone(),
+            self.user.clone(),
+        );
+        let connection = match registered {
+            Ok(connection) => connection,
+            Err(e) => {
+                STATS.rejected_connections.add(1);
+                println!("Refused client {}: {}", self.stream.peer_addr(), e);
+                let reply = Value::Error(e.to_string()).encode_as(self.protocol);
+                let _ = self.writer.write_all(&reply);
+                let _ = self.stream.shutdown(Shutdown::Both);
+                return;
+            }
+        };
+        STATS.connections.add(1);
+        println!("Client {} connected", self.stream.peer_addr());
+
+        match self.writer.write(b"connect to kv\r\n") {
+            Ok(0) | Err(_) => {
+                println!("Error writing to stream {}", self.stream.peer_addr());
+                self.clients.remove(connection.id);
+                return;
+            }
+            Ok(_) => (),
+        }
+        let mut writer = std::mem::replace(&mut self.writer, Box::new(std::io::sink()));
 
         // Spawn the writing stream
         thread::spawn(move || {
-            while let Ok(message) = rx.recv() {
-                stream
-                    .write_all(&message[..])
-                    .expect("Error writing to stream");
+            while let Some(message) = rx.recv() {
+                if writer.write_all(&message[..]).is_err() {
+                    println!("Error writing to stream {}", stream.peer_addr());
+                    rx.close();
+                    let _ = stream.shutdown(Shutdown::Both);
+                    break;
+                }
+                STATS.net_output.add(message.len() as u64);
             }
             println!("Closing sender");
         });
 
         // Spawn the reading stream
+        let shared = self.dbs.clone();
         thread::spawn(move || {
             let mut buffer = [0u8; 1024];
             'outer: loop {
-                let read_bytes = match self.stream.read(&mut buffer) {
+                let read_bytes = match self.reader.read(&mut buffer) {
                     Ok(r) => r,
+                    // The idle timeout ran out, which subscribers are spared
+                    Err(ref e)
+                        if e.kind() == std::io::ErrorKind::WouldBlock
+                            || e.kind() == std::io::ErrorKind::TimedOut =>
+                    {
+                        if connection.state().subscriptions > 0 {
+                            continue;
+                        }
+                        println!("Closing idle client {}", self.stream.peer_addr());
+                        let _ = self.stream.shutdown(Shutdown::Both);
+                        break;
+                    }
                     Err(_) => {
-                        println!("Error reading from stream {:?}", self.stream.peer_addr());
+                        println!("Error reading from stream {}", self.stream.peer_addr());
                         break;
                     }
                 };
@@ -196,98 +663,376 @@ impl Client {
                     break 'outer;
                 }
 
-                match Parser::from(&buffer[0..read_bytes]) {
-                    Err(e) => {
-                        println!("Error constructing parser {:?}", e);
-                    }
-                    Ok(mut parser) => match parser.parse() {
+                STATS.net_input.add(read_bytes as u64);
+                self.pending.extend_from_slice(&buffer[0..read_bytes]);
+                loop {
+                    let mut parser = match self.frame.feed(&self.pending) {
+                        Ok(Some((parser, used))) => {
+                            self.pending.drain(..used);
+                            parser
+                        }
+                        Ok(None) => break,
+                        // The rest of the request can't be told apart
+                        // from the commands after it
+                        Err(parser::Error::TooLarge) => {
+                            println!(
+                                "Closing client {} that sent too big a request",
+                                self.stream.peer_addr()
+                            );
+                            break 'outer;
+                        }
+                        Err(e) => {
+                            println!("Error constructing parser {:?}", e);
+                            self.pending.clear();
+                            break;
+                        }
+                    };
+                    match parser.parse() {
                         Ok(commands) => {
-                            let mut db = match self.db.lock() {
-                                Ok(db) => db,
-                                Err(_) => {
-                                    println!(
-                                        "Poisoned lock on thread connected to {:?}",
-                                        self.stream.peer_addr()
-                                    );
-                                    break;
-                                }
-                            };
+                            self.clients.wait_unpaused(&commands);
+                            let mut db = shared.lock(self.index);
                             for cmd in commands {
-                                let mut response: Option<Value> = match cmd {
+                                // Checked before anything touches a database
+                                let allowed = self.acl.check(self.user.as_deref(), &cmd);
+                                STATS.commands.add(1);
+                                if allowed.is_ok() && acl::category(&cmd) == Some(Category::Write) {
+                                    STATS.changes.add(1);
+                                }
+                                let response: Option<Value> = match cmd {
+                                    _ if allowed.is_err() => {
+                                        allowed.err().map(|e| Value::Error(e.to_string()))
+                                    }
                                     Command::Disconnect => {
                                         println!(
                                             "Client {} requesting disconnect",
-                                            self.stream.peer_addr().unwrap()
+                                            self.stream.peer_addr()
                                         );
                                         self.stream.shutdown(Shutdown::Both).unwrap();
                                         break 'outer;
                                     }
-                                    Command::Create(key, val) => db.create(key, val),
-                                    Command::Delete(key) => db.delete(&key),
-                                    Command::Read(key) => db.read(&key).cloned(),
-                                    Command::Update(key, val) => {
-                                        match db.update(&key, val).map(|v| v.clone()) {
-                                            Ok(r) => r,
-                                            _ => {
-                                                println!(
-                                                    "Error writing to stream {:?}",
-                                                    self.stream.peer_addr()
-                                                );
-                                                break;
-                                            }
-                                        }
-                                    }
                                     Command::Subscribe(key) => {
+                                        tx.set_class(Class::Pubsub);
                                         db.subscribe(&key, tx.clone());
+                                        connection.state().subscriptions += 1;
                                         None
                                     }
+                                    Command::BLPop(keys, timeout) => {
+                                        let op = Blocked::Pop(End::Front);
+                                        let (guard, reply) =
+                                            self.block(&shared, db, keys, op, timeout, &connection);
+                                        db = guard;
+                                        match reply {
+                                            Some(reply) => Some(reply),
+                                            None => break 'outer,
+                                        }
+                                    }
+                                    Command::BRPop(keys, timeout) => {
+                                        let op = Blocked::Pop(End::Back);
+                                        let (guard, reply) =
+                                            self.block(&shared, db, keys, op, timeout, &connection);
+                                        db = guard;
+                                        match reply {
+                                            Some(reply) => Some(reply),
+                                            None => break 'outer,
+                                        }
+                                    }
+                                    Command::BLMove(source, dest, from, to, timeout) => {
+                                        let op = Blocked::Move(dest, from, to);
+                                        let (guard, reply) = self.block(
+                                            &shared,
+                                            db,
+                                            vec![source],
+                                            op,
+                                            timeout,
+                                            &connection,
+                                        );
+                                        db = guard;
+                                        match reply {
+                                            Some(reply) => Some(reply),
+                                            None => break 'outer,
+                                        }
+                                    }
+                                    Command::XRead(read) if read.block.is_some() => {
+                                        let keys =
+                                            read.streams.iter().map(|(k, _)| k.clone()).collect();
+                                        let timeout = read.block.flatten();
+                                        let op = Blocked::Read(read);
+                                        let (guard, reply) =
+                                            self.block(&shared, db, keys, op, timeout, &connection);
+                                        db = guard;
+                                        match reply {
+                                            Some(reply) => Some(reply),
+                                            None => break 'outer,
+                                        }
+                                    }
+                                    Command::Hello(version) => Some(self.hello(version)),
+                                    Command::Auth(name, password) => {
+                                        let reply =
+                                            self.acl.authenticate(name.as_deref(), &password);
+                                        Some(match reply {
+                                            Ok(name) => {
+                                                self.user = Some(name);
+                                                Value::Status("OK".into())
+                                            }
+                                            Err(e) => Value::Error(e.to_string()),
+                                        })
+                                    }
+                                    Command::Acl(op) => Some(
+                                        self.acl(op)
+                                            .unwrap_or_else(|e| Value::Error(e.to_string())),
+                                    ),
+                                    Command::Client(op) => Some(
+                                        self.clients
+                                            .execute(&connection, op)
+                                            .unwrap_or_else(|e| Value::Error(e.to_string())),
+                                    ),
+                                    Command::Info(section) => {
+                                        drop(db);
+                                        let reply = stats::info(
+                                            section.as_deref(),
+                                            &self.config,
+                                            &self.clients,
+                                            &shared,
+                                        );
+                                        db = shared.lock(self.index);
+                                        Some(Value::Text(reply))
+                                    }
+                                    Command::Select(index) if index >= shared.len() => {
+                                        Some(Value::Error(Error::DbIndex.to_string()))
+                                    }
+                                    Command::Select(index) => {
+                                        self.index = index;
+                                        drop(db);
+                                        db = shared.lock(index);
+                                        Some(Value::Status("OK".into()))
+                                    }
+                                    // Commands spanning databases take
+                                    // their locks in order themselves
+                                    Command::Move(key, to) => {
+                                        drop(db);
+                                        let reply = shared.move_key(self.index, &key, to);
+                                        db = shared.lock(self.index);
+                                        Some(reply.unwrap_or_else(|e| Value::Error(e.to_string())))
+                                    }
+                                    Command::SwapDb(a, b) => {
+                                        drop(db);
+                                        let reply = shared.swap(a, b);
+                                        db = shared.lock(self.index);
+                                        Some(reply.unwrap_or_else(|e| Value::Error(e.to_string())))
+                                    }
+                                    Command::Copy(source, dest, Some(to), replace)
+                                        if to != self.index =>
+                                    {
+                                        drop(db);
+                                        let reply =
+                                            shared.copy(self.index, &source, to, dest, replace);
+                                        db = shared.lock(self.index);
+                                        Some(reply.unwrap_or_else(|e| Value::Error(e.to_string())))
+                                    }
+                                    Command::FlushAll => {
+                                        drop(db);
+                                        let reply = shared.flush_all();
+                                        db = shared.lock(self.index);
+                                        Some(reply)
+                                    }
+                                    cmd => db.execute(cmd),
                                 };
 
-                                if let Some(mut r) = response {
-                                    if tx.send(Vec::from(r.encode().as_bytes())).is_err() {
+                                if let Some(r) = response {
+                                    if let Err(e) = tx.send(r.encode_as(self.protocol)) {
                                         println!(
-                                            "Error writing to stream {:?}",
-                                            self.stream.peer_addr()
+                                            "Error writing to stream {:?}: {}",
+                                            self.stream.peer_addr(),
+                                            e
                                         );
-                                        break;
+                                        break 'outer;
                                     }
                                 };
                             }
                             drop(db);
+                            let mut state = connection.state();
+                            state.db = self.index;
+                            state.user = self.user.clone();
+                            state.input = self.pending.len();
+                            state.last_command = Instant::now();
                         }
-                        Err(e) => println!("Parser error {:?}", e),
-                    },
-                };
+                        Err(e) => {
+                            println!("Parser error {:?}", e);
+                            let reply = Value::Error(format!("ERR {}", e));
+                            if tx.send(reply.encode_as(self.protocol)).is_err() {
+                                break 'outer;
+                            }
+                        }
+                    }
+                }
             }
-            println!("Dropped connection to {:?}", self.stream.peer_addr());
+            tx.close();
+            self.clients.remove(connection.id);
+            println!("Dropped connection to {}", self.stream.peer_addr());
         });
     }
 }
 
 struct Server {
-    db: Arc<Mutex<Database>>,
-    listener: TcpListener,
+    dbs: Arc<Databases>,
+    acl: Arc<Acl>,
+    clients: Arc<Clients>,
+    config: Arc<Config>,
 }
 
 impl Server {
-    pub fn listen<A: ToSocketAddrs>(addr: A) -> Result<(), std::io::Error> {
-        let server = Server {
-            db: Arc::new(Mutex::new(Database::new())),
-            listener: TcpListener::bind(addr)?,
-        };
-        for stream in server.listener.incoming() {
-            match stream {
-                Ok(stream) => Client::spawn(stream, server.db.clone()).run(),
-                Err(e) => {
-                    println!("Error connecting to stream {:?}", e);
+    /// Accept clients on the TCP port, Unix socket and TLS port
+    /// configured, each from its own thread
+    pub fn listen(config: Config, acl: Acl) -> Result<(), std::io::Error> {
+        let server = Arc::new(Server {
+            dbs: Arc::new(Databases::new(&config)),
+            acl: Arc::new(acl),
+            clients: Arc::new(Clients::new(&config)),
+            config: Arc::new(config),
+        });
+        let mut listeners = Vec::new();
+        if server.config.port != 0 {
+            let listener = TcpListener::bind(server.config.addr())?;
+            println!("kv listening on {}", server.config.addr());
+            let server = server.clone();
+            listeners.push(thread::spawn(move || {
+                for stream in listener.incoming() {
+                    server.accept(stream.map(Socket::Tcp));
                 }
+            }));
+        }
+        if let Some(ref path) = server.config.unixsocket {
+            #[cfg(unix)]
+            {
+                let listener = socket::bind_unix(path, server.config.unixsocketperm)?;
+                println!("kv listening on {}", path);
+                let server = server.clone();
+                listeners.push(thread::spawn(move || {
+                    for stream in listener.incoming() {
+                        server.accept(stream.map(Socket::Unix));
+                    }
+                }));
+            }
+            #[cfg(not(unix))]
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("cannot listen on {}: Unix sockets are unsupported", path),
+            ));
+        }
+        #[cfg(feature = "tls")]
+        {
+            if let Some(port) = server.config.tls_port {
+                let tls = tls::Tls::new(&server.config)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+                listeners.push(Server::listen_tls(server.clone(), tls, port)?);
             }
         }
+        if listeners.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "nothing to listen on, as port is 0 without a unixsocket",
+            ));
+        }
+        for listener in listeners {
+            let _ = listener.join();
+        }
         Ok(())
     }
+
+    fn accept(&self, socket: std::io::Result<Socket>) {
+        match socket.and_then(|socket| Ok((plaintext(&socket)?, socket))) {
+            Ok((halves, socket)) => Client::spawn(socket, halves, self).run(),
+            Err(e) => {
+                println!("Error connecting to stream {:?}", e);
+            }
+        }
+    }
+
+    /// Accept TLS connections on `port`, handshaking with each on its own
+    /// thread so that a slow client can't hold up the others. Handshakes
+    /// count towards the limits on clients, and time out like idle clients.
+    #[cfg(feature = "tls")]
+    fn listen_tls(
+        server: Arc<Server>,
+        tls: tls::Tls,
+        port: u16,
+    ) -> Result<thread::JoinHandle<()>, std::io::Error> {
+        let listener = TcpListener::bind(format!("{}:{}", server.config.bind, port))?;
+        println!("kv listening for TLS on {}:{}", server.config.bind, port);
+        let tls = Arc::new(tls);
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        println!("Error connecting to stream {:?}", e);
+                        continue;
+                    }
+                };
+                let ip = stream.peer_addr().ok().map(|a| a.ip());
+                let handshake = match server.clients.handshake(ip) {
+                    Ok(handshake) => handshake,
+                    Err(e) => {
+                        STATS.rejected_connections.add(1);
+                        println!("Refused client {:?}: {}", stream.peer_addr(), e);
+                        let _ = stream.shutdown(Shutdown::Both);
+                        continue;
+                    }
+                };
+                let timeout = server.config.timeout.unwrap_or(TLS_HANDSHAKE_TIMEOUT);
+                if let Err(e) = stream.set_read_timeout(Some(timeout.min(TLS_HANDSHAKE_TIMEOUT))) {
+                    println!("Error configuring stream {:?}: {}", stream.peer_addr(), e);
+                    continue;
+                }
+                let (tls, server) = (tls.clone(), server.clone());
+                thread::spawn(move || match tls.accept(&stream) {
+                    Ok((reader, writer, name)) => {
+                        // Registering the client takes over its place
+                        drop(handshake);
+                        let user = name.and_then(|name| server.acl.certificate_user(&name));
+                        let halves: Halves = (Box::new(reader), Box::new(writer));
+                        let socket = Socket::Tcp(stream);
+                        let mut client = Client::spawn(socket, halves, &server);
+                        if user.is_some() {
+                            client.user = user;
+                        }
+                        client.run()
+                    }
+                    Err(e) => println!(
+                        "Error in TLS handshake with {:?}: {}",
+                        stream.peer_addr(),
+                        e
+                    ),
+                });
+            }
+        }))
+    }
 }
 
 fn main() {
-    println!("kv listening on 1122");
-    Server::listen("0.0.0.0:1122").unwrap();
+    stats::start();
+    let config = match env::args().nth(1) {
+        Some(path) => match Config::load(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Error reading config {}: {}", path, e);
+                return;
+            }
+        },
+        None => Config::default(),
+    };
+    let acl = match Acl::load(&config) {
+        Ok(acl) => acl,
+        Err(e) => {
+            println!("Error loading ACL file: {}", e);
+            return;
+        }
+    };
+    if cfg!(not(feature = "tls")) && config.tls_port.is_some() {
+        println!("Error: tls-port requires kv to be built with the tls feature");
+        return;
+    }
+    if let Err(e) = Server::listen(config, acl) {
+        println!("Error starting kv: {}", e);
+    }
 }
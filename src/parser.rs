@@ -1,29 +1,108 @@
+use super::geo::{GeoSet, LAT_MAX, LON_MAX};
+use super::hash::Fields;
+use super::hll::HyperLogLog;
+use super::json::{Json, Path};
 use super::lexer;
-use super::lexer::{Lexer, Token, Token::*};
+use super::lexer::{Lexer, Token, Token::*, MAX_BULK_LEN};
+use super::set::Set;
+use super::stream::{Stream, StreamId};
+use super::zset::SortedSet;
 use std::collections::VecDeque;
 use std::fmt;
-use std::str;
+use std::io::Write;
+use std::mem;
+use std::ops::Bound;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
 pub struct Parser {
     tokens: VecDeque<Token>,
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Value {
     Text(String),
+    Bytes(Vec<u8>),
     Integer(i64),
     Array(Vec<Value>),
+    List(VecDeque<Value>),
+    Map(Fields),
+    Set(Set),
+    SortedSet(SortedSet),
+    HyperLogLog(HyperLogLog),
+    Stream(Stream),
+    Geo(GeoSet),
+    Json(Json),
+    Status(String),
+    Error(String),
     Null,
 }
 
+impl Value {
+    /// Name of the type of value stored, as reported by TYPE
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Text(_) | Value::Bytes(_) | Value::Integer(_) | Value::HyperLogLog(_) => {
+                "string"
+            }
+            Value::Array(_) | Value::List(_) => "list",
+            Value::Map(_) => "hash",
+            Value::Set(_) => "set",
+            // Redis stores geospatial indexes as sorted sets
+            Value::SortedSet(_) | Value::Geo(_) => "zset",
+            Value::Stream(_) => "stream",
+            Value::Json(_) => "ReJSON-RL",
+            Value::Status(_) | Value::Error(_) | Value::Null => "none",
+        }
+    }
+
+    /// Name of the internal representation, as reported by OBJECT ENCODING
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::Integer(_) => "int",
+            Value::Text(s) if s.len() <= 44 => "embstr",
+            Value::Bytes(b) if b.len() <= 44 => "embstr",
+            Value::Text(_) | Value::Bytes(_) | Value::HyperLogLog(_) | Value::Json(_) => "raw",
+            Value::Array(_) | Value::List(_) => "quicklist",
+            Value::Map(_) => "hashtable",
+            Value::Set(set) => set.encoding(),
+            Value::SortedSet(_) | Value::Geo(_) => "skiplist",
+            Value::Stream(_) => "stream",
+            Value::Status(_) | Value::Error(_) | Value::Null => "none",
+        }
+    }
+
+    /// Number of allocations making up a stored value, estimating the work
+    /// of freeing it
+    pub fn size(&self) -> usize {
+        match self {
+            Value::Array(a) => a.len(),
+            Value::List(l) => l.len(),
+            Value::Map(m) => m.len(),
+            Value::Set(set) => set.len(),
+            Value::SortedSet(zset) => zset.len(),
+            Value::Stream(stream) => stream.len(),
+            Value::Geo(geo) => geo.len(),
+            Value::Json(json) => json.children(),
+            _ => 1,
+        }
+    }
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.encode())
     }
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Clone)]
+/// Wire protocol version negotiated by a client
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Protocol {
+    Resp2,
+    Resp3,
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum Command {
     Disconnect,
     Create(String, Value),
@@ -31,6 +110,458 @@ pub enum Command {
     Update(String, Value),
     Delete(String),
     Subscribe(String),
+    Incr(String),
+    Decr(String),
+    IncrBy(String, i64),
+    DecrBy(String, i64),
+    IncrByFloat(String, f64),
+    Append(String, Vec<u8>),
+    GetRange(String, i64, i64),
+    SetRange(String, usize, Vec<u8>),
+    StrLen(String),
+    GetSet(String, Value),
+    GetDel(String),
+    GetEx(String, Option<Expire>),
+    SetBit(String, u64, bool),
+    GetBit(String, u64),
+    BitCount(String, Option<BitRange>),
+    BitPos(String, bool, Option<BitRange>),
+    BitOp(BitOp, String, Vec<String>),
+    BitField(String, Vec<BitFieldOp>),
+    PfAdd(String, Vec<Vec<u8>>),
+    PfCount(Vec<String>),
+    PfMerge(String, Vec<String>),
+    LPush(String, Vec<Value>),
+    RPush(String, Vec<Value>),
+    LPop(String, Option<usize>),
+    RPop(String, Option<usize>),
+    LRange(String, i64, i64),
+    LLen(String),
+    LIndex(String, i64),
+    LSet(String, i64, Value),
+    LTrim(String, i64, i64),
+    LRem(String, i64, Value),
+    LInsert(String, Position, Value, Value),
+    LMove(String, String, End, End),
+    BLPop(Vec<String>, Option<Duration>),
+    BRPop(Vec<String>, Option<Duration>),
+    BLMove(String, String, End, End, Option<Duration>),
+    Hello(Option<i64>),
+    HSet(String, Vec<(String, Value)>),
+    HGet(String, String),
+    HMGet(String, Vec<String>),
+    HDel(String, Vec<String>),
+    HGetAll(String),
+    HKeys(String),
+    HVals(String),
+    HLen(String),
+    HExists(String, String),
+    HIncrBy(String, String, i64),
+    HScan(String, Scan),
+    SScan(String, Scan),
+    ZScan(String, Scan),
+    Scan(Scan),
+    Keys(String),
+    DbSize,
+    RandomKey,
+    /// Keys between two bounds, lowest first, with a limit and whether to
+    /// reply in reverse order
+    Range(Bound<String>, Bound<String>, Option<usize>, bool),
+    Prefix(String),
+    Select(usize),
+    Move(String, usize),
+    SwapDb(usize, usize),
+    FlushDb,
+    FlushAll,
+    Exists(Vec<String>),
+    Type(String),
+    Rename(String, String),
+    RenameNx(String, String),
+    /// Source, destination, destination database and whether to replace
+    Copy(String, String, Option<usize>, bool),
+    Touch(Vec<String>),
+    Unlink(Vec<String>),
+    Object(ObjectInfo, String),
+    Set(String, Value, SetOptions),
+    MGet(Vec<String>),
+    /// Optional username and password
+    Auth(Option<String>, String),
+    Acl(AclOp),
+    Client(ClientOp),
+    /// INFO, of one section or all of them
+    Info(Option<String>),
+    MSet(Vec<(String, Value)>),
+    MSetNx(Vec<(String, Value)>),
+    SAdd(String, Vec<String>),
+    SRem(String, Vec<String>),
+    SIsMember(String, String),
+    SMembers(String),
+    SCard(String),
+    SPop(String, Option<usize>),
+    SRandMember(String, Option<i64>),
+    SUnion(Vec<String>),
+    SInter(Vec<String>),
+    SDiff(Vec<String>),
+    SUnionStore(String, Vec<String>),
+    SInterStore(String, Vec<String>),
+    SDiffStore(String, Vec<String>),
+    ZAdd(String, ZAddFlags, Vec<(f64, String)>),
+    ZScore(String, String),
+    ZRank(String, String),
+    ZCard(String),
+    ZRange(String, ZRange),
+    ZRem(String, Vec<String>),
+    ZRemRangeByScore(String, Bound<f64>, Bound<f64>),
+    ZPopMin(String, Option<usize>),
+    ZPopMax(String, Option<usize>),
+    ZUnionStore(String, Vec<String>, Vec<f64>, Aggregate),
+    ZInterStore(String, Vec<String>, Vec<f64>, Aggregate),
+    XAdd(String, XAdd),
+    XLen(String),
+    XRange(String, Bound<StreamId>, Bound<StreamId>, Option<usize>),
+    XRevRange(String, Bound<StreamId>, Bound<StreamId>, Option<usize>),
+    XRead(XRead),
+    XGroup(XGroup),
+    XAck(String, String, Vec<StreamId>),
+    XPending(String, String, Option<XPendingRange>),
+    XClaim(XClaim),
+    XAutoClaim(XAutoClaim),
+    GeoAdd(String, ZAddFlags, Vec<GeoMember>),
+    GeoPos(String, Vec<String>),
+    GeoDist(String, String, String, f64),
+    GeoHash(String, Vec<String>),
+    GeoSearch(String, GeoSearch),
+    JsonSet(String, Path, Json, Option<Condition>),
+    JsonGet(String, JsonFormat, Vec<Path>),
+    JsonDel(String, Path),
+    JsonArrAppend(String, Path, Vec<Json>),
+    JsonNumIncrBy(String, Path, Json),
+}
+
+/// Arguments shared by the SCAN family of commands
+#[derive(Debug, PartialEq, Clone)]
+pub struct Scan {
+    pub cursor: u64,
+    pub pattern: Option<String>,
+    pub count: usize,
+    /// Type of value SCAN is limited to
+    pub kind: Option<String>,
+}
+
+/// When a key should expire
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Expire {
+    In(Duration),
+    At(SystemTime),
+    Persist,
+}
+
+/// Whether BITCOUNT and BITPOS offsets count bytes or bits
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BitUnit {
+    Byte,
+    Bit,
+}
+
+/// Range of a string searched by BITCOUNT and BITPOS
+#[derive(Debug, PartialEq, Clone)]
+pub struct BitRange {
+    pub start: i64,
+    pub end: Option<i64>,
+    pub unit: BitUnit,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+/// Integer field type of BITFIELD, such as `i5` or `u16`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct BitType {
+    pub signed: bool,
+    pub bits: u32,
+}
+
+/// How BITFIELD writes treat values that don't fit their field
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum BitFieldOp {
+    Get(BitType, u64),
+    Set(BitType, u64, i64),
+    IncrBy(BitType, u64, i64),
+    Overflow(Overflow),
+}
+
+/// Flags modifying how ZADD treats existing and missing members
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct ZAddFlags {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+    pub ch: bool,
+    pub incr: bool,
+}
+
+/// How ZRANGE selects members
+#[derive(Debug, PartialEq, Clone)]
+pub enum RangeBy {
+    Index(i64, i64),
+    Score(Bound<f64>, Bound<f64>),
+    Lex(Bound<String>, Bound<String>),
+}
+
+/// Arguments of ZRANGE. Bounds are always stored lowest first, even when
+/// `rev` is set.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ZRange {
+    pub by: RangeBy,
+    pub rev: bool,
+    pub limit: Option<(i64, i64)>,
+    pub with_scores: bool,
+}
+
+/// How ZUNIONSTORE and ZINTERSTORE combine the scores of a member
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+/// ID requested for a new stream entry
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum XAddId {
+    /// `*`, generated from the current time
+    Auto,
+    /// `ms-*`, with the sequence number generated
+    Partial(u64),
+    Explicit(StreamId),
+}
+
+/// Arguments of XADD
+#[derive(Debug, PartialEq, Clone)]
+pub struct XAdd {
+    pub id: XAddId,
+    pub maxlen: Option<usize>,
+    pub nomkstream: bool,
+    pub fields: Vec<(String, Value)>,
+}
+
+/// Where XREAD and XREADGROUP start reading a stream
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReadFrom {
+    After(StreamId),
+    /// `$`, only entries added after the read began
+    Last,
+    /// `>`, entries never delivered to the group
+    Undelivered,
+}
+
+/// Arguments of XREAD, or of XREADGROUP when `group` holds the group and
+/// consumer names
+#[derive(Debug, PartialEq, Clone)]
+pub struct XRead {
+    pub group: Option<(String, String)>,
+    pub count: Option<usize>,
+    /// Set by BLOCK, holding the timeout, where `None` waits forever
+    pub block: Option<Option<Duration>>,
+    pub noack: bool,
+    pub streams: Vec<(String, ReadFrom)>,
+}
+
+/// Subcommands of XGROUP. Start IDs of `None` stand for `$`, the last
+/// entry of the stream.
+#[derive(Debug, PartialEq, Clone)]
+pub enum XGroup {
+    Create(String, String, Option<StreamId>, bool),
+    SetId(String, String, Option<StreamId>),
+    Destroy(String, String),
+    CreateConsumer(String, String, String),
+    DelConsumer(String, String, String),
+}
+
+impl XGroup {
+    /// The stream the subcommand acts on
+    pub fn key(&self) -> &str {
+        match self {
+            XGroup::Create(key, ..)
+            | XGroup::SetId(key, ..)
+            | XGroup::Destroy(key, _)
+            | XGroup::CreateConsumer(key, ..)
+            | XGroup::DelConsumer(key, ..) => key,
+        }
+    }
+}
+
+/// The extended form of XPENDING, listing pending entries in a range
+#[derive(Debug, PartialEq, Clone)]
+pub struct XPendingRange {
+    pub idle: Option<u64>,
+    pub start: Bound<StreamId>,
+    pub end: Bound<StreamId>,
+    pub count: usize,
+    pub consumer: Option<String>,
+}
+
+/// Arguments of XCLAIM, with times in milliseconds
+#[derive(Debug, PartialEq, Clone)]
+pub struct XClaim {
+    pub key: String,
+    pub group: String,
+    pub consumer: String,
+    pub min_idle: u64,
+    pub ids: Vec<StreamId>,
+    pub idle: Option<u64>,
+    pub time: Option<u64>,
+    pub retry_count: Option<u64>,
+    pub force: bool,
+    pub justid: bool,
+}
+
+/// Arguments of XAUTOCLAIM, with times in milliseconds
+#[derive(Debug, PartialEq, Clone)]
+pub struct XAutoClaim {
+    pub key: String,
+    pub group: String,
+    pub consumer: String,
+    pub min_idle: u64,
+    pub start: StreamId,
+    pub count: usize,
+    pub justid: bool,
+}
+
+/// Longitude, latitude and name of a member given to GEOADD
+pub type GeoMember = (f64, f64, String);
+
+/// Centre of a GEOSEARCH
+#[derive(Debug, PartialEq, Clone)]
+pub enum GeoFrom {
+    Member(String),
+    LonLat(f64, f64),
+}
+
+/// Area of a GEOSEARCH, in metres: a radius, or a box's width and height
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum GeoShape {
+    Radius(f64),
+    Box(f64, f64),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Sort {
+    Asc,
+    Desc,
+}
+
+/// Arguments of GEOSEARCH. Distances are replied in `unit` metres, and
+/// `count` holds whether ANY was given.
+#[derive(Debug, PartialEq, Clone)]
+pub struct GeoSearch {
+    pub from: GeoFrom,
+    pub shape: GeoShape,
+    pub unit: f64,
+    pub sort: Option<Sort>,
+    pub count: Option<(usize, bool)>,
+    pub with_coord: bool,
+    pub with_dist: bool,
+    pub with_hash: bool,
+}
+
+/// What OBJECT reports about a key
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ObjectInfo {
+    Encoding,
+    IdleTime,
+    Freq,
+}
+
+/// Whether a write requires its target to be missing (NX) or to exist (XX)
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Condition {
+    Nx,
+    Xx,
+}
+
+/// Subcommands of ACL
+#[derive(Debug, PartialEq, Clone)]
+pub enum AclOp {
+    /// A user and the rules to apply to it
+    SetUser(String, Vec<String>),
+    GetUser(String),
+    List,
+    WhoAmI,
+}
+
+/// Subcommands of CLIENT
+#[derive(Debug, PartialEq, Clone)]
+pub enum ClientOp {
+    Id,
+    Info,
+    List,
+    SetName(String),
+    GetName,
+    Kill(ClientKill),
+    /// How long to pause for, and whether to hold up every command rather
+    /// than only writes
+    Pause(Duration, bool),
+    Unpause,
+}
+
+/// Filters of CLIENT KILL, all of which a client must match
+#[derive(Debug, PartialEq, Clone)]
+pub struct ClientKill {
+    pub id: Option<u64>,
+    pub addr: Option<String>,
+    pub user: Option<String>,
+    /// Whether to spare the client running the command
+    pub skipme: bool,
+    /// Given as `CLIENT KILL addr`, which replies OK rather than a count
+    pub legacy: bool,
+}
+
+/// Options of SET
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SetOptions {
+    pub condition: Option<Condition>,
+    /// Reply with the old value rather than whether it was set
+    pub get: bool,
+    pub expire: Option<Expire>,
+    /// Keep the expiration time of the key being overwritten, rather than
+    /// clearing it
+    pub keep_ttl: bool,
+}
+
+/// Layout of JSON.GET replies: the string indenting each nesting level,
+/// the one ending each line, and the one following each object key
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct JsonFormat {
+    pub indent: String,
+    pub newline: String,
+    pub space: String,
+}
+
+/// Which end of a list to push to or pop from
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum End {
+    Front,
+    Back,
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum Position {
+    Before,
+    After,
 }
 
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
@@ -39,14 +570,97 @@ pub enum Error {
     Terminated,
     Syntax(lexer::Error),
     InvalidUTF8,
+    Invalid(String),
+    /// A command still incomplete past `MAX_REQUEST_LEN`
+    TooLarge,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Expected(s, t) => write!(f, "expected {}, found {:?}", s, t),
+            Error::Terminated => write!(f, "wrong number of arguments"),
+            Error::Syntax(e) => write!(f, "protocol error {:?}", e),
+            Error::InvalidUTF8 => write!(f, "invalid UTF-8"),
+            Error::Invalid(s) => write!(f, "{}", s),
+            Error::TooLarge => write!(f, "too big request"),
+        }
+    }
+}
+
+/// Largest command accepted, matching Redis' default
+/// client-query-buffer-limit
+pub const MAX_REQUEST_LEN: usize = 1024 * 1024 * 1024;
+
+/// Progress lexing a command that arrives over several reads. Lexing
+/// resumes after the last complete element of the command array, so that
+/// however the input is split up, a command takes time linear in its size.
+#[derive(Debug, Default)]
+pub struct Frame {
+    /// Elements of the command array, once its header is lexed
+    len: Option<usize>,
+    tokens: Vec<Token>,
+    /// Bytes taken up by the header and `tokens`
+    pos: usize,
+}
+
+impl Frame {
+    /// Continue lexing `input`, which holds the input fed before followed
+    /// by what has arrived since. Returns the command once complete, along
+    /// with the number of bytes it took up, and then starts over.
+    pub fn feed(&mut self, input: &[u8]) -> Result<Option<(Parser, usize)>, Error> {
+        let frame = self.resume(input);
+        match frame {
+            Ok(None) if input.len() > MAX_REQUEST_LEN => {
+                *self = Frame::default();
+                Err(Error::TooLarge)
+            }
+            Ok(None) => Ok(None),
+            _ => {
+                *self = Frame::default();
+                frame
+            }
+        }
+    }
+
+    fn resume(&mut self, input: &[u8]) -> Result<Option<(Parser, usize)>, Error> {
+        let len = match self.len {
+            Some(len) => len,
+            // Anything else is an error, reported as before
+            None if input.first() != Some(&b'*') => return Parser::frame(input),
+            None => {
+                let mut lexer = Lexer::from(input);
+                match lexer.array_header() {
+                    Ok(len) => {
+                        self.pos = lexer.pos();
+                        *self.len.insert(len)
+                    }
+                    Err(lexer::Error::UnexpectedEOF) => return Ok(None),
+                    Err(e) => return Err(Error::Syntax(e)),
+                }
+            }
+        };
+        while self.tokens.len() < len {
+            let mut lexer = Lexer::from(&input[self.pos..]);
+            match lexer.lex() {
+                Ok(token) => {
+                    self.tokens.push(token);
+                    self.pos += lexer.pos();
+                }
+                Err(lexer::Error::UnexpectedEOF) => return Ok(None),
+                Err(e) => return Err(Error::Syntax(e)),
+            }
+        }
+        let parser = Parser {
+            tokens: VecDeque::from(mem::take(&mut self.tokens)),
+        };
+        Ok(Some((parser, self.pos)))
+    }
 }
 
 impl Parser {
     pub fn from(s: &[u8]) -> Result<Parser, Error> {
-        match Lexer::from(str::from_utf8(s).map_err(|_| Error::InvalidUTF8)?)
-            .lex()
-            .map_err(Error::Syntax)?
-        {
+        match Lexer::from(s).lex().map_err(Error::Syntax)? {
             Token::Array(array) => Ok(Parser {
                 tokens: VecDeque::from(array),
             }),
@@ -54,9 +668,27 @@ impl Parser {
         }
     }
 
+    /// Lex the first complete frame in `s`, returning it along with the
+    /// number of bytes it took up, or `None` if more input is needed
+    pub fn frame(s: &[u8]) -> Result<Option<(Parser, usize)>, Error> {
+        let mut lexer = Lexer::from(s);
+        match lexer.lex() {
+            Ok(Token::Array(array)) => Ok(Some((
+                Parser {
+                    tokens: VecDeque::from(array),
+                },
+                lexer.pos(),
+            ))),
+            Ok(t) => Err(Error::Expected("token array".into(), t)),
+            Err(lexer::Error::UnexpectedEOF) => Ok(None),
+            Err(e) => Err(Error::Syntax(e)),
+        }
+    }
+
     fn token_to_value(&self, token: Token) -> Value {
         match token {
             Token::Identifier(s) => Value::Text(s),
+            Token::Bytes(b) => Value::Bytes(b),
             Token::Integer(i) => Value::Integer(i),
             Token::Array(array) => Value::Array(
                 array
@@ -64,95 +696,2112 @@ impl Parser {
                     .map(|token| self.token_to_value(token))
                     .collect(),
             ),
-            _ => Value::Null,
+            _ => match token.keyword() {
+                Some(s) => Value::Text(s.into()),
+                None => Value::Null,
+            },
         }
     }
 
     fn expect_identifier(&mut self) -> Result<String, Error> {
         match self.tokens.pop_front() {
             Some(Token::Identifier(s)) => Ok(s),
-            Some(t) => Err(Error::Expected("identifier".into(), t)),
+            Some(Token::Bytes(_)) => Err(Error::InvalidUTF8),
+            Some(t) => match t.keyword() {
+                Some(s) => Ok(s.into()),
+                None => Err(Error::Expected("identifier".into(), t)),
+            },
             None => Err(Error::Terminated),
         }
     }
 
-    fn pop_front(&mut self) -> Result<Value, Error> {
+    /// Consume a string, taking integers in their decimal form
+    fn expect_string(&mut self) -> Result<String, Error> {
         match self.tokens.pop_front() {
-            Some(token) => Ok(self.token_to_value(token)),
+            Some(Token::Integer(i)) => Ok(i.to_string()),
+            Some(t) => {
+                self.tokens.push_front(t);
+                self.expect_identifier()
+            }
             None => Err(Error::Terminated),
         }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<Command>, Error> {
-        let mut cmd = Vec::new();
-        while let Some(token) = self.tokens.pop_front() {
-            match token {
-                Disconnect => cmd.push(Command::Disconnect),
-                Create => cmd.push(Command::Create(
-                    self.expect_identifier()?,
-                    self.pop_front()?,
-                )),
-                Read => cmd.push(Command::Read(self.expect_identifier()?)),
-                Update => cmd.push(Command::Update(
-                    self.expect_identifier()?,
-                    self.pop_front()?,
-                )),
-                Delete => cmd.push(Command::Delete(self.expect_identifier()?)),
-                Subscribe => cmd.push(Command::Subscribe(self.expect_identifier()?)),
-                Token::Array(array) => {
-                    self.tokens.extend(array);
+    /// Consume every remaining string in the current command array,
+    /// requiring at least one
+    fn strings(&mut self) -> Result<Vec<String>, Error> {
+        let mut strings = vec![self.expect_string()?];
+        while !self.tokens.is_empty() {
+            strings.push(self.expect_string()?);
+        }
+        Ok(strings)
+    }
+
+    fn expect_integer(&mut self) -> Result<i64, Error> {
+        match self.tokens.pop_front() {
+            Some(Token::Integer(i)) => Ok(i),
+            Some(Token::Identifier(s)) => s
+                .parse()
+                .map_err(|_| Error::Expected("integer".into(), Token::Identifier(s))),
+            Some(t) => Err(Error::Expected("integer".into(), t)),
+            None => Err(Error::Terminated),
+        }
+    }
+
+    /// Consume an identifier matching one of `options`, ignoring case
+    fn expect_option(&mut self, options: &[&'static str]) -> Result<&'static str, Error> {
+        let s = self.expect_identifier()?;
+        options
+            .iter()
+            .find(|o| o.eq_ignore_ascii_case(&s))
+            .cloned()
+            .ok_or_else(|| Error::Expected(options.join(" or "), Token::Identifier(s)))
+    }
+
+    /// The next token as a word, whether it was lexed as an identifier or
+    /// as a command name, without consuming it
+    fn peek_word(&self) -> Option<&str> {
+        match self.tokens.front()? {
+            Token::Identifier(s) => Some(s),
+            t => t.keyword(),
+        }
+    }
+
+    /// Consume an optional trailing integer
+    fn optional_integer(&mut self) -> Result<Option<i64>, Error> {
+        match self.tokens.front() {
+            Some(Token::Integer(_)) => (),
+            Some(Token::Identifier(s)) if s.parse::<i64>().is_ok() => (),
+            _ => return Ok(None),
+        }
+        self.expect_integer().map(Some)
+    }
+
+    /// Consume an optional trailing count, which must be positive
+    fn optional_count(&mut self) -> Result<Option<usize>, Error> {
+        match self.optional_integer()? {
+            Some(n) if n > 0 => Ok(Some(n as usize)),
+            Some(n) => Err(Error::Expected("positive count".into(), Token::Integer(n))),
+            None => Ok(None),
+        }
+    }
+
+    fn expect_end(&mut self) -> Result<End, Error> {
+        match self.expect_option(&["LEFT", "RIGHT"])? {
+            "LEFT" => Ok(End::Front),
+            _ => Ok(End::Back),
+        }
+    }
+
+    /// Consume a timeout in (possibly fractional) seconds, where zero means
+    /// waiting forever
+    fn expect_timeout(&mut self) -> Result<Option<Duration>, Error> {
+        let token = self.tokens.pop_front();
+        self.timeout(token)
+    }
+
+    fn timeout(&self, token: Option<Token>) -> Result<Option<Duration>, Error> {
+        let secs = match token {
+            Some(Token::Integer(i)) => i as f64,
+            Some(Token::Identifier(s)) => s
+                .parse::<f64>()
+                .map_err(|_| Error::Expected("timeout".into(), Token::Identifier(s)))?,
+            Some(t) => return Err(Error::Expected("timeout".into(), t)),
+            None => return Err(Error::Terminated),
+        };
+        if !secs.is_finite() || secs < 0.0 {
+            return Err(Error::Expected(
+                "non-negative timeout".into(),
+                Token::Identifier(secs.to_string()),
+            ));
+        }
+        Ok(if secs == 0.0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64(secs))
+        })
+    }
+
+    /// Consume every remaining identifier in the current command array,
+    /// requiring at least one
+    fn identifiers(&mut self) -> Result<Vec<String>, Error> {
+        let mut ids = vec![self.expect_identifier()?];
+        while !self.tokens.is_empty() {
+            ids.push(self.expect_identifier()?);
+        }
+        Ok(ids)
+    }
+
+    /// Consume every remaining identifier in the current command array
+    /// except for a trailing timeout, requiring at least one
+    fn identifiers_then_timeout(&mut self) -> Result<(Vec<String>, Option<Duration>), Error> {
+        let token = self.tokens.pop_back();
+        let timeout = self.timeout(token)?;
+        Ok((self.identifiers()?, timeout))
+    }
+
+    /// Consume the remaining `field value` pairs in the current command
+    /// array, requiring at least one
+    fn field_value_pairs(&mut self) -> Result<Vec<(String, Value)>, Error> {
+        let mut pairs = vec![(self.expect_identifier()?, self.pop_front()?)];
+        while !self.tokens.is_empty() {
+            pairs.push((self.expect_identifier()?, self.pop_front()?));
+        }
+        Ok(pairs)
+    }
+
+    /// Consume a cursor followed by optional `MATCH pattern` and
+    /// `COUNT count` clauses, and for SCAN over the keyspace, `TYPE type`
+    fn expect_scan(&mut self, keyspace: bool) -> Result<Scan, Error> {
+        // Cursors span the whole unsigned range
+        let cursor = match self.tokens.pop_front() {
+            Some(Token::Integer(c)) if c >= 0 => c as u64,
+            Some(Token::Identifier(s)) => match s.parse() {
+                Ok(c) => c,
+                Err(_) => return Err(Error::Expected("cursor".into(), Token::Identifier(s))),
+            },
+            Some(t) => return Err(Error::Expected("cursor".into(), t)),
+            None => return Err(Error::Terminated),
+        };
+        let mut scan = Scan {
+            cursor,
+            pattern: None,
+            count: 10,
+            kind: None,
+        };
+        let options: &[&str] = match keyspace {
+            true => &["MATCH", "COUNT", "TYPE"],
+            false => &["MATCH", "COUNT"],
+        };
+        while !self.tokens.is_empty() {
+            match self.expect_option(options)? {
+                "MATCH" => scan.pattern = Some(self.expect_identifier()?),
+                "TYPE" => scan.kind = Some(self.expect_identifier()?),
+                _ => {
+                    scan.count = match self.expect_integer()? {
+                        n if n > 0 => n as usize,
+                        n => {
+                            return Err(Error::Expected("positive count".into(), Token::Integer(n)))
+                        }
+                    }
                 }
-                t => return Err(Error::Expected("command or array".into(), t)),
             }
         }
-        Ok(cmd)
+        Ok(scan)
     }
-}
 
-impl Value {
-    pub fn encode(&self) -> String {
-        match self {
-            Value::Null => "*0\r\n".to_string(),
-            Value::Text(ref s) => format!("${}\r\n{}\r\n", s.len(), s),
-            Value::Integer(i) => format!(":{}\r\n", i),
-            Value::Array(ref a) => a.iter().fold(format!("*{}\r\n", a.len()), |mut acc, val| {
-                acc.push_str(&val.encode());
-                acc
-            }),
+    /// Consume a binary safe string, taking integers in their decimal form
+    fn expect_bytes(&mut self) -> Result<Vec<u8>, Error> {
+        match self.tokens.pop_front() {
+            Some(Token::Bytes(b)) => Ok(b),
+            Some(t) => {
+                self.tokens.push_front(t);
+                self.expect_string().map(String::into_bytes)
+            }
+            None => Err(Error::Terminated),
         }
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    /// Consume every remaining binary safe string in the current command
+    /// array, possibly none
+    fn byte_strings(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        let mut strings = Vec::new();
+        while !self.tokens.is_empty() {
+            strings.push(self.expect_bytes()?);
+        }
+        Ok(strings)
+    }
 
-    #[test]
-    fn enocde_array() {
-        let answer = "*2\r\n$4\r\nval1\r\n$4\r\nval2\r\n";
-        assert_eq!(
-            answer,
-            encode(&Value::Array(vec![
-                Value::Text(String::from("val1")),
-                Value::Text(String::from("val2"))
-            ]))
-        );
+    /// Consume an optional `EX seconds`, `PX milliseconds`, `EXAT timestamp`,
+    /// `PXAT timestamp` or, if `persist` is allowed, `PERSIST` clause
+    fn optional_expire(&mut self, command: &str, persist: bool) -> Result<Option<Expire>, Error> {
+        let options: &[&'static str] = if persist {
+            &["EX", "PX", "EXAT", "PXAT", "PERSIST"]
+        } else {
+            &["EX", "PX", "EXAT", "PXAT"]
+        };
+        if self.tokens.is_empty() {
+            return Ok(None);
+        }
+        let option = self.expect_option(options)?;
+        if option == "PERSIST" {
+            return Ok(Some(Expire::Persist));
+        }
+        let invalid = || Error::Invalid(format!("invalid expire time in '{}' command", command));
+        let millis = match (option, self.expect_integer()?) {
+            (_, n) if n <= 0 => return Err(invalid()),
+            ("EX", n) | ("EXAT", n) => n.checked_mul(1000).ok_or_else(invalid)? as u64,
+            (_, n) => n as u64,
+        };
+        let duration = Duration::from_millis(millis);
+        Ok(Some(match option {
+            "EX" | "PX" => Expire::In(duration),
+            _ => Expire::At(UNIX_EPOCH + duration),
+        }))
     }
 
-    #[test]
-    fn parse_array() {
-        let mut parser =
-            Parser::from(b"*3\r\n$6\r\nCREATE\r\n$3\r\nkey\r\n*2\r\n$4\r\nval1\r\n$4\r\nval2\r\n")
-                .unwrap();
-        assert_eq!(
-            parser.parse(),
-            Ok(vec![Command::Create(
-                String::from("key"),
-                Value::Array(vec![
-                    Value::Text(String::from("val1")),
-                    Value::Text(String::from("val2"))
-                ])
-            ),])
+    /// Consume a bit offset, which must fall within the largest string
+    fn expect_bit_offset(&mut self) -> Result<u64, Error> {
+        match self.expect_integer() {
+            Ok(n) if n >= 0 && (n as u64) < MAX_BULK_LEN as u64 * 8 => Ok(n as u64),
+            _ => Err(Error::Invalid(
+                "bit offset is not an integer or out of range".into(),
+            )),
+        }
+    }
+
+    fn expect_bit(&mut self) -> Result<bool, Error> {
+        match self.expect_integer() {
+            Ok(0) => Ok(false),
+            Ok(1) => Ok(true),
+            _ => Err(Error::Invalid(
+                "bit is not an integer or out of range".into(),
+            )),
+        }
+    }
+
+    /// Consume the optional `start [end [BYTE|BIT]]` of BITCOUNT or BITPOS
+    fn optional_bit_range(&mut self, end_required: bool) -> Result<Option<BitRange>, Error> {
+        if self.tokens.is_empty() {
+            return Ok(None);
+        }
+        let start = self.expect_integer()?;
+        let end = if end_required || !self.tokens.is_empty() {
+            Some(self.expect_integer()?)
+        } else {
+            None
+        };
+        let unit = if self.tokens.is_empty() {
+            BitUnit::Byte
+        } else {
+            match self.expect_option(&["BYTE", "BIT"])? {
+                "BYTE" => BitUnit::Byte,
+                _ => BitUnit::Bit,
+            }
+        };
+        Ok(Some(BitRange { start, end, unit }))
+    }
+
+    /// Consume a BITFIELD type and offset, where an offset prefixed with
+    /// `#` counts in multiples of the type's width
+    fn expect_bit_field(&mut self) -> Result<(BitType, u64), Error> {
+        let ty = self.expect_identifier()?;
+        let signed = ty.starts_with(['i', 'I']);
+        let bits = match ty.get(1..).map(str::parse::<u32>) {
+            Some(Ok(bits)) if ty.starts_with(['u', 'U']) && (1..64).contains(&bits) => bits,
+            Some(Ok(bits)) if signed && (1..=64).contains(&bits) => bits,
+            _ => {
+                return Err(Error::Invalid(
+                    "Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.".into(),
+                ))
+            }
+        };
+        let offset = self.expect_string()?;
+        let offset = match offset.strip_prefix('#') {
+            Some(n) => n
+                .parse::<u64>()
+                .ok()
+                .and_then(|n| n.checked_mul(bits as u64)),
+            None => offset.parse::<u64>().ok(),
+        };
+        let end = offset.and_then(|n| n.checked_add(bits as u64));
+        match (offset, end) {
+            (Some(n), Some(end)) if end <= MAX_BULK_LEN as u64 * 8 => {
+                Ok((BitType { signed, bits }, n))
+            }
+            _ => Err(Error::Invalid(
+                "bit offset is not an integer or out of range".into(),
+            )),
+        }
+    }
+
+    /// Consume the operations of BITFIELD
+    fn bit_field_ops(&mut self) -> Result<Vec<BitFieldOp>, Error> {
+        let mut ops = Vec::new();
+        while !self.tokens.is_empty() {
+            ops.push(
+                match self.expect_option(&["GET", "SET", "INCRBY", "OVERFLOW"])? {
+                    "GET" => {
+                        let (ty, offset) = self.expect_bit_field()?;
+                        BitFieldOp::Get(ty, offset)
+                    }
+                    "SET" => {
+                        let (ty, offset) = self.expect_bit_field()?;
+                        BitFieldOp::Set(ty, offset, self.expect_integer()?)
+                    }
+                    "INCRBY" => {
+                        let (ty, offset) = self.expect_bit_field()?;
+                        BitFieldOp::IncrBy(ty, offset, self.expect_integer()?)
+                    }
+                    _ => BitFieldOp::Overflow(
+                        match self.expect_option(&["WRAP", "SAT", "FAIL"])? {
+                            "WRAP" => Overflow::Wrap,
+                            "SAT" => Overflow::Sat,
+                            _ => Overflow::Fail,
+                        },
+                    ),
+                },
+            );
+        }
+        Ok(ops)
+    }
+
+    /// Consume a float, accepting `inf` and `-inf` but not NaN
+    fn expect_float(&mut self) -> Result<f64, Error> {
+        let s = self.expect_string()?;
+        float(&s)
+    }
+
+    /// Consume a score range bound, exclusive when prefixed with `(`
+    fn expect_score_bound(&mut self) -> Result<Bound<f64>, Error> {
+        let s = self.expect_string()?;
+        score_bound(&s)
+    }
+
+    /// Consume the `score member` pairs of ZADD, after any leading flags
+    fn zadd(&mut self) -> Result<(ZAddFlags, Vec<(f64, String)>), Error> {
+        let mut flags = ZAddFlags::default();
+        while let Some(s) = self.peek_word() {
+            match s.to_ascii_uppercase().as_str() {
+                "NX" => flags.nx = true,
+                "XX" => flags.xx = true,
+                "GT" => flags.gt = true,
+                "LT" => flags.lt = true,
+                "CH" => flags.ch = true,
+                "INCR" => flags.incr = true,
+                _ => break,
+            }
+            self.tokens.pop_front();
+        }
+        if flags.nx && flags.xx {
+            return Err(Error::Invalid(
+                "XX and NX options at the same time are not compatible".into(),
+            ));
+        }
+        if (flags.gt && flags.lt) || (flags.nx && (flags.gt || flags.lt)) {
+            return Err(Error::Invalid(
+                "GT, LT, and/or NX options at the same time are not compatible".into(),
+            ));
+        }
+        let mut pairs = vec![(self.expect_float()?, self.expect_string()?)];
+        while !self.tokens.is_empty() {
+            pairs.push((self.expect_float()?, self.expect_string()?));
+        }
+        if flags.incr && pairs.len() > 1 {
+            return Err(Error::Invalid(
+                "INCR option supports a single increment-element pair".into(),
+            ));
+        }
+        Ok((flags, pairs))
+    }
+
+    /// Consume `start stop` followed by the options of ZRANGE
+    fn zrange(&mut self) -> Result<ZRange, Error> {
+        let (start, stop) = (self.expect_string()?, self.expect_string()?);
+        let (mut by, mut rev, mut limit, mut with_scores) = ("", false, None, false);
+        while !self.tokens.is_empty() {
+            match self.expect_option(&["BYSCORE", "BYLEX", "REV", "LIMIT", "WITHSCORES"])? {
+                "REV" => rev = true,
+                "LIMIT" => limit = Some((self.expect_integer()?, self.expect_integer()?)),
+                "WITHSCORES" => with_scores = true,
+                option => by = option,
+            }
+        }
+        // Reversed score and lex ranges are given highest bound first
+        let (min, max) = if rev && !by.is_empty() {
+            (stop, start)
+        } else {
+            (start, stop)
+        };
+        let by = match by {
+            "BYSCORE" => RangeBy::Score(score_bound(&min)?, score_bound(&max)?),
+            "BYLEX" => RangeBy::Lex(lex_bound(min)?, lex_bound(max)?),
+            _ if limit.is_some() => return Err(Error::Invalid(
+                "syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+                    .into(),
+            )),
+            _ => RangeBy::Index(integer(min)?, integer(max)?),
+        };
+        Ok(ZRange {
+            by,
+            rev,
+            limit,
+            with_scores,
+        })
+    }
+
+    /// Consume `numkeys key [key ...]` followed by optional `WEIGHTS` and
+    /// `AGGREGATE` clauses
+    fn zstore(&mut self) -> Result<(Vec<String>, Vec<f64>, Aggregate), Error> {
+        let numkeys = match self.expect_integer()? {
+            n if n > 0 => n as usize,
+            n => {
+                return Err(Error::Expected(
+                    "positive numkeys".into(),
+                    Token::Integer(n),
+                ))
+            }
+        };
+        let mut keys = Vec::with_capacity(numkeys);
+        for _ in 0..numkeys {
+            keys.push(self.expect_identifier()?);
+        }
+        let (mut weights, mut aggregate) = (Vec::new(), Aggregate::Sum);
+        while !self.tokens.is_empty() {
+            match self.expect_option(&["WEIGHTS", "AGGREGATE"])? {
+                "WEIGHTS" => {
+                    weights = (0..numkeys)
+                        .map(|_| self.expect_float())
+                        .collect::<Result<_, _>>()?
+                }
+                _ => {
+                    aggregate = match self.expect_option(&["SUM", "MIN", "MAX"])? {
+                        "SUM" => Aggregate::Sum,
+                        "MIN" => Aggregate::Min,
+                        _ => Aggregate::Max,
+                    }
+                }
+            }
+        }
+        Ok((keys, weights, aggregate))
+    }
+
+    /// Consume a count, which may be zero
+    fn expect_count(&mut self) -> Result<usize, Error> {
+        match self.expect_integer()? {
+            n if n >= 0 => Ok(n as usize),
+            n => Err(Error::Expected(
+                "non-negative integer".into(),
+                Token::Integer(n),
+            )),
+        }
+    }
+
+    /// Consume an optional trailing `COUNT count` clause
+    fn optional_count_clause(&mut self) -> Result<Option<usize>, Error> {
+        if self.tokens.is_empty() {
+            return Ok(None);
+        }
+        self.expect_option(&["COUNT"])?;
+        self.expect_count().map(Some)
+    }
+
+    /// Consume a stream ID, where a bare `ms` takes the sequence number `seq`
+    fn expect_stream_id(&mut self, seq: u64) -> Result<StreamId, Error> {
+        let s = self.expect_string()?;
+        stream_id(&s, seq)
+    }
+
+    /// Consume a stream range bound, where a bare `ms` takes the sequence
+    /// number `seq`
+    fn expect_stream_bound(&mut self, seq: u64) -> Result<Bound<StreamId>, Error> {
+        let s = self.expect_string()?;
+        stream_bound(&s, seq)
+    }
+
+    /// Consume the options, ID and `field value` pairs of XADD
+    fn xadd(&mut self) -> Result<XAdd, Error> {
+        let (mut nomkstream, mut maxlen) = (false, None);
+        loop {
+            let s = self.expect_string()?;
+            match s.to_ascii_uppercase().as_str() {
+                "NOMKSTREAM" => nomkstream = true,
+                "MAXLEN" => {
+                    // Trimming is always exact, which `~` allows for
+                    if let Some(Token::Identifier(s)) = self.tokens.front() {
+                        if s == "=" || s == "~" {
+                            self.tokens.pop_front();
+                        }
+                    }
+                    maxlen = Some(self.expect_count()?);
+                }
+                _ => {
+                    let id = match s.strip_suffix("-*") {
+                        _ if s == "*" => XAddId::Auto,
+                        Some(ms) => XAddId::Partial(stream_id(ms, 0)?.ms),
+                        None => XAddId::Explicit(stream_id(&s, 0)?),
+                    };
+                    return Ok(XAdd {
+                        id,
+                        maxlen,
+                        nomkstream,
+                        fields: self.field_value_pairs()?,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Consume the options and `STREAMS key [key ...] id [id ...]` of XREAD,
+    /// or of XREADGROUP reading for `group`
+    fn xread(&mut self, group: Option<(String, String)>) -> Result<XRead, Error> {
+        let options: &[&'static str] = match group {
+            Some(_) => &["COUNT", "BLOCK", "NOACK", "STREAMS"],
+            None => &["COUNT", "BLOCK", "STREAMS"],
+        };
+        let (mut count, mut block, mut noack) = (None, None, false);
+        loop {
+            match self.expect_option(options)? {
+                "COUNT" => count = Some(self.expect_count()?),
+                "BLOCK" => {
+                    block = Some(match self.expect_integer()? {
+                        0 => None,
+                        n if n > 0 => Some(Duration::from_millis(n as u64)),
+                        _ => return Err(Error::Invalid("timeout is negative".into())),
+                    })
+                }
+                "NOACK" => noack = true,
+                _ => break,
+            }
+        }
+        let mut keys = self.strings()?;
+        if keys.len() % 2 != 0 {
+            return Err(Error::Invalid(
+                "Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified."
+                    .into(),
+            ));
+        }
+        let ids = keys.split_off(keys.len() / 2);
+        let mut streams = Vec::with_capacity(keys.len());
+        for (key, id) in keys.into_iter().zip(ids) {
+            let from = match (id.as_str(), &group) {
+                ("$", None) => ReadFrom::Last,
+                (">", Some(_)) => ReadFrom::Undelivered,
+                ("$", Some(_)) => {
+                    return Err(Error::Invalid(
+                        "The $ ID is meaningless in the context of XREADGROUP".into(),
+                    ))
+                }
+                (">", None) => return Err(Error::Invalid(
+                    "The > ID can be specified only when calling XREADGROUP using the GROUP <group> <consumer> option."
+                        .into(),
+                )),
+                (id, _) => ReadFrom::After(stream_id(id, 0)?),
+            };
+            streams.push((key, from));
+        }
+        Ok(XRead {
+            group,
+            count,
+            block,
+            noack,
+            streams,
+        })
+    }
+
+    /// Consume the start ID of a consumer group, where `$` is `None`
+    fn group_start(&mut self) -> Result<Option<StreamId>, Error> {
+        match self.expect_string()?.as_str() {
+            "$" => Ok(None),
+            s => stream_id(s, 0).map(Some),
+        }
+    }
+
+    /// Consume an XGROUP subcommand and its arguments
+    fn xgroup(&mut self) -> Result<XGroup, Error> {
+        let subcommand = self.expect_option(&[
+            "CREATE",
+            "SETID",
+            "DESTROY",
+            "CREATECONSUMER",
+            "DELCONSUMER",
+        ])?;
+        let (key, group) = (self.expect_identifier()?, self.expect_identifier()?);
+        Ok(match subcommand {
+            "CREATE" => {
+                let start = self.group_start()?;
+                let mkstream = !self.tokens.is_empty();
+                if mkstream {
+                    self.expect_option(&["MKSTREAM"])?;
+                }
+                XGroup::Create(key, group, start, mkstream)
+            }
+            "SETID" => XGroup::SetId(key, group, self.group_start()?),
+            "DESTROY" => XGroup::Destroy(key, group),
+            "CREATECONSUMER" => XGroup::CreateConsumer(key, group, self.expect_identifier()?),
+            _ => XGroup::DelConsumer(key, group, self.expect_identifier()?),
+        })
+    }
+
+    /// Consume the optional `[IDLE min-idle] start end count [consumer]` of
+    /// XPENDING
+    fn xpending_range(&mut self) -> Result<Option<XPendingRange>, Error> {
+        if self.tokens.is_empty() {
+            return Ok(None);
+        }
+        let mut start = self.expect_string()?;
+        let mut idle = None;
+        if start.eq_ignore_ascii_case("IDLE") {
+            idle = Some(self.expect_count()? as u64);
+            start = self.expect_string()?;
+        }
+        let start = stream_bound(&start, 0)?;
+        let end = self.expect_stream_bound(u64::MAX)?;
+        let count = self.expect_count()?;
+        let consumer = if self.tokens.is_empty() {
+            None
+        } else {
+            Some(self.expect_identifier()?)
+        };
+        Ok(Some(XPendingRange {
+            idle,
+            start,
+            end,
+            count,
+            consumer,
+        }))
+    }
+
+    /// Consume the arguments of XCLAIM: IDs followed by options
+    fn xclaim(&mut self) -> Result<XClaim, Error> {
+        let mut claim = XClaim {
+            key: self.expect_identifier()?,
+            group: self.expect_identifier()?,
+            consumer: self.expect_identifier()?,
+            min_idle: self.expect_count()? as u64,
+            ids: vec![self.expect_stream_id(0)?],
+            idle: None,
+            time: None,
+            retry_count: None,
+            force: false,
+            justid: false,
+        };
+        let mut options = false;
+        while !self.tokens.is_empty() {
+            let s = self.expect_string()?;
+            match s.to_ascii_uppercase().as_str() {
+                "IDLE" => claim.idle = Some(self.expect_count()? as u64),
+                "TIME" => claim.time = Some(self.expect_count()? as u64),
+                "RETRYCOUNT" => claim.retry_count = Some(self.expect_count()? as u64),
+                "FORCE" => claim.force = true,
+                "JUSTID" => claim.justid = true,
+                _ if !options => {
+                    claim.ids.push(stream_id(&s, 0)?);
+                    continue;
+                }
+                _ => {
+                    return Err(Error::Invalid(format!(
+                        "Unrecognized XCLAIM option '{}'",
+                        s
+                    )))
+                }
+            }
+            options = true;
+        }
+        Ok(claim)
+    }
+
+    /// Consume the arguments of XAUTOCLAIM
+    fn xautoclaim(&mut self) -> Result<XAutoClaim, Error> {
+        let mut claim = XAutoClaim {
+            key: self.expect_identifier()?,
+            group: self.expect_identifier()?,
+            consumer: self.expect_identifier()?,
+            min_idle: self.expect_count()? as u64,
+            start: self.expect_stream_id(0)?,
+            count: 100,
+            justid: false,
+        };
+        while !self.tokens.is_empty() {
+            match self.expect_option(&["COUNT", "JUSTID"])? {
+                "COUNT" => {
+                    claim.count = match self.expect_count()? {
+                        0 => return Err(Error::Invalid("COUNT must be > 0".into())),
+                        n => n,
+                    }
+                }
+                _ => claim.justid = true,
+            }
+        }
+        Ok(claim)
+    }
+
+    /// Consume a longitude and latitude, which must be within the area
+    /// geohashes can map
+    fn expect_lon_lat(&mut self) -> Result<(f64, f64), Error> {
+        let (lon, lat) = (self.expect_float()?, self.expect_float()?);
+        if lon.abs() > LON_MAX || lat.abs() > LAT_MAX {
+            return Err(Error::Invalid(format!(
+                "invalid longitude,latitude pair {:.6},{:.6}",
+                lon, lat
+            )));
+        }
+        Ok((lon, lat))
+    }
+
+    /// Consume a distance unit, returning its length in metres
+    fn expect_unit(&mut self) -> Result<f64, Error> {
+        Ok(match self.expect_option(&["M", "KM", "FT", "MI"])? {
+            "M" => 1.0,
+            "KM" => 1000.0,
+            "FT" => 0.3048,
+            _ => 1609.34,
+        })
+    }
+
+    /// Consume a CLIENT subcommand and its arguments
+    fn client(&mut self) -> Result<ClientOp, Error> {
+        let subcommand = self.expect_option(&[
+            "ID", "INFO", "LIST", "SETNAME", "GETNAME", "KILL", "PAUSE", "UNPAUSE",
+        ])?;
+        Ok(match subcommand {
+            "ID" => ClientOp::Id,
+            "INFO" => ClientOp::Info,
+            "LIST" => ClientOp::List,
+            "SETNAME" => ClientOp::SetName(self.expect_string()?),
+            "GETNAME" => ClientOp::GetName,
+            "KILL" => ClientOp::Kill(self.client_kill()?),
+            "PAUSE" => {
+                let timeout = match self.expect_integer()? {
+                    ms if ms >= 0 => Duration::from_millis(ms as u64),
+                    _ => return Err(Error::Invalid("timeout is negative".into())),
+                };
+                let all = self.tokens.is_empty() || self.expect_option(&["WRITE", "ALL"])? == "ALL";
+                ClientOp::Pause(timeout, all)
+            }
+            _ => ClientOp::Unpause,
+        })
+    }
+
+    /// Consume the address of `CLIENT KILL addr`, or the filters of the
+    /// newer `CLIENT KILL filter value...` form
+    fn client_kill(&mut self) -> Result<ClientKill, Error> {
+        let mut kill = ClientKill {
+            id: None,
+            addr: None,
+            user: None,
+            skipme: true,
+            legacy: false,
+        };
+        if self.tokens.len() == 1 {
+            kill.addr = Some(self.expect_string()?);
+            kill.skipme = false;
+            kill.legacy = true;
+            return Ok(kill);
+        }
+        while !self.tokens.is_empty() {
+            match self.expect_option(&["ID", "ADDR", "USER", "SKIPME"])? {
+                "ID" => kill.id = Some(self.expect_count()? as u64),
+                "ADDR" => kill.addr = Some(self.expect_string()?),
+                "USER" => kill.user = Some(self.expect_string()?),
+                _ => kill.skipme = self.expect_option(&["YES", "NO"])? == "YES",
+            }
+        }
+        Ok(kill)
+    }
+
+    /// Consume the options of SET, which may come in any order
+    fn set_options(&mut self) -> Result<SetOptions, Error> {
+        let mut options = SetOptions::default();
+        let syntax = || Error::Invalid("syntax error".into());
+        while let Some(Token::Identifier(s)) = self.tokens.front() {
+            match s.to_ascii_uppercase().as_str() {
+                "NX" | "XX" if options.condition.is_some() => return Err(syntax()),
+                "NX" => options.condition = Some(Condition::Nx),
+                "XX" => options.condition = Some(Condition::Xx),
+                "GET" => options.get = true,
+                _ if options.keep_ttl || options.expire.is_some() => return Err(syntax()),
+                "KEEPTTL" => options.keep_ttl = true,
+                _ => {
+                    options.expire = self.optional_expire("set", false)?;
+                    continue;
+                }
+            }
+            self.tokens.pop_front();
+        }
+        match self.tokens.front() {
+            Some(_) => Err(syntax()),
+            None => Ok(options),
+        }
+    }
+
+    /// Consume a database index
+    fn expect_db(&mut self) -> Result<usize, Error> {
+        match self.expect_integer() {
+            Ok(i) if i >= 0 => Ok(i as usize),
+            Ok(_) | Err(Error::Expected(..)) => {
+                Err(Error::Invalid("DB index is out of range".into()))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Consume the ASYNC or SYNC flag of FLUSHDB and FLUSHALL. Flushing
+    /// always happens synchronously.
+    fn optional_flush_mode(&mut self) -> Result<(), Error> {
+        if !self.tokens.is_empty() {
+            self.expect_option(&["ASYNC", "SYNC"])?;
+        }
+        Ok(())
+    }
+
+    fn expect_json(&mut self) -> Result<Json, Error> {
+        let s = self.expect_string()?;
+        Json::parse(&s).map_err(|e| Error::Invalid(format!("invalid JSON: {}", e)))
+    }
+
+    fn expect_json_number(&mut self) -> Result<Json, Error> {
+        match self.expect_json()? {
+            json if json.is_number() => Ok(json),
+            _ => Err(Error::Invalid("expected a number".into())),
+        }
+    }
+
+    fn expect_path(&mut self) -> Result<Path, Error> {
+        Path::parse(&self.expect_string()?).map_err(Error::Invalid)
+    }
+
+    /// Consume an optional path, which defaults to the root
+    fn optional_path(&mut self) -> Result<Path, Error> {
+        match self.tokens.is_empty() {
+            true => Ok(Path::root()),
+            false => self.expect_path(),
+        }
+    }
+
+    /// Consume the formatting options and paths of JSON.GET
+    fn json_get(&mut self) -> Result<(JsonFormat, Vec<Path>), Error> {
+        let mut format = JsonFormat::default();
+        while let Some(Token::Identifier(s)) = self.tokens.front() {
+            let option = match s.to_ascii_uppercase().as_str() {
+                "INDENT" => &mut format.indent,
+                "NEWLINE" => &mut format.newline,
+                "SPACE" => &mut format.space,
+                _ => break,
+            };
+            self.tokens.pop_front();
+            *option = self.expect_string()?;
+        }
+        let mut paths = Vec::new();
+        while !self.tokens.is_empty() {
+            paths.push(self.expect_path()?);
+        }
+        Ok((format, paths))
+    }
+
+    /// Consume the flags and `longitude latitude member` triples of GEOADD
+    fn geoadd(&mut self) -> Result<(ZAddFlags, Vec<GeoMember>), Error> {
+        let mut flags = ZAddFlags::default();
+        while let Some(s) = self.peek_word() {
+            match s.to_ascii_uppercase().as_str() {
+                "NX" => flags.nx = true,
+                "XX" => flags.xx = true,
+                "CH" => flags.ch = true,
+                _ => break,
+            }
+            self.tokens.pop_front();
+        }
+        if flags.nx && flags.xx {
+            return Err(Error::Invalid(
+                "XX and NX options at the same time are not compatible".into(),
+            ));
+        }
+        let mut members = Vec::new();
+        loop {
+            let (lon, lat) = self.expect_lon_lat()?;
+            members.push((lon, lat, self.expect_string()?));
+            if self.tokens.is_empty() {
+                return Ok((flags, members));
+            }
+        }
+    }
+
+    /// Consume the centre, shape and options of GEOSEARCH
+    fn geosearch(&mut self) -> Result<GeoSearch, Error> {
+        let (mut from, mut shape, mut unit) = (None, None, 1.0);
+        let mut search = GeoSearch {
+            from: GeoFrom::LonLat(0.0, 0.0),
+            shape: GeoShape::Radius(0.0),
+            unit,
+            sort: None,
+            count: None,
+            with_coord: false,
+            with_dist: false,
+            with_hash: false,
+        };
+        while !self.tokens.is_empty() {
+            let options = [
+                "FROMMEMBER",
+                "FROMLONLAT",
+                "BYRADIUS",
+                "BYBOX",
+                "ASC",
+                "DESC",
+                "COUNT",
+                "WITHCOORD",
+                "WITHDIST",
+                "WITHHASH",
+            ];
+            match self.expect_option(&options)? {
+                "FROMMEMBER" => from = Some(GeoFrom::Member(self.expect_string()?)),
+                "FROMLONLAT" => {
+                    let (lon, lat) = self.expect_lon_lat()?;
+                    from = Some(GeoFrom::LonLat(lon, lat))
+                }
+                "BYRADIUS" => {
+                    let radius = self.expect_float()?;
+                    unit = self.expect_unit()?;
+                    shape = Some(GeoShape::Radius(radius * unit))
+                }
+                "BYBOX" => {
+                    let (width, height) = (self.expect_float()?, self.expect_float()?);
+                    unit = self.expect_unit()?;
+                    shape = Some(GeoShape::Box(width * unit, height * unit))
+                }
+                "ASC" => search.sort = Some(Sort::Asc),
+                "DESC" => search.sort = Some(Sort::Desc),
+                "COUNT" => {
+                    let count = match self.expect_integer()? {
+                        n if n > 0 => n as usize,
+                        _ => return Err(Error::Invalid("COUNT must be > 0".into())),
+                    };
+                    let any = match self.tokens.front() {
+                        Some(Token::Identifier(s)) => s.eq_ignore_ascii_case("ANY"),
+                        _ => false,
+                    };
+                    if any {
+                        self.tokens.pop_front();
+                    }
+                    search.count = Some((count, any))
+                }
+                "WITHCOORD" => search.with_coord = true,
+                "WITHDIST" => search.with_dist = true,
+                _ => search.with_hash = true,
+            }
+        }
+        match (from, shape) {
+            (Some(from), Some(shape)) => {
+                search.from = from;
+                search.shape = shape;
+                search.unit = unit;
+                Ok(search)
+            }
+            (None, _) => Err(Error::Invalid(
+                "exactly one of FROMMEMBER or FROMLONLAT can be specified for geosearch".into(),
+            )),
+            (_, None) => Err(Error::Invalid(
+                "exactly one of BYRADIUS and BYBOX can be specified for geosearch".into(),
+            )),
+        }
+    }
+
+    fn pop_front(&mut self) -> Result<Value, Error> {
+        match self.tokens.pop_front() {
+            Some(token) => Ok(self.token_to_value(token)),
+            None => Err(Error::Terminated),
+        }
+    }
+
+    /// Consume every remaining value in the current command array, requiring
+    /// at least one
+    fn rest(&mut self) -> Result<Vec<Value>, Error> {
+        let mut values = vec![self.pop_front()?];
+        while let Some(token) = self.tokens.pop_front() {
+            values.push(self.token_to_value(token));
+        }
+        Ok(values)
+    }
+
+    pub fn parse(&mut self) -> Result<Vec<Command>, Error> {
+        let mut cmd = Vec::new();
+        while let Some(token) = self.tokens.pop_front() {
+            match token {
+                Disconnect => cmd.push(Command::Disconnect),
+                Create => cmd.push(Command::Create(
+                    self.expect_identifier()?,
+                    self.pop_front()?,
+                )),
+                Read => cmd.push(Command::Read(self.expect_identifier()?)),
+                Update => cmd.push(Command::Update(
+                    self.expect_identifier()?,
+                    self.pop_front()?,
+                )),
+                Delete => cmd.push(Command::Delete(self.expect_identifier()?)),
+                Subscribe => cmd.push(Command::Subscribe(self.expect_identifier()?)),
+                Incr => cmd.push(Command::Incr(self.expect_identifier()?)),
+                Decr => cmd.push(Command::Decr(self.expect_identifier()?)),
+                IncrBy => cmd.push(Command::IncrBy(
+                    self.expect_identifier()?,
+                    self.expect_integer()?,
+                )),
+                DecrBy => cmd.push(Command::DecrBy(
+                    self.expect_identifier()?,
+                    self.expect_integer()?,
+                )),
+                IncrByFloat => cmd.push(Command::IncrByFloat(
+                    self.expect_identifier()?,
+                    self.expect_float()?,
+                )),
+                Append => cmd.push(Command::Append(
+                    self.expect_identifier()?,
+                    self.expect_bytes()?,
+                )),
+                GetRange => cmd.push(Command::GetRange(
+                    self.expect_identifier()?,
+                    self.expect_integer()?,
+                    self.expect_integer()?,
+                )),
+                SetRange => {
+                    let key = self.expect_identifier()?;
+                    let offset = match self.expect_integer()? {
+                        n if n >= 0 => n as usize,
+                        _ => return Err(Error::Invalid("offset is out of range".into())),
+                    };
+                    cmd.push(Command::SetRange(key, offset, self.expect_bytes()?))
+                }
+                StrLen => cmd.push(Command::StrLen(self.expect_identifier()?)),
+                GetSet => cmd.push(Command::GetSet(
+                    self.expect_identifier()?,
+                    self.pop_front()?,
+                )),
+                GetDel => cmd.push(Command::GetDel(self.expect_identifier()?)),
+                GetEx => {
+                    let key = self.expect_identifier()?;
+                    let expire = self.optional_expire("getex", true)?;
+                    cmd.push(Command::GetEx(key, expire))
+                }
+                SetBit => cmd.push(Command::SetBit(
+                    self.expect_identifier()?,
+                    self.expect_bit_offset()?,
+                    self.expect_bit()?,
+                )),
+                GetBit => cmd.push(Command::GetBit(
+                    self.expect_identifier()?,
+                    self.expect_bit_offset()?,
+                )),
+                BitCount => cmd.push(Command::BitCount(
+                    self.expect_identifier()?,
+                    self.optional_bit_range(true)?,
+                )),
+                BitPos => {
+                    let key = self.expect_identifier()?;
+                    let bit = match self.expect_integer()? {
+                        0 => false,
+                        1 => true,
+                        _ => return Err(Error::Invalid("The bit argument must be 1 or 0.".into())),
+                    };
+                    cmd.push(Command::BitPos(key, bit, self.optional_bit_range(false)?))
+                }
+                BitOp => {
+                    let op = match self.expect_option(&["AND", "OR", "XOR", "NOT"])? {
+                        "AND" => BitOp::And,
+                        "OR" => BitOp::Or,
+                        "XOR" => BitOp::Xor,
+                        _ => BitOp::Not,
+                    };
+                    let dest = self.expect_identifier()?;
+                    let keys = self.identifiers()?;
+                    if op == BitOp::Not && keys.len() != 1 {
+                        return Err(Error::Invalid(
+                            "BITOP NOT must be called with a single source key.".into(),
+                        ));
+                    }
+                    cmd.push(Command::BitOp(op, dest, keys))
+                }
+                BitField => cmd.push(Command::BitField(
+                    self.expect_identifier()?,
+                    self.bit_field_ops()?,
+                )),
+                PfAdd => cmd.push(Command::PfAdd(
+                    self.expect_identifier()?,
+                    self.byte_strings()?,
+                )),
+                PfCount => cmd.push(Command::PfCount(self.identifiers()?)),
+                PfMerge => {
+                    let dest = self.expect_identifier()?;
+                    let keys = if self.tokens.is_empty() {
+                        Vec::new()
+                    } else {
+                        self.identifiers()?
+                    };
+                    cmd.push(Command::PfMerge(dest, keys))
+                }
+                LPush => cmd.push(Command::LPush(self.expect_identifier()?, self.rest()?)),
+                RPush => cmd.push(Command::RPush(self.expect_identifier()?, self.rest()?)),
+                LPop => cmd.push(Command::LPop(
+                    self.expect_identifier()?,
+                    self.optional_count()?,
+                )),
+                RPop => cmd.push(Command::RPop(
+                    self.expect_identifier()?,
+                    self.optional_count()?,
+                )),
+                LRange => cmd.push(Command::LRange(
+                    self.expect_identifier()?,
+                    self.expect_integer()?,
+                    self.expect_integer()?,
+                )),
+                LLen => cmd.push(Command::LLen(self.expect_identifier()?)),
+                LIndex => cmd.push(Command::LIndex(
+                    self.expect_identifier()?,
+                    self.expect_integer()?,
+                )),
+                LSet => cmd.push(Command::LSet(
+                    self.expect_identifier()?,
+                    self.expect_integer()?,
+                    self.pop_front()?,
+                )),
+                LTrim => cmd.push(Command::LTrim(
+                    self.expect_identifier()?,
+                    self.expect_integer()?,
+                    self.expect_integer()?,
+                )),
+                LRem => cmd.push(Command::LRem(
+                    self.expect_identifier()?,
+                    self.expect_integer()?,
+                    self.pop_front()?,
+                )),
+                LInsert => {
+                    let key = self.expect_identifier()?;
+                    let position = match self.expect_option(&["BEFORE", "AFTER"])? {
+                        "BEFORE" => Position::Before,
+                        _ => Position::After,
+                    };
+                    cmd.push(Command::LInsert(
+                        key,
+                        position,
+                        self.pop_front()?,
+                        self.pop_front()?,
+                    ))
+                }
+                LMove => cmd.push(Command::LMove(
+                    self.expect_identifier()?,
+                    self.expect_identifier()?,
+                    self.expect_end()?,
+                    self.expect_end()?,
+                )),
+                BLPop => {
+                    let (keys, timeout) = self.identifiers_then_timeout()?;
+                    cmd.push(Command::BLPop(keys, timeout))
+                }
+                BRPop => {
+                    let (keys, timeout) = self.identifiers_then_timeout()?;
+                    cmd.push(Command::BRPop(keys, timeout))
+                }
+                BLMove => cmd.push(Command::BLMove(
+                    self.expect_identifier()?,
+                    self.expect_identifier()?,
+                    self.expect_end()?,
+                    self.expect_end()?,
+                    self.expect_timeout()?,
+                )),
+                Hello => cmd.push(Command::Hello(if self.tokens.is_empty() {
+                    None
+                } else {
+                    Some(self.expect_integer()?)
+                })),
+                HSet => cmd.push(Command::HSet(
+                    self.expect_identifier()?,
+                    self.field_value_pairs()?,
+                )),
+                HGet => cmd.push(Command::HGet(
+                    self.expect_identifier()?,
+                    self.expect_identifier()?,
+                )),
+                HMGet => cmd.push(Command::HMGet(
+                    self.expect_identifier()?,
+                    self.identifiers()?,
+                )),
+                HDel => cmd.push(Command::HDel(
+                    self.expect_identifier()?,
+                    self.identifiers()?,
+                )),
+                HGetAll => cmd.push(Command::HGetAll(self.expect_identifier()?)),
+                HKeys => cmd.push(Command::HKeys(self.expect_identifier()?)),
+                HVals => cmd.push(Command::HVals(self.expect_identifier()?)),
+                HLen => cmd.push(Command::HLen(self.expect_identifier()?)),
+                HExists => cmd.push(Command::HExists(
+                    self.expect_identifier()?,
+                    self.expect_identifier()?,
+                )),
+                HIncrBy => cmd.push(Command::HIncrBy(
+                    self.expect_identifier()?,
+                    self.expect_identifier()?,
+                    self.expect_integer()?,
+                )),
+                HScan => cmd.push(Command::HScan(
+                    self.expect_identifier()?,
+                    self.expect_scan(false)?,
+                )),
+                SScan => cmd.push(Command::SScan(
+                    self.expect_identifier()?,
+                    self.expect_scan(false)?,
+                )),
+                ZScan => cmd.push(Command::ZScan(
+                    self.expect_identifier()?,
+                    self.expect_scan(false)?,
+                )),
+                Scan => cmd.push(Command::Scan(self.expect_scan(true)?)),
+                Keys => cmd.push(Command::Keys(self.expect_identifier()?)),
+                DbSize => cmd.push(Command::DbSize),
+                RandomKey => cmd.push(Command::RandomKey),
+                Range => {
+                    let start = key_bound(self.expect_string()?)?;
+                    let end = key_bound(self.expect_string()?)?;
+                    let (mut limit, mut rev) = (None, false);
+                    while !self.tokens.is_empty() {
+                        match self.expect_option(&["LIMIT", "REV"])? {
+                            "LIMIT" => limit = Some(self.expect_count()?),
+                            _ => rev = true,
+                        }
+                    }
+                    cmd.push(Command::Range(start, end, limit, rev))
+                }
+                Prefix => cmd.push(Command::Prefix(self.expect_string()?)),
+                Select => cmd.push(Command::Select(self.expect_db()?)),
+                Move => cmd.push(Command::Move(self.expect_identifier()?, self.expect_db()?)),
+                SwapDb => cmd.push(Command::SwapDb(self.expect_db()?, self.expect_db()?)),
+                FlushDb => {
+                    self.optional_flush_mode()?;
+                    cmd.push(Command::FlushDb)
+                }
+                FlushAll => {
+                    self.optional_flush_mode()?;
+                    cmd.push(Command::FlushAll)
+                }
+                Exists => cmd.push(Command::Exists(self.strings()?)),
+                Type => cmd.push(Command::Type(self.expect_identifier()?)),
+                Rename => cmd.push(Command::Rename(
+                    self.expect_identifier()?,
+                    self.expect_identifier()?,
+                )),
+                RenameNx => cmd.push(Command::RenameNx(
+                    self.expect_identifier()?,
+                    self.expect_identifier()?,
+                )),
+                Copy => {
+                    let (source, dest) = (self.expect_identifier()?, self.expect_identifier()?);
+                    let (mut db, mut replace) = (None, false);
+                    while !self.tokens.is_empty() {
+                        match self.expect_option(&["DB", "REPLACE"])? {
+                            "DB" => db = Some(self.expect_db()?),
+                            _ => replace = true,
+                        }
+                    }
+                    cmd.push(Command::Copy(source, dest, db, replace))
+                }
+                Touch => cmd.push(Command::Touch(self.strings()?)),
+                Token::Set => {
+                    let (key, value) = (self.expect_identifier()?, self.pop_front()?);
+                    cmd.push(Command::Set(key, value, self.set_options()?))
+                }
+                MGet => cmd.push(Command::MGet(self.strings()?)),
+                MSet => cmd.push(Command::MSet(self.field_value_pairs()?)),
+                MSetNx => cmd.push(Command::MSetNx(self.field_value_pairs()?)),
+                Auth => {
+                    let first = self.expect_string()?;
+                    cmd.push(match self.tokens.is_empty() {
+                        true => Command::Auth(None, first),
+                        false => Command::Auth(Some(first), self.expect_string()?),
+                    })
+                }
+                Acl => {
+                    let op = match self.expect_option(&["SETUSER", "GETUSER", "LIST", "WHOAMI"])? {
+                        "SETUSER" => {
+                            let name = self.expect_identifier()?;
+                            let mut rules = Vec::new();
+                            while !self.tokens.is_empty() {
+                                rules.push(self.expect_string()?);
+                            }
+                            AclOp::SetUser(name, rules)
+                        }
+                        "GETUSER" => AclOp::GetUser(self.expect_identifier()?),
+                        "LIST" => AclOp::List,
+                        _ => AclOp::WhoAmI,
+                    };
+                    cmd.push(Command::Acl(op))
+                }
+                Client => cmd.push(Command::Client(self.client()?)),
+                Info => {
+                    let section = match self.tokens.is_empty() {
+                        true => None,
+                        false => Some(self.expect_string()?),
+                    };
+                    cmd.push(Command::Info(section))
+                }
+                Unlink => cmd.push(Command::Unlink(self.strings()?)),
+                Object => {
+                    let info = match self.expect_option(&["ENCODING", "IDLETIME", "FREQ"])? {
+                        "ENCODING" => ObjectInfo::Encoding,
+                        "IDLETIME" => ObjectInfo::IdleTime,
+                        _ => ObjectInfo::Freq,
+                    };
+                    cmd.push(Command::Object(info, self.expect_identifier()?))
+                }
+                SAdd => cmd.push(Command::SAdd(self.expect_identifier()?, self.strings()?)),
+                SRem => cmd.push(Command::SRem(self.expect_identifier()?, self.strings()?)),
+                SIsMember => cmd.push(Command::SIsMember(
+                    self.expect_identifier()?,
+                    self.expect_string()?,
+                )),
+                SMembers => cmd.push(Command::SMembers(self.expect_identifier()?)),
+                SCard => cmd.push(Command::SCard(self.expect_identifier()?)),
+                SPop => cmd.push(Command::SPop(
+                    self.expect_identifier()?,
+                    self.optional_count()?,
+                )),
+                SRandMember => cmd.push(Command::SRandMember(
+                    self.expect_identifier()?,
+                    self.optional_integer()?,
+                )),
+                SUnion => cmd.push(Command::SUnion(self.identifiers()?)),
+                SInter => cmd.push(Command::SInter(self.identifiers()?)),
+                SDiff => cmd.push(Command::SDiff(self.identifiers()?)),
+                SUnionStore => cmd.push(Command::SUnionStore(
+                    self.expect_identifier()?,
+                    self.identifiers()?,
+                )),
+                SInterStore => cmd.push(Command::SInterStore(
+                    self.expect_identifier()?,
+                    self.identifiers()?,
+                )),
+                SDiffStore => cmd.push(Command::SDiffStore(
+                    self.expect_identifier()?,
+                    self.identifiers()?,
+                )),
+                ZAdd => {
+                    let key = self.expect_identifier()?;
+                    let (flags, pairs) = self.zadd()?;
+                    cmd.push(Command::ZAdd(key, flags, pairs))
+                }
+                ZScore => cmd.push(Command::ZScore(
+                    self.expect_identifier()?,
+                    self.expect_string()?,
+                )),
+                ZRank => cmd.push(Command::ZRank(
+                    self.expect_identifier()?,
+                    self.expect_string()?,
+                )),
+                ZCard => cmd.push(Command::ZCard(self.expect_identifier()?)),
+                ZRange => cmd.push(Command::ZRange(self.expect_identifier()?, self.zrange()?)),
+                ZRem => cmd.push(Command::ZRem(self.expect_identifier()?, self.strings()?)),
+                ZRemRangeByScore => cmd.push(Command::ZRemRangeByScore(
+                    self.expect_identifier()?,
+                    self.expect_score_bound()?,
+                    self.expect_score_bound()?,
+                )),
+                ZPopMin => cmd.push(Command::ZPopMin(
+                    self.expect_identifier()?,
+                    self.optional_count()?,
+                )),
+                ZPopMax => cmd.push(Command::ZPopMax(
+                    self.expect_identifier()?,
+                    self.optional_count()?,
+                )),
+                ZUnionStore => {
+                    let dest = self.expect_identifier()?;
+                    let (keys, weights, aggregate) = self.zstore()?;
+                    cmd.push(Command::ZUnionStore(dest, keys, weights, aggregate))
+                }
+                ZInterStore => {
+                    let dest = self.expect_identifier()?;
+                    let (keys, weights, aggregate) = self.zstore()?;
+                    cmd.push(Command::ZInterStore(dest, keys, weights, aggregate))
+                }
+                XAdd => {
+                    let key = self.expect_identifier()?;
+                    cmd.push(Command::XAdd(key, self.xadd()?))
+                }
+                XLen => cmd.push(Command::XLen(self.expect_identifier()?)),
+                XRange => cmd.push(Command::XRange(
+                    self.expect_identifier()?,
+                    self.expect_stream_bound(0)?,
+                    self.expect_stream_bound(u64::MAX)?,
+                    self.optional_count_clause()?,
+                )),
+                XRevRange => {
+                    let key = self.expect_identifier()?;
+                    let end = self.expect_stream_bound(u64::MAX)?;
+                    let start = self.expect_stream_bound(0)?;
+                    cmd.push(Command::XRevRange(
+                        key,
+                        start,
+                        end,
+                        self.optional_count_clause()?,
+                    ))
+                }
+                XRead => cmd.push(Command::XRead(self.xread(None)?)),
+                XReadGroup => {
+                    self.expect_option(&["GROUP"])?;
+                    let group = (self.expect_identifier()?, self.expect_identifier()?);
+                    cmd.push(Command::XRead(self.xread(Some(group))?))
+                }
+                XGroup => cmd.push(Command::XGroup(self.xgroup()?)),
+                XAck => {
+                    let (key, group) = (self.expect_identifier()?, self.expect_identifier()?);
+                    let ids = self
+                        .strings()?
+                        .iter()
+                        .map(|s| stream_id(s, 0))
+                        .collect::<Result<_, _>>()?;
+                    cmd.push(Command::XAck(key, group, ids))
+                }
+                XPending => cmd.push(Command::XPending(
+                    self.expect_identifier()?,
+                    self.expect_identifier()?,
+                    self.xpending_range()?,
+                )),
+                XClaim => cmd.push(Command::XClaim(self.xclaim()?)),
+                XAutoClaim => cmd.push(Command::XAutoClaim(self.xautoclaim()?)),
+                GeoAdd => {
+                    let key = self.expect_identifier()?;
+                    let (flags, members) = self.geoadd()?;
+                    cmd.push(Command::GeoAdd(key, flags, members))
+                }
+                GeoPos => cmd.push(Command::GeoPos(self.expect_identifier()?, self.strings()?)),
+                GeoDist => {
+                    let key = self.expect_identifier()?;
+                    let (a, b) = (self.expect_string()?, self.expect_string()?);
+                    let unit = if self.tokens.is_empty() {
+                        1.0
+                    } else {
+                        self.expect_unit()?
+                    };
+                    cmd.push(Command::GeoDist(key, a, b, unit))
+                }
+                GeoHash => cmd.push(Command::GeoHash(self.expect_identifier()?, self.strings()?)),
+                GeoSearch => cmd.push(Command::GeoSearch(
+                    self.expect_identifier()?,
+                    self.geosearch()?,
+                )),
+                JsonSet => {
+                    let key = self.expect_identifier()?;
+                    let (path, value) = (self.expect_path()?, self.expect_json()?);
+                    let condition = match self.tokens.is_empty() {
+                        true => None,
+                        false => match self.expect_option(&["NX", "XX"])? {
+                            "NX" => Some(Condition::Nx),
+                            _ => Some(Condition::Xx),
+                        },
+                    };
+                    cmd.push(Command::JsonSet(key, path, value, condition))
+                }
+                JsonGet => {
+                    let key = self.expect_identifier()?;
+                    let (format, paths) = self.json_get()?;
+                    cmd.push(Command::JsonGet(key, format, paths))
+                }
+                JsonDel => cmd.push(Command::JsonDel(
+                    self.expect_identifier()?,
+                    self.optional_path()?,
+                )),
+                JsonArrAppend => {
+                    let key = self.expect_identifier()?;
+                    let path = self.expect_path()?;
+                    let mut values = vec![self.expect_json()?];
+                    while !self.tokens.is_empty() {
+                        values.push(self.expect_json()?);
+                    }
+                    cmd.push(Command::JsonArrAppend(key, path, values))
+                }
+                JsonNumIncrBy => cmd.push(Command::JsonNumIncrBy(
+                    self.expect_identifier()?,
+                    self.expect_path()?,
+                    self.expect_json_number()?,
+                )),
+                // Each nested array is its own command, so that variadic
+                // commands can consume the remainder of it
+                Token::Array(array) => cmd.extend(
+                    Parser {
+                        tokens: VecDeque::from(array),
+                    }
+                    .parse()?,
+                ),
+                t => return Err(Error::Expected("command or array".into(), t)),
+            }
+        }
+        Ok(cmd)
+    }
+}
+
+fn integer(s: String) -> Result<i64, Error> {
+    s.parse()
+        .map_err(|_| Error::Expected("integer".into(), Token::Identifier(s)))
+}
+
+fn float(s: &str) -> Result<f64, Error> {
+    match s.parse::<f64>() {
+        Ok(f) if !f.is_nan() => Ok(f),
+        _ => Err(Error::Invalid("value is not a valid float".into())),
+    }
+}
+
+fn score_bound(s: &str) -> Result<Bound<f64>, Error> {
+    match s.strip_prefix('(') {
+        Some(rest) => float(rest).map(Bound::Excluded),
+        None => float(s).map(Bound::Included),
+    }
+    .map_err(|_| Error::Invalid("min or max is not a float".into()))
+}
+
+/// Parse a lex range bound: `-` and `+` are unbounded, otherwise it must be
+/// prefixed with `[` (inclusive) or `(` (exclusive)
+fn lex_bound(s: String) -> Result<Bound<String>, Error> {
+    match s.chars().next() {
+        Some('-') | Some('+') if s.len() == 1 => Ok(Bound::Unbounded),
+        Some('[') => Ok(Bound::Included(s[1..].into())),
+        Some('(') => Ok(Bound::Excluded(s[1..].into())),
+        _ => Err(Error::Invalid(
+            "min or max not valid string range item".into(),
+        )),
+    }
+}
+
+/// Parse a RANGE bound, which is a key included in the range. The syntax
+/// of lex range bounds may be used as well, so a key starting with `[` or
+/// `(`, or one that is just `-` or `+`, has to be given with a `[` prefix.
+fn key_bound(s: String) -> Result<Bound<String>, Error> {
+    match s.as_str() {
+        "-" | "+" => lex_bound(s),
+        _ if s.starts_with('[') || s.starts_with('(') => lex_bound(s),
+        _ => Ok(Bound::Included(s)),
+    }
+}
+
+fn stream_id(s: &str, seq: u64) -> Result<StreamId, Error> {
+    StreamId::parse(s, seq).ok_or_else(|| {
+        Error::Invalid("Invalid stream ID specified as stream command argument".into())
+    })
+}
+
+/// Parse a stream range bound: `-` and `+` are the smallest and greatest
+/// IDs, and any other ID is exclusive when prefixed with `(`
+fn stream_bound(s: &str, seq: u64) -> Result<Bound<StreamId>, Error> {
+    match s {
+        "-" => Ok(Bound::Included(StreamId::default())),
+        "+" => Ok(Bound::Included(StreamId::MAX)),
+        _ => match s.strip_prefix('(') {
+            Some(id) => stream_id(id, seq).map(Bound::Excluded),
+            None => stream_id(s, seq).map(Bound::Included),
+        },
+    }
+}
+
+/// Write a single line reply such as an integer or an aggregate's header
+fn line<T: fmt::Display>(out: &mut Vec<u8>, kind: char, value: T) {
+    // Writing to a Vec cannot fail
+    let _ = write!(out, "{}{}\r\n", kind, value);
+}
+
+/// Write a bulk string
+fn bulk(out: &mut Vec<u8>, bytes: &[u8]) {
+    line(out, '$', bytes.len());
+    out.extend_from_slice(bytes);
+    out.extend_from_slice(b"\r\n");
+}
+
+impl Value {
+    /// Encode as RESP2 for display, replacing any invalid UTF-8
+    pub fn encode(&self) -> String {
+        String::from_utf8_lossy(&self.encode_as(Protocol::Resp2)).into_owned()
+    }
+
+    /// Encode for a client speaking `protocol`. RESP2 has no map type, so
+    /// maps are flattened into arrays of alternating keys and values.
+    pub fn encode_as(&self, protocol: Protocol) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(protocol, &mut out);
+        out
+    }
+
+    fn encode_into(&self, protocol: Protocol, out: &mut Vec<u8>) {
+        match self {
+            Value::Null if protocol == Protocol::Resp3 => out.extend_from_slice(b"_\r\n"),
+            Value::Null => out.extend_from_slice(b"*0\r\n"),
+            Value::Text(ref s) => bulk(out, s.as_bytes()),
+            Value::Bytes(ref b) => bulk(out, b),
+            Value::Integer(i) => line(out, ':', i),
+            Value::Array(ref a) => {
+                line(out, '*', a.len());
+                a.iter().for_each(|val| val.encode_into(protocol, out));
+            }
+            Value::List(ref l) => {
+                line(out, '*', l.len());
+                l.iter().for_each(|val| val.encode_into(protocol, out));
+            }
+            Value::Map(ref m) => {
+                match protocol {
+                    Protocol::Resp2 => line(out, '*', m.len() * 2),
+                    Protocol::Resp3 => line(out, '%', m.len()),
+                }
+                for (k, v) in m.iter() {
+                    bulk(out, k.as_bytes());
+                    v.encode_into(protocol, out);
+                }
+            }
+            Value::Set(ref set) => {
+                match protocol {
+                    Protocol::Resp2 => line(out, '*', set.len()),
+                    Protocol::Resp3 => line(out, '~', set.len()),
+                }
+                for m in set.members() {
+                    bulk(out, m.as_bytes());
+                }
+            }
+            Value::SortedSet(ref zset) => {
+                line(out, '*', zset.len());
+                zset.iter().for_each(|(m, _)| bulk(out, m.as_bytes()));
+            }
+            Value::HyperLogLog(ref hll) => bulk(out, &hll.to_bytes()),
+            Value::Stream(ref stream) => stream.to_array().encode_into(protocol, out),
+            Value::Geo(ref geo) => {
+                line(out, '*', geo.len());
+                geo.members().for_each(|m| bulk(out, m.as_bytes()));
+            }
+            Value::Json(ref json) => bulk(out, json.serialize(&JsonFormat::default()).as_bytes()),
+            Value::Status(ref s) => line(out, '+', s),
+            Value::Error(ref s) => line(out, '-', s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn enocde_array() {
+        let answer = "*2\r\n$4\r\nval1\r\n$4\r\nval2\r\n";
+        assert_eq!(
+            answer,
+            Value::Array(vec![
+                Value::Text(String::from("val1")),
+                Value::Text(String::from("val2"))
+            ])
+            .encode()
+        );
+    }
+
+    #[test]
+    fn encode_map() {
+        let mut map = HashMap::new();
+        map.insert(String::from("f"), Value::Integer(1));
+        let map = Value::Map(map.into());
+        assert_eq!(map.encode(), "*2\r\n$1\r\nf\r\n:1\r\n");
+        assert_eq!(
+            Value::Array(vec![map, Value::Null]).encode_as(Protocol::Resp3),
+            b"*2\r\n%1\r\n$1\r\nf\r\n:1\r\n_\r\n"
+        );
+    }
+
+    #[test]
+    fn parse_scan() {
+        let mut parser = Parser::from(
+            b"*6\r\n$5\r\nHSCAN\r\n$1\r\nh\r\n$1\r\n0\r\n$5\r\ncount\r\n:5\r\n$5\r\nMATCH\r\n",
+        )
+        .unwrap();
+        assert_eq!(parser.parse(), Err(Error::Terminated));
+        let mut parser = Parser::from(
+            b"*4\r\n$4\r\nSCAN\r\n$20\r\n18446744073709551615\r\n$4\r\nTYPE\r\n$4\r\nhash\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Command::Scan(Scan {
+                cursor: u64::MAX,
+                pattern: None,
+                count: 10,
+                kind: Some(String::from("hash")),
+            })])
+        );
+        let mut parser =
+            Parser::from(b"*6\r\n$4\r\nHSET\r\n$1\r\nh\r\n$1\r\na\r\n:1\r\n$1\r\nb\r\n$1\r\n2\r\n")
+                .unwrap();
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Command::HSet(
+                String::from("h"),
+                vec![
+                    (String::from("a"), Value::Integer(1)),
+                    (String::from("b"), Value::Text(String::from("2")))
+                ]
+            )])
+        );
+    }
+
+    #[test]
+    fn parse_bitfield_offsets() {
+        let out_of_range = Err(Error::Invalid(
+            "bit offset is not an integer or out of range".into(),
+        ));
+        // Offsets whose end would wrap past u64::MAX
+        let mut parser = Parser::from(
+            b"*5\r\n$8\r\nBITFIELD\r\n$1\r\nk\r\n$3\r\nGET\r\n$2\r\nu8\r\n$20\r\n18446744073709551615\r\n",
+        )
+        .unwrap();
+        assert_eq!(parser.parse(), out_of_range);
+        let mut parser = Parser::from(
+            b"*5\r\n$8\r\nBITFIELD\r\n$1\r\nk\r\n$3\r\nGET\r\n$3\r\ni64\r\n$19\r\n#288230376151711743\r\n",
+        )
+        .unwrap();
+        assert_eq!(parser.parse(), out_of_range);
+        let mut parser = Parser::from(
+            b"*5\r\n$8\r\nBITFIELD\r\n$1\r\nk\r\n$3\r\nGET\r\n$2\r\nu8\r\n$2\r\n#2\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Command::BitField(
+                "k".into(),
+                vec![BitFieldOp::Get(
+                    BitType {
+                        signed: false,
+                        bits: 8
+                    },
+                    16
+                )]
+            )])
+        );
+    }
+
+    #[test]
+    fn parse_acl() {
+        let mut parser = Parser::from(
+            b"*5\r\n$3\r\nACL\r\n$7\r\nsetuser\r\n$5\r\nalice\r\n$2\r\non\r\n$5\r\n>1234\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Command::Acl(AclOp::SetUser(
+                "alice".into(),
+                vec!["on".into(), ">1234".into()]
+            ))])
+        );
+        let mut parser = Parser::from(b"*2\r\n$4\r\nAUTH\r\n:1234\r\n").unwrap();
+        assert_eq!(parser.parse(), Ok(vec![Command::Auth(None, "1234".into())]));
+    }
+
+    #[test]
+    fn parse_client() {
+        let mut parser = Parser::from(
+            b"*6\r\n$6\r\nCLIENT\r\n$4\r\nkill\r\n$4\r\nuser\r\n$3\r\napp\r\n$6\r\nSKIPME\r\n$2\r\nno\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Command::Client(ClientOp::Kill(ClientKill {
+                id: None,
+                addr: None,
+                user: Some("app".into()),
+                skipme: false,
+                legacy: false,
+            }))])
+        );
+        let mut parser =
+            Parser::from(b"*4\r\n$6\r\nCLIENT\r\n$5\r\nPAUSE\r\n:100\r\n$5\r\nwrite\r\n").unwrap();
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Command::Client(ClientOp::Pause(
+                Duration::from_millis(100),
+                false
+            ))])
+        );
+    }
+
+    #[test]
+    fn parse_frames() {
+        let input =
+            b"*2\r\n$6\r\nAPPEND\r\n$1\r\nk\r\n*3\r\n$6\r\nAPPEND\r\n$1\r\nk\r\n$2\r\n\xff\x00\r\n";
+        assert_eq!(Parser::frame(&input[..20]), Ok(None));
+        let (mut parser, used) = Parser::frame(input).unwrap().unwrap();
+        assert_eq!(used, 23);
+        assert_eq!(parser.parse(), Err(Error::Terminated));
+        let (mut parser, used) = Parser::frame(&input[used..]).unwrap().unwrap();
+        assert_eq!(used, input.len() - 23);
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Command::Append(String::from("k"), vec![0xff, 0])])
+        );
+        assert_eq!(
+            Value::Bytes(vec![0xff]).encode_as(Protocol::Resp2),
+            b"$1\r\n\xff\r\n"
+        );
+
+        // Fed a byte at a time, frames come out whole once complete
+        let mut frame = Frame::default();
+        let mut pending = Vec::new();
+        let mut used = Vec::new();
+        for &byte in input.iter() {
+            pending.push(byte);
+            if let Some((_, n)) = frame.feed(&pending).unwrap() {
+                pending.drain(..n);
+                used.push(n);
+            }
+        }
+        assert_eq!(used, vec![23, input.len() - 23]);
+        assert!(pending.is_empty());
+        assert_eq!(
+            frame.feed(b"+OK\r\n").err(),
+            Some(Error::Syntax(lexer::Error::Delimiter(0)))
+        );
+        assert!(matches!(frame.feed(b"*1\r\n$536870912\r\n"), Ok(None)));
+    }
+
+    #[test]
+    fn parse_members() {
+        let mut parser =
+            Parser::from(b"*4\r\n$4\r\nSADD\r\n$1\r\ns\r\n:10\r\n$4\r\nSPOP\r\n").unwrap();
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Command::SAdd(
+                String::from("s"),
+                vec![String::from("10"), String::from("SPOP")]
+            )])
+        );
+        let mut parser =
+            Parser::from(b"*3\r\n$11\r\nSRANDMEMBER\r\n$1\r\ns\r\n$2\r\n-2\r\n").unwrap();
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Command::SRandMember(String::from("s"), Some(-2))])
+        );
+    }
+
+    #[test]
+    fn parse_array() {
+        let mut parser =
+            Parser::from(b"*3\r\n$6\r\nCREATE\r\n$3\r\nkey\r\n*2\r\n$4\r\nval1\r\n$4\r\nval2\r\n")
+                .unwrap();
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Command::Create(
+                String::from("key"),
+                Value::Array(vec![
+                    Value::Text(String::from("val1")),
+                    Value::Text(String::from("val2"))
+                ])
+            ),])
+        );
+    }
+
+    #[test]
+    fn parse_variadic() {
+        let mut parser = Parser::from(
+            b"*2\r\n*4\r\n$5\r\nRPUSH\r\n$1\r\nk\r\n$4\r\nREAD\r\n:1\r\n*3\r\n$4\r\nLPOP\r\n$1\r\nk\r\n$1\r\n2\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![
+                Command::RPush(
+                    String::from("k"),
+                    vec![Value::Text(String::from("READ")), Value::Integer(1)]
+                ),
+                Command::LPop(String::from("k"), Some(2)),
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_options() {
+        let mut parser = Parser::from(
+            b"*5\r\n$7\r\nLINSERT\r\n$1\r\nk\r\n$6\r\nbefore\r\n$1\r\na\r\n$1\r\nb\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Command::LInsert(
+                String::from("k"),
+                Position::Before,
+                Value::Text(String::from("a")),
+                Value::Text(String::from("b"))
+            )])
+        );
+        let mut parser =
+            Parser::from(b"*4\r\n$6\r\nLRANGE\r\n$1\r\nk\r\n$1\r\n0\r\n$1\r\nx\r\n").unwrap();
+        assert_eq!(
+            parser.parse(),
+            Err(Error::Expected(
+                "integer".into(),
+                Token::Identifier(String::from("x"))
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_timeout() {
+        let mut parser =
+            Parser::from(b"*4\r\n$5\r\nBLPOP\r\n$1\r\na\r\n$1\r\nb\r\n$3\r\n1.5\r\n").unwrap();
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Command::BLPop(
+                vec![String::from("a"), String::from("b")],
+                Some(Duration::from_millis(1500))
+            )])
+        );
+        let mut parser = Parser::from(
+            b"*6\r\n$6\r\nBLMOVE\r\n$1\r\na\r\n$1\r\nb\r\n$5\r\nRIGHT\r\n$4\r\nleft\r\n:0\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Command::BLMove(
+                String::from("a"),
+                String::from("b"),
+                End::Back,
+                End::Front,
+                None
+            )])
+        );
+    }
+
+    /// Parse a command sent as an array of bulk strings, as clients send
+    fn parse_args(args: &[&str]) -> Result<Vec<Command>, Error> {
+        let args = args.iter().map(|a| Value::Text(a.to_string())).collect();
+        Parser::from(Value::Array(args).encode().as_bytes())?.parse()
+    }
+
+    #[test]
+    fn parse_zadd_flags() {
+        let flags = |f: ZAddFlags| {
+            let pairs = vec![(1.0, String::from("m"))];
+            Ok(vec![Command::ZAdd(String::from("z"), f, pairs)])
+        };
+        let none = ZAddFlags::default();
+        for (flag, set) in [
+            ("NX", ZAddFlags { nx: true, ..none }),
+            ("XX", ZAddFlags { xx: true, ..none }),
+            ("GT", ZAddFlags { gt: true, ..none }),
+            ("LT", ZAddFlags { lt: true, ..none }),
+            ("CH", ZAddFlags { ch: true, ..none }),
+            ("INCR", ZAddFlags { incr: true, ..none }),
+        ] {
+            assert_eq!(parse_args(&["ZADD", "z", flag, "1", "m"]), flags(set));
+        }
+        let both = ZAddFlags {
+            xx: true,
+            ch: true,
+            incr: true,
+            ..none
+        };
+        assert_eq!(
+            parse_args(&["ZADD", "z", "XX", "CH", "INCR", "1", "m"]),
+            flags(both)
+        );
+    }
+
+    #[test]
+    fn parse_range() {
+        let (start, end) = (String::from("user:1000"), String::from("user:2000"));
+        assert_eq!(
+            parse_args(&["RANGE", "user:1000", "user:2000"]),
+            Ok(vec![Command::Range(
+                Bound::Included(start.clone()),
+                Bound::Included(end.clone()),
+                None,
+                false
+            )])
+        );
+        assert_eq!(
+            parse_args(&["RANGE", "(user:1000", "+", "LIMIT", "5", "REV"]),
+            Ok(vec![Command::Range(
+                Bound::Excluded(start),
+                Bound::Unbounded,
+                Some(5),
+                true
+            )])
+        );
+        assert_eq!(
+            parse_args(&["RANGE", "-", "[user:2000"]),
+            Ok(vec![Command::Range(
+                Bound::Unbounded,
+                Bound::Included(end),
+                None,
+                false
+            )])
+        );
+        assert_eq!(
+            parse_args(&["RANGE", "-x", "[["]),
+            Ok(vec![Command::Range(
+                Bound::Included(String::from("-x")),
+                Bound::Included(String::from("[")),
+                None,
+                false
+            )])
+        );
+    }
+
+    #[test]
+    fn parse_zrange() {
+        let mut parser = Parser::from(
+            b"*8\r\n$6\r\nZRANGE\r\n$1\r\nz\r\n$4\r\n+inf\r\n$2\r\n(1\r\n$7\r\nBYSCORE\r\n$3\r\nrev\r\n$5\r\nLIMIT\r\n:0\r\n",
+        )
+        .unwrap();
+        assert_eq!(parser.parse(), Err(Error::Terminated));
+        let mut parser = Parser::from(
+            b"*9\r\n$6\r\nZRANGE\r\n$1\r\nz\r\n$4\r\n+inf\r\n$2\r\n(1\r\n$7\r\nBYSCORE\r\n$3\r\nrev\r\n$5\r\nLIMIT\r\n:0\r\n:2\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Command::ZRange(
+                String::from("z"),
+                ZRange {
+                    by: RangeBy::Score(Bound::Excluded(1.0), Bound::Included(f64::INFINITY)),
+                    rev: true,
+                    limit: Some((0, 2)),
+                    with_scores: false,
+                }
+            )])
+        );
+        let mut parser =
+            Parser::from(b"*5\r\n$4\r\nZADD\r\n$1\r\nz\r\n$2\r\nNX\r\n$2\r\nGT\r\n:1\r\n").unwrap();
+        assert!(parser.parse().is_err());
+    }
+
+    #[test]
+    fn parse_streams() {
+        let mut parser = Parser::from(
+            b"*8\r\n$4\r\nXADD\r\n$1\r\ns\r\n$6\r\nMAXLEN\r\n$1\r\n~\r\n:5\r\n$3\r\n7-*\r\n$1\r\nf\r\n$1\r\nv\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Command::XAdd(
+                String::from("s"),
+                XAdd {
+                    id: XAddId::Partial(7),
+                    maxlen: Some(5),
+                    nomkstream: false,
+                    fields: vec![(String::from("f"), Value::Text(String::from("v")))],
+                }
+            )])
+        );
+        let mut parser = Parser::from(
+            b"*10\r\n$10\r\nXREADGROUP\r\n$5\r\nGROUP\r\n$1\r\ng\r\n$1\r\nc\r\n$5\r\nBLOCK\r\n:0\r\n$7\r\nSTREAMS\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\n>\r\n",
+        )
+        .unwrap();
+        assert!(parser.parse().is_err());
+        let mut parser = Parser::from(
+            b"*11\r\n$10\r\nXREADGROUP\r\n$5\r\nGROUP\r\n$1\r\ng\r\n$1\r\nc\r\n$5\r\nBLOCK\r\n:0\r\n$7\r\nSTREAMS\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\n>\r\n$1\r\n3\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Command::XRead(XRead {
+                group: Some((String::from("g"), String::from("c"))),
+                count: None,
+                block: Some(None),
+                noack: false,
+                streams: vec![
+                    (String::from("a"), ReadFrom::Undelivered),
+                    (
+                        String::from("b"),
+                        ReadFrom::After(StreamId { ms: 3, seq: 0 })
+                    ),
+                ],
+            })])
+        );
+        let mut parser =
+            Parser::from(b"*4\r\n$9\r\nXREVRANGE\r\n$1\r\ns\r\n$1\r\n+\r\n$2\r\n(5\r\n").unwrap();
+        assert_eq!(
+            parser.parse(),
+            Ok(vec![Command::XRevRange(
+                String::from("s"),
+                Bound::Excluded(StreamId { ms: 5, seq: 0 }),
+                Bound::Included(StreamId::MAX),
+                None
+            )])
         );
     }
 
@@ -0,0 +1,91 @@
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::hash::{BuildHasher, Hasher};
+
+thread_local! {
+    static STATE: Cell<u64> = Cell::new(seed());
+}
+
+/// Seed from the randomly keyed hasher std uses for `HashMap`, which is
+/// as much entropy as we can get without a dependency
+fn seed() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    hasher.finish() | 1
+}
+
+/// Fast non-cryptographic random number (xorshift64*), used for sampling
+pub fn next_u64() -> u64 {
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    })
+}
+
+/// Random index in `0..n`, where `n` must be non-zero
+pub fn below(n: usize) -> usize {
+    (next_u64() % n as u64) as usize
+}
+
+/// Put `items` in a random order
+pub fn shuffle<T>(items: &mut [T]) {
+    for i in (1..items.len()).rev() {
+        items.swap(i, below(i + 1));
+    }
+}
+
+/// Up to `count` distinct random indices in `0..n`, in no particular
+/// order. Floyd's algorithm takes O(count) rather than O(n).
+pub fn distinct(n: usize, count: usize) -> Vec<usize> {
+    let count = count.min(n);
+    let mut chosen = HashSet::with_capacity(count);
+    let mut indices = Vec::with_capacity(count);
+    for j in n - count..n {
+        let i = below(j + 1);
+        // Should `i` be taken already, `j` can't be, as no earlier index
+        // was above `j - 1`
+        let i = match chosen.insert(i) {
+            true => i,
+            false => {
+                chosen.insert(j);
+                j
+            }
+        };
+        indices.push(i);
+    }
+    indices
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn spread() {
+        let mut seen = [0usize; 8];
+        for _ in 0..8000 {
+            seen[below(8)] += 1;
+        }
+        assert!(seen.iter().all(|&n| n > 800 && n < 1200));
+    }
+
+    #[test]
+    fn distinct_indices() {
+        let mut seen = [0usize; 8];
+        for _ in 0..2000 {
+            let mut indices = distinct(8, 4);
+            indices.sort_unstable();
+            indices.dedup();
+            assert_eq!(indices.len(), 4);
+            indices.iter().for_each(|&i| seen[i] += 1);
+        }
+        assert!(seen.iter().all(|&n| n > 800 && n < 1200));
+        assert_eq!(distinct(3, 10).len(), 3);
+        assert!(distinct(0, 1).is_empty());
+    }
+}
@@ -0,0 +1,516 @@
+use super::keys::{self, Positions};
+use super::parser::{Scan, Value};
+use super::random;
+use super::{Database, Entry, Error, Key};
+use std::collections::HashSet;
+
+/// Largest set kept in the compact integer encoding
+const MAX_INTSET_ENTRIES: usize = 512;
+
+/// Unordered collection of unique members. Small sets of integers are kept
+/// as a sorted vector, like Redis' intset, and converted to a hash set once
+/// they grow too large or gain a member that is not an integer, which is
+/// then indexed in SCAN order too.
+#[derive(Debug, Clone)]
+pub enum Set {
+    Ints(Vec<i64>),
+    Hash(HashSet<String>, Positions),
+}
+
+/// Which set operation to combine several keys with
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Algebra {
+    Union,
+    Inter,
+    Diff,
+}
+
+/// Parse `s` as an integer only if it is in canonical form, so that the
+/// member can be recreated exactly from the integer
+fn canonical_int(s: &str) -> Option<i64> {
+    s.parse::<i64>().ok().filter(|i| i.to_string() == s)
+}
+
+impl Default for Set {
+    fn default() -> Self {
+        Set::Ints(Vec::new())
+    }
+}
+
+impl PartialEq for Set {
+    fn eq(&self, other: &Set) -> bool {
+        self.len() == other.len() && self.members().iter().all(|m| other.contains(m))
+    }
+}
+
+impl Set {
+    pub fn len(&self) -> usize {
+        match self {
+            Set::Ints(ints) => ints.len(),
+            Set::Hash(set, _) => set.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, member: &str) -> bool {
+        match self {
+            Set::Ints(ints) => {
+                canonical_int(member).is_some_and(|i| ints.binary_search(&i).is_ok())
+            }
+            Set::Hash(set, _) => set.contains(member),
+        }
+    }
+
+    pub fn insert(&mut self, member: String) -> bool {
+        if let Set::Ints(ref mut ints) = self {
+            if let Some(i) = canonical_int(&member) {
+                if let Err(pos) = ints.binary_search(&i) {
+                    if ints.len() < MAX_INTSET_ENTRIES {
+                        ints.insert(pos, i);
+                        return true;
+                    }
+                } else {
+                    return false;
+                }
+            }
+            let mut positions = Positions::default();
+            let set = ints.iter().map(|i| i.to_string()).collect::<HashSet<_>>();
+            set.iter().for_each(|m| positions.insert(m));
+            *self = Set::Hash(set, positions);
+        }
+        match self {
+            Set::Hash(set, positions) => {
+                positions.insert(&member);
+                set.insert(member)
+            }
+            Set::Ints(_) => unreachable!("converted above"),
+        }
+    }
+
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self {
+            Set::Ints(ints) => match canonical_int(member).map(|i| ints.binary_search(&i)) {
+                Some(Ok(pos)) => {
+                    ints.remove(pos);
+                    true
+                }
+                _ => false,
+            },
+            Set::Hash(set, positions) => {
+                positions.remove(member);
+                set.remove(member)
+            }
+        }
+    }
+
+    pub fn members(&self) -> Vec<String> {
+        match self {
+            Set::Ints(ints) => ints.iter().map(|i| i.to_string()).collect(),
+            Set::Hash(set, _) => set.iter().cloned().collect(),
+        }
+    }
+
+    /// Name of the internal representation, as reported by OBJECT ENCODING
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Set::Ints(_) => "intset",
+            Set::Hash(..) => "hashtable",
+        }
+    }
+
+    /// A page of members for SSCAN, as `keys::page` would select. Integer
+    /// sets are small enough to walk whole.
+    pub fn scan(&self, scan: &Scan) -> (u64, Vec<String>) {
+        match self {
+            Set::Ints(_) => {
+                let members = self.members();
+                let (next, found) = keys::page(members.iter().map(String::as_str), scan);
+                (next, found.into_iter().map(String::from).collect())
+            }
+            Set::Hash(_, positions) => {
+                let (next, found) = positions.page(scan);
+                (next, found.into_iter().map(String::from).collect())
+            }
+        }
+    }
+
+    /// The members at `indices` in iteration order, once for each time
+    /// their index is given, in random order. Hash sets are walked once,
+    /// only as far as the last index, rather than copied whole.
+    fn pick(&self, mut indices: Vec<usize>) -> Vec<String> {
+        let mut picked = Vec::with_capacity(indices.len());
+        match self {
+            Set::Ints(ints) => picked.extend(indices.iter().map(|&i| ints[i].to_string())),
+            Set::Hash(set, _) => {
+                indices.sort_unstable();
+                let mut indices = indices.into_iter().peekable();
+                for (i, member) in set.iter().enumerate() {
+                    while indices.next_if_eq(&i).is_some() {
+                        picked.push(member.clone());
+                    }
+                    if indices.peek().is_none() {
+                        break;
+                    }
+                }
+            }
+        }
+        random::shuffle(&mut picked);
+        picked
+    }
+
+    /// Up to `count` distinct members chosen at random
+    pub fn sample(&self, count: usize) -> Vec<String> {
+        self.pick(random::distinct(self.len(), count))
+    }
+
+    /// `count` members chosen at random, possibly the same one repeatedly
+    pub fn sample_repeated(&self, count: usize) -> Vec<String> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        self.pick((0..count).map(|_| random::below(self.len())).collect())
+    }
+}
+
+impl Database {
+    pub fn set(&self, key: &str) -> Result<Option<&Set>, Error> {
+        match self.data.get(key).map(|e| &e.value) {
+            Some(Value::Set(set)) => Ok(Some(set)),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn set_mut(&mut self, key: &str) -> Result<Option<&mut Set>, Error> {
+        match self.data.get_mut(key).map(|e| &mut e.value) {
+            Some(Value::Set(set)) => Ok(Some(set)),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets are never stored empty, so drop `key` once its last member is gone
+    fn remove_if_empty_set(&mut self, key: &str) {
+        if let Ok(Some(set)) = self.set(key) {
+            if set.is_empty() {
+                self.data.remove(key);
+            }
+        }
+    }
+
+    pub fn sadd(&mut self, key: Key, members: Vec<String>) -> Result<Value, Error> {
+        self.set(&key)?;
+        let entry = self
+            .data
+            .get_or_insert_with(key, || Entry::new(Value::Set(Set::default())));
+        match entry.value {
+            Value::Set(ref mut set) => {
+                let added = members.into_iter().filter(|m| set.insert(m.clone()));
+                Ok(Value::Integer(added.count() as i64))
+            }
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    pub fn srem(&mut self, key: &str, members: &[String]) -> Result<Value, Error> {
+        let removed = match self.set_mut(key)? {
+            Some(set) => members.iter().filter(|m| set.remove(m)).count(),
+            None => 0,
+        };
+        self.remove_if_empty_set(key);
+        Ok(Value::Integer(removed as i64))
+    }
+
+    pub fn sismember(&self, key: &str, member: &str) -> Result<Value, Error> {
+        let found = self.set(key)?.is_some_and(|s| s.contains(member));
+        Ok(Value::Integer(found as i64))
+    }
+
+    pub fn smembers(&self, key: &str) -> Result<Value, Error> {
+        Ok(Value::Set(self.set(key)?.cloned().unwrap_or_default()))
+    }
+
+    pub fn scard(&self, key: &str) -> Result<Value, Error> {
+        Ok(Value::Integer(
+            self.set(key)?.map(|s| s.len()).unwrap_or(0) as i64
+        ))
+    }
+
+    /// Remove and return a random member, or up to `count` of them
+    pub fn spop(&mut self, key: &str, count: Option<usize>) -> Result<Value, Error> {
+        let popped = match self.set_mut(key)? {
+            Some(set) => {
+                let popped = set.sample(count.unwrap_or(1));
+                for member in &popped {
+                    set.remove(member);
+                }
+                popped
+            }
+            None => Vec::new(),
+        };
+        self.remove_if_empty_set(key);
+        Ok(match count {
+            Some(_) => Value::Array(popped.into_iter().map(Value::Text).collect()),
+            None => popped.into_iter().next().map_or(Value::Null, Value::Text),
+        })
+    }
+
+    /// Return a random member, or `count` of them: distinct ones if `count`
+    /// is positive, or possibly repeated ones if it is negative
+    pub fn srandmember(&self, key: &str, count: Option<i64>) -> Result<Value, Error> {
+        let set = match (self.set(key)?, count) {
+            (Some(set), _) => set,
+            (None, Some(_)) => return Ok(Value::Array(Vec::new())),
+            (None, None) => return Ok(Value::Null),
+        };
+        Ok(match count {
+            None => set
+                .sample(1)
+                .into_iter()
+                .next()
+                .map_or(Value::Null, Value::Text),
+            Some(n) if n >= 0 => Value::Array(
+                set.sample(n as usize)
+                    .into_iter()
+                    .map(Value::Text)
+                    .collect(),
+            ),
+            Some(n) => Value::Array(
+                set.sample_repeated(n.unsigned_abs() as usize)
+                    .into_iter()
+                    .map(Value::Text)
+                    .collect(),
+            ),
+        })
+    }
+
+    /// Combine the sets at `keys`, treating missing keys as empty sets
+    pub fn sscan(&self, key: &str, scan: &Scan) -> Result<Value, Error> {
+        let (next, found) = match self.set(key)? {
+            Some(set) => set.scan(scan),
+            None => (0, Vec::new()),
+        };
+        let found = found.into_iter().map(Value::Text).collect();
+        Ok(keys::reply(next, found))
+    }
+
+    pub fn combine_sets(&self, keys: &[String], op: Algebra) -> Result<Set, Error> {
+        let sets = keys
+            .iter()
+            .map(|k| self.set(k))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut result = Set::default();
+        // How many of the other sets contain `member`
+        let others = |member: &str| {
+            sets[1..]
+                .iter()
+                .flatten()
+                .filter(|s| s.contains(member))
+                .count()
+        };
+        match (op, sets.first()) {
+            (Algebra::Union, _) => {
+                for member in sets.iter().flatten().flat_map(|s| s.members()) {
+                    result.insert(member);
+                }
+            }
+            (Algebra::Inter, Some(Some(first))) => {
+                for member in first.members() {
+                    if others(&member) == sets.len() - 1 {
+                        result.insert(member);
+                    }
+                }
+            }
+            (Algebra::Diff, Some(Some(first))) => {
+                for member in first.members() {
+                    if others(&member) == 0 {
+                        result.insert(member);
+                    }
+                }
+            }
+            _ => (),
+        }
+        Ok(result)
+    }
+
+    pub fn sets(&self, keys: &[String], op: Algebra) -> Result<Value, Error> {
+        self.combine_sets(keys, op).map(Value::Set)
+    }
+
+    /// Store the combination of `keys` in `dest`, replacing whatever was
+    /// there, and return its size
+    pub fn sets_store(&mut self, dest: Key, keys: &[String], op: Algebra) -> Result<Value, Error> {
+        let set = self.combine_sets(keys, op)?;
+        let len = set.len();
+        if set.is_empty() {
+            self.data.remove(&dest);
+        } else {
+            self.data.insert(dest, Entry::new(Value::Set(set)));
+        }
+        Ok(Value::Integer(len as i64))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn set(s: &[&str]) -> Set {
+        let mut set = Set::default();
+        for m in s {
+            set.insert(m.to_string());
+        }
+        set
+    }
+
+    #[test]
+    fn encoding() {
+        let mut s = set(&["3", "1", "2"]);
+        assert_eq!(s.encoding(), "intset");
+        assert!(s.contains("2"));
+        assert!(!s.contains("02"));
+        assert!(!s.insert("1".into()));
+        assert!(s.insert("01".into()));
+        assert_eq!(s.encoding(), "hashtable");
+        assert!(s.contains("1") && s.contains("01"));
+        assert_eq!(s.len(), 4);
+
+        let mut big = set(&[]);
+        for i in 0..=MAX_INTSET_ENTRIES {
+            big.insert(i.to_string());
+        }
+        assert_eq!(big.encoding(), "hashtable");
+        assert_eq!(big.len(), MAX_INTSET_ENTRIES + 1);
+    }
+
+    #[test]
+    fn add_remove() {
+        let mut db = Database::new();
+        assert_eq!(
+            db.sadd("s".into(), strings(&["a", "b", "a"])),
+            Ok(Value::Integer(2))
+        );
+        assert_eq!(db.sismember("s", "a"), Ok(Value::Integer(1)));
+        assert_eq!(db.scard("s"), Ok(Value::Integer(2)));
+        assert_eq!(
+            db.srem("s", &strings(&["a", "b", "c"])),
+            Ok(Value::Integer(2))
+        );
+        assert!(db.read("s").is_none());
+    }
+
+    #[test]
+    fn pop_random() {
+        let mut db = Database::new();
+        db.sadd("s".into(), strings(&["1", "2", "3"])).unwrap();
+        match db.srandmember("s", Some(-5)) {
+            Ok(Value::Array(a)) => assert_eq!(a.len(), 5),
+            r => panic!("unexpected reply {:?}", r),
+        }
+        match db.srandmember("s", Some(5)) {
+            Ok(Value::Array(a)) => assert_eq!(a.len(), 3),
+            r => panic!("unexpected reply {:?}", r),
+        }
+        match db.spop("s", Some(2)) {
+            Ok(Value::Array(a)) => assert_eq!(a.len(), 2),
+            r => panic!("unexpected reply {:?}", r),
+        }
+        assert_eq!(db.scard("s"), Ok(Value::Integer(1)));
+        db.spop("s", None).unwrap();
+        assert_eq!(db.spop("s", None), Ok(Value::Null));
+
+        let hash = set(&["a", "b", "c", "d", "e"]);
+        let mut sample = hash.sample(4);
+        sample.sort();
+        sample.dedup();
+        assert_eq!(sample.len(), 4);
+        assert!(sample.iter().all(|m| hash.contains(m)));
+        let repeated = hash.sample_repeated(20);
+        assert_eq!(repeated.len(), 20);
+        assert!(repeated.iter().all(|m| hash.contains(m)));
+    }
+
+    #[test]
+    fn scan_with_mutations() {
+        let mut db = Database::new();
+        let members = (0..600).map(|i| format!("m{}", i)).collect();
+        db.sadd("s".into(), members).unwrap();
+        let mut scan = Scan {
+            cursor: 0,
+            pattern: None,
+            count: 10,
+            kind: None,
+        };
+        let mut seen = Vec::new();
+        for calls in 0.. {
+            let next = match db.sscan("s", &scan) {
+                Ok(Value::Array(reply)) => match (&reply[0], &reply[1]) {
+                    (Value::Text(next), Value::Array(found)) => {
+                        seen.extend(found.iter().cloned());
+                        next.parse().unwrap()
+                    }
+                    r => panic!("unexpected reply {:?}", r),
+                },
+                r => panic!("unexpected reply {:?}", r),
+            };
+            let gone = [format!("m{}", calls * 7 % 600)];
+            db.srem("s", &gone).unwrap();
+            db.sadd("s".into(), strings(&[&format!("new{}", calls)]))
+                .unwrap();
+            match next {
+                0 => break,
+                next => scan.cursor = next,
+            }
+        }
+        for member in db.set("s").unwrap().unwrap().members() {
+            if member.starts_with('m') {
+                assert!(seen.contains(&Value::Text(member)));
+            }
+        }
+    }
+
+    #[test]
+    fn algebra() {
+        let mut db = Database::new();
+        db.sadd("a".into(), strings(&["1", "2", "3"])).unwrap();
+        db.sadd("b".into(), strings(&["2", "3", "x"])).unwrap();
+        let keys = strings(&["a", "b", "missing"]);
+        assert_eq!(
+            db.sets(&keys, Algebra::Union),
+            Ok(Value::Set(set(&["1", "2", "3", "x"])))
+        );
+        assert_eq!(
+            db.sets(&keys[..2], Algebra::Inter),
+            Ok(Value::Set(set(&["2", "3"])))
+        );
+        assert_eq!(db.sets(&keys, Algebra::Inter), Ok(Value::Set(set(&[]))));
+        assert_eq!(db.sets(&keys, Algebra::Diff), Ok(Value::Set(set(&["1"]))));
+        assert_eq!(
+            db.sets_store("d".into(), &keys, Algebra::Union),
+            Ok(Value::Integer(4))
+        );
+        assert_eq!(
+            db.sets_store("d".into(), &keys, Algebra::Inter),
+            Ok(Value::Integer(0))
+        );
+        assert!(db.read("d").is_none());
+    }
+
+    #[test]
+    fn wrong_type() {
+        let mut db = Database::new();
+        db.create("s".into(), Value::Text("v".into()));
+        assert_eq!(db.scard("s"), Err(Error::WrongType));
+        assert_eq!(
+            db.sets(&strings(&["x", "s"]), Algebra::Union),
+            Err(Error::WrongType)
+        );
+    }
+}
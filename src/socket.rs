@@ -0,0 +1,169 @@
+#[cfg(feature = "keepalive")]
+use socket2::{SockRef, TcpKeepalive};
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+/// A client connection over TCP or a Unix domain socket. Clients and their
+/// output buffers each hold a clone, to read, write and shut it down.
+#[derive(Debug)]
+pub enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Socket {
+    pub fn try_clone(&self) -> io::Result<Socket> {
+        match self {
+            Socket::Tcp(s) => s.try_clone().map(Socket::Tcp),
+            #[cfg(unix)]
+            Socket::Unix(s) => s.try_clone().map(Socket::Unix),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Socket::Tcp(s) => s.shutdown(how),
+            #[cfg(unix)]
+            Socket::Unix(s) => s.shutdown(how),
+        }
+    }
+
+    /// Address of the client, which for Unix sockets is the path of the
+    /// socket it connected to, as clients are rarely bound to a path
+    pub fn peer_addr(&self) -> String {
+        let addr = match self {
+            Socket::Tcp(s) => s.peer_addr().map(|a| a.to_string()),
+            #[cfg(unix)]
+            Socket::Unix(s) => s.local_addr().map(|a| match a.as_pathname() {
+                Some(path) => format!("{}:0", path.display()),
+                None => "unix:0".into(),
+            }),
+        };
+        addr.unwrap_or_else(|e| format!("unknown ({})", e))
+    }
+
+    /// IP address of a TCP client
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        match self {
+            Socket::Tcp(s) => s.peer_addr().ok().map(|a| a.ip()),
+            #[cfg(unix)]
+            Socket::Unix(_) => None,
+        }
+    }
+
+    /// Fail reads that wait longer than `timeout` with `WouldBlock` or
+    /// `TimedOut`, depending on the platform
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Socket::Tcp(s) => s.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Socket::Unix(s) => s.set_read_timeout(timeout),
+        }
+    }
+
+    /// Probe an idle TCP connection every `interval` to detect dead peers
+    #[cfg(feature = "keepalive")]
+    pub fn set_keepalive(&self, interval: Option<Duration>) -> io::Result<()> {
+        match self {
+            Socket::Tcp(s) => match interval {
+                Some(interval) => SockRef::from(s).set_tcp_keepalive(
+                    &TcpKeepalive::new()
+                        .with_time(interval)
+                        .with_interval(interval),
+                ),
+                None => SockRef::from(s).set_keepalive(false),
+            },
+            #[cfg(unix)]
+            Socket::Unix(_) => Ok(()),
+        }
+    }
+
+    /// Leave keepalive as the system configures it, as std can't set the
+    /// interval of its probes
+    #[cfg(not(feature = "keepalive"))]
+    pub fn set_keepalive(&self, _interval: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Socket::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Socket::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Socket::Unix(s) => s.flush(),
+        }
+    }
+}
+
+/// Listen on the Unix socket at `path`, replacing any stale socket file
+/// left behind, and setting its permissions if given
+#[cfg(unix)]
+pub fn bind_unix(path: &str, mode: Option<u32>) -> io::Result<UnixListener> {
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn unix_socket() {
+        let path = env::temp_dir().join(format!("kv-test-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        // A stale socket file doesn't stop a new server binding the path
+        drop(bind_unix(path, None).unwrap());
+        let listener = bind_unix(path, Some(0o700)).unwrap();
+        assert_eq!(
+            fs::metadata(path).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+
+        let mut client = UnixStream::connect(path).unwrap();
+        let server = Socket::Unix(listener.accept().unwrap().0);
+        assert_eq!(server.peer_addr(), format!("{}:0", path));
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        server.try_clone().unwrap().read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        server.shutdown(Shutdown::Both).unwrap();
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,323 @@
+use super::clients::Clients;
+use super::config::Config;
+use super::databases::Databases;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::process;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A statistic bumped on the hot path, so only ever a relaxed atomic add
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The commands processed as of the last ops/sec measurement
+struct Sample {
+    at: Instant,
+    commands: u64,
+    rate: u64,
+}
+
+/// Server-wide statistics reported by INFO
+pub struct Stats {
+    pub commands: Counter,
+    pub connections: Counter,
+    pub rejected_connections: Counter,
+    pub hits: Counter,
+    pub misses: Counter,
+    pub expired: Counter,
+    /// Write commands run, which would be lost on a restart
+    pub changes: Counter,
+    pub net_input: Counter,
+    pub net_output: Counter,
+    sample: Mutex<Option<Sample>>,
+}
+
+pub static STATS: Stats = Stats {
+    commands: Counter::new(),
+    connections: Counter::new(),
+    rejected_connections: Counter::new(),
+    hits: Counter::new(),
+    misses: Counter::new(),
+    expired: Counter::new(),
+    changes: Counter::new(),
+    net_input: Counter::new(),
+    net_output: Counter::new(),
+    sample: Mutex::new(None),
+};
+
+static STARTED: OnceLock<(Instant, SystemTime)> = OnceLock::new();
+
+/// Record the start of the server, which uptime is measured from
+pub fn start() {
+    STARTED.get_or_init(|| (Instant::now(), SystemTime::now()));
+}
+
+fn started() -> (Instant, SystemTime) {
+    *STARTED.get_or_init(|| (Instant::now(), SystemTime::now()))
+}
+
+impl Stats {
+    /// Commands per second since the previous measurement, which is taken
+    /// at most once a second so that back to back INFOs agree
+    fn ops_per_sec(&self) -> u64 {
+        let mut sample = self.sample.lock().expect("Poisoned stats sample");
+        let commands = self.commands.get();
+        let (at, since) = match *sample {
+            Some(ref s) if s.at.elapsed() < Duration::from_secs(1) => return s.rate,
+            Some(ref s) => (s.at, s.commands),
+            None => (started().0, 0),
+        };
+        let secs = at.elapsed().as_secs_f64();
+        let rate = match secs > 0.0 {
+            true => ((commands - since) as f64 / secs) as u64,
+            false => 0,
+        };
+        *sample = Some(Sample {
+            at: Instant::now(),
+            commands,
+            rate,
+        });
+        rate
+    }
+}
+
+static USED_MEMORY: AtomicUsize = AtomicUsize::new(0);
+static PEAK_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, keeping count of the bytes allocated through it
+pub struct Counting;
+
+fn allocated(size: usize) {
+    let used = USED_MEMORY.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK_MEMORY.fetch_max(used, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        USED_MEMORY.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            USED_MEMORY.fetch_sub(layout.size(), Ordering::Relaxed);
+            allocated(new_size);
+        }
+        new
+    }
+}
+
+/// Resident set size of the process in bytes, where the platform has a
+/// /proc to read it from
+fn resident_memory() -> Option<usize> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+    Some(kb * 1024)
+}
+
+const SECTIONS: &[&str] = &[
+    "server",
+    "clients",
+    "memory",
+    "persistence",
+    "stats",
+    "keyspace",
+];
+
+/// The INFO report of `section`, or of every section if none is given.
+/// Unknown sections are empty, as in Redis.
+pub fn info(section: Option<&str>, config: &Config, clients: &Clients, dbs: &Databases) -> String {
+    let sections = match section.map(|s| s.to_ascii_lowercase()) {
+        None => SECTIONS.to_vec(),
+        Some(ref s) if s == "all" || s == "default" || s == "everything" => SECTIONS.to_vec(),
+        Some(s) => SECTIONS
+            .iter()
+            .filter(|&&name| name == s)
+            .cloned()
+            .collect(),
+    };
+    let mut out = String::new();
+    for name in sections {
+        if !out.is_empty() {
+            out.push_str("\r\n");
+        }
+        let mut title = name.to_string();
+        title[..1].make_ascii_uppercase();
+        let _ = write!(out, "# {}\r\n", title);
+        for (field, value) in fields(name, config, clients, dbs) {
+            let _ = write!(out, "{}:{}\r\n", field, value);
+        }
+    }
+    out
+}
+
+fn fields(
+    section: &str,
+    config: &Config,
+    clients: &Clients,
+    dbs: &Databases,
+) -> Vec<(String, String)> {
+    let (started, start_time) = started();
+    let uptime = started.elapsed().as_secs();
+    let mut fields: Vec<(&str, String)> = Vec::new();
+    match section {
+        "server" => {
+            fields.push(("kv_version", env!("CARGO_PKG_VERSION").into()));
+            fields.push(("os", format!("{} {}", env::consts::OS, env::consts::ARCH)));
+            fields.push(("arch_bits", (8 * std::mem::size_of::<usize>()).to_string()));
+            fields.push(("process_id", process::id().to_string()));
+            fields.push(("tcp_port", config.port.to_string()));
+            fields.push(("uptime_in_seconds", uptime.to_string()));
+            fields.push(("uptime_in_days", (uptime / 86400).to_string()));
+            fields.push((
+                "executable",
+                env::current_exe()
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_default(),
+            ));
+            fields.push(("config_file", config.file.clone().unwrap_or_default()));
+        }
+        "clients" => {
+            let blocked = (0..dbs.len())
+                .map(|i| dbs.lock(i).blocked.len())
+                .sum::<usize>();
+            fields.push(("connected_clients", clients.len().to_string()));
+            fields.push(("blocked_clients", blocked.to_string()));
+            fields.push(("maxclients", config.maxclients.to_string()));
+        }
+        "memory" => {
+            let used = USED_MEMORY.load(Ordering::Relaxed);
+            fields.push(("used_memory", used.to_string()));
+            fields.push((
+                "used_memory_peak",
+                PEAK_MEMORY.load(Ordering::Relaxed).to_string(),
+            ));
+            if let Some(rss) = resident_memory() {
+                fields.push(("used_memory_rss", rss.to_string()));
+                let ratio = rss as f64 / used.max(1) as f64;
+                fields.push(("mem_fragmentation_ratio", format!("{:.2}", ratio)));
+            }
+        }
+        // Nothing is kept on disk, so as in a Redis that never saved, the
+        // last save is the start of the server
+        "persistence" => {
+            let since_epoch = start_time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            fields.push(("loading", "0".into()));
+            fields.push((
+                "rdb_changes_since_last_save",
+                STATS.changes.get().to_string(),
+            ));
+            fields.push(("rdb_last_save_time", since_epoch.as_secs().to_string()));
+            fields.push(("rdb_last_bgsave_status", "ok".into()));
+            fields.push(("aof_enabled", "0".into()));
+        }
+        "stats" => {
+            let counters = [
+                ("total_connections_received", &STATS.connections),
+                ("rejected_connections", &STATS.rejected_connections),
+                ("total_commands_processed", &STATS.commands),
+                ("total_net_input_bytes", &STATS.net_input),
+                ("total_net_output_bytes", &STATS.net_output),
+                ("expired_keys", &STATS.expired),
+                ("keyspace_hits", &STATS.hits),
+                ("keyspace_misses", &STATS.misses),
+            ];
+            fields.extend(counters.iter().map(|(f, c)| (*f, c.get().to_string())));
+            fields.push(("instantaneous_ops_per_sec", STATS.ops_per_sec().to_string()));
+            // Keys are only ever removed by expiring or deleting them
+            fields.push(("evicted_keys", "0".into()));
+        }
+        "keyspace" => {
+            let mut dbs_fields = Vec::new();
+            for i in 0..dbs.len() {
+                let db = dbs.lock(i);
+                if !db.data.is_empty() {
+                    let value = format!("keys={},expires={}", db.data.len(), db.expiring());
+                    dbs_fields.push((format!("db{}", i), value));
+                }
+            }
+            return dbs_fields;
+        }
+        _ => (),
+    }
+    fields.into_iter().map(|(f, v)| (f.into(), v)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parser::{Command, Value};
+    use Database;
+
+    #[test]
+    fn hits_and_misses() {
+        let mut db = Database::new();
+        db.create("k".into(), Value::Integer(1));
+        let (hits, misses) = (STATS.hits.get(), STATS.misses.get());
+        assert_eq!(
+            db.execute(Command::Read("k".into())),
+            Some(Value::Integer(1))
+        );
+        assert_eq!((STATS.hits.get(), STATS.misses.get()), (hits + 1, misses));
+        assert_eq!(db.execute(Command::Read("missing".into())), None);
+        assert_eq!(
+            (STATS.hits.get(), STATS.misses.get()),
+            (hits + 1, misses + 1)
+        );
+    }
+
+    #[test]
+    fn sections() {
+        let config = Config::default();
+        let (clients, dbs) = (Clients::new(&config), Databases::new(&config));
+        dbs.lock(2).create("k".into(), Value::Integer(1));
+
+        let all = info(None, &config, &clients, &dbs);
+        for title in &["# Server", "# Clients", "# Memory", "# Stats", "# Keyspace"] {
+            assert!(all.contains(title), "missing {}", title);
+        }
+        assert!(all.contains("\r\ndb2:keys=1,expires=0\r\n"));
+        assert!(all.contains("\r\nconnected_clients:0\r\n"));
+
+        let server = info(Some("SERVER"), &config, &clients, &dbs);
+        assert!(server.starts_with("# Server\r\n"));
+        assert!(!server.contains("# Clients"));
+        assert!(server.contains(&format!("process_id:{}\r\n", process::id())));
+        assert_eq!(info(Some("nonsense"), &config, &clients, &dbs), "");
+    }
+}
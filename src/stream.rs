@@ -0,0 +1,943 @@
+use super::parser::{
+    ReadFrom, Value, XAdd, XAddId, XAutoClaim, XClaim, XGroup, XPendingRange, XRead,
+};
+use super::{Database, Entry, Error, Key};
+use std::collections::{btree_map, BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// ID of a stream entry: the millisecond time it was added at, plus a
+/// sequence number telling apart entries added within the same millisecond
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Default)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// Parse `ms-seq`, or a bare `ms` with the sequence number `seq`
+    pub fn parse(s: &str, seq: u64) -> Option<StreamId> {
+        let (ms, seq) = match s.split_once('-') {
+            Some((ms, seq)) => (ms, seq.parse().ok()?),
+            None => (s, seq),
+        };
+        Some(StreamId {
+            ms: ms.parse().ok()?,
+            seq,
+        })
+    }
+
+    /// The smallest ID greater than this one
+    fn next(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId { ms: self.ms, seq }),
+            None => self.ms.checked_add(1).map(|ms| StreamId { ms, seq: 0 }),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+type Fields = Vec<(String, Value)>;
+type Entries = BTreeMap<StreamId, Fields>;
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// `map.range`, but empty rather than panicking when `start` is past `end`
+fn range<V>(
+    map: &BTreeMap<StreamId, V>,
+    start: Bound<StreamId>,
+    end: Bound<StreamId>,
+) -> btree_map::Range<'_, StreamId, V> {
+    let empty = match (start, end) {
+        (Excluded(s), Excluded(e)) => s >= e,
+        (Included(s), Included(e)) | (Included(s), Excluded(e)) | (Excluded(s), Included(e)) => {
+            s > e
+        }
+        _ => false,
+    };
+    if empty {
+        map.range((Excluded(StreamId::MAX), Unbounded))
+    } else {
+        map.range((start, end))
+    }
+}
+
+/// Reply for a single entry: its ID followed by its fields and values
+fn entry(id: &StreamId, fields: &Fields) -> Value {
+    let mut flat = Vec::with_capacity(fields.len() * 2);
+    for (field, value) in fields {
+        flat.push(Value::Text(field.clone()));
+        flat.push(value.clone());
+    }
+    Value::Array(vec![Value::Text(id.to_string()), Value::Array(flat)])
+}
+
+/// An entry delivered to a consumer that has not been acknowledged yet
+#[derive(Debug, PartialEq, Clone)]
+struct Pending {
+    consumer: String,
+    /// Millisecond time of the last delivery
+    delivered: u64,
+    deliveries: u64,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+struct Consumer {
+    /// IDs of the entries in the group's pending list held by this consumer
+    pending: BTreeSet<StreamId>,
+    seen: u64,
+}
+
+/// A consumer group, which hands out each entry to one of its consumers and
+/// keeps track of it until acknowledged
+#[derive(Debug, PartialEq, Clone, Default)]
+struct Group {
+    last_delivered: StreamId,
+    pending: BTreeMap<StreamId, Pending>,
+    consumers: BTreeMap<String, Consumer>,
+}
+
+impl Group {
+    /// Look up a consumer, creating it if missing
+    fn consumer(&mut self, name: &str) -> &mut Consumer {
+        let consumer = self.consumers.entry(name.into()).or_default();
+        consumer.seen = now_ms();
+        consumer
+    }
+
+    /// Record `id` as delivered to `consumer`, taking it away from whichever
+    /// consumer held it before
+    fn deliver(&mut self, id: StreamId, consumer: &str, delivered: u64, deliveries: u64) {
+        let pending = Pending {
+            consumer: consumer.into(),
+            delivered,
+            deliveries,
+        };
+        if let Some(old) = self.pending.insert(id, pending) {
+            if let Some(old) = self.consumers.get_mut(&old.consumer) {
+                old.pending.remove(&id);
+            }
+        }
+        self.consumer(consumer).pending.insert(id);
+    }
+
+    /// Remove `id` from the pending list, returning whether it was there
+    fn ack(&mut self, id: StreamId) -> bool {
+        match self.pending.remove(&id) {
+            Some(pending) => {
+                if let Some(consumer) = self.consumers.get_mut(&pending.consumer) {
+                    consumer.pending.remove(&id);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Append-only log of entries, each a list of field-value pairs, ordered by
+/// ID. The last ID is kept even once its entry is trimmed away, so that new
+/// IDs keep increasing.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Stream {
+    entries: Entries,
+    last_id: StreamId,
+    groups: BTreeMap<String, Group>,
+}
+
+impl Stream {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    /// Every entry, oldest first, as XRANGE replies with them
+    pub fn to_array(&self) -> Value {
+        Value::Array(self.entries.iter().map(|(id, f)| entry(id, f)).collect())
+    }
+
+    /// Resolve the ID to give a new entry, which must be greater than any
+    /// given before
+    fn next_id(&self, id: XAddId) -> Result<StreamId, Error> {
+        let last = self.last_id;
+        let id = match id {
+            XAddId::Auto => match now_ms() {
+                ms if ms > last.ms => StreamId { ms, seq: 0 },
+                _ => last.next().ok_or(Error::StreamIdTooSmall)?,
+            },
+            XAddId::Partial(ms) if ms == last.ms => StreamId {
+                ms,
+                seq: last.seq.checked_add(1).ok_or(Error::StreamIdTooSmall)?,
+            },
+            XAddId::Partial(ms) => StreamId { ms, seq: 0 },
+            XAddId::Explicit(id) => id,
+        };
+        if id == StreamId::default() {
+            return Err(Error::StreamIdZero);
+        }
+        if id <= last {
+            return Err(Error::StreamIdTooSmall);
+        }
+        Ok(id)
+    }
+
+    /// Drop the oldest entries until at most `maxlen` remain
+    fn trim(&mut self, maxlen: usize) {
+        while self.entries.len() > maxlen {
+            self.entries.pop_first();
+        }
+    }
+
+    /// Up to `count` entries with IDs greater than `id`
+    fn after(&self, id: StreamId, count: usize) -> Vec<Value> {
+        self.entries
+            .range((Excluded(id), Unbounded))
+            .take(count)
+            .map(|(id, f)| entry(id, f))
+            .collect()
+    }
+
+    /// Read for `consumer` of `group`, which must exist. Reading new entries
+    /// delivers them, adding them to the pending list unless `noack` is set,
+    /// while reading from an ID replays the consumer's own pending entries.
+    /// Pending entries that were since trimmed are replied as null.
+    fn read_group(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        from: ReadFrom,
+        count: usize,
+        noack: bool,
+    ) -> Vec<Value> {
+        let Stream {
+            ref entries,
+            ref mut groups,
+            ..
+        } = *self;
+        let group = groups.get_mut(group).expect("Group checked by caller");
+        match from {
+            ReadFrom::Undelivered => {
+                let now = now_ms();
+                group.consumer(consumer);
+                let mut read = Vec::new();
+                for (id, fields) in entries
+                    .range((Excluded(group.last_delivered), Unbounded))
+                    .take(count)
+                {
+                    group.last_delivered = *id;
+                    if !noack {
+                        group.deliver(*id, consumer, now, 1);
+                    }
+                    read.push(entry(id, fields));
+                }
+                read
+            }
+            ReadFrom::After(after) => group
+                .consumer(consumer)
+                .pending
+                .range((Excluded(after), Unbounded))
+                .take(count)
+                .map(|id| match entries.get(id) {
+                    Some(fields) => entry(id, fields),
+                    None => Value::Array(vec![Value::Text(id.to_string()), Value::Null]),
+                })
+                .collect(),
+            ReadFrom::Last => Vec::new(),
+        }
+    }
+}
+
+impl Database {
+    pub fn stream(&self, key: &str) -> Result<Option<&Stream>, Error> {
+        match self.data.get(key).map(|e| &e.value) {
+            Some(Value::Stream(stream)) => Ok(Some(stream)),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn stream_mut(&mut self, key: &str) -> Result<Option<&mut Stream>, Error> {
+        match self.data.get_mut(key).map(|e| &mut e.value) {
+            Some(Value::Stream(stream)) => Ok(Some(stream)),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// The entries of the stream at `key` along with its consumer group
+    /// `group`, either of which must exist
+    fn group_mut(&mut self, key: &str, group: &str) -> Result<(&Entries, &mut Group), Error> {
+        let no_group = || Error::NoGroup(key.into(), group.into());
+        let Stream {
+            ref entries,
+            ref mut groups,
+            ..
+        } = *self.stream_mut(key)?.ok_or_else(no_group)?;
+        match groups.get_mut(group) {
+            Some(group) => Ok((entries, group)),
+            None => Err(no_group()),
+        }
+    }
+
+    /// Append an entry, creating the stream unless NOMKSTREAM is given, and
+    /// wake any clients blocked reading it. Replies with the new entry's ID.
+    pub fn xadd(&mut self, key: Key, add: XAdd) -> Result<Value, Error> {
+        let empty = Stream::default();
+        let id = match self.stream(&key)? {
+            Some(stream) => stream.next_id(add.id)?,
+            None if add.nomkstream => return Ok(Value::Null),
+            None => empty.next_id(add.id)?,
+        };
+        let entry = self
+            .data
+            .get_or_insert_with(key.clone(), || Entry::new(Value::Stream(empty)));
+        let stream = match entry.value {
+            Value::Stream(ref mut stream) => stream,
+            _ => return Err(Error::WrongType),
+        };
+        stream.entries.insert(id, add.fields);
+        stream.last_id = id;
+        if let Some(maxlen) = add.maxlen {
+            stream.trim(maxlen);
+        }
+        self.serve_blocked(&key);
+        Ok(Value::Text(id.to_string()))
+    }
+
+    pub fn xlen(&self, key: &str) -> Result<Value, Error> {
+        Ok(Value::Integer(
+            self.stream(key)?.map_or(0, Stream::len) as i64
+        ))
+    }
+
+    /// Entries with IDs between `start` and `end`, newest first if `rev` is
+    /// set
+    pub fn xrange(
+        &self,
+        key: &str,
+        start: Bound<StreamId>,
+        end: Bound<StreamId>,
+        count: Option<usize>,
+        rev: bool,
+    ) -> Result<Value, Error> {
+        let stream = match self.stream(key)? {
+            Some(stream) => stream,
+            None => return Ok(Value::Array(Vec::new())),
+        };
+        let entries = range(&stream.entries, start, end);
+        let count = count.unwrap_or(usize::MAX);
+        let entries: Vec<Value> = if rev {
+            entries
+                .rev()
+                .take(count)
+                .map(|(id, f)| entry(id, f))
+                .collect()
+        } else {
+            entries.take(count).map(|(id, f)| entry(id, f)).collect()
+        };
+        Ok(Value::Array(entries))
+    }
+
+    /// Check the keys and group of a read, and pin `$` to the last ID of each
+    /// stream, so that a blocked read only sees entries added after it began
+    pub fn prepare_read(&self, mut read: XRead) -> Result<XRead, Error> {
+        for (key, from) in &mut read.streams {
+            let stream = self.stream(key)?;
+            if let Some((ref group, _)) = read.group {
+                if !stream.is_some_and(|s| s.groups.contains_key(group)) {
+                    return Err(Error::NoGroup(key.clone(), group.clone()));
+                }
+            }
+            if *from == ReadFrom::Last {
+                *from = ReadFrom::After(stream.map_or(StreamId::default(), Stream::last_id));
+            }
+        }
+        Ok(read)
+    }
+
+    /// Whether reading `key` for `read` would return anything. Reads of a
+    /// consumer's pending entries never wait.
+    pub fn stream_ready(&self, key: &str, read: &XRead) -> bool {
+        let from = match read.streams.iter().find(|(k, _)| k == key) {
+            Some((_, from)) => from,
+            None => return false,
+        };
+        let stream = match self.stream(key) {
+            Ok(Some(stream)) => stream,
+            _ => return false,
+        };
+        let after = match (from, &read.group) {
+            (ReadFrom::After(_), Some(_)) => return true,
+            (ReadFrom::After(id), None) => *id,
+            (ReadFrom::Undelivered, Some((group, _))) => match stream.groups.get(group) {
+                Some(group) => group.last_delivered,
+                None => return false,
+            },
+            _ => return false,
+        };
+        stream
+            .entries
+            .range((Excluded(after), Unbounded))
+            .next()
+            .is_some()
+    }
+
+    /// Read the streams of a prepared XREAD or XREADGROUP, replying with
+    /// each stream that had entries to return, or null if none did
+    pub fn read_streams(&mut self, read: &XRead) -> Result<Value, Error> {
+        let count = read.count.unwrap_or(usize::MAX);
+        let mut replies = Vec::new();
+        for (key, from) in &read.streams {
+            let entries = match read.group {
+                None => match (from, self.stream(key)?) {
+                    (ReadFrom::After(id), Some(stream)) => stream.after(*id, count),
+                    _ => Vec::new(),
+                },
+                Some((ref group, ref consumer)) => {
+                    self.group_mut(key, group)?;
+                    let stream = self.stream_mut(key)?.expect("Stream checked above");
+                    stream.read_group(group, consumer, *from, count, read.noack)
+                }
+            };
+            // Replaying pending entries replies for every stream, as an empty
+            // history is an answer in itself
+            let history = read.group.is_some() && *from != ReadFrom::Undelivered;
+            if !entries.is_empty() || history {
+                replies.push(Value::Array(vec![
+                    Value::Text(key.clone()),
+                    Value::Array(entries),
+                ]));
+            }
+        }
+        Ok(if replies.is_empty() {
+            Value::Null
+        } else {
+            Value::Array(replies)
+        })
+    }
+
+    /// Read without blocking
+    pub fn xread(&mut self, read: XRead) -> Result<Value, Error> {
+        let read = self.prepare_read(read)?;
+        self.read_streams(&read)
+    }
+
+    pub fn xgroup(&mut self, op: XGroup) -> Result<Value, Error> {
+        let ok = Value::Status("OK".into());
+        match op {
+            XGroup::Create(key, group, start, mkstream) => {
+                if self.stream(&key)?.is_none() {
+                    if !mkstream {
+                        return Err(Error::NoStream);
+                    }
+                    self.data
+                        .insert(key.clone(), Entry::new(Value::Stream(Stream::default())));
+                }
+                let stream = self.stream_mut(&key)?.expect("Stream exists");
+                if stream.groups.contains_key(&group) {
+                    return Err(Error::BusyGroup);
+                }
+                let group_state = Group {
+                    last_delivered: start.unwrap_or(stream.last_id),
+                    ..Group::default()
+                };
+                stream.groups.insert(group, group_state);
+                Ok(ok)
+            }
+            XGroup::SetId(key, group, start) => {
+                let last = self.stream(&key)?.ok_or(Error::NoStream)?.last_id;
+                self.group_mut(&key, &group)?.1.last_delivered = start.unwrap_or(last);
+                Ok(ok)
+            }
+            XGroup::Destroy(key, group) => {
+                let stream = self.stream_mut(&key)?.ok_or(Error::NoStream)?;
+                Ok(Value::Integer(stream.groups.remove(&group).is_some() as i64))
+            }
+            XGroup::CreateConsumer(key, group, consumer) => {
+                let group = self.group_mut(&key, &group)?.1;
+                let created = !group.consumers.contains_key(&consumer);
+                group.consumer(&consumer);
+                Ok(Value::Integer(created as i64))
+            }
+            XGroup::DelConsumer(key, group, consumer) => {
+                let group = self.group_mut(&key, &group)?.1;
+                let pending = match group.consumers.remove(&consumer) {
+                    Some(consumer) => consumer.pending,
+                    None => BTreeSet::new(),
+                };
+                for id in &pending {
+                    group.pending.remove(id);
+                }
+                Ok(Value::Integer(pending.len() as i64))
+            }
+        }
+    }
+
+    /// Acknowledge entries, replying with how many were pending
+    pub fn xack(&mut self, key: &str, group: &str, ids: &[StreamId]) -> Result<Value, Error> {
+        let group = match self.group_mut(key, group) {
+            Ok((_, group)) => group,
+            Err(Error::NoGroup(..)) => return Ok(Value::Integer(0)),
+            Err(e) => return Err(e),
+        };
+        Ok(Value::Integer(
+            ids.iter().filter(|&&id| group.ack(id)).count() as i64,
+        ))
+    }
+
+    /// Summarize the pending entries of a group, or list those in `range`
+    pub fn xpending(
+        &mut self,
+        key: &str,
+        group: &str,
+        extended: Option<XPendingRange>,
+    ) -> Result<Value, Error> {
+        let group = self.group_mut(key, group)?.1;
+        let r = match extended {
+            Some(r) => r,
+            None => {
+                let (first, last) = match (group.pending.keys().next(), group.pending.keys().last())
+                {
+                    (Some(first), Some(last)) => (first, last),
+                    _ => {
+                        return Ok(Value::Array(vec![
+                            Value::Integer(0),
+                            Value::Null,
+                            Value::Null,
+                            Value::Null,
+                        ]))
+                    }
+                };
+                let consumers = group
+                    .consumers
+                    .iter()
+                    .filter(|(_, c)| !c.pending.is_empty())
+                    .map(|(name, c)| {
+                        Value::Array(vec![
+                            Value::Text(name.clone()),
+                            Value::Text(c.pending.len().to_string()),
+                        ])
+                    })
+                    .collect();
+                return Ok(Value::Array(vec![
+                    Value::Integer(group.pending.len() as i64),
+                    Value::Text(first.to_string()),
+                    Value::Text(last.to_string()),
+                    Value::Array(consumers),
+                ]));
+            }
+        };
+        let now = now_ms();
+        let min_idle = r.idle.unwrap_or(0);
+        let pending = range(&group.pending, r.start, r.end)
+            .filter(|(_, p)| r.consumer.as_ref().is_none_or(|c| *c == p.consumer))
+            .filter(|(_, p)| now.saturating_sub(p.delivered) >= min_idle)
+            .take(r.count)
+            .map(|(id, p)| {
+                Value::Array(vec![
+                    Value::Text(id.to_string()),
+                    Value::Text(p.consumer.clone()),
+                    Value::Integer(now.saturating_sub(p.delivered) as i64),
+                    Value::Integer(p.deliveries as i64),
+                ])
+            })
+            .collect();
+        Ok(Value::Array(pending))
+    }
+
+    /// Transfer pending entries idle for at least `min_idle` milliseconds to
+    /// another consumer. Entries trimmed from the stream are dropped from
+    /// the pending list instead.
+    pub fn xclaim(&mut self, claim: XClaim) -> Result<Value, Error> {
+        let (entries, group) = self.group_mut(&claim.key, &claim.group)?;
+        let now = now_ms();
+        let delivered = match (claim.time, claim.idle) {
+            (Some(time), _) => time,
+            (None, idle) => now.saturating_sub(idle.unwrap_or(0)),
+        };
+        let mut claimed = Vec::new();
+        for id in claim.ids {
+            let deliveries = match group.pending.get(&id) {
+                Some(p) if now.saturating_sub(p.delivered) < claim.min_idle => continue,
+                Some(p) => p.deliveries,
+                None if claim.force && entries.contains_key(&id) => 0,
+                None => continue,
+            };
+            let fields = match entries.get(&id) {
+                Some(fields) => fields,
+                None => {
+                    group.ack(id);
+                    continue;
+                }
+            };
+            let deliveries = match claim.retry_count {
+                Some(n) => n,
+                None if claim.justid => deliveries,
+                None => deliveries + 1,
+            };
+            group.deliver(id, &claim.consumer, delivered, deliveries);
+            claimed.push(if claim.justid {
+                Value::Text(id.to_string())
+            } else {
+                entry(&id, fields)
+            });
+        }
+        group.consumer(&claim.consumer);
+        Ok(Value::Array(claimed))
+    }
+
+    /// Scan the pending list from `start`, claiming up to `count` entries
+    /// idle for at least `min_idle` milliseconds. Replies with the ID to
+    /// continue scanning from, or `0-0` once done, the claimed entries and
+    /// the IDs of entries that were trimmed and dropped meanwhile.
+    pub fn xautoclaim(&mut self, claim: XAutoClaim) -> Result<Value, Error> {
+        let (entries, group) = self.group_mut(&claim.key, &claim.group)?;
+        let now = now_ms();
+        // Bound the work done per call, as most entries may not be idle
+        let candidates: Vec<(StreamId, u64, u64)> = group
+            .pending
+            .range((Included(claim.start), Unbounded))
+            .take(claim.count.saturating_mul(10))
+            .map(|(id, p)| (*id, p.delivered, p.deliveries))
+            .collect();
+        let (mut claimed, mut deleted, mut next) = (Vec::new(), Vec::new(), None);
+        for &(id, delivered, deliveries) in &candidates {
+            if claimed.len() == claim.count {
+                next = Some(id);
+                break;
+            }
+            let fields = match entries.get(&id) {
+                Some(fields) => fields,
+                None => {
+                    group.ack(id);
+                    deleted.push(Value::Text(id.to_string()));
+                    continue;
+                }
+            };
+            if now.saturating_sub(delivered) < claim.min_idle {
+                continue;
+            }
+            let deliveries = if claim.justid {
+                deliveries
+            } else {
+                deliveries + 1
+            };
+            group.deliver(id, &claim.consumer, now, deliveries);
+            claimed.push(if claim.justid {
+                Value::Text(id.to_string())
+            } else {
+                entry(&id, fields)
+            });
+        }
+        let next = next.or_else(|| {
+            let &(last, ..) = candidates.last()?;
+            group
+                .pending
+                .range((Excluded(last), Unbounded))
+                .next()
+                .map(|(id, _)| *id)
+        });
+        group.consumer(&claim.consumer);
+        Ok(Value::Array(vec![
+            Value::Text(next.unwrap_or_default().to_string()),
+            Value::Array(claimed),
+            Value::Array(deleted),
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    fn add(db: &mut Database, key: &str, id: XAddId) -> Result<Value, Error> {
+        db.xadd(
+            key.into(),
+            XAdd {
+                id,
+                maxlen: None,
+                nomkstream: false,
+                fields: vec![("f".into(), Value::Integer(1))],
+            },
+        )
+    }
+
+    fn group_read(group: &str, consumer: &str, from: ReadFrom) -> XRead {
+        XRead {
+            group: Some((group.into(), consumer.into())),
+            count: None,
+            block: None,
+            noack: false,
+            streams: vec![("s".into(), from)],
+        }
+    }
+
+    /// IDs of the entries in the reply for the first stream read
+    fn read_ids(reply: Value) -> Vec<String> {
+        let entries = match reply {
+            Value::Array(mut streams) => match streams.remove(0) {
+                Value::Array(mut stream) => stream.remove(1),
+                r => panic!("unexpected stream {:?}", r),
+            },
+            r => panic!("unexpected reply {:?}", r),
+        };
+        match entries {
+            Value::Array(entries) => entries
+                .into_iter()
+                .map(|e| match e {
+                    Value::Array(mut e) => match e.remove(0) {
+                        Value::Text(id) => id,
+                        r => panic!("unexpected id {:?}", r),
+                    },
+                    r => panic!("unexpected entry {:?}", r),
+                })
+                .collect(),
+            r => panic!("unexpected entries {:?}", r),
+        }
+    }
+
+    #[test]
+    fn ids() {
+        assert_eq!(StreamId::parse("5-3", 0), Some(id(5, 3)));
+        assert_eq!(StreamId::parse("5", u64::MAX), Some(id(5, u64::MAX)));
+        assert_eq!(StreamId::parse("5-x", 0), None);
+        assert_eq!(id(5, u64::MAX).next(), Some(id(6, 0)));
+
+        let mut db = Database::new();
+        assert_eq!(
+            add(&mut db, "s", XAddId::Explicit(id(0, 0))),
+            Err(Error::StreamIdZero)
+        );
+        assert!(db.stream("s").unwrap().is_none());
+        assert_eq!(
+            add(&mut db, "s", XAddId::Partial(0)),
+            Ok(Value::Text("0-1".into()))
+        );
+        assert_eq!(
+            add(&mut db, "s", XAddId::Explicit(id(5, 0))),
+            Ok(Value::Text("5-0".into()))
+        );
+        assert_eq!(
+            add(&mut db, "s", XAddId::Partial(5)),
+            Ok(Value::Text("5-1".into()))
+        );
+        assert_eq!(
+            add(&mut db, "s", XAddId::Explicit(id(5, 1))),
+            Err(Error::StreamIdTooSmall)
+        );
+        let auto = match add(&mut db, "s", XAddId::Auto) {
+            Ok(Value::Text(id)) => StreamId::parse(&id, 0).unwrap(),
+            r => panic!("unexpected reply {:?}", r),
+        };
+        assert!(auto > id(5, 1));
+        assert_eq!(db.xlen("s"), Ok(Value::Integer(4)));
+    }
+
+    #[test]
+    fn ranges_and_trimming() {
+        let mut db = Database::new();
+        for ms in 1..=5 {
+            add(&mut db, "s", XAddId::Explicit(id(ms, 0))).unwrap();
+        }
+        let ids = |reply: Result<Value, Error>| {
+            read_ids(Value::Array(vec![Value::Array(vec![
+                Value::Text("s".into()),
+                reply.unwrap(),
+            ])]))
+        };
+        assert_eq!(
+            ids(db.xrange("s", Included(id(2, 0)), Included(id(4, 0)), None, false)),
+            vec!["2-0", "3-0", "4-0"]
+        );
+        assert_eq!(
+            ids(db.xrange("s", Excluded(id(2, 0)), Unbounded, Some(2), true)),
+            vec!["5-0", "4-0"]
+        );
+        assert_eq!(
+            db.xrange("s", Excluded(id(3, 0)), Excluded(id(3, 0)), None, false),
+            Ok(Value::Array(vec![]))
+        );
+        db.xadd(
+            "s".into(),
+            XAdd {
+                id: XAddId::Explicit(id(6, 0)),
+                maxlen: Some(2),
+                nomkstream: false,
+                fields: vec![],
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            ids(db.xrange("s", Unbounded, Unbounded, None, false)),
+            vec!["5-0", "6-0"]
+        );
+        // Trimmed IDs are never handed out again
+        assert_eq!(
+            add(&mut db, "s", XAddId::Explicit(id(3, 0))),
+            Err(Error::StreamIdTooSmall)
+        );
+    }
+
+    #[test]
+    fn consumer_groups() {
+        let mut db = Database::new();
+        assert_eq!(
+            db.xgroup(XGroup::Create("s".into(), "g".into(), None, false)),
+            Err(Error::NoStream)
+        );
+        db.xgroup(XGroup::Create("s".into(), "g".into(), None, true))
+            .unwrap();
+        assert_eq!(
+            db.xgroup(XGroup::Create("s".into(), "g".into(), None, false)),
+            Err(Error::BusyGroup)
+        );
+        for ms in 1..=3 {
+            add(&mut db, "s", XAddId::Explicit(id(ms, 0))).unwrap();
+        }
+
+        let mut read = group_read("g", "alice", ReadFrom::Undelivered);
+        read.count = Some(2);
+        assert_eq!(read_ids(db.xread(read).unwrap()), vec!["1-0", "2-0"]);
+        let read = group_read("g", "bob", ReadFrom::Undelivered);
+        assert_eq!(read_ids(db.xread(read).unwrap()), vec!["3-0"]);
+        let read = group_read("g", "bob", ReadFrom::Undelivered);
+        assert_eq!(db.xread(read), Ok(Value::Null));
+
+        // History reads replay the consumer's own pending entries
+        let read = group_read("g", "alice", ReadFrom::After(StreamId::default()));
+        assert_eq!(read_ids(db.xread(read).unwrap()), vec!["1-0", "2-0"]);
+        assert_eq!(
+            db.xack("s", "g", &[id(1, 0), id(1, 0)]),
+            Ok(Value::Integer(1))
+        );
+        assert_eq!(
+            db.xpending("s", "g", None),
+            Ok(Value::Array(vec![
+                Value::Integer(2),
+                Value::Text("2-0".into()),
+                Value::Text("3-0".into()),
+                Value::Array(vec![
+                    Value::Array(vec![Value::Text("alice".into()), Value::Text("1".into())]),
+                    Value::Array(vec![Value::Text("bob".into()), Value::Text("1".into())]),
+                ]),
+            ]))
+        );
+
+        let claim = XClaim {
+            key: "s".into(),
+            group: "g".into(),
+            consumer: "bob".into(),
+            min_idle: 0,
+            ids: vec![id(2, 0), id(9, 0)],
+            idle: None,
+            time: None,
+            retry_count: None,
+            force: false,
+            justid: true,
+        };
+        assert_eq!(
+            db.xclaim(claim),
+            Ok(Value::Array(vec![Value::Text("2-0".into())]))
+        );
+        let range = XPendingRange {
+            idle: None,
+            start: Unbounded,
+            end: Unbounded,
+            count: 10,
+            consumer: Some("bob".into()),
+        };
+        match db.xpending("s", "g", Some(range)) {
+            Ok(Value::Array(pending)) => assert_eq!(pending.len(), 2),
+            r => panic!("unexpected reply {:?}", r),
+        }
+        assert_eq!(
+            db.xgroup(XGroup::DelConsumer("s".into(), "g".into(), "bob".into())),
+            Ok(Value::Integer(2))
+        );
+        assert_eq!(
+            db.xpending("s", "g", None).map(|r| match r {
+                Value::Array(mut summary) => summary.remove(0),
+                r => r,
+            }),
+            Ok(Value::Integer(0))
+        );
+        assert_eq!(
+            db.xread(group_read("nope", "c", ReadFrom::Undelivered)),
+            Err(Error::NoGroup("s".into(), "nope".into()))
+        );
+    }
+
+    #[test]
+    fn autoclaim() {
+        let mut db = Database::new();
+        db.xgroup(XGroup::Create("s".into(), "g".into(), None, true))
+            .unwrap();
+        for ms in 1..=4 {
+            add(&mut db, "s", XAddId::Explicit(id(ms, 0))).unwrap();
+        }
+        db.xread(group_read("g", "alice", ReadFrom::Undelivered))
+            .unwrap();
+        // Trim away the first entry while it is still pending
+        db.xadd(
+            "s".into(),
+            XAdd {
+                id: XAddId::Explicit(id(5, 0)),
+                maxlen: Some(4),
+                nomkstream: false,
+                fields: vec![],
+            },
+        )
+        .unwrap();
+        let claim = |start, count| XAutoClaim {
+            key: "s".into(),
+            group: "g".into(),
+            consumer: "bob".into(),
+            min_idle: 0,
+            start,
+            count,
+            justid: true,
+        };
+        assert_eq!(
+            db.xautoclaim(claim(StreamId::default(), 2)),
+            Ok(Value::Array(vec![
+                Value::Text("4-0".into()),
+                Value::Array(vec![Value::Text("2-0".into()), Value::Text("3-0".into())]),
+                Value::Array(vec![Value::Text("1-0".into())]),
+            ]))
+        );
+        assert_eq!(
+            db.xautoclaim(claim(id(4, 0), 2)),
+            Ok(Value::Array(vec![
+                Value::Text("0-0".into()),
+                Value::Array(vec![Value::Text("4-0".into())]),
+                Value::Array(vec![]),
+            ]))
+        );
+    }
+}
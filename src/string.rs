@@ -0,0 +1,574 @@
+use super::lexer::MAX_BULK_LEN;
+use super::parser::{Condition, Expire, SetOptions, Value};
+use super::{Database, Entry, Error, Key};
+use std::borrow::Cow;
+use std::mem;
+use std::str;
+
+/// Parse an integer the way Redis does: an optional minus sign followed by
+/// digits, with no surrounding whitespace, no `+` and no leading zeros
+pub fn parse_integer(s: &str) -> Option<i64> {
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    let canonical = match digits.as_bytes() {
+        [b'0'] => digits.len() == s.len(),
+        [b'1'..=b'9', rest @ ..] => rest.iter().all(u8::is_ascii_digit),
+        _ => false,
+    };
+    if canonical {
+        s.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Parse a float the way Redis does, rejecting surrounding whitespace and NaN
+pub fn parse_float(s: &str) -> Option<f64> {
+    match s.parse::<f64>() {
+        Ok(f) if !f.is_nan() => Some(f),
+        _ => None,
+    }
+}
+
+/// The bytes of a string value, with integers in their decimal form
+pub fn as_bytes(value: &Value) -> Cow<'_, [u8]> {
+    match value {
+        Value::Text(s) => Cow::Borrowed(s.as_bytes()),
+        Value::Bytes(b) => Cow::Borrowed(b),
+        Value::Integer(i) => Cow::Owned(i.to_string().into_bytes()),
+        _ => Cow::Borrowed(&[]),
+    }
+}
+
+/// The number a string value holds, parsed with `parse`. Bitmap commands
+/// leave values as raw bytes, which hold a number if they are its text.
+fn number<T>(value: &Value, parse: fn(&str) -> Option<T>) -> Option<T> {
+    str::from_utf8(&as_bytes(value)).ok().and_then(parse)
+}
+
+/// Wrap bytes as a string value, keeping valid UTF-8 as text
+pub fn from_bytes(bytes: Vec<u8>) -> Value {
+    match String::from_utf8(bytes) {
+        Ok(s) => Value::Text(s),
+        Err(e) => Value::Bytes(e.into_bytes()),
+    }
+}
+
+/// Clamp an inclusive, possibly negative, range of offsets into a string
+/// of `len` bytes (or bits) the way GETRANGE and BITCOUNT do, returning
+/// `None` if nothing is left of it
+pub fn range(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    if len == 0 || (start < 0 && stop < 0 && start > stop) {
+        return None;
+    }
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        (len + stop).max(0)
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop {
+        None
+    } else {
+        Some((start as usize, stop as usize))
+    }
+}
+
+impl Database {
+    /// Look up a string value, which may be stored as text or an integer
+    pub fn string(&self, key: &str) -> Result<Option<&Value>, Error> {
+        match self.data.get(key).map(|e| &e.value) {
+            Some(value @ Value::Text(_))
+            | Some(value @ Value::Bytes(_))
+            | Some(value @ Value::Integer(_)) => Ok(Some(value)),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Store `value` at `key`, notifying subscribers if it already existed.
+    /// Any expiration time is kept.
+    pub fn store(&mut self, key: Key, value: Value) {
+        self.data
+            .get_or_insert_with(key.clone(), || Entry::new(Value::Null));
+        self.update(&key, value);
+    }
+
+    /// Store `value` at `key` as a fresh string, clearing any expiration
+    pub fn set_string(&mut self, key: Key, value: Value) {
+        self.store(key.clone(), value);
+        self.set_expiration(&key, None);
+    }
+
+    /// The bytes of the string at `key` for editing in place, creating it
+    /// empty if missing. The value is converted to raw bytes, which encode
+    /// the same way as the text or integer it was.
+    pub fn bytes_mut(&mut self, key: Key) -> Result<&mut Vec<u8>, Error> {
+        self.string(&key)?;
+        let entry = self
+            .data
+            .get_or_insert_with(key, || Entry::new(Value::Bytes(Vec::new())));
+        entry.value = match mem::replace(&mut entry.value, Value::Null) {
+            Value::Text(s) => Value::Bytes(s.into_bytes()),
+            Value::Integer(i) => Value::Bytes(i.to_string().into_bytes()),
+            value => value,
+        };
+        match entry.value {
+            Value::Bytes(ref mut bytes) => Ok(bytes),
+            _ => Err(Error::WrongType),
+        }
+    }
+
+    /// The string at `key` for editing in place, creating it empty if
+    /// missing. Integers are converted to their text, so the value is
+    /// always text or raw bytes.
+    fn string_mut(&mut self, key: Key) -> Result<&mut Value, Error> {
+        self.string(&key)?;
+        let entry = self
+            .data
+            .get_or_insert_with(key, || Entry::new(Value::Text(String::new())));
+        if let Value::Integer(i) = entry.value {
+            entry.value = Value::Text(i.to_string());
+        }
+        Ok(&mut entry.value)
+    }
+
+    /// Add `delta` to the integer at `key`, creating it at zero if missing
+    pub fn incrby(&mut self, key: Key, delta: i64) -> Result<Value, Error> {
+        let current = match self.string(&key)? {
+            None => 0,
+            Some(Value::Integer(i)) => *i,
+            Some(value) => number(value, parse_integer).ok_or(Error::NotInteger)?,
+        };
+        let next = current.checked_add(delta).ok_or(Error::Overflow)?;
+        self.store(key, Value::Integer(next));
+        Ok(Value::Integer(next))
+    }
+
+    pub fn decrby(&mut self, key: Key, delta: i64) -> Result<Value, Error> {
+        self.incrby(key, delta.checked_neg().ok_or(Error::Overflow)?)
+    }
+
+    /// Add `delta` to the number at `key`, creating it at zero if missing.
+    /// The result is stored as text, since it need not be an integer.
+    pub fn incrbyfloat(&mut self, key: Key, delta: f64) -> Result<Value, Error> {
+        let current = match self.string(&key)? {
+            None => 0.0,
+            Some(Value::Integer(i)) => *i as f64,
+            Some(value) => number(value, parse_float).ok_or(Error::NotFloat)?,
+        };
+        let next = current + delta;
+        if !next.is_finite() {
+            return Err(Error::NotFinite);
+        }
+        let next = Value::Text(next.to_string());
+        self.store(key, next.clone());
+        Ok(next)
+    }
+
+    /// Append to the string at `key`, creating it if missing, and return
+    /// its new length. The value is extended in place, so that it can keep
+    /// growing without being copied each time.
+    pub fn append(&mut self, key: Key, bytes: Vec<u8>) -> Result<Value, Error> {
+        let len = self.string(&key)?.map_or(0, |v| as_bytes(v).len());
+        if len + bytes.len() > MAX_BULK_LEN {
+            return Err(Error::TooLarge);
+        }
+        let value = self.string_mut(key.clone())?;
+        match (&mut *value, str::from_utf8(&bytes)) {
+            (Value::Text(s), Ok(tail)) => s.push_str(tail),
+            (Value::Text(s), Err(_)) => {
+                let mut raw = mem::take(s).into_bytes();
+                raw.extend_from_slice(&bytes);
+                *value = Value::Bytes(raw);
+            }
+            (Value::Bytes(raw), _) => raw.extend_from_slice(&bytes),
+            _ => unreachable!("strings are edited as text or bytes"),
+        }
+        let len = as_bytes(value).len();
+        self.notify(&key);
+        Ok(Value::Integer(len as i64))
+    }
+
+    /// Substring between two inclusive byte offsets, which count from the
+    /// end when negative
+    pub fn getrange(&self, key: &str, start: i64, stop: i64) -> Result<Value, Error> {
+        let value = match self.string(key)? {
+            Some(value) => as_bytes(value),
+            None => return Ok(Value::Text(String::new())),
+        };
+        Ok(match range(value.len(), start, stop) {
+            Some((start, stop)) => from_bytes(value[start..=stop].to_vec()),
+            None => Value::Text(String::new()),
+        })
+    }
+
+    /// Overwrite part of the string at `key` starting at `offset`, padding
+    /// it with zero bytes if it is too short, and return its new length.
+    /// The value is edited in place, staying text unless a character would
+    /// be split.
+    pub fn setrange(&mut self, key: Key, offset: usize, bytes: Vec<u8>) -> Result<Value, Error> {
+        let len = self.string(&key)?.map_or(0, |v| as_bytes(v).len());
+        // Nothing would change, so don't create an empty key
+        if bytes.is_empty() {
+            return Ok(Value::Integer(len as i64));
+        }
+        let end = offset.checked_add(bytes.len()).ok_or(Error::TooLarge)?;
+        if end > MAX_BULK_LEN {
+            return Err(Error::TooLarge);
+        }
+        let value = self.string_mut(key.clone())?;
+        if let Value::Text(s) = value {
+            let boundary = |i: usize| i >= s.len() || s.is_char_boundary(i);
+            match str::from_utf8(&bytes) {
+                Ok(text) if boundary(offset) && boundary(end) => {
+                    if s.len() < offset {
+                        let padding = offset - s.len();
+                        s.extend(std::iter::repeat_n('\0', padding));
+                    }
+                    let stop = end.min(s.len());
+                    s.replace_range(offset..stop, text);
+                }
+                _ => *value = Value::Bytes(mem::take(s).into_bytes()),
+            }
+        }
+        if let Value::Bytes(raw) = value {
+            if raw.len() < end {
+                raw.resize(end, 0);
+            }
+            raw[offset..end].copy_from_slice(&bytes);
+        }
+        let len = as_bytes(value).len();
+        self.notify(&key);
+        Ok(Value::Integer(len as i64))
+    }
+
+    pub fn strlen(&self, key: &str) -> Result<Value, Error> {
+        let len = self.string(key)?.map(|v| as_bytes(v).len()).unwrap_or(0);
+        Ok(Value::Integer(len as i64))
+    }
+
+    /// Replace the string at `key`, clearing its expiration time, and reply
+    /// with the old value
+    pub fn getset(&mut self, key: Key, value: Value) -> Result<Value, Error> {
+        let old = self.string(&key)?.cloned().unwrap_or(Value::Null);
+        self.set_string(key, value);
+        Ok(old)
+    }
+
+    pub fn getdel(&mut self, key: &str) -> Result<Value, Error> {
+        let old = self.string(key)?.cloned().unwrap_or(Value::Null);
+        self.data.remove(key);
+        Ok(old)
+    }
+
+    /// Store `value` at `key`, if `options` allow for it given whether the
+    /// key exists. Replies OK if set or null if not, or with GET, the old
+    /// value either way.
+    pub fn set_key(
+        &mut self,
+        key: Key,
+        value: Value,
+        options: &SetOptions,
+    ) -> Result<Value, Error> {
+        let old = match options.get {
+            true => Some(self.string(&key)?.cloned().unwrap_or(Value::Null)),
+            false => None,
+        };
+        let exists = self.data.contains_key(&key);
+        let allowed = match options.condition {
+            Some(Condition::Nx) => !exists,
+            Some(Condition::Xx) => exists,
+            None => true,
+        };
+        if allowed && options.keep_ttl {
+            self.store(key, value);
+        } else if allowed {
+            self.set_string(key.clone(), value);
+            if let Some(expire) = options.expire {
+                self.set_expiration(&key, expire.at());
+            }
+        }
+        Ok(match (old, allowed) {
+            (Some(old), _) => old,
+            (None, true) => Value::Status("OK".into()),
+            (None, false) => Value::Null,
+        })
+    }
+
+    /// The strings at `keys` in order, with null for those that are missing
+    /// or hold another type of value
+    pub fn mget(&self, keys: &[Key]) -> Result<Value, Error> {
+        let values = keys
+            .iter()
+            .map(|k| match self.string(k) {
+                Ok(Some(value)) => value.clone(),
+                _ => Value::Null,
+            })
+            .collect();
+        Ok(Value::Array(values))
+    }
+
+    pub fn mset(&mut self, pairs: Vec<(Key, Value)>) -> Result<Value, Error> {
+        for (key, value) in pairs {
+            self.set_string(key, value);
+        }
+        Ok(Value::Status("OK".into()))
+    }
+
+    /// Set every pair only if none of the keys exist, replying whether set
+    pub fn msetnx(&mut self, pairs: Vec<(Key, Value)>) -> Result<Value, Error> {
+        if pairs.iter().any(|(k, _)| self.data.contains_key(k)) {
+            return Ok(Value::Integer(0));
+        }
+        self.mset(pairs)?;
+        Ok(Value::Integer(1))
+    }
+
+    /// Get the string at `key`, optionally changing its expiration time
+    pub fn getex(&mut self, key: &str, expire: Option<Expire>) -> Result<Value, Error> {
+        let value = self.string(key)?.cloned().unwrap_or(Value::Null);
+        if let Some(expire) = expire {
+            self.set_expiration(key, expire.at());
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parser::BitOp;
+    use std::time::{Duration, SystemTime};
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.into())
+    }
+
+    #[test]
+    fn conditional_set() {
+        let mut db = Database::new();
+        let ok = Ok(Value::Status("OK".into()));
+        let options = |condition, get, expire, keep_ttl| SetOptions {
+            condition,
+            get,
+            expire,
+            keep_ttl,
+        };
+        let xx = options(Some(Condition::Xx), false, None, false);
+        assert_eq!(db.set_key("k".into(), text("1"), &xx), Ok(Value::Null));
+        assert!(db.read("k").is_none());
+
+        let in_a_minute = Some(Expire::In(Duration::from_secs(60)));
+        let nx = options(Some(Condition::Nx), false, in_a_minute, false);
+        assert_eq!(db.set_key("k".into(), text("1"), &nx), ok);
+        assert_eq!(db.set_key("k".into(), text("2"), &nx), Ok(Value::Null));
+        assert!(db.data.get("k").unwrap().expiration.is_some());
+
+        let keep = options(Some(Condition::Xx), true, None, true);
+        assert_eq!(db.set_key("k".into(), text("3"), &keep), Ok(text("1")));
+        assert!(db.data.get("k").unwrap().expiration.is_some());
+
+        let get = options(None, true, None, false);
+        assert_eq!(db.set_key("k".into(), text("4"), &get), Ok(text("3")));
+        assert!(db.data.get("k").unwrap().expiration.is_none());
+        assert_eq!(db.set_key("new".into(), text("5"), &get), Ok(Value::Null));
+
+        db.sadd("s".into(), vec!["x".into()]).unwrap();
+        assert_eq!(
+            db.set_key("s".into(), text("6"), &get),
+            Err(Error::WrongType)
+        );
+        assert_eq!(
+            db.set_key("s".into(), text("6"), &SetOptions::default()),
+            ok
+        );
+        assert_eq!(db.read("s"), Some(&text("6")));
+    }
+
+    #[test]
+    fn multiple_keys() {
+        let mut db = Database::new();
+        let pairs = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), text(v)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            db.mset(pairs(&[("a", "1"), ("b", "2")])),
+            Ok(Value::Status("OK".into()))
+        );
+        db.sadd("s".into(), vec!["x".into()]).unwrap();
+        assert_eq!(
+            db.mget(&["b".into(), "z".into(), "s".into(), "a".into()]),
+            Ok(Value::Array(vec![
+                text("2"),
+                Value::Null,
+                Value::Null,
+                text("1")
+            ]))
+        );
+        assert_eq!(
+            db.msetnx(pairs(&[("c", "3"), ("a", "4")])),
+            Ok(Value::Integer(0))
+        );
+        assert!(db.read("c").is_none());
+        assert_eq!(
+            db.msetnx(pairs(&[("c", "3"), ("d", "4")])),
+            Ok(Value::Integer(1))
+        );
+        assert_eq!(db.read("d"), Some(&text("4")));
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(parse_integer("-42"), Some(-42));
+        assert_eq!(parse_integer("0"), Some(0));
+        assert_eq!(parse_integer("-0"), None);
+        assert_eq!(parse_integer("007"), None);
+        assert_eq!(parse_integer("+1"), None);
+        assert_eq!(parse_integer(" 1"), None);
+        assert_eq!(parse_integer("9223372036854775808"), None);
+        assert_eq!(parse_float("1e3"), Some(1000.0));
+        assert_eq!(parse_float("nan"), None);
+        assert_eq!(parse_float("1.5 "), None);
+    }
+
+    #[test]
+    fn incr_decr() {
+        let mut db = Database::new();
+        assert_eq!(db.incrby("n".into(), 1), Ok(Value::Integer(1)));
+        assert_eq!(db.decrby("n".into(), 5), Ok(Value::Integer(-4)));
+        db.create("t".into(), text("10"));
+        assert_eq!(db.incrby("t".into(), 1), Ok(Value::Integer(11)));
+        db.create("x".into(), text("010"));
+        assert_eq!(db.incrby("x".into(), 1), Err(Error::NotInteger));
+        db.create("m".into(), Value::Integer(i64::MAX));
+        assert_eq!(db.incrby("m".into(), 1), Err(Error::Overflow));
+        assert_eq!(db.decrby("n".into(), i64::MIN), Err(Error::Overflow));
+        assert_eq!(db.read("m"), Some(&Value::Integer(i64::MAX)));
+        db.sadd("s".into(), vec!["1".into()]).unwrap();
+        assert_eq!(db.incrby("s".into(), 1), Err(Error::WrongType));
+    }
+
+    #[test]
+    fn incr_after_bitmap_writes() {
+        let mut db = Database::new();
+        db.create("k".into(), text("12"));
+        assert_eq!(db.setbit("k".into(), 0, false), Ok(Value::Integer(0)));
+        assert_eq!(db.incrby("k".into(), 1), Ok(Value::Integer(13)));
+
+        db.create("a".into(), text("12"));
+        let keys = vec![String::from("a")];
+        assert_eq!(
+            db.bitop(BitOp::Or, "d".into(), &keys),
+            Ok(Value::Integer(2))
+        );
+        assert_eq!(db.incrby("d".into(), 1), Ok(Value::Integer(13)));
+        db.bitop(BitOp::Or, "f".into(), &keys).unwrap();
+        assert_eq!(db.incrbyfloat("f".into(), 0.5), Ok(text("12.5")));
+
+        db.create("b".into(), Value::Bytes(vec![0xff]));
+        assert_eq!(db.incrby("b".into(), 1), Err(Error::NotInteger));
+    }
+
+    #[test]
+    fn ranges() {
+        let mut db = Database::new();
+        assert_eq!(
+            db.append("s".into(), b"Hello".to_vec()),
+            Ok(Value::Integer(5))
+        );
+        assert_eq!(
+            db.append("s".into(), b" World".to_vec()),
+            Ok(Value::Integer(11))
+        );
+        assert_eq!(db.getrange("s", 0, 3), Ok(text("Hell")));
+        assert_eq!(db.getrange("s", -3, -1), Ok(text("rld")));
+        assert_eq!(db.getrange("s", 0, -1), Ok(text("Hello World")));
+        assert_eq!(db.getrange("s", 10, 100), Ok(text("d")));
+        assert_eq!(db.getrange("s", -1, -5), Ok(text("")));
+        assert_eq!(
+            db.setrange("s".into(), 6, b"Redis".to_vec()),
+            Ok(Value::Integer(11))
+        );
+        assert_eq!(db.read("s"), Some(&text("Hello Redis")));
+        assert_eq!(
+            db.setrange("p".into(), 3, vec![0xff]),
+            Ok(Value::Integer(4))
+        );
+        assert_eq!(db.read("p"), Some(&Value::Bytes(vec![0, 0, 0, 0xff])));
+        assert_eq!(db.strlen("p"), Ok(Value::Integer(4)));
+        assert_eq!(db.setrange("e".into(), 5, vec![]), Ok(Value::Integer(0)));
+        assert!(db.read("e").is_none());
+        assert_eq!(
+            db.setrange("s".into(), MAX_BULK_LEN, b"x".to_vec()),
+            Err(Error::TooLarge)
+        );
+    }
+
+    #[test]
+    fn edit_in_place() {
+        let mut db = Database::new();
+        db.create("n".into(), Value::Integer(12));
+        assert_eq!(db.append("n".into(), b"3".to_vec()), Ok(Value::Integer(3)));
+        assert_eq!(db.read("n"), Some(&text("123")));
+        assert_eq!(
+            db.setrange("n".into(), 5, b"4".to_vec()),
+            Ok(Value::Integer(6))
+        );
+        assert_eq!(db.read("n"), Some(&text("123\u{0}\u{0}4")));
+
+        // Splitting a character leaves raw bytes, which appending extends
+        db.create("u".into(), text("é!"));
+        assert_eq!(
+            db.setrange("u".into(), 1, b"e".to_vec()),
+            Ok(Value::Integer(3))
+        );
+        assert_eq!(db.read("u"), Some(&Value::Bytes(vec![0xc3, b'e', b'!'])));
+        assert_eq!(db.append("u".into(), b"?".to_vec()), Ok(Value::Integer(4)));
+        assert_eq!(
+            db.read("u"),
+            Some(&Value::Bytes(vec![0xc3, b'e', b'!', b'?']))
+        );
+
+        db.create("t".into(), text("ab"));
+        assert_eq!(db.append("t".into(), vec![0xff]), Ok(Value::Integer(3)));
+        assert_eq!(db.read("t"), Some(&Value::Bytes(vec![b'a', b'b', 0xff])));
+    }
+
+    #[test]
+    fn get_and_modify() {
+        let mut db = Database::new();
+        assert_eq!(db.getset("k".into(), text("a")), Ok(Value::Null));
+        assert_eq!(db.getset("k".into(), Value::Integer(1)), Ok(text("a")));
+        let expire = Expire::At(SystemTime::now() + Duration::from_secs(60));
+        assert_eq!(db.getex("k", Some(expire)), Ok(Value::Integer(1)));
+        assert!(db.data.get("k").unwrap().expiration.is_some());
+        assert_eq!(db.getex("k", Some(Expire::Persist)), Ok(Value::Integer(1)));
+        assert!(db.data.get("k").unwrap().expiration.is_none());
+        assert_eq!(db.getdel("k"), Ok(Value::Integer(1)));
+        assert_eq!(db.getdel("k"), Ok(Value::Null));
+    }
+
+    #[test]
+    fn incr_float() {
+        let mut db = Database::new();
+        assert_eq!(db.incrbyfloat("f".into(), 10.5), Ok(text("10.5")));
+        assert_eq!(db.incrbyfloat("f".into(), 0.1), Ok(text("10.6")));
+        assert_eq!(db.incrbyfloat("f".into(), -0.6), Ok(text("10")));
+        assert_eq!(db.incrby("f".into(), 1), Ok(Value::Integer(11)));
+        assert_eq!(db.incrbyfloat("f".into(), 5e3), Ok(text("5011")));
+        assert_eq!(
+            db.incrbyfloat("f".into(), f64::INFINITY),
+            Err(Error::NotFinite)
+        );
+        db.create("s".into(), text("abc"));
+        assert_eq!(db.incrbyfloat("s".into(), 1.0), Err(Error::NotFloat));
+    }
+}
@@ -0,0 +1,342 @@
+use super::config::{Config, TlsAuth};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+/// Config read from the files, along with when they were last modified,
+/// so that replacing them takes effect on the next connection
+struct Loaded {
+    modified: Vec<Option<SystemTime>>,
+    config: Arc<ServerConfig>,
+}
+
+/// Files the TLS config is read from, and whether clients present
+/// certificates
+struct Files {
+    cert_file: String,
+    key_file: String,
+    ca_cert_file: Option<String>,
+    auth: TlsAuth,
+}
+
+impl Files {
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        let mut files = vec![&self.cert_file, &self.key_file];
+        files.extend(self.ca_cert_file.as_ref());
+        files
+            .iter()
+            .map(|f| fs::metadata(f).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    /// Read the certificate chain, key and CA certificates into a config
+    fn load(&self) -> Result<Loaded, String> {
+        let modified = self.modified();
+        let read = |path: &str| fs::read(path).map_err(|e| format!("{}: {}", path, e));
+        let certs = CertificateDer::pem_slice_iter(&read(&self.cert_file)?)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("{}: {}", self.cert_file, e))?;
+        let key = PrivateKeyDer::from_pem_slice(&read(&self.key_file)?)
+            .map_err(|e| format!("{}: {}", self.key_file, e))?;
+
+        let builder = ServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13]);
+        let builder = match (self.auth, &self.ca_cert_file) {
+            (TlsAuth::No, _) | (_, None) => builder.with_no_client_auth(),
+            (auth, Some(ca_file)) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_slice_iter(&read(ca_file)?) {
+                    let cert = cert.map_err(|e| format!("{}: {}", ca_file, e))?;
+                    roots.add(cert).map_err(|e| format!("{}: {}", ca_file, e))?;
+                }
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+                let verifier = match auth {
+                    TlsAuth::Optional => verifier.allow_unauthenticated(),
+                    _ => verifier,
+                };
+                builder.with_client_cert_verifier(verifier.build().map_err(|e| e.to_string())?)
+            }
+        };
+        let config = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| e.to_string())?;
+        Ok(Loaded {
+            modified,
+            config: Arc::new(config),
+        })
+    }
+}
+
+/// TLS 1.3 settings of the TLS port, reloaded whenever the certificate, key
+/// or CA files change
+pub struct Tls {
+    files: Files,
+    loaded: Mutex<Loaded>,
+}
+
+impl Tls {
+    pub fn new(config: &Config) -> Result<Tls, String> {
+        let (cert_file, key_file) = match (&config.tls_cert_file, &config.tls_key_file) {
+            (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+            _ => return Err("tls-port requires tls-cert-file and tls-key-file".into()),
+        };
+        if config.tls_auth_clients != TlsAuth::No && config.tls_ca_cert_file.is_none() {
+            return Err("tls-auth-clients requires tls-ca-cert-file".into());
+        }
+        let files = Files {
+            cert_file,
+            key_file,
+            ca_cert_file: config.tls_ca_cert_file.clone(),
+            auth: config.tls_auth_clients,
+        };
+        Ok(Tls {
+            loaded: Mutex::new(files.load()?),
+            files,
+        })
+    }
+
+    /// The config to accept a connection with, reloaded first if any of
+    /// the files changed. A failed reload keeps the previous config.
+    fn config(&self) -> Arc<ServerConfig> {
+        let mut loaded = self.loaded.lock().expect("Poisoned TLS lock");
+        if self.files.modified() != loaded.modified {
+            match self.files.load() {
+                Ok(reloaded) => {
+                    println!("Reloaded TLS certificates");
+                    *loaded = reloaded;
+                }
+                Err(e) => {
+                    println!(
+                        "Error reloading TLS certificates, keeping the old ones: {}",
+                        e
+                    );
+                    loaded.modified = self.files.modified();
+                }
+            }
+        }
+        loaded.config.clone()
+    }
+
+    /// Complete a handshake over `socket`, returning the reading and writing
+    /// halves of the connection, and the common name of the verified
+    /// client certificate, if one was presented
+    pub fn accept(&self, socket: &TcpStream) -> io::Result<(TlsReader, TlsWriter, Option<String>)> {
+        let mut conn = ServerConnection::new(self.config()).map_err(io::Error::other)?;
+        let mut stream = socket.try_clone()?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)?;
+        }
+        let name = conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| common_name(cert));
+        let conn = Arc::new(Mutex::new(conn));
+        let reader = TlsReader {
+            conn: conn.clone(),
+            socket: socket.try_clone()?,
+            pending: Vec::new(),
+        };
+        let writer = TlsWriter {
+            conn,
+            socket: socket.try_clone()?,
+        };
+        Ok((reader, writer, name))
+    }
+}
+
+fn lock(conn: &Mutex<ServerConnection>) -> MutexGuard<'_, ServerConnection> {
+    conn.lock().expect("Poisoned TLS connection")
+}
+
+/// Send whatever TLS records `conn` has queued
+fn flush(conn: &mut ServerConnection, socket: &mut TcpStream) -> io::Result<()> {
+    while conn.wants_write() {
+        conn.write_tls(socket)?;
+    }
+    Ok(())
+}
+
+/// Reading half of a TLS connection. The connection state is shared with
+/// the writing half, but only locked while records are decrypted, never
+/// while waiting on the socket.
+pub struct TlsReader {
+    conn: Arc<Mutex<ServerConnection>>,
+    socket: TcpStream,
+    /// Records read from the socket but not yet taken by the connection
+    pending: Vec<u8>,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buffer = [0u8; 4096];
+        loop {
+            {
+                let mut conn = lock(&self.conn);
+                match conn.reader().read(buf) {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    result => return result,
+                }
+                if !self.pending.is_empty() {
+                    let used = conn.read_tls(&mut &self.pending[..])?;
+                    self.pending.drain(..used);
+                    let processed = conn.process_new_packets();
+                    // Send any alert, or handshake and key update records
+                    flush(&mut conn, &mut self.socket)?;
+                    processed.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    continue;
+                }
+            }
+            match self.socket.read(&mut buffer)? {
+                0 => return Ok(0),
+                n => self.pending.extend_from_slice(&buffer[..n]),
+            }
+        }
+    }
+}
+
+/// Writing half of a TLS connection
+pub struct TlsWriter {
+    conn: Arc<Mutex<ServerConnection>>,
+    socket: TcpStream,
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = lock(&self.conn);
+        let n = conn.writer().write(buf)?;
+        flush(&mut conn, &mut self.socket)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        flush(&mut lock(&self.conn), &mut self.socket)
+    }
+}
+
+/// Read a DER tag and length, returning the tag, contents and remainder
+fn der(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = match first {
+        n if n < 0x80 => (n as usize, rest),
+        n => {
+            let bytes = (n & 0x7f) as usize;
+            if bytes > 4 || rest.len() < bytes {
+                return None;
+            }
+            let len = rest[..bytes]
+                .iter()
+                .fold(0usize, |len, &b| len << 8 | b as usize);
+            (len, &rest[bytes..])
+        }
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+/// The subject common name of an X.509 certificate
+fn common_name(cert: &[u8]) -> Option<String> {
+    const SEQUENCE: u8 = 0x30;
+    const SET: u8 = 0x31;
+    const OID: u8 = 0x06;
+    const VERSION: u8 = 0xa0;
+    const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+    let (_, cert, _) = der(cert)?;
+    let (_, tbs, _) = der(cert)?;
+    let mut fields = tbs;
+    // Skip the version, serial number, signature algorithm, issuer and
+    // validity before the subject
+    if fields.first() == Some(&VERSION) {
+        fields = der(fields)?.2;
+    }
+    for _ in 0..4 {
+        fields = der(fields)?.2;
+    }
+    let (tag, mut names, _) = der(fields)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+    while !names.is_empty() {
+        let (tag, mut set, rest) = der(names)?;
+        names = rest;
+        if tag != SET {
+            return None;
+        }
+        while !set.is_empty() {
+            let (_, pair, rest) = der(set)?;
+            set = rest;
+            let (tag, oid, value) = der(pair)?;
+            if tag == OID && oid == COMMON_NAME {
+                let (_, value, _) = der(value)?;
+                return String::from_utf8(value.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// DER encoding of `contents` under `tag`, for lengths below 256
+    fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        if contents.len() >= 0x80 {
+            out.push(0x81);
+        }
+        out.push(contents.len() as u8);
+        out.extend_from_slice(contents);
+        out
+    }
+
+    fn name(attributes: &[(&[u8], &str)]) -> Vec<u8> {
+        let mut sets = Vec::new();
+        for (oid, value) in attributes {
+            let pair = [tlv(0x06, oid), tlv(0x0c, value.as_bytes())].concat();
+            sets.extend(tlv(0x31, &tlv(0x30, &pair)));
+        }
+        tlv(0x30, &sets)
+    }
+
+    /// A certificate with the given subject, and nothing valid elsewhere
+    fn certificate(subject: &[(&[u8], &str)]) -> Vec<u8> {
+        let tbs = [
+            tlv(0xa0, &tlv(0x02, &[2])),
+            tlv(0x02, &[0x01, 0x02, 0x03]),
+            tlv(0x30, &tlv(0x06, &[0x2a, 0x86, 0x48])),
+            name(&[(COMMON_NAME, "issuer")]),
+            tlv(
+                0x30,
+                &[tlv(0x17, b"250101000000Z"), tlv(0x17, b"350101000000Z")].concat(),
+            ),
+            name(subject),
+            tlv(0x30, &[]),
+        ]
+        .concat();
+        tlv(
+            0x30,
+            &[tlv(0x30, &tbs), tlv(0x30, &[]), tlv(0x03, &[0])].concat(),
+        )
+    }
+
+    const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+    const ORGANIZATION: &[u8] = &[0x55, 0x04, 0x0a];
+
+    #[test]
+    fn subject_common_name() {
+        let cert = certificate(&[(ORGANIZATION, "kv"), (COMMON_NAME, "alice")]);
+        assert_eq!(common_name(&cert), Some("alice".into()));
+        assert_eq!(common_name(&certificate(&[(ORGANIZATION, "kv")])), None);
+        assert_eq!(common_name(&cert[..cert.len() / 2]), None);
+        assert_eq!(common_name(&[0x30, 0x85]), None);
+    }
+}
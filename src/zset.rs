@@ -0,0 +1,883 @@
+use super::keys::{self, Positions};
+use super::list;
+use super::parser::{Aggregate, RangeBy, Scan, Value, ZAddFlags, ZRange};
+use super::random;
+use super::{Database, Entry, Error, Key};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::Bound;
+
+const MAX_LEVEL: usize = 32;
+const HEAD: usize = 0;
+
+#[derive(Debug, Clone, Copy)]
+struct Level {
+    next: Option<usize>,
+    /// Number of nodes skipped by following `next`
+    span: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    member: String,
+    score: f64,
+    levels: Vec<Level>,
+    prev: Option<usize>,
+}
+
+/// Skip list ordered by (score, member), with the span of every link kept so
+/// that ranks can be found in logarithmic time. Nodes live in an arena and
+/// link to each other by index, with the head at index 0.
+#[derive(Debug, Clone)]
+pub struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    level: usize,
+    len: usize,
+    tail: Option<usize>,
+}
+
+/// Compare `(score, member)` pairs, the order of a sorted set
+fn cmp(a: (f64, &str), b: (f64, &str)) -> Ordering {
+    a.0.partial_cmp(&b.0)
+        .unwrap_or(Ordering::Equal)
+        .then_with(|| a.1.cmp(b.1))
+}
+
+/// Like Redis, each extra level is kept with probability 1/4
+fn random_level() -> usize {
+    let mut level = 1;
+    while level < MAX_LEVEL && random::next_u64().is_multiple_of(4) {
+        level += 1;
+    }
+    level
+}
+
+/// Format a score the way replies carry it
+pub fn format_score(score: f64) -> String {
+    match score {
+        s if s == f64::INFINITY => "inf".into(),
+        s if s == f64::NEG_INFINITY => "-inf".into(),
+        s => s.to_string(),
+    }
+}
+
+fn above_min<T: PartialOrd>(value: &T, min: &Bound<T>) -> bool {
+    match min {
+        Bound::Included(m) => value >= m,
+        Bound::Excluded(m) => value > m,
+        Bound::Unbounded => true,
+    }
+}
+
+fn below_max<T: PartialOrd>(value: &T, max: &Bound<T>) -> bool {
+    match max {
+        Bound::Included(m) => value <= m,
+        Bound::Excluded(m) => value < m,
+        Bound::Unbounded => true,
+    }
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        SkipList {
+            nodes: vec![Node {
+                member: String::new(),
+                score: 0.0,
+                levels: vec![
+                    Level {
+                        next: None,
+                        span: 0
+                    };
+                    MAX_LEVEL
+                ],
+                prev: None,
+            }],
+            free: Vec::new(),
+            level: 1,
+            len: 0,
+            tail: None,
+        }
+    }
+}
+
+impl SkipList {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn next(&self, x: usize, level: usize) -> Option<usize> {
+        self.nodes[x].levels[level].next
+    }
+
+    fn key(&self, x: usize) -> (f64, &str) {
+        (self.nodes[x].score, &self.nodes[x].member)
+    }
+
+    /// Member and score of node `x`
+    pub fn get(&self, x: usize) -> (&str, f64) {
+        (&self.nodes[x].member, self.nodes[x].score)
+    }
+
+    /// Find the last node before `(score, member)` at every level
+    fn predecessors(&self, score: f64, member: &str) -> ([usize; MAX_LEVEL], [usize; MAX_LEVEL]) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i + 1 == self.level { 0 } else { rank[i + 1] };
+            while let Some(next) = self.next(x, i) {
+                if cmp(self.key(next), (score, member)) == Ordering::Less {
+                    rank[i] += self.nodes[x].levels[i].span;
+                    x = next;
+                } else {
+                    break;
+                }
+            }
+            update[i] = x;
+        }
+        (update, rank)
+    }
+
+    /// Insert a member, which must not already be present
+    pub fn insert(&mut self, score: f64, member: String) {
+        let (mut update, mut rank) = self.predecessors(score, &member);
+        let level = random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let node = Node {
+            member,
+            score,
+            levels: vec![
+                Level {
+                    next: None,
+                    span: 0
+                };
+                level
+            ],
+            prev: if update[0] == HEAD {
+                None
+            } else {
+                Some(update[0])
+            },
+        };
+        let x = match self.free.pop() {
+            Some(x) => {
+                self.nodes[x] = node;
+                x
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+
+        for i in 0..level {
+            let before = self.nodes[update[i]].levels[i];
+            self.nodes[x].levels[i] = Level {
+                next: before.next,
+                span: before.span - (rank[0] - rank[i]),
+            };
+            self.nodes[update[i]].levels[i] = Level {
+                next: Some(x),
+                span: rank[0] - rank[i] + 1,
+            };
+        }
+        for (i, &u) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[u].levels[i].span += 1;
+        }
+        match self.next(x, 0) {
+            Some(next) => self.nodes[next].prev = Some(x),
+            None => self.tail = Some(x),
+        }
+        self.len += 1;
+    }
+
+    /// Remove a member, returning whether it was present
+    pub fn remove(&mut self, score: f64, member: &str) -> bool {
+        let (update, _) = self.predecessors(score, member);
+        let x = match self.next(update[0], 0) {
+            Some(x) if cmp(self.key(x), (score, member)) == Ordering::Equal => x,
+            _ => return false,
+        };
+        for (i, &u) in update.iter().enumerate().take(self.level) {
+            if self.next(u, i) == Some(x) {
+                let removed = self.nodes[x].levels[i];
+                self.nodes[u].levels[i] = Level {
+                    next: removed.next,
+                    span: self.nodes[u].levels[i].span + removed.span - 1,
+                };
+            } else {
+                self.nodes[u].levels[i].span -= 1;
+            }
+        }
+        let prev = self.nodes[x].prev;
+        match self.next(x, 0) {
+            Some(next) => self.nodes[next].prev = prev,
+            None => self.tail = prev,
+        }
+        while self.level > 1 && self.next(HEAD, self.level - 1).is_none() {
+            self.level -= 1;
+        }
+        self.nodes[x].member = String::new();
+        self.free.push(x);
+        self.len -= 1;
+        true
+    }
+
+    /// Zero based rank of a member
+    pub fn rank(&self, score: f64, member: &str) -> Option<usize> {
+        let mut rank = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.next(x, i) {
+                if cmp(self.key(next), (score, member)) != Ordering::Greater {
+                    rank += self.nodes[x].levels[i].span;
+                    x = next;
+                } else {
+                    break;
+                }
+            }
+            if x != HEAD && self.nodes[x].member == member {
+                return Some(rank - 1);
+            }
+        }
+        None
+    }
+
+    /// Node at a zero based rank
+    pub fn by_rank(&self, rank: usize) -> Option<usize> {
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.next(x, i) {
+                if traversed + self.nodes[x].levels[i].span <= target {
+                    traversed += self.nodes[x].levels[i].span;
+                    x = next;
+                } else {
+                    break;
+                }
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    /// First node for which `pred` holds, given that it holds for every node
+    /// after it as well
+    pub fn first_where<F: Fn(&str, f64) -> bool>(&self, pred: F) -> Option<usize> {
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.next(x, i) {
+                let (member, score) = self.get(next);
+                if pred(member, score) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        self.next(x, 0)
+    }
+
+    /// Last node for which `pred` holds, given that it holds for every node
+    /// before it as well
+    pub fn last_where<F: Fn(&str, f64) -> bool>(&self, pred: F) -> Option<usize> {
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.next(x, i) {
+                let (member, score) = self.get(next);
+                if !pred(member, score) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        if x == HEAD {
+            None
+        } else {
+            Some(x)
+        }
+    }
+
+    /// Walk nodes starting at `from`, forwards or in reverse
+    pub fn walk(&self, from: Option<usize>, rev: bool) -> Walk<'_> {
+        Walk {
+            list: self,
+            at: from,
+            rev,
+        }
+    }
+
+    pub fn first(&self) -> Option<usize> {
+        self.next(HEAD, 0)
+    }
+
+    pub fn last(&self) -> Option<usize> {
+        self.tail
+    }
+}
+
+pub struct Walk<'a> {
+    list: &'a SkipList,
+    at: Option<usize>,
+    rev: bool,
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = (&'a str, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let x = self.at?;
+        self.at = if self.rev {
+            self.list.nodes[x].prev
+        } else {
+            self.list.next(x, 0)
+        };
+        Some(self.list.get(x))
+    }
+}
+
+/// Members ordered by score, indexed by a hash map for constant time score
+/// lookups and a skip list for ordered and ranked access
+#[derive(Debug, Clone, Default)]
+pub struct SortedSet {
+    scores: HashMap<String, f64>,
+    list: SkipList,
+    positions: Positions,
+}
+
+impl PartialEq for SortedSet {
+    fn eq(&self, other: &SortedSet) -> bool {
+        self.scores == other.scores
+    }
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).cloned()
+    }
+
+    /// Set the score of a member, returning whether it is new
+    pub fn insert(&mut self, member: String, score: f64) -> bool {
+        match self.scores.insert(member.clone(), score) {
+            Some(old) if old == score => false,
+            Some(old) => {
+                self.list.remove(old, &member);
+                self.list.insert(score, member);
+                false
+            }
+            None => {
+                self.positions.insert(&member);
+                self.list.insert(score, member);
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.positions.remove(member);
+                self.list.remove(score, member)
+            }
+            None => false,
+        }
+    }
+
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        self.list.rank(self.score(member)?, member)
+    }
+
+    /// Members in order, lowest score first
+    pub fn iter(&self) -> Walk<'_> {
+        self.list.walk(self.list.first(), false)
+    }
+
+    /// Members selected by `range`, after applying its limit
+    pub fn range(&self, range: &ZRange) -> Vec<(String, f64)> {
+        let (offset, count) = match range.limit {
+            Some((offset, _)) if offset < 0 => return Vec::new(),
+            Some((offset, count)) if count >= 0 => (offset as usize, count as usize),
+            Some((offset, _)) => (offset as usize, usize::MAX),
+            None => (0, usize::MAX),
+        };
+        let rev = range.rev;
+        let list = &self.list;
+        let selected: Vec<(&str, f64)> = match range.by {
+            RangeBy::Index(start, stop) => match list::range(self.len(), start, stop) {
+                Some((start, stop)) => {
+                    let rank = if rev { self.len() - 1 - start } else { start };
+                    list.walk(list.by_rank(rank), rev)
+                        .take(stop - start + 1)
+                        .collect()
+                }
+                None => Vec::new(),
+            },
+            RangeBy::Score(ref min, ref max) => {
+                let from = if rev {
+                    list.last_where(|_, s| below_max(&s, max))
+                } else {
+                    list.first_where(|_, s| above_min(&s, min))
+                };
+                list.walk(from, rev)
+                    .skip(offset)
+                    .take_while(|(_, s)| above_min(s, min) && below_max(s, max))
+                    .take(count)
+                    .collect()
+            }
+            RangeBy::Lex(ref min, ref max) => {
+                let from = if rev {
+                    list.last_where(|m, _| below_max(&m, &max.as_ref().map(|s| s.as_str())))
+                } else {
+                    list.first_where(|m, _| above_min(&m, &min.as_ref().map(|s| s.as_str())))
+                };
+                let (min, max) = (
+                    min.as_ref().map(|s| s.as_str()),
+                    max.as_ref().map(|s| s.as_str()),
+                );
+                list.walk(from, rev)
+                    .skip(offset)
+                    .take_while(|(m, _)| above_min(m, &min) && below_max(m, &max))
+                    .take(count)
+                    .collect()
+            }
+        };
+        selected
+            .into_iter()
+            .map(|(m, s)| (m.to_string(), s))
+            .collect()
+    }
+
+    /// Remove and return up to `count` members from the lowest or highest end
+    pub fn pop(&mut self, count: usize, max: bool) -> Vec<(String, f64)> {
+        let from = if max {
+            self.list.last()
+        } else {
+            self.list.first()
+        };
+        let popped: Vec<(String, f64)> = self
+            .list
+            .walk(from, max)
+            .take(count)
+            .map(|(m, s)| (m.to_string(), s))
+            .collect();
+        for (member, _) in &popped {
+            self.remove(member);
+        }
+        popped
+    }
+}
+
+/// Flatten member/score pairs into a reply, optionally leaving out scores
+fn reply(pairs: Vec<(String, f64)>, with_scores: bool) -> Value {
+    let mut reply = Vec::new();
+    for (member, score) in pairs {
+        reply.push(Value::Text(member));
+        if with_scores {
+            reply.push(Value::Text(format_score(score)));
+        }
+    }
+    Value::Array(reply)
+}
+
+impl Database {
+    pub fn zset(&self, key: &str) -> Result<Option<&SortedSet>, Error> {
+        match self.data.get(key).map(|e| &e.value) {
+            Some(Value::SortedSet(zset)) => Ok(Some(zset)),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn zset_mut(&mut self, key: &str) -> Result<Option<&mut SortedSet>, Error> {
+        match self.data.get_mut(key).map(|e| &mut e.value) {
+            Some(Value::SortedSet(zset)) => Ok(Some(zset)),
+            Some(_) => Err(Error::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Sorted sets are never stored empty, so drop `key` once its last
+    /// member is gone
+    fn remove_if_empty_zset(&mut self, key: &str) {
+        if let Ok(Some(zset)) = self.zset(key) {
+            if zset.is_empty() {
+                self.data.remove(key);
+            }
+        }
+    }
+
+    /// Add or update members, honouring the NX/XX/GT/LT/CH/INCR flags. With
+    /// INCR, replies with the new score, or null if the update was refused.
+    pub fn zadd(
+        &mut self,
+        key: Key,
+        flags: ZAddFlags,
+        pairs: Vec<(f64, String)>,
+    ) -> Result<Value, Error> {
+        self.zset(&key)?;
+        let mut zset = match self.data.remove(&key) {
+            Some(Entry {
+                value: Value::SortedSet(zset),
+                ..
+            }) => zset,
+            _ => SortedSet::default(),
+        };
+        let (mut added, mut changed) = (0, 0);
+        let mut result = Ok(Value::Null);
+        for (score, member) in pairs {
+            let current = zset.score(&member);
+            let score = match (current, flags.incr) {
+                (Some(cur), true) => cur + score,
+                _ => score,
+            };
+            if score.is_nan() {
+                result = Err(Error::NotANumber);
+                break;
+            }
+            let refused = match current {
+                None => flags.xx,
+                Some(cur) => flags.nx || (flags.gt && score <= cur) || (flags.lt && score >= cur),
+            };
+            if refused {
+                continue;
+            }
+            match current {
+                None => added += 1,
+                Some(cur) if cur != score => changed += 1,
+                Some(_) => (),
+            }
+            zset.insert(member, score);
+            result = Ok(Value::Text(format_score(score)));
+        }
+        if !zset.is_empty() {
+            self.data.insert(key, Entry::new(Value::SortedSet(zset)));
+        }
+        match (flags.incr, flags.ch) {
+            (true, _) => result,
+            (false, true) => result.map(|_| Value::Integer(added + changed)),
+            (false, false) => result.map(|_| Value::Integer(added)),
+        }
+    }
+
+    pub fn zscore(&self, key: &str, member: &str) -> Result<Value, Error> {
+        Ok(self
+            .zset(key)?
+            .and_then(|z| z.score(member))
+            .map_or(Value::Null, |s| Value::Text(format_score(s))))
+    }
+
+    pub fn zrank(&self, key: &str, member: &str) -> Result<Value, Error> {
+        Ok(self
+            .zset(key)?
+            .and_then(|z| z.rank(member))
+            .map_or(Value::Null, |r| Value::Integer(r as i64)))
+    }
+
+    pub fn zcard(&self, key: &str) -> Result<Value, Error> {
+        Ok(Value::Integer(
+            self.zset(key)?.map(|z| z.len()).unwrap_or(0) as i64,
+        ))
+    }
+
+    pub fn zrange(&self, key: &str, range: &ZRange) -> Result<Value, Error> {
+        let pairs = self.zset(key)?.map(|z| z.range(range)).unwrap_or_default();
+        Ok(reply(pairs, range.with_scores))
+    }
+
+    /// Iterate over the members of a sorted set, replying with each member
+    /// followed by its score
+    pub fn zscan(&self, key: &str, scan: &Scan) -> Result<Value, Error> {
+        let zset = match self.zset(key)? {
+            Some(zset) => zset,
+            None => return Ok(keys::reply(0, Vec::new())),
+        };
+        let (next, members) = zset.positions.page(scan);
+        let found = members
+            .into_iter()
+            .flat_map(|m| {
+                let score = zset.score(m).expect("Scanned member");
+                vec![Value::Text(m.into()), Value::Text(format_score(score))]
+            })
+            .collect();
+        Ok(keys::reply(next, found))
+    }
+
+    pub fn zrem(&mut self, key: &str, members: &[String]) -> Result<Value, Error> {
+        let removed = match self.zset_mut(key)? {
+            Some(zset) => members.iter().filter(|m| zset.remove(m)).count(),
+            None => 0,
+        };
+        self.remove_if_empty_zset(key);
+        Ok(Value::Integer(removed as i64))
+    }
+
+    pub fn zremrangebyscore(
+        &mut self,
+        key: &str,
+        min: Bound<f64>,
+        max: Bound<f64>,
+    ) -> Result<Value, Error> {
+        let range = ZRange {
+            by: RangeBy::Score(min, max),
+            rev: false,
+            limit: None,
+            with_scores: false,
+        };
+        let removed = match self.zset_mut(key)? {
+            Some(zset) => {
+                let members = zset.range(&range);
+                members.iter().filter(|(m, _)| zset.remove(m)).count()
+            }
+            None => 0,
+        };
+        self.remove_if_empty_zset(key);
+        Ok(Value::Integer(removed as i64))
+    }
+
+    /// Pop the lowest (or with `max`, highest) scoring members
+    pub fn zpop(&mut self, key: &str, count: Option<usize>, max: bool) -> Result<Value, Error> {
+        let popped = match self.zset_mut(key)? {
+            Some(zset) => zset.pop(count.unwrap_or(1), max),
+            None => Vec::new(),
+        };
+        self.remove_if_empty_zset(key);
+        Ok(reply(popped, true))
+    }
+
+    /// Combine the sorted sets (or plain sets, with every score being 1) at
+    /// `keys` into `dest`, returning the size of the result
+    pub fn zstore(
+        &mut self,
+        dest: Key,
+        keys: &[String],
+        weights: &[f64],
+        aggregate: Aggregate,
+        inter: bool,
+    ) -> Result<Value, Error> {
+        let mut inputs: Vec<HashMap<String, f64>> = Vec::new();
+        for key in keys {
+            inputs.push(match self.data.get(key).map(|e| &e.value) {
+                Some(Value::SortedSet(zset)) => zset.scores.clone(),
+                Some(Value::Set(set)) => set.members().into_iter().map(|m| (m, 1.0)).collect(),
+                Some(_) => return Err(Error::WrongType),
+                None => HashMap::new(),
+            });
+        }
+        let weighted = |i: usize, score: f64| {
+            let s = score * weights.get(i).cloned().unwrap_or(1.0);
+            // inf * 0 is defined as 0 rather than NaN
+            if s.is_nan() {
+                0.0
+            } else {
+                s
+            }
+        };
+        let combine = |a: f64, b: f64| match aggregate {
+            Aggregate::Sum => {
+                let s = a + b;
+                // -inf + inf is defined as 0 rather than NaN
+                if s.is_nan() {
+                    0.0
+                } else {
+                    s
+                }
+            }
+            Aggregate::Min => a.min(b),
+            Aggregate::Max => a.max(b),
+        };
+
+        let mut result: HashMap<String, f64> = HashMap::new();
+        for (i, input) in inputs.iter().enumerate() {
+            for (member, &score) in input {
+                if inter && !inputs.iter().all(|other| other.contains_key(member)) {
+                    continue;
+                }
+                let score = weighted(i, score);
+                let combined = match result.get(member) {
+                    Some(&prev) => combine(prev, score),
+                    None => score,
+                };
+                result.insert(member.clone(), combined);
+            }
+        }
+
+        let len = result.len();
+        self.data.remove(&dest);
+        if len > 0 {
+            let mut zset = SortedSet::default();
+            for (member, score) in result {
+                zset.insert(member, score);
+            }
+            self.data.insert(dest, Entry::new(Value::SortedSet(zset)));
+        }
+        Ok(Value::Integer(len as i64))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn zset(pairs: &[(&str, f64)]) -> SortedSet {
+        let mut z = SortedSet::default();
+        for (m, s) in pairs {
+            z.insert(m.to_string(), *s);
+        }
+        z
+    }
+
+    fn members(pairs: Vec<(String, f64)>) -> Vec<String> {
+        pairs.into_iter().map(|(m, _)| m).collect()
+    }
+
+    fn index(start: i64, stop: i64, rev: bool) -> ZRange {
+        ZRange {
+            by: RangeBy::Index(start, stop),
+            rev,
+            limit: None,
+            with_scores: false,
+        }
+    }
+
+    #[test]
+    fn skiplist_ranks() {
+        let mut z = SortedSet::default();
+        for i in 0..1000 {
+            z.insert(format!("m{}", i), ((i * 7919) % 1000) as f64);
+        }
+        for i in (0..1000).step_by(3) {
+            z.remove(&format!("m{}", i));
+        }
+        let ordered: Vec<(String, f64)> = z.iter().map(|(m, s)| (m.to_string(), s)).collect();
+        assert_eq!(ordered.len(), z.len());
+        for (rank, (member, score)) in ordered.iter().enumerate() {
+            assert_eq!(z.rank(member), Some(rank));
+            assert_eq!(
+                z.list.get(z.list.by_rank(rank).unwrap()),
+                (member.as_str(), *score)
+            );
+        }
+        assert!(ordered.windows(2).all(|w| w[0].1 < w[1].1));
+    }
+
+    #[test]
+    fn ranges() {
+        let z = zset(&[("a", 1.0), ("b", 2.0), ("c", 3.0), ("d", 4.0)]);
+        assert_eq!(members(z.range(&index(1, -2, false))), vec!["b", "c"]);
+        assert_eq!(members(z.range(&index(0, 1, true))), vec!["d", "c"]);
+        let mut by_score = ZRange {
+            by: RangeBy::Score(Bound::Excluded(1.0), Bound::Unbounded),
+            rev: false,
+            limit: Some((1, 1)),
+            with_scores: true,
+        };
+        assert_eq!(members(z.range(&by_score)), vec!["c"]);
+        by_score.rev = true;
+        by_score.limit = None;
+        assert_eq!(members(z.range(&by_score)), vec!["d", "c", "b"]);
+
+        let z = zset(&[("a", 0.0), ("b", 0.0), ("c", 0.0), ("d", 0.0)]);
+        let by_lex = ZRange {
+            by: RangeBy::Lex(Bound::Included("b".into()), Bound::Excluded("d".into())),
+            rev: false,
+            limit: None,
+            with_scores: false,
+        };
+        assert_eq!(members(z.range(&by_lex)), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn add_flags() {
+        let mut db = Database::new();
+        let add =
+            |db: &mut Database, flags, s: f64| db.zadd("z".into(), flags, vec![(s, "m".into())]);
+        let none = ZAddFlags::default();
+        assert_eq!(add(&mut db, none, 5.0), Ok(Value::Integer(1)));
+        let gt_ch = ZAddFlags {
+            gt: true,
+            ch: true,
+            ..none
+        };
+        assert_eq!(add(&mut db, gt_ch, 4.0), Ok(Value::Integer(0)));
+        assert_eq!(add(&mut db, gt_ch, 6.0), Ok(Value::Integer(1)));
+        let incr = ZAddFlags { incr: true, ..none };
+        assert_eq!(add(&mut db, incr, 1.5), Ok(Value::Text("7.5".into())));
+        let nx_incr = ZAddFlags { nx: true, ..incr };
+        assert_eq!(add(&mut db, nx_incr, 1.0), Ok(Value::Null));
+        let xx = ZAddFlags { xx: true, ..none };
+        assert_eq!(
+            db.zadd("y".into(), xx, vec![(1.0, "m".into())]),
+            Ok(Value::Integer(0))
+        );
+        assert!(db.read("y").is_none());
+        db.zadd("z".into(), none, vec![(f64::INFINITY, "inf".into())])
+            .unwrap();
+        assert_eq!(
+            db.zadd("z".into(), incr, vec![(f64::NEG_INFINITY, "inf".into())]),
+            Err(Error::NotANumber)
+        );
+    }
+
+    #[test]
+    fn pop_and_remove() {
+        let mut db = Database::new();
+        let pairs = vec![(1.0, "a".into()), (2.0, "b".into()), (3.0, "c".into())];
+        db.zadd("z".into(), ZAddFlags::default(), pairs).unwrap();
+        assert_eq!(
+            db.zpop("z", None, true),
+            Ok(Value::Array(vec![
+                Value::Text("c".into()),
+                Value::Text("3".into())
+            ]))
+        );
+        assert_eq!(db.zrank("z", "b"), Ok(Value::Integer(1)));
+        assert_eq!(
+            db.zremrangebyscore("z", Bound::Unbounded, Bound::Included(1.0)),
+            Ok(Value::Integer(1))
+        );
+        assert_eq!(db.zrem("z", &["b".into()]), Ok(Value::Integer(1)));
+        assert!(db.read("z").is_none());
+    }
+
+    #[test]
+    fn store() {
+        let mut db = Database::new();
+        let pairs = vec![(1.0, "a".into()), (2.0, "b".into())];
+        db.zadd("z".into(), ZAddFlags::default(), pairs).unwrap();
+        db.sadd("s".into(), vec!["b".into(), "c".into()]).unwrap();
+        let keys = vec!["z".to_string(), "s".to_string()];
+        assert_eq!(
+            db.zstore("u".into(), &keys, &[2.0, 10.0], Aggregate::Sum, false),
+            Ok(Value::Integer(3))
+        );
+        assert_eq!(db.zscore("u", "b"), Ok(Value::Text("14".into())));
+        assert_eq!(
+            db.zstore("i".into(), &keys, &[], Aggregate::Max, true),
+            Ok(Value::Integer(1))
+        );
+        assert_eq!(db.zscore("i", "b"), Ok(Value::Text("2".into())));
+    }
+}